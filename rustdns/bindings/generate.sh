@@ -0,0 +1,16 @@
+#!/usr/bin/env bash
+# Generate the Swift and Kotlin bindings for the uniffi interface (src/mobile.rs) from the built
+# library, into bindings/swift and bindings/kotlin. Pass --release to bind a release build.
+set -e
+cd "$(dirname "$0")/.."
+profile=debug
+if [ "$1" = "--release" ]; then profile=release; fi
+cargo build --lib --features uniffi ${1:+"$1"}
+case "$(uname)" in
+    Darwin) lib=target/$profile/librustdns.dylib ;;
+    *) lib=target/$profile/librustdns.so ;;
+esac
+for language in swift kotlin; do
+    cargo run --quiet --features uniffi-bindgen --bin uniffi-bindgen -- generate --library "$lib" --language "$language" --out-dir "bindings/$language"
+done
+echo "bindings written to bindings/swift and bindings/kotlin"
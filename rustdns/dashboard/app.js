@@ -0,0 +1,170 @@
+// PiBlock dashboard: plain script against the /api/v1 control API. The API token, if the
+// server needs one, is kept in localStorage.
+"use strict";
+
+const API = "/api/v1";
+const $ = (id) => document.getElementById(id);
+
+function token() {
+  return localStorage.getItem("piblock-token") || "";
+}
+
+function showError(msg) {
+  $("error").textContent = msg;
+  $("error").hidden = !msg;
+}
+
+async function api(path, opts = {}) {
+  const headers = Object.assign({}, opts.headers);
+  if (token()) headers.Authorization = "Bearer " + token();
+  if (opts.json !== undefined) {
+    headers["Content-Type"] = "application/json";
+    opts.body = JSON.stringify(opts.json);
+  }
+  const resp = await fetch(API + path, { method: opts.method || "GET", headers, body: opts.body });
+  if (resp.status === 401) {
+    showError("The server needs an API token. Tap “Token” to enter one.");
+    throw new Error("unauthorized");
+  }
+  const data = await resp.json();
+  if (data.ok === false) {
+    showError(data.error || "request failed");
+    throw new Error(data.error);
+  }
+  showError("");
+  return data;
+}
+
+const post = (path, json) => api(path, { method: "POST", json: json || {} });
+
+function el(tag, attrs, ...children) {
+  const e = document.createElement(tag);
+  for (const [k, v] of Object.entries(attrs || {})) {
+    if (k.startsWith("on")) e.addEventListener(k.slice(2), v);
+    else e.setAttribute(k, v);
+  }
+  for (const c of children) e.append(c);
+  return e;
+}
+
+function button(label, onclick) {
+  return el("button", { class: "small", onclick }, label);
+}
+
+// --- stats and blocking state ---
+
+async function refreshStats() {
+  const s = await api("/stats");
+  $("queries").textContent = s.queries.toLocaleString();
+  $("blocked").textContent = s.blocked.toLocaleString();
+  $("percent").textContent = s.queries ? (100 * s.blocked / s.queries).toFixed(1) + "%" : "–";
+  const lookups = s.cache.hits + s.cache.misses;
+  $("cache").textContent = lookups ? (100 * s.cache.hits / lookups).toFixed(0) + "%" : "–";
+  const status = $("status");
+  if (s.blocking_enabled) {
+    status.textContent = s.audit ? "Audit" : "Blocking";
+    status.className = "badge on";
+  } else {
+    const left = s.seconds_remaining;
+    status.textContent = left == null ? "Paused" : "Paused " + Math.ceil(left / 60) + " min";
+    status.className = "badge off";
+  }
+  $("audit").checked = s.audit;
+}
+
+async function refreshMode() {
+  // the mode is part of /config, which read-only tokens can't see
+  try {
+    const c = await api("/config");
+    $("mode").value = c.blocking.mode;
+  } catch (e) {
+    $("mode").disabled = true;
+  }
+}
+
+// --- query log ---
+
+async function refreshLog() {
+  const params = new URLSearchParams({ limit: "50" });
+  for (const [key, id] of [["domain", "q-domain"], ["client", "q-client"], ["action", "q-action"]]) {
+    if ($(id).value.trim()) params.set(key, $(id).value.trim());
+  }
+  const data = await api("/queries?" + params);
+  const rows = data.queries.map((q) => {
+    const blocked = q.action === "blocked";
+    const act = blocked
+      ? button("Allow", () => post("/allow", { domain: q.name }).then(refreshLists))
+      : button("Block", () => post("/add", { pattern: q.name }).then(refreshLists));
+    return el("tr", { class: blocked ? "blocked" : "" },
+      el("td", { class: "time" }, new Date(q.time * 1000).toLocaleTimeString()),
+      el("td", { class: "client", title: q.client }, q.client_name || q.client),
+      el("td", { class: "name" }, q.name + " " + q.qtype),
+      el("td", { class: "result" }, q.action),
+      el("td", {}, act));
+  });
+  $("log").replaceChildren(...rows);
+}
+
+// --- lists ---
+
+async function refreshLists() {
+  const q = $("search").value.trim();
+  const lists = await api("/lists?limit=50" + (q ? "&q=" + encodeURIComponent(q) : ""));
+  $("rules").replaceChildren(
+    ...lists.patterns.map((p) => el("li", {}, p, button("Remove", () => post("/remove", { pattern: p }).then(refreshLists)))),
+    ...lists.tlds.map((t) => el("li", {}, "." + t, button("Remove", () => post("/remove", { tld: t }).then(refreshLists)))),
+  );
+  const allow = await api("/allowlist");
+  $("allowlist").replaceChildren(...allow.entries.map((e) => el("li", {},
+    e.domain + (e.seconds_remaining != null ? " (" + Math.ceil(e.seconds_remaining / 60) + " min)" : ""),
+    button("Remove", () => post("/allow/remove", { domain: e.domain }).then(refreshLists)))));
+  const groups = await api("/groups");
+  $("groups").replaceChildren(...groups.groups.map((g) => el("li", {},
+    g.name + " (" + g.count.toLocaleString() + ")",
+    button(g.enabled ? "Disable" : "Enable", () => post("/groups/" + encodeURIComponent(g.name) + (g.enabled ? "/disable" : "/enable")).then(refreshLists)))));
+}
+
+// --- controls ---
+
+function wire() {
+  for (const b of document.querySelectorAll("[data-pause]")) {
+    b.addEventListener("click", () => post("/disable?duration=" + b.dataset.pause).then(refreshStats));
+  }
+  $("resume").addEventListener("click", () => post("/enable").then(refreshStats));
+  $("mode").addEventListener("change", () => post("/mode", { mode: $("mode").value }));
+  $("audit").addEventListener("change", () => post("/audit", { enabled: $("audit").checked }).then(refreshStats));
+  $("add").addEventListener("submit", (ev) => {
+    ev.preventDefault();
+    post("/add", { pattern: $("add-pattern").value.trim() }).then(() => { $("add-pattern").value = ""; refreshLists(); });
+  });
+  $("allow").addEventListener("click", () => {
+    if (!$("add-pattern").value.trim()) return;
+    post("/allow", { domain: $("add-pattern").value.trim() }).then(() => { $("add-pattern").value = ""; refreshLists(); });
+  });
+  let searchTimer;
+  $("search").addEventListener("input", () => {
+    clearTimeout(searchTimer);
+    searchTimer = setTimeout(refreshLists, 300);
+  });
+  for (const id of ["q-domain", "q-client", "q-action"]) $(id).addEventListener("change", refreshLog);
+  $("token").addEventListener("click", () => {
+    const t = prompt("API token (leave empty if the server has none)", token());
+    if (t === null) return;
+    localStorage.setItem("piblock-token", t.trim());
+    start();
+  });
+}
+
+const ignore = () => {};
+
+function start() {
+  refreshStats().catch(ignore);
+  refreshMode().catch(ignore);
+  refreshLog().catch(ignore);
+  refreshLists().catch(ignore);
+}
+
+wire();
+start();
+setInterval(() => refreshStats().catch(ignore), 5000);
+setInterval(() => refreshLog().catch(ignore), 5000);
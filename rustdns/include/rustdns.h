@@ -6,8 +6,90 @@
 extern "C" {
 #endif
 
+// Return codes. Functions that act on the server return RUSTDNS_ERR_NOT_RUNNING when there is
+// none; rustdns_last_error_message has the details of any failure.
+#define RUSTDNS_OK 0
+#define RUSTDNS_ERR_ALREADY_RUNNING 1
+#define RUSTDNS_ERR_INVALID_ARGUMENT 2
+#define RUSTDNS_ERR_INVALID_ADDRESS 3  // not an IP address with a port
+#define RUSTDNS_ERR_BIND 4             // control API or DNS address couldn't be bound
+#define RUSTDNS_ERR_STARTUP 5          // runtime or thread creation failed, or the server stopped
+#define RUSTDNS_ERR_INVALID_CONFIG 6   // bad JSON or setting, e.g. an unknown RUSTDNS_BLOCKING_MODE
+#define RUSTDNS_ERR_LOGGER 7           // the process has a logger of its own
+#define RUSTDNS_ERR_NOT_RUNNING 8      // the call acts on the server and none is running
+
+// Start the server on a background thread and return once it listens (after the lists load);
+// RUSTDNS_OK or an error code. It can be started again after rustdns_stop.
 int rustdns_start(const char* http_addr, const char* udp_bind);
+
+// Scheduling for the server threads (Linux/Android). 0 keeps the inherited value.
+typedef struct RustdnsThreadOptions {
+    int nice;                        // -20..19; negative values need CAP_SYS_NICE
+    unsigned long long cpu_affinity; // bit n = CPU n
+} RustdnsThreadOptions;
+
+// Like rustdns_start; `options` may be NULL.
+int rustdns_start_with_options(const char* http_addr, const char* udp_bind, const RustdnsThreadOptions* options);
+
+// Like rustdns_start, configured by a JSON object instead of RUSTDNS_* variables, e.g.
+// {"udp_bind": "127.0.0.1:5353", "upstream": "9.9.9.9", "blocking_mode": "nx",
+//  "settings": {"RUSTDNS_CACHE_SIZE": 5000}}. See the README for the keys.
+int rustdns_start_with_config(const char* config_json);
+// Stop the server and return once it has (queries in flight are answered first), so it can be
+// started again; 0 on success, RUSTDNS_ERR_NOT_RUNNING if not running.
 int rustdns_stop();
+// Pause blocking for `seconds` (0 = until resumed); 0 on success, RUSTDNS_ERR_NOT_RUNNING if not
+// running.
+int rustdns_pause(unsigned int seconds);
+// Resume blocking after a pause; 0 on success, RUSTDNS_ERR_NOT_RUNNING if not running.
+int rustdns_resume();
+
+// The server's counters as a JSON object, or NULL if not running; free with rustdns_free_string.
+char* rustdns_stats_json();
+// Free a string returned by rustdns; NULL is ignored.
+void rustdns_free_string(char* s);
+// 1 if the server is running, else 0.
+int rustdns_is_running();
+// 1 if blocking is on, 0 if paused, -1 if not running.
+int rustdns_blocking_enabled();
+
+// List management, as POST /add, /remove, /reload and /mode: 0 on success, RUSTDNS_ERR_NOT_RUNNING
+// if not running, 2 if an argument is invalid (or, for remove, there's no such rule; for reload,
+// the lists couldn't be read).
+int rustdns_add_pattern(const char* pattern, const char* group); // group may be NULL for "custom"
+int rustdns_remove_pattern(const char* pattern);
+int rustdns_reload_lists();
+// mode: "nx", "null", "nodata" or "redirect"; block_ip (IPv4, redirect only) may be NULL
+int rustdns_set_mode(const char* mode, const char* block_ip);
+
+// A query passed to the query callback; the strings are valid during the call only.
+typedef struct RustdnsQueryEvent {
+    unsigned long long id;   // query log id, as in GET /queries
+    unsigned long long time; // unix seconds
+    const char* name;
+    const char* qtype;       // "A", "AAAA", ...
+    const char* client;      // source address
+    const char* action;      // "blocked", "cached", "forwarded", ...
+    double latency_ms;
+} RustdnsQueryEvent;
+
+typedef void (*RustdnsQueryCallback)(const RustdnsQueryEvent* event, void* user_data);
+
+// Call `callback` for each query answered, one call at a time on a thread of rustdns' own; NULL
+// stops the calls. Kept across stop and start. Once it returns the previous callback won't be
+// called again (unless called from within it). Returns 0.
+int rustdns_set_query_callback(RustdnsQueryCallback callback, void* user_data);
+
+// Nothing is logged until one of these. Levels: 1 = error .. 5 = trace, 0 = RUSTDNS_LOG_LEVEL.
+// Calling either again changes the level and destination.
+int rustdns_init_logging(int max_level); // to stderr
+// Each line (without newline, valid during the call) goes to `callback`; NULL goes back to stderr.
+typedef void (*RustdnsLogCallback)(int level, const char* message, void* user_data);
+int rustdns_set_log_callback(RustdnsLogCallback callback, void* user_data, int max_level);
+
+// Why the last failed rustdns call on this thread failed, or NULL. Valid until the next failure
+// on this thread; don't free it.
+const char* rustdns_last_error_message();
 
 #ifdef __cplusplus
 }
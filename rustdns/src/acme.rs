@@ -0,0 +1,299 @@
+use anyhow::{anyhow, Result};
+#[cfg(feature = "acme")]
+use anyhow::Context;
+#[cfg(feature = "acme")]
+use instant_acme::{Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, OrderStatus};
+#[cfg(feature = "acme")]
+use rcgen::{Certificate, CertificateParams, DistinguishedName};
+use serde::Serialize;
+use std::collections::HashMap;
+#[cfg(feature = "acme")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::tls::CertStore;
+use tokio::task::JoinSet;
+
+// Let's Encrypt certificates are valid for 90 days.
+const CERT_LIFETIME: Duration = Duration::from_secs(90 * 86400);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub enum ChallengeKind {
+    // TXT records for `_acme-challenge.<domain>` answered by our own DNS server; the name has to
+    // be delegated (NS or CNAME) to this resolver.
+    #[serde(rename = "dns-01")]
+    Dns01,
+    // Key authorizations served at /.well-known/acme-challenge/ by the HTTP server; port 80 has to
+    // reach it.
+    #[serde(rename = "http-01")]
+    Http01,
+}
+
+// Certificate provisioning for the TLS listeners. Enabled by RUSTDNS_ACME_DOMAINS (comma
+// separated); RUSTDNS_ACME_EMAIL, RUSTDNS_ACME_DIRECTORY (default Let's Encrypt production, or
+// "staging"), RUSTDNS_ACME_CHALLENGE=dns-01|http-01, RUSTDNS_ACME_RENEW_DAYS (default 60) and
+// RUSTDNS_TLS_DIR (default ./tls, where cert.pem and key.pem are written) tune it.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub email: Option<String>,
+    pub directory: String,
+    pub challenge: ChallengeKind,
+    pub renew_after: Duration,
+    pub dir: PathBuf,
+}
+
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+impl AcmeConfig {
+    pub fn from_env() -> Option<Self> {
+        let domains: Vec<String> = crate::envvars::var("RUSTDNS_ACME_DOMAINS").ok()?
+            .split(',')
+            .map(|d| d.trim().trim_end_matches('.').to_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+        if domains.is_empty() { return None }
+        let directory = match crate::envvars::var("RUSTDNS_ACME_DIRECTORY").as_deref() {
+            Ok("staging") => LETS_ENCRYPT_STAGING.to_string(),
+            Ok(url) if !url.is_empty() => url.to_string(),
+            _ => LETS_ENCRYPT.to_string(),
+        };
+        let challenge = match crate::envvars::var("RUSTDNS_ACME_CHALLENGE").as_deref() {
+            Ok("http-01") | Ok("http") => ChallengeKind::Http01,
+            _ => ChallengeKind::Dns01,
+        };
+        let renew_days = crate::envvars::var("RUSTDNS_ACME_RENEW_DAYS").ok().and_then(|v| v.parse().ok()).unwrap_or(60u64);
+        Some(AcmeConfig {
+            domains,
+            email: crate::envvars::var("RUSTDNS_ACME_EMAIL").ok().filter(|e| !e.is_empty()),
+            directory,
+            challenge,
+            renew_after: Duration::from_secs(renew_days * 86400),
+            dir: PathBuf::from(crate::envvars::var("RUSTDNS_TLS_DIR").unwrap_or_else(|_| "./tls".to_string())),
+        })
+    }
+
+    pub fn cert_path(&self) -> PathBuf { self.dir.join("cert.pem") }
+    pub fn key_path(&self) -> PathBuf { self.dir.join("key.pem") }
+    fn account_path(&self) -> PathBuf { self.dir.join("acme-account.json") }
+    fn issued_path(&self) -> PathBuf { self.dir.join("acme-issued") }
+}
+
+// Reported by GET /acme.
+#[derive(Clone, Default, Serialize)]
+pub struct AcmeStatus {
+    pub enabled: bool,
+    pub domains: Vec<String>,
+    pub challenge: Option<ChallengeKind>,
+    pub cert_path: Option<String>,
+    pub issued_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    pub renew_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Acme {
+    pub config: Option<AcmeConfig>,
+    // outstanding challenge responses: TXT values by name (several when an order covers both
+    // `example.com` and `*.example.com`), key authorizations by token
+    txt: RwLock<HashMap<String, Vec<String>>>,
+    http: RwLock<HashMap<String, String>>,
+    last_error: Mutex<Option<String>>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Acme {
+    pub fn from_env() -> Self {
+        Acme { config: AcmeConfig::from_env(), ..Default::default() }
+    }
+
+    // The dns-01 TXT values to answer for `name`, while an order is in progress.
+    pub fn txt_records(&self, name: &str) -> Option<Vec<String>> {
+        let txt = self.txt.read().unwrap();
+        if txt.is_empty() { return None }
+        txt.get(&name.trim_end_matches('.').to_lowercase()).cloned()
+    }
+
+    // The http-01 key authorization for `token`, while an order is in progress.
+    pub fn http_token(&self, token: &str) -> Option<String> {
+        self.http.read().unwrap().get(token).cloned()
+    }
+
+    fn issued_at(&self) -> Option<u64> {
+        let cfg = self.config.as_ref()?;
+        if !cfg.cert_path().exists() { return None }
+        std::fs::read_to_string(cfg.issued_path()).ok()?.trim().parse().ok()
+    }
+
+    pub fn status(&self) -> AcmeStatus {
+        let Some(cfg) = &self.config else { return AcmeStatus::default() };
+        let issued_at = self.issued_at();
+        AcmeStatus {
+            enabled: true,
+            domains: cfg.domains.clone(),
+            challenge: Some(cfg.challenge),
+            cert_path: Some(cfg.cert_path().display().to_string()),
+            issued_at,
+            expires_at: issued_at.map(|t| t + CERT_LIFETIME.as_secs()),
+            renew_at: issued_at.map(|t| t + cfg.renew_after.as_secs()),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+
+    fn due(&self) -> bool {
+        let Some(cfg) = &self.config else { return false };
+        match self.issued_at() {
+            Some(t) => now_secs() >= t + cfg.renew_after.as_secs(),
+            None => true,
+        }
+    }
+
+    #[cfg(feature = "acme")]
+    async fn account(&self, cfg: &AcmeConfig) -> Result<Account> {
+        if let Ok(raw) = tokio::fs::read_to_string(cfg.account_path()).await {
+            let creds: AccountCredentials = serde_json::from_str(&raw).context("parsing ACME account")?;
+            return Ok(Account::from_credentials(creds).await?);
+        }
+        let contact = cfg.email.as_ref().map(|e| format!("mailto:{}", e));
+        let contact: Vec<&str> = contact.iter().map(|c| c.as_str()).collect();
+        let (account, creds) = Account::create(
+            &NewAccount { contact: &contact, terms_of_service_agreed: true, only_return_existing: false },
+            &cfg.directory,
+            None,
+        ).await?;
+        write_private(&cfg.account_path(), serde_json::to_string(&creds)?.as_bytes()).await?;
+        Ok(account)
+    }
+
+    // Run one order for all configured domains and write the resulting certificate and key.
+    #[cfg(feature = "acme")]
+    async fn provision(&self) -> Result<()> {
+        let cfg = self.config.as_ref().ok_or_else(|| anyhow!("ACME is not configured"))?;
+        tokio::fs::create_dir_all(&cfg.dir).await?;
+        let account = self.account(cfg).await?;
+        let identifiers: Vec<Identifier> = cfg.domains.iter().map(|d| Identifier::Dns(d.clone())).collect();
+        let mut order = account.new_order(&NewOrder { identifiers: &identifiers }).await?;
+
+        let wanted = match cfg.challenge {
+            ChallengeKind::Dns01 => ChallengeType::Dns01,
+            ChallengeKind::Http01 => ChallengeType::Http01,
+        };
+        let mut ready = Vec::new();
+        for authz in order.authorizations().await? {
+            match authz.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                s => return Err(anyhow!("authorization for {:?} is {:?}", authz.identifier, s)),
+            }
+            let Identifier::Dns(domain) = &authz.identifier;
+            let challenge = authz.challenges.iter().find(|c| c.r#type == wanted)
+                .ok_or_else(|| anyhow!("no {:?} challenge offered for {}", cfg.challenge, domain))?;
+            let key_auth = order.key_authorization(challenge);
+            match cfg.challenge {
+                ChallengeKind::Dns01 => {
+                    let name = format!("_acme-challenge.{}", domain.trim_start_matches("*."));
+                    self.txt.write().unwrap().entry(name).or_default().push(key_auth.dns_value());
+                }
+                ChallengeKind::Http01 => {
+                    self.http.write().unwrap().insert(challenge.token.clone(), key_auth.as_str().to_string());
+                }
+            }
+            ready.push(challenge.url.clone());
+        }
+        let result = self.finish(&mut order, &ready, cfg).await;
+        self.txt.write().unwrap().clear();
+        self.http.write().unwrap().clear();
+        result
+    }
+
+    #[cfg(not(feature = "acme"))]
+    async fn provision(&self) -> Result<()> {
+        Err(anyhow!("built without the acme feature"))
+    }
+
+    #[cfg(feature = "acme")]
+    async fn finish(&self, order: &mut instant_acme::Order, ready: &[String], cfg: &AcmeConfig) -> Result<()> {
+        for url in ready {
+            order.set_challenge_ready(url).await?;
+        }
+        // back off until the CA has validated every challenge
+        let mut delay = Duration::from_secs(1);
+        loop {
+            tokio::time::sleep(delay).await;
+            let state = order.refresh().await?;
+            match state.status {
+                OrderStatus::Ready | OrderStatus::Valid => break,
+                OrderStatus::Invalid => return Err(anyhow!("order invalid: {:?}", state.error)),
+                _ if delay >= Duration::from_secs(64) => return Err(anyhow!("order still {:?} after waiting", state.status)),
+                _ => delay *= 2,
+            }
+        }
+
+        let mut params = CertificateParams::new(cfg.domains.clone());
+        params.distinguished_name = DistinguishedName::new();
+        let cert = Certificate::from_params(params)?;
+        order.finalize(&cert.serialize_request_der()?).await?;
+        let chain = loop {
+            match order.certificate().await? {
+                Some(chain) => break chain,
+                None => tokio::time::sleep(Duration::from_secs(1)).await,
+            }
+        };
+        write_private(&cfg.key_path(), cert.serialize_private_key_pem().as_bytes()).await?;
+        tokio::fs::write(cfg.cert_path(), chain).await?;
+        tokio::fs::write(cfg.issued_path(), now_secs().to_string()).await?;
+        Ok(())
+    }
+}
+
+// Write a file only the owner can read (account key, certificate key).
+#[cfg(feature = "acme")]
+async fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    tokio::fs::write(path, data).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    Ok(())
+}
+
+// Issue a certificate when none exists and renew it once it is `renew_after` old. Checks twice a
+// day; failed attempts are retried hourly and reported under `last_error` in GET /acme. New
+// certificates are loaded into `certs` right away.
+pub fn spawn_renewal(tasks: &mut JoinSet<()>, acme: Arc<Acme>, certs: Arc<CertStore>) {
+    let Some(cfg) = acme.config.clone() else { return };
+    tracing::info!("ACME enabled for {} ({:?})", cfg.domains.join(", "), cfg.challenge);
+    tasks.spawn(async move {
+        loop {
+            let mut wait = CHECK_INTERVAL;
+            if acme.due() {
+                match acme.provision().await {
+                    Ok(()) => {
+                        tracing::info!("ACME certificate for {} written to {}", cfg.domains.join(", "), cfg.cert_path().display());
+                        *acme.last_error.lock().unwrap() = None;
+                        if let Err(e) = certs.reload() {
+                            tracing::warn!("loading the new certificate failed: {:#}", e);
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("ACME certificate request failed: {:?}", e);
+                        *acme.last_error.lock().unwrap() = Some(e.to_string());
+                        wait = RETRY_INTERVAL;
+                    }
+                }
+            }
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
@@ -0,0 +1,68 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Alerts kept in memory for GET /alerts.
+const MAX_ALERTS: usize = 1000;
+// The same (kind, client, domain) alert is raised at most once per window.
+const DEDUPE_WINDOW: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Serialize)]
+pub struct Alert {
+    pub id: u64,
+    pub time: u64,
+    pub kind: &'static str,
+    pub client: String,
+    pub domain: String,
+    pub detail: Value,
+}
+
+#[derive(Default)]
+pub struct Alerts {
+    inner: Mutex<AlertsInner>,
+}
+
+#[derive(Default)]
+struct AlertsInner {
+    next_id: u64,
+    buf: VecDeque<Alert>,
+    recent: HashMap<(&'static str, String, String), Instant>,
+}
+
+impl Alerts {
+    // Record an alert unless an identical one was raised within the dedupe window.
+    // Returns the stored alert so callers can forward it.
+    pub fn push(&self, kind: &'static str, client: &str, domain: &str, detail: Value) -> Option<Alert> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let key = (kind, client.to_string(), domain.to_string());
+        if let Some(at) = inner.recent.get(&key) {
+            if now.duration_since(*at) < DEDUPE_WINDOW { return None }
+        }
+        inner.recent.retain(|_, at| now.duration_since(*at) < DEDUPE_WINDOW);
+        inner.recent.insert(key, now);
+        inner.next_id += 1;
+        let alert = Alert {
+            id: inner.next_id,
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            kind,
+            client: client.to_string(),
+            domain: domain.to_string(),
+            detail,
+        };
+        if inner.buf.len() >= MAX_ALERTS { inner.buf.pop_front(); }
+        inner.buf.push_back(alert.clone());
+        Some(alert)
+    }
+
+    // Alerts newer than `since_id`, optionally of one kind, oldest first.
+    pub fn list(&self, since_id: u64, kind: Option<&str>) -> Vec<Alert> {
+        let inner = self.inner.lock().unwrap();
+        inner.buf.iter()
+            .filter(|a| a.id > since_id && kind.map(|k| a.kind == k).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+// Longest an entry can be allowed for. The API rejects longer times; `add` clamps to it, so a
+// restored backup can't push an expiry past what an `Instant` can hold.
+pub const MAX_ALLOW_TTL: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+// Names that are never blocked, even when a list matches them. Entries are exact names or
+// `*.example.com` suffix rules and may carry an expiry, after which the background purge task
+// drops them. Enabled presets (see PRESETS) allow their rules alongside the entries.
+#[derive(Default)]
+pub struct Allowlist {
+    entries: RwLock<HashMap<String, AllowEntry>>,
+    presets: RwLock<HashSet<&'static str>>,
+    // rules of the enabled presets
+    preset_rules: RwLock<HashSet<&'static str>>,
+    // bumped by every add, so re-adding an entry with a new expiry counts as a change
+    adds: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct AllowEntry {
+    pub expires: Option<Instant>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AllowEntryView {
+    pub domain: String,
+    pub seconds_remaining: Option<u64>,
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+// A curated set of names commonly broken by blocklists, which users can allow in one go.
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub rules: &'static [&'static str],
+}
+
+// Opt-in presets, kept to endpoints whose blocking breaks something visible (logins, captchas,
+// page assets, connectivity checks) rather than anything that only reports usage.
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "connectivity",
+        description: "OS captive-portal and connectivity checks; blocking them shows devices as offline",
+        rules: &[
+            "captive.apple.com", "connectivitycheck.gstatic.com", "connectivitycheck.android.com",
+            "clients3.google.com", "www.msftconnecttest.com", "www.msftncsi.com", "dns.msftncsi.com",
+            "detectportal.firefox.com", "nmcheck.gnome.org", "connectivity-check.ubuntu.com",
+        ],
+    },
+    Preset {
+        name: "captcha",
+        description: "captcha and bot-check endpoints that gate logins and sign-ups",
+        rules: &[
+            "www.google.com", "www.gstatic.com", "www.recaptcha.net", "recaptcha.net",
+            "hcaptcha.com", "*.hcaptcha.com", "challenges.cloudflare.com", "client-api.arkoselabs.com",
+        ],
+    },
+    Preset {
+        name: "cdn",
+        description: "public CDNs serving page scripts, styles and fonts",
+        rules: &[
+            "ajax.googleapis.com", "fonts.googleapis.com", "fonts.gstatic.com", "cdnjs.cloudflare.com",
+            "cdn.jsdelivr.net", "unpkg.com", "code.jquery.com", "stackpath.bootstrapcdn.com",
+            "maxcdn.bootstrapcdn.com", "use.fontawesome.com",
+        ],
+    },
+    Preset {
+        name: "apps",
+        description: "app store, account and sync backends of the major platforms",
+        rules: &[
+            "appleid.apple.com", "itunes.apple.com", "*.mzstatic.com", "gsp-ssl.ls.apple.com",
+            "android.clients.google.com", "play.googleapis.com", "clients4.google.com", "dl.google.com",
+            "login.live.com", "login.microsoftonline.com", "officeclient.microsoft.com",
+            "spclient.wg.spotify.com", "s.youtube.com", "video-stats.l.google.com",
+        ],
+    },
+    Preset {
+        name: "links",
+        description: "link shorteners and mail click trackers that links in messages go through",
+        rules: &[
+            "t.co", "bit.ly", "ow.ly", "lnkd.in", "tinyurl.com", "buff.ly", "click.redditmail.com",
+        ],
+    },
+];
+
+// A preset as reported by GET /allowlist/presets.
+#[derive(Serialize)]
+pub struct PresetView {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub rules: &'static [&'static str],
+    pub enabled: bool,
+}
+
+impl Allowlist {
+    // Presets named in RUSTDNS_ALLOW_PRESETS (comma separated) start enabled.
+    pub fn from_env() -> Self {
+        let list = Allowlist::default();
+        for name in crate::envvars::var("RUSTDNS_ALLOW_PRESETS").unwrap_or_default().split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if !list.set_preset(name, true) {
+                tracing::warn!("unknown allowlist preset {} in RUSTDNS_ALLOW_PRESETS", name);
+            }
+        }
+        list
+    }
+
+    // Enable or disable preset `name`. False for a preset that doesn't exist.
+    pub fn set_preset(&self, name: &str, enabled: bool) -> bool {
+        let name = name.to_lowercase();
+        let Some(preset) = PRESETS.iter().find(|p| p.name == name) else { return false };
+        let mut presets = self.presets.write().unwrap();
+        if enabled { presets.insert(preset.name); } else { presets.remove(preset.name); }
+        let rules = PRESETS.iter().filter(|p| presets.contains(p.name)).flat_map(|p| p.rules.iter().copied()).collect();
+        *self.preset_rules.write().unwrap() = rules;
+        true
+    }
+
+    // Names of the enabled presets, sorted.
+    pub fn enabled_presets(&self) -> Vec<&'static str> {
+        let mut v: Vec<&'static str> = self.presets.read().unwrap().iter().copied().collect();
+        v.sort();
+        v
+    }
+
+    pub fn presets(&self) -> Vec<PresetView> {
+        let enabled = self.presets.read().unwrap();
+        PRESETS.iter()
+            .map(|p| PresetView { name: p.name, description: p.description, rules: p.rules, enabled: enabled.contains(p.name) })
+            .collect()
+    }
+
+    // Allow `domain`, permanently or for `ttl`. Re-adding replaces any previous expiry.
+    pub fn add(&self, domain: &str, ttl: Option<Duration>) -> Option<String> {
+        let d = normalize(domain);
+        if d.is_empty() { return None }
+        let entry = AllowEntry { expires: ttl.map(|t| Instant::now() + t.min(MAX_ALLOW_TTL)) };
+        self.entries.write().unwrap().insert(d.clone(), entry);
+        self.adds.fetch_add(1, Ordering::Relaxed);
+        Some(d)
+    }
+
+    // Number of adds so far, for noticing changes `list` can't show.
+    pub fn adds(&self) -> u64 {
+        self.adds.load(Ordering::Relaxed)
+    }
+
+    pub fn remove(&self, domain: &str) -> bool {
+        self.entries.write().unwrap().remove(&normalize(domain)).is_some()
+    }
+
+    // The allow rule covering `name`, if any unexpired one exists.
+    pub fn check(&self, name: &str) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        let preset_rules = self.preset_rules.read().unwrap();
+        if entries.is_empty() && preset_rules.is_empty() { return None }
+        let name = normalize(name);
+        let now = Instant::now();
+        let live = |k: &str| preset_rules.contains(k)
+            || entries.get(k).map(|e| e.expires.map(|x| now < x).unwrap_or(true)).unwrap_or(false);
+        if live(&name) { return Some(name) }
+        let mut cur = name.as_str();
+        while let Some((_, parent)) = cur.split_once('.') {
+            let rule = format!("*.{}", parent);
+            if live(&rule) { return Some(rule) }
+            cur = parent;
+        }
+        None
+    }
+
+    pub fn list(&self) -> Vec<AllowEntryView> {
+        let now = Instant::now();
+        let mut v: Vec<AllowEntryView> = self.entries.read().unwrap().iter().map(|(d, e)| AllowEntryView {
+            domain: d.clone(),
+            seconds_remaining: e.expires.map(|x| x.saturating_duration_since(now).as_secs()),
+        }).collect();
+        v.sort_by(|a, b| a.domain.cmp(&b.domain));
+        v
+    }
+
+    // Drop expired entries, returning the names removed.
+    pub fn purge_expired(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut entries = self.entries.write().unwrap();
+        let expired: Vec<String> = entries.iter()
+            .filter(|(_, e)| e.expires.map(|x| now >= x).unwrap_or(false))
+            .map(|(d, _)| d.clone())
+            .collect();
+        for d in &expired { entries.remove(d); }
+        expired
+    }
+}
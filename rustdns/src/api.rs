@@ -0,0 +1,57 @@
+use crate::config::build_features;
+use axum::{
+    http::{header::HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde_json::Value;
+
+// Version of the control API, sent with every response and part of the path of every route.
+pub const API_VERSION: u32 = 1;
+pub const API_PREFIX: &str = "/api/v1";
+
+// Response header carrying API_VERSION.
+pub const API_VERSION_HEADER: &str = "api-version";
+
+// `path` with the API prefix removed, so checks written against the route paths apply to the
+// versioned ones and the legacy aliases alike.
+pub fn route_path(path: &str) -> &str {
+    match path.strip_prefix(API_PREFIX) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+// Middleware adding the API version header to every response.
+pub async fn version_header<B>(req: Request<B>, next: Next<B>) -> Response {
+    let mut resp = next.run(req).await;
+    resp.headers_mut().insert(HeaderName::from_static(API_VERSION_HEADER), HeaderValue::from(API_VERSION));
+    resp
+}
+
+// Middleware for the unversioned routes, kept as aliases of the /api/v1 ones for clients written
+// before versioning: they work as before, but responses are marked deprecated (RFC 9745) and
+// link to the versioned path.
+pub async fn deprecated_alias<B>(req: Request<B>, next: Next<B>) -> Response {
+    let successor = format!("<{}{}>; rel=\"successor-version\"", API_PREFIX, req.uri().path());
+    let mut resp = next.run(req).await;
+    resp.headers_mut().insert(HeaderName::from_static("deprecation"), HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        resp.headers_mut().insert(HeaderName::from_static("link"), link);
+    }
+    resp
+}
+
+// GET /api and /api/v1 — the API versions this server speaks and what it was built with, for clients to
+// check before they rely on a route.
+pub async fn http_api_versions() -> Json<Value> {
+    Json(serde_json::json!({
+        "api_version": API_VERSION,
+        "versions": [format!("v{}", API_VERSION)],
+        "prefix": API_PREFIX,
+        "server_version": env!("CARGO_PKG_VERSION"),
+        "features": build_features(),
+    }))
+}
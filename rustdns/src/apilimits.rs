@@ -0,0 +1,282 @@
+use crate::state::ServerState;
+use axum::{
+    extract::{connect_info::Connected, ConnectInfo, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::server::{accept::Accept, conn::AddrStream};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+
+// Longest a client may take to send the request headers, so a connection opened and left idle
+// mid-request doesn't hold a slot.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Addresses tracked by the rate limiter before idle ones are forgotten.
+const MAX_TRACKED: usize = 4096;
+
+// Limits keeping one misbehaving device from taking down the control API on a small board. Read
+// from RUSTDNS_API_RATE_LIMIT (requests per minute per client address, 0 turns it off) and
+// RUSTDNS_API_RATE_BURST (requests a client may send at once before the rate applies),
+// RUSTDNS_API_MAX_CONNECTIONS and RUSTDNS_API_MAX_CONNECTIONS_PER_IP (open connections in all
+// and per address, gRPC included), and RUSTDNS_API_BULK_MAX_BYTES and
+// RUSTDNS_API_RESTORE_MAX_BYTES (request bodies of /add-bulk and /remove-bulk, and of /restore).
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ApiLimits {
+    pub rate_per_minute: u64,
+    pub rate_burst: u64,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub bulk_max_bytes: usize,
+    pub restore_max_bytes: usize,
+}
+
+impl ApiLimits {
+    pub fn from_env() -> Self {
+        let num = |key: &str, default: usize| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        let minimal = cfg!(feature = "minimal");
+        ApiLimits {
+            rate_per_minute: num("RUSTDNS_API_RATE_LIMIT", 600) as u64,
+            rate_burst: num("RUSTDNS_API_RATE_BURST", 120).max(1) as u64,
+            max_connections: num("RUSTDNS_API_MAX_CONNECTIONS", if minimal { 16 } else { 64 }),
+            max_connections_per_ip: num("RUSTDNS_API_MAX_CONNECTIONS_PER_IP", if minimal { 8 } else { 16 }),
+            bulk_max_bytes: num("RUSTDNS_API_BULK_MAX_BYTES", if minimal { 2 << 20 } else { 8 << 20 }),
+            restore_max_bytes: num("RUSTDNS_API_RESTORE_MAX_BYTES", if minimal { 16 << 20 } else { 64 << 20 }),
+        }
+    }
+}
+
+// Token bucket of one client address.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// Live state behind ApiLimits: a token bucket per client address and the open connections.
+pub struct ApiLimiter {
+    pub limits: ApiLimits,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    connections: Mutex<Connections>,
+    throttled: AtomicU64,
+    refused_connections: AtomicU64,
+}
+
+// Snapshot reported under `api` in /stats.
+#[derive(Serialize)]
+pub struct ApiLimitUsage {
+    pub connections: usize,
+    pub throttled: u64,
+    pub refused_connections: u64,
+    pub limits: ApiLimits,
+}
+
+// An open control API connection; its slot is given back when dropped.
+pub struct ConnSlot {
+    limiter: Arc<ApiLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnSlot {
+    fn drop(&mut self) {
+        let mut conns = self.limiter.connections.lock().unwrap();
+        conns.total -= 1;
+        if let Some(n) = conns.per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 { conns.per_ip.remove(&self.ip); }
+        }
+    }
+}
+
+impl ApiLimiter {
+    pub fn new(limits: ApiLimits) -> Self {
+        ApiLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+            connections: Mutex::new(Connections::default()),
+            throttled: AtomicU64::new(0),
+            refused_connections: AtomicU64::new(0),
+        }
+    }
+
+    // Take a token for a request from `ip`, or return how many seconds until one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        if self.limits.rate_per_minute == 0 { return Ok(()) }
+        let rate = self.limits.rate_per_minute as f64 / 60.0;
+        let burst = self.limits.rate_burst as f64;
+        let now = Instant::now();
+        let refill = |b: &mut Bucket| {
+            b.tokens = (b.tokens + now.duration_since(b.updated).as_secs_f64() * rate).min(burst);
+            b.updated = now;
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&ip) {
+            // clients whose bucket has filled up again are as good as new
+            buckets.retain(|_, b| { refill(b); b.tokens < burst });
+        }
+        let b = buckets.entry(ip).or_insert(Bucket { tokens: burst, updated: now });
+        refill(b);
+        if b.tokens >= 1.0 {
+            b.tokens -= 1.0;
+            return Ok(());
+        }
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        Err(((1.0 - b.tokens) / rate).ceil() as u64)
+    }
+
+    // A slot for a new connection from `ip`, or None (counting the refusal) when either cap is reached.
+    pub fn open(self: &Arc<Self>, ip: IpAddr) -> Option<ConnSlot> {
+        let mut conns = self.connections.lock().unwrap();
+        let from_ip = conns.per_ip.get(&ip).copied().unwrap_or(0);
+        if conns.total >= self.limits.max_connections || from_ip >= self.limits.max_connections_per_ip {
+            self.refused_connections.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        conns.total += 1;
+        *conns.per_ip.entry(ip).or_insert(0) += 1;
+        Some(ConnSlot { limiter: self.clone(), ip })
+    }
+
+    pub fn usage(&self) -> ApiLimitUsage {
+        ApiLimitUsage {
+            connections: self.connections.lock().unwrap().total,
+            throttled: self.throttled.load(Ordering::Relaxed),
+            refused_connections: self.refused_connections.load(Ordering::Relaxed),
+            limits: self.limits,
+        }
+    }
+}
+
+// Middleware answering 429 to clients over their request rate. It runs first, so token guessing
+// and preflight floods are throttled too. Requests without a peer address (made in-process) pass.
+pub async fn rate_limit<B>(State(state): State<Arc<ServerState>>, req: Request<B>, next: Next<B>) -> Response {
+    let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() else {
+        return next.run(req).await;
+    };
+    match state.api_limits.check(peer.ip()) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            tracing::debug!("throttled {} {} from {}", req.method(), req.uri().path(), peer.ip());
+            let body = Json(serde_json::json!({ "ok": false, "error": "too many requests; slow down" }));
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after.to_string())], body).into_response()
+        }
+    }
+}
+
+// Connections that know their peer address.
+pub trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl PeerAddr for AddrStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+impl PeerAddr for TlsStream<TcpStream> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
+    }
+}
+
+// Wraps a listener's connections, closing new ones over the connection caps right after accept.
+pub struct LimitedIncoming<I> {
+    inner: I,
+    limiter: Arc<ApiLimiter>,
+}
+
+impl<I> LimitedIncoming<I> {
+    pub fn new(inner: I, limiter: Arc<ApiLimiter>) -> Self {
+        LimitedIncoming { inner, limiter }
+    }
+}
+
+impl<I> Accept for LimitedIncoming<I>
+where
+    I: Accept + Unpin,
+    I::Conn: PeerAddr,
+{
+    type Conn = ApiConn<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        loop {
+            let conn = match Pin::new(&mut self.inner).poll_accept(cx) {
+                Poll::Ready(Some(Ok(conn))) => conn,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let Some(peer) = conn.peer_addr() else { continue };
+            match self.limiter.open(peer.ip()) {
+                Some(slot) => return Poll::Ready(Some(Ok(ApiConn { inner: conn, peer, _slot: slot }))),
+                None => tracing::debug!("refused control API connection from {}: too many open connections", peer),
+            }
+        }
+    }
+}
+
+// An accepted connection holding its slot.
+pub struct ApiConn<C> {
+    inner: C,
+    peer: SocketAddr,
+    _slot: ConnSlot,
+}
+
+impl<C> ApiConn<C> {
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl<C> Connected<&ApiConn<C>> for SocketAddr {
+    fn connect_info(target: &ApiConn<C>) -> Self {
+        target.peer
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for ApiConn<C> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for ApiConn<C> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
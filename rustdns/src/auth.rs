@@ -0,0 +1,219 @@
+use crate::api::route_path;
+use crate::state::ServerState;
+use axum::{
+    extract::State,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Paths served without a token: ACME HTTP-01 challenges are fetched by the CA, health checks
+// come from supervisors that have no token, the API description only lists the routes, and the
+// dashboard is static files that ask for a token before calling the API.
+const PUBLIC_PREFIXES: [&str; 6] = ["/.well-known/acme-challenge/", "/healthz", "/readyz", "/api/openapi.json", "/api/docs", "/ui"];
+
+// Reads that still need an admin token: the configuration names hook commands and file paths, and
+// a backup holds the client table and every list file.
+const ADMIN_READS: [&str; 2] = ["/config", "/backup"];
+
+// Query parameters that make a read admin-only on the route they are given to: /resolve with
+// `upstream` sends a query to any host and port the caller names.
+const ADMIN_PARAMS: [(&str, &str); 1] = [("/resolve", "upstream")];
+
+// POSTs a read token may send, as route patterns: GraphQL queries are sent as POST but can't
+// change anything, and an unblock request only asks an admin to approve or confirm the block.
+const READ_POSTS: [&str; 2] = ["/graphql", "/review/:id/request"];
+
+// Tokens this short are accepted but warned about.
+const MIN_TOKEN_LEN: usize = 16;
+
+// What a token may do. `read` tokens (dashboards, widgets) get GET requests; everything that
+// changes state (POST, PUT, DELETE, except READ_POSTS), the reads in ADMIN_READS and those with
+// ADMIN_PARAMS need `admin`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Admin,
+}
+
+impl Role {
+    // The role a request needs.
+    fn required<B>(req: &Request<B>) -> Self {
+        let path = route_path(req.uri().path());
+        let read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+            || (req.method() == Method::POST && READ_POSTS.iter().any(|p| route_matches(p, path)));
+        let admin_param = || {
+            let params = axum::extract::Query::<HashMap<String, String>>::try_from_uri(req.uri()).map(|q| q.0).unwrap_or_default();
+            ADMIN_PARAMS.iter().any(|(route, param)| *route == path && params.contains_key(*param))
+        };
+        if read && !ADMIN_READS.contains(&path) && !admin_param() { Role::Read } else { Role::Admin }
+    }
+}
+
+// Whether `path` is a path of the route `pattern`, whose `:name` segments match any one segment.
+fn route_matches(pattern: &str, path: &str) -> bool {
+    let (mut pattern, mut path) = (pattern.split('/'), path.split('/'));
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some(p), Some(s)) if p == s || (p.starts_with(':') && !s.is_empty()) => {}
+            _ => return false,
+        }
+    }
+}
+
+// Bearer-token authentication for the control API. Tokens come from RUSTDNS_API_TOKENS
+// (comma-separated) and RUSTDNS_API_TOKEN_FILE (one per line, `#` comments); with neither set the
+// API stays open, as before. A token can be prefixed with its role, `read:<token>` or
+// `admin:<token>`; a bare token is an admin token. Only SHA-256 digests of the tokens are kept,
+// and a presented token is compared against every one of them in constant time, so neither the
+// comparison nor which token matched shows in the response time.
+pub struct ApiAuth {
+    tokens: Vec<([u8; 32], Role)>,
+}
+
+// A configured token and its role.
+fn parse_token(entry: &str) -> (Role, &str) {
+    match entry.split_once(':') {
+        Some(("read", token)) => (Role::Read, token.trim()),
+        Some(("admin", token)) => (Role::Admin, token.trim()),
+        _ => (Role::Admin, entry),
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(digest(&SHA256, data).as_ref());
+    out
+}
+
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl ApiAuth {
+    pub fn from_env() -> Self {
+        let mut tokens: Vec<String> = crate::envvars::var("RUSTDNS_API_TOKENS").unwrap_or_default()
+            .split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect();
+        if let Ok(path) = crate::envvars::var("RUSTDNS_API_TOKEN_FILE") {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => tokens.extend(text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')).map(str::to_string)),
+                Err(e) => tracing::warn!("reading API tokens from {} failed: {}", path, e),
+            }
+        }
+        let tokens: Vec<(Role, &str)> = tokens.iter().map(|t| parse_token(t)).filter(|(_, t)| !t.is_empty()).collect();
+        if tokens.iter().any(|(_, t)| t.len() < MIN_TOKEN_LEN) {
+            tracing::warn!("some API tokens are shorter than {} characters; use long random tokens", MIN_TOKEN_LEN);
+        }
+        ApiAuth { tokens: tokens.iter().map(|(role, t)| (sha256(t.as_bytes()), *role)).collect() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    pub fn token_count(&self, role: Role) -> usize {
+        self.tokens.iter().filter(|(_, r)| *r == role).count()
+    }
+
+    // The role of `token`, if it is one of the configured tokens. Every token is compared, match
+    // or not; a token configured twice gets the higher role.
+    pub fn verify(&self, token: &str) -> Option<Role> {
+        let presented = sha256(token.as_bytes());
+        self.tokens.iter().fold(None, |found, (t, role)| if constant_time_eq(t, &presented) { found.max(Some(*role)) } else { found })
+    }
+}
+
+// The token of an `Authorization: Bearer <token>` header.
+fn bearer_token<B>(req: &Request<B>) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then_some(token.trim())
+}
+
+fn unauthorized(error: &str) -> Response {
+    let mut resp = (StatusCode::UNAUTHORIZED, Json(serde_json::json!({ "ok": false, "error": error }))).into_response();
+    resp.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    resp
+}
+
+fn forbidden() -> Response {
+    let error = "this token is read-only; changes and /config need an admin token";
+    (StatusCode::FORBIDDEN, Json(serde_json::json!({ "ok": false, "error": error }))).into_response()
+}
+
+// Middleware rejecting control API requests without a valid token, or with a token whose role
+// doesn't cover the request, once tokens are configured.
+pub async fn require_token<B>(State(state): State<Arc<ServerState>>, req: Request<B>, next: Next<B>) -> Response {
+    let auth = &state.auth;
+    if !auth.enabled() || req.uri().path() == "/" || PUBLIC_PREFIXES.iter().any(|p| req.uri().path().starts_with(p)) {
+        return next.run(req).await;
+    }
+    let required = Role::required(&req);
+    match bearer_token(&req).map(|t| auth.verify(t)) {
+        Some(Some(role)) if role >= required => next.run(req).await,
+        Some(Some(_)) => {
+            tracing::warn!("rejected {} {}: read-only API token", req.method(), req.uri().path());
+            forbidden()
+        }
+        Some(None) => {
+            tracing::warn!("rejected {} {}: invalid API token", req.method(), req.uri().path());
+            unauthorized("invalid API token")
+        }
+        None => unauthorized("missing API token; send Authorization: Bearer <token>"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn role(method: Method, uri: &str) -> Role {
+        Role::required(&Request::builder().method(method).uri(uri).body(()).unwrap())
+    }
+
+    #[test]
+    fn route_patterns_match_one_segment_per_parameter() {
+        assert!(route_matches("/review/:id/request", "/review/42/request"));
+        assert!(route_matches("/graphql", "/graphql"));
+        assert!(!route_matches("/review/:id/request", "/review/42/approve"));
+        assert!(!route_matches("/review/:id/request", "/review//request"));
+        assert!(!route_matches("/review/:id/request", "/review/42/request/x"));
+        assert!(!route_matches("/review/:id/request", "/review/42"));
+        assert!(!route_matches("/graphql", "/graphql/x"));
+    }
+
+    #[test]
+    fn read_tokens_may_get_and_ask_for_unblocks() {
+        for prefix in ["", "/api/v1"] {
+            let at = |path: &str| format!("{}{}", prefix, path);
+            assert_eq!(role(Method::GET, &at("/stats")), Role::Read);
+            assert_eq!(role(Method::HEAD, &at("/lists")), Role::Read);
+            assert_eq!(role(Method::GET, &at("/resolve?name=example.com")), Role::Read);
+            assert_eq!(role(Method::POST, &at("/graphql")), Role::Read);
+            assert_eq!(role(Method::POST, &at("/review/7/request")), Role::Read);
+        }
+    }
+
+    #[test]
+    fn changes_and_sensitive_reads_need_admin() {
+        for prefix in ["", "/api/v1"] {
+            let at = |path: &str| format!("{}{}", prefix, path);
+            assert_eq!(role(Method::POST, &at("/add")), Role::Admin);
+            assert_eq!(role(Method::PUT, &at("/config")), Role::Admin);
+            assert_eq!(role(Method::DELETE, &at("/clients/1")), Role::Admin);
+            assert_eq!(role(Method::POST, &at("/review/7/approve")), Role::Admin);
+            assert_eq!(role(Method::POST, &at("/review/7/confirm")), Role::Admin);
+            assert_eq!(role(Method::GET, &at("/config")), Role::Admin);
+            assert_eq!(role(Method::GET, &at("/backup")), Role::Admin);
+            assert_eq!(role(Method::GET, &at("/resolve?name=example.com&upstream=10.0.0.1:53")), Role::Admin);
+            assert_eq!(role(Method::GET, &at("/resolve?upstream=")), Role::Admin);
+        }
+    }
+}
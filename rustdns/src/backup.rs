@@ -0,0 +1,373 @@
+use crate::allowlist::{AllowEntryView, PRESETS};
+use crate::blocklist::{discover_lists, read_list_file, snapshot, BlockingMode, GroupMode, PatternMeta, UserRules, API_SOURCE};
+use crate::clients::ClientRecord;
+use crate::state::ServerState;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use trust_dns_proto::rr::RecordType;
+
+pub const BACKUP_FORMAT: &str = "rustdns-backup";
+pub const BACKUP_VERSION: u32 = 1;
+
+// Largest archive POST /restore accepts, compressed or not.
+pub const BACKUP_MAX_BYTES: usize = 256 << 20;
+
+// A rule added through the API, with its groups and annotations.
+#[derive(Serialize, Deserialize)]
+pub struct RuleBackup {
+    pub rule: String,
+    #[serde(default)]
+    pub tld: bool,
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub types: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct AllowlistBackup {
+    pub entries: Vec<AllowEntryView>,
+    pub presets: Vec<String>,
+}
+
+// The global blocking mode and audit switch.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockingBackup {
+    pub mode: BlockingMode,
+    #[serde(default)]
+    pub block_page_ip: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub audit: bool,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleBackup {
+    pub windows: Vec<String>,
+    pub timezone: String,
+}
+
+// State of a group that differs from the default: disabled, its own mode or a schedule.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GroupBackup {
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<GroupMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<ScheduleBackup>,
+}
+
+// Everything a user set up, as exported by GET /backup and imported by POST /restore: rules and
+// allowlist entries added through the API, modes, group and category settings, brands, canaries,
+// the client table, and the list files (local records and dnsmasq configuration included). A
+// section missing from an archive is left alone on restore; a present one replaces what is there.
+// Counters, logs and caches are not part of it.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    pub format: String,
+    pub version: u32,
+    pub created: u64,
+    pub server_version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<Vec<RuleBackup>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowlist: Option<AllowlistBackup>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocking: Option<BlockingBackup>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groups: Option<BTreeMap<String, GroupBackup>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub categories: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brands: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canaries: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clients: Option<Vec<ClientRecord>>,
+    // list files by path relative to the list directory, decompressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list_files: Option<BTreeMap<String, String>>,
+}
+
+// What POST /restore put back, per section, and what it couldn't.
+#[derive(Default, Serialize)]
+pub struct RestoreReport {
+    pub restored: BTreeMap<&'static str, usize>,
+    pub errors: Vec<String>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn rule_backup(rule: &str, tld: bool, meta: &PatternMeta) -> RuleBackup {
+    RuleBackup {
+        rule: rule.to_string(),
+        tld,
+        groups: meta.origins.iter().filter(|o| o.source == API_SOURCE).map(|o| o.group.clone()).collect(),
+        comment: meta.comment.clone(),
+        tags: meta.tags.clone(),
+        types: meta.types.iter().map(|t| t.to_string()).collect(),
+    }
+}
+
+// The list files under `dir` as (path relative to `dir`, contents). Compressed files are stored
+// decompressed under their name without the `.gz` / `.zst`. Zones under `rpz/` are left out: the
+// configured feeds transfer them again.
+fn read_list_files(dir: &str) -> Result<BTreeMap<String, String>> {
+    let mut files = BTreeMap::new();
+    // glob drops a leading `./` from the paths it returns
+    let base: PathBuf = Path::new(dir).components().filter(|c| !matches!(c, Component::CurDir)).collect();
+    for (path, _) in discover_lists(dir)? {
+        let path_base: PathBuf = path.components().filter(|c| !matches!(c, Component::CurDir)).collect();
+        let Ok(rel) = path_base.strip_prefix(&base) else { continue };
+        let mut name = rel.to_string_lossy().replace('\\', "/");
+        if name.starts_with("rpz/") { continue }
+        for ext in [".gz", ".zst"] {
+            if let Some(n) = name.strip_suffix(ext) { name = n.to_string(); }
+        }
+        files.insert(name, read_list_file(&path)?);
+    }
+    Ok(files)
+}
+
+// Whether `name` is somewhere restore may write a list file: a relative `.txt`, `.conf` or `.rpz`
+// path at most one directory deep, outside `rpz/`.
+fn valid_list_path(name: &str) -> bool {
+    let path = Path::new(name);
+    let parts: Vec<Component> = path.components().collect();
+    parts.len() <= 2
+        && parts.iter().all(|c| matches!(c, Component::Normal(_)))
+        && !name.starts_with("rpz/")
+        && matches!(path.extension().and_then(|e| e.to_str()), Some("txt" | "conf" | "rpz"))
+}
+
+pub async fn create_backup(state: &ServerState) -> Result<Backup> {
+    let dir = state.lists_dir.clone();
+    let list_files = tokio::task::spawn_blocking(move || read_list_files(&dir)).await??;
+    Ok(Backup {
+        clients: Some(state.clients.list()),
+        list_files: Some(list_files),
+        ..runtime_backup(state).await
+    })
+}
+
+// The sections of a backup that change while the server runs, for the saved state: everything
+// but the client table and the list files, which are files of their own.
+pub async fn runtime_backup(state: &ServerState) -> Backup {
+    let lists = snapshot(&state.lists).await;
+    let mut rules: Vec<RuleBackup> = lists.user.patterns.iter().map(|(r, m)| rule_backup(r, false, m))
+        .chain(lists.user.tlds.iter().map(|(r, m)| rule_backup(r, true, m)))
+        .collect();
+    rules.sort_by(|a, b| (a.tld, &a.rule).cmp(&(b.tld, &b.rule)));
+    let mut groups: BTreeMap<String, GroupBackup> = BTreeMap::new();
+    for g in &lists.disabled_groups {
+        groups.entry(g.clone()).or_default();
+    }
+    for (g, mode) in state.group_modes.all() {
+        groups.entry(g.clone()).or_insert_with(|| GroupBackup { enabled: !lists.disabled_groups.contains(&g), ..Default::default() }).mode = Some(mode);
+    }
+    for (g, s) in state.schedules.all() {
+        groups.entry(g.clone()).or_insert_with(|| GroupBackup { enabled: !lists.disabled_groups.contains(&g), ..Default::default() }).schedule =
+            Some(ScheduleBackup { windows: s.windows, timezone: s.timezone });
+    }
+    drop(lists);
+    Backup {
+        format: BACKUP_FORMAT.to_string(),
+        version: BACKUP_VERSION,
+        created: now_secs(),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        rules: Some(rules),
+        allowlist: Some(AllowlistBackup {
+            entries: state.allowlist.list(),
+            presets: state.allowlist.enabled_presets().into_iter().map(str::to_string).collect(),
+        }),
+        blocking: Some(BlockingBackup {
+            mode: *state.mode.read().await,
+            block_page_ip: *state.block_page_ip.read().await,
+            audit: state.audit.load(Ordering::Relaxed),
+        }),
+        groups: Some(groups),
+        categories: Some(state.categories.blocked()),
+        brands: Some(state.brands.list()),
+        canaries: Some(state.canaries.list()),
+        clients: None,
+        list_files: None,
+    }
+}
+
+// `backup` as a gzip-compressed JSON document.
+pub fn encode_backup(backup: &Backup) -> Result<Vec<u8>> {
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    serde_json::to_writer(&mut gz, backup)?;
+    gz.flush()?;
+    Ok(gz.finish()?)
+}
+
+// Read an archive written by `encode_backup`, or the same document uncompressed.
+pub fn decode_backup(raw: &[u8]) -> Result<Backup> {
+    let mut json = Vec::new();
+    let raw = if raw.starts_with(&[0x1f, 0x8b]) {
+        flate2::read::MultiGzDecoder::new(raw).take(BACKUP_MAX_BYTES as u64 + 1).read_to_end(&mut json)?;
+        if json.len() > BACKUP_MAX_BYTES { bail!("archive expands to more than {} bytes", BACKUP_MAX_BYTES) }
+        &json
+    } else {
+        raw
+    };
+    let backup: Backup = serde_json::from_slice(raw)?;
+    if backup.format != BACKUP_FORMAT { bail!("not a {} archive", BACKUP_FORMAT) }
+    if backup.version > BACKUP_VERSION {
+        bail!("archive version {} is newer than this server reads ({})", backup.version, BACKUP_VERSION)
+    }
+    if let Some(name) = backup.list_files.iter().flat_map(|f| f.keys()).find(|n| !valid_list_path(n)) {
+        bail!("invalid list file path {:?}", name)
+    }
+    Ok(backup)
+}
+
+fn user_rules(rules: &[RuleBackup]) -> Result<UserRules> {
+    let mut user = UserRules::default();
+    for r in rules {
+        let types = r.types.iter()
+            .map(|t| RecordType::from_str(&t.to_uppercase()).map_err(|_| anyhow!("rule {}: invalid record type {:?}", r.rule, t)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut meta = PatternMeta::default();
+        for g in &r.groups { meta.add_origin(&g.to_lowercase(), API_SOURCE); }
+        meta.annotate(r.comment.as_deref(), &r.tags);
+        meta.types = types;
+        if meta.origins.is_empty() { bail!("rule {} has no group", r.rule) }
+        if r.tld { user.tlds.insert(r.rule.to_lowercase(), meta) } else { user.patterns.insert(r.rule.to_lowercase(), meta) };
+    }
+    Ok(user)
+}
+
+// Replace the state covered by `backup`'s sections with theirs. List files are written before the
+// lists are reloaded; files that aren't in the archive stay. A section that fails is reported and
+// the others are still restored.
+pub async fn restore_backup(state: &Arc<ServerState>, backup: Backup) -> RestoreReport {
+    let mut report = RestoreReport::default();
+    if let Some(files) = backup.list_files {
+        let dir = state.lists_dir.clone();
+        let count = files.len();
+        let written = tokio::task::spawn_blocking(move || -> Result<()> {
+            for (name, contents) in files {
+                let path = Path::new(&dir).join(&name);
+                if let Some(parent) = path.parent() { std::fs::create_dir_all(parent)?; }
+                std::fs::write(&path, contents).map_err(|e| anyhow!("writing {}: {}", path.display(), e))?;
+            }
+            Ok(())
+        }).await;
+        match written {
+            Ok(Ok(())) => { report.restored.insert("list_files", count); }
+            Ok(Err(e)) => report.errors.push(format!("list files: {:#}", e)),
+            Err(e) => report.errors.push(format!("list files: {}", e)),
+        }
+    }
+    if let Some(rules) = &backup.rules {
+        match user_rules(rules) {
+            Ok(user) => {
+                let mut lists = state.lists.write().await;
+                Arc::make_mut(&mut lists).replace_user_rules(user);
+                report.restored.insert("rules", rules.len());
+            }
+            Err(e) => report.errors.push(format!("rules: {:#}", e)),
+        }
+    }
+    if let Some(groups) = &backup.groups {
+        {
+            let mut lists = state.lists.write().await;
+            Arc::make_mut(&mut lists).disabled_groups = groups.iter().filter(|(_, g)| !g.enabled).map(|(n, _)| n.to_lowercase()).collect();
+        }
+        for g in state.group_modes.all().keys() { state.group_modes.set(g, None); }
+        for g in state.schedules.all().keys() { let _ = state.schedules.set(g, None, None); }
+        for (name, g) in groups {
+            if let Some(mode) = &g.mode {
+                match GroupMode::new(mode.mode, mode.block_ip) {
+                    Some(mode) => state.group_modes.set(name, Some(mode)),
+                    None => report.errors.push(format!("group {}: block_ip is only valid with mode redirect", name)),
+                }
+            }
+            if let Some(s) = &g.schedule {
+                if let Err(e) = state.schedules.set(name, Some(&s.windows), Some(&s.timezone)) {
+                    report.errors.push(format!("group {} schedule: {}", name, e));
+                }
+            }
+        }
+        report.restored.insert("groups", groups.len());
+    }
+    if let Some(b) = &backup.blocking {
+        *state.mode.write().await = b.mode;
+        *state.block_page_ip.write().await = b.block_page_ip;
+        state.audit.store(b.audit, Ordering::Relaxed);
+        report.restored.insert("blocking", 1);
+    }
+    if let Some(allow) = &backup.allowlist {
+        for e in state.allowlist.list() { state.allowlist.remove(&e.domain); }
+        for e in &allow.entries {
+            state.allowlist.add(&e.domain, e.seconds_remaining.map(Duration::from_secs));
+        }
+        for p in PRESETS { state.allowlist.set_preset(p.name, allow.presets.iter().any(|n| n.eq_ignore_ascii_case(p.name))); }
+        report.restored.insert("allowlist", allow.entries.len());
+    }
+    if let Some(categories) = &backup.categories {
+        for c in state.categories.list() { state.categories.set_blocked(&c.name, false); }
+        let unknown: Vec<&str> = categories.iter().filter(|c| !state.categories.set_blocked(c, true)).map(String::as_str).collect();
+        if !unknown.is_empty() { report.errors.push(format!("categories: unknown {}", unknown.join(", "))); }
+        report.restored.insert("categories", categories.len() - unknown.len());
+    }
+    if let Some(brands) = &backup.brands {
+        for b in state.brands.list() { state.brands.remove(&b); }
+        report.restored.insert("brands", brands.iter().filter_map(|b| state.brands.add(b)).count());
+    }
+    if let Some(canaries) = &backup.canaries {
+        for c in state.canaries.list() { state.canaries.remove(&c); }
+        report.restored.insert("canaries", canaries.iter().filter_map(|c| state.canaries.add(c)).count());
+    }
+    if let Some(clients) = backup.clients {
+        let count = clients.len();
+        match state.clients.replace(clients) {
+            Ok(()) => { report.restored.insert("clients", count); }
+            Err(e) => report.errors.push(format!("clients: {}", e)),
+        }
+    }
+    if report.restored.contains_key("list_files") {
+        if let Err(e) = state.reload_lists(&state.lists_dir).await {
+            report.errors.push(format!("reloading lists: {:#}", e));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Restore writes list files under the list directory only, where the loader reads them.
+    #[test]
+    fn list_paths_stay_in_the_list_directory() {
+        for ok in ["ads.txt", "local.conf", "zone.rpz", "ads/more.txt", "kids/dnsmasq.conf"] {
+            assert!(valid_list_path(ok), "{} should be accepted", ok);
+        }
+        for bad in ["../x.txt", "ads/../../x.txt", "/etc/x.txt", "./x.txt", "a/b/c.txt", "rpz/x.rpz", "rpz/feed/x.rpz", "x.sh", "x.txt.gz", "x", "ads/", ""] {
+            assert!(!valid_list_path(bad), "{} should be refused", bad);
+        }
+    }
+
+    #[test]
+    fn archives_with_other_list_paths_are_refused() {
+        let archive = |name: &str| format!(r#"{{"format": "{}", "version": 1, "created": 0, "server_version": "", "list_files": {{"{}": "x.example\n"}}}}"#, BACKUP_FORMAT, name);
+        assert!(decode_backup(archive("ads/x.txt").as_bytes()).is_ok());
+        let err = decode_backup(archive("../x.txt").as_bytes()).err().unwrap();
+        assert_eq!(err.to_string(), "invalid list file path \"../x.txt\"");
+    }
+}
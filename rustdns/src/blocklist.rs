@@ -1,49 +1,1447 @@
+use crate::bloom::BloomFilter;
+use crate::rpz::{parse_rpz, RpzRule};
+use crate::wildcard::{is_glob, GlobMatcher};
+use crate::compiled::{compiled_path, file_stamp, write_compiled, CompiledList, CompiledRule, CompiledSource, Manifest};
 use anyhow::Result;
+use futures_util::stream::{self, Stream, StreamExt};
 use glob::glob;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
+use trust_dns_proto::rr::RecordType;
 
-// Load all .txt files from `dir` into the provided `lists` set. Accepts hosts-style and plain lines.
-pub async fn load_blocklists_into(dir: &str, lists: &Arc<RwLock<HashSet<String>>>) -> Result<usize> {
-    let mut set = HashSet::new();
-    let pattern = format!("{}/*.txt", dir);
-    for entry in glob(&pattern)? {
-        if let Ok(path) = entry {
-            if path.is_file() {
-                if let Ok(s) = tokio::fs::read_to_string(&path).await {
-                    for line in s.lines() {
-                        let line = line.trim();
-                        if line.is_empty() || line.starts_with('#') { continue }
-                        // accept hosts-style (ip domain) or plain domain
-                        let domain = if line.contains(char::is_whitespace) {
-                            line.split_whitespace().last().unwrap_or(line)
-                        } else { line };
-                        let d = domain.trim().to_lowercase();
-                        if !d.is_empty() { set.insert(d); }
-                    }
+// Group used for top-level list files in the blocklist directory.
+pub const DEFAULT_GROUP: &str = "default";
+// Group used for entries added at runtime through the control API when none is given.
+pub const CUSTOM_GROUP: &str = "custom";
+
+// Source recorded for entries added at runtime through the control API.
+pub const API_SOURCE: &str = "api";
+
+// One place a pattern was loaded from: the group and the list file (or `api`).
+#[derive(Clone, PartialEq, Serialize)]
+pub struct Origin {
+    pub group: String,
+    pub source: String,
+}
+
+// Where a pattern came from. A pattern listed in several files or groups keeps all of them.
+// Entries added through the control API can also carry a free-form comment and tags, and be
+// limited to some record types (block TXT and NULL for a name while still answering A).
+#[derive(Clone, Default)]
+pub struct PatternMeta {
+    pub origins: Vec<Origin>,
+    pub comment: Option<String>,
+    pub tags: Vec<String>,
+    // record types the rule blocks; empty for every type
+    pub types: Vec<RecordType>,
+    // answer for this rule instead of the global blocking mode (RPZ policies)
+    pub action: Option<BlockAction>,
+}
+
+// How a rule answers when it is not left to the global blocking mode.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockAction {
+    // empty NOERROR answer
+    NoData,
+    // CNAME to a walled-garden name, which is resolved as usual
+    Redirect(String),
+}
+
+impl BlockAction {
+    // Compact form used in the compiled list manifest.
+    fn encode(&self) -> String {
+        match self {
+            BlockAction::NoData => "nodata".to_string(),
+            BlockAction::Redirect(target) => format!("redirect:{}", target),
+        }
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            Some(("redirect", target)) => Some(BlockAction::Redirect(target.to_string())),
+            None if s == "nodata" => Some(BlockAction::NoData),
+            _ => None,
+        }
+    }
+}
+
+// Comment and tags of an annotated rule, as reported under `annotations` in /lists.
+#[derive(Serialize)]
+pub struct Annotation<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<&'a str>,
+    pub tags: &'a [String],
+}
+
+impl PatternMeta {
+    pub fn add_origin(&mut self, group: &str, source: &str) {
+        let o = Origin { group: group.to_string(), source: source.to_string() };
+        if !self.origins.contains(&o) { self.origins.push(o); }
+    }
+
+    // Replace the comment (when given) and add tags; an empty comment clears it.
+    pub fn annotate(&mut self, comment: Option<&str>, tags: &[String]) {
+        if let Some(c) = comment {
+            self.comment = Some(c.trim().to_string()).filter(|c| !c.is_empty());
+        }
+        for t in tags {
+            if !self.tags.contains(t) { self.tags.push(t.clone()); }
+        }
+    }
+
+    // Whether the rule blocks queries of `qtype`; `None` (no particular type) matches any rule.
+    pub fn applies_to(&self, qtype: Option<RecordType>) -> bool {
+        match qtype {
+            Some(t) if !self.types.is_empty() => self.types.contains(&t),
+            _ => true,
+        }
+    }
+
+    pub fn annotation(&self) -> Option<Annotation<'_>> {
+        if self.comment.is_none() && self.tags.is_empty() { return None }
+        Some(Annotation { comment: self.comment.as_deref(), tags: &self.tags })
+    }
+}
+
+// The rule that caused a name to be blocked, with every origin that is currently enabled.
+#[derive(Clone, Serialize)]
+pub struct BlockMatch {
+    pub rule: String,
+    pub kind: &'static str,
+    pub origins: Vec<Origin>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<BlockAction>,
+    // record types the rule is limited to, as names
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub types: Vec<String>,
+}
+
+impl BlockMatch {
+    // List files the rule came from, for logs; detectors that match without a list report their kind.
+    pub fn sources(&self) -> String {
+        if self.origins.is_empty() { return self.kind.to_string() }
+        self.origins.iter().map(|o| o.source.as_str()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+// A name answered locally with fixed addresses (hosts-file override) instead of being blocked.
+#[derive(Clone, Default)]
+pub struct LocalRecord {
+    pub ips: Vec<IpAddr>,
+    pub meta: PatternMeta,
+}
+
+// Queries for `zone` and names beneath it go to `upstream` instead of the default resolver
+// (dnsmasq `server=/zone/upstream`).
+#[derive(Clone, Default)]
+pub struct ForwardZone {
+    pub upstream: String,
+    pub meta: PatternMeta,
+}
+
+// In-memory blocklist. Whole-TLD rules (`.zip` in a list file) are kept apart from domain patterns
+// so they can be matched with a single lookup on the last label. Disabled groups stay loaded but
+// are skipped during matching.
+#[derive(Clone, Default)]
+pub struct Blocklist {
+    pub patterns: HashMap<String, PatternMeta>,
+    pub tlds: HashMap<String, PatternMeta>,
+    pub local_records: HashMap<String, LocalRecord>,
+    pub forward_zones: HashMap<String, ForwardZone>,
+    // Names (or `*.suffix` subtrees) exempt from every block rule (RPZ `rpz-passthru.`).
+    pub passthru: HashMap<String, PatternMeta>,
+    pub disabled_groups: HashSet<String>,
+    // Fast negative check in front of `find_match`, built once a load completes. Most queries
+    // aren't blocked, and for them a few filter probes replace the scan over every pattern.
+    filter: Option<BloomFilter>,
+    // Patterns with a `*` in the middle (`ads.*.example.com`); see `GlobMatcher`.
+    globs: GlobMatcher,
+    // Exact names from the compiled list (see `compile_blocklists`), when one is in use. They are
+    // looked up in the memory-mapped file and never copied into `patterns`.
+    pub compiled: Option<Arc<CompiledList>>,
+    // Rules added through the API, kept apart from the ones loaded from list files so a reload
+    // merges them back into the new set instead of dropping them.
+    pub user: Arc<UserRules>,
+}
+
+// Pattern and TLD rules from `POST /add`, with their metadata as the API set it.
+#[derive(Clone, Default)]
+pub struct UserRules {
+    pub patterns: HashMap<String, PatternMeta>,
+    pub tlds: HashMap<String, PatternMeta>,
+}
+
+impl UserRules {
+    pub fn len(&self) -> usize {
+        self.patterns.len() + self.tlds.len()
+    }
+}
+
+// Wildcard rules are entered into the filter by at most this many bytes of their fixed part, so a
+// query needs one probe per length up to it rather than one per possible suffix.
+const FILTER_AFFIX_LEN: usize = 8;
+
+// Filter keys for a block rule; `tld` rules and the three pattern shapes use distinct tags.
+fn filter_key(rule: &str, tld: bool) -> Vec<u8> {
+    let mut key = Vec::with_capacity(rule.len() + 2);
+    if tld {
+        key.extend_from_slice(b"t:");
+        key.extend_from_slice(rule.as_bytes());
+    } else if is_glob(rule) {
+        // a matching name ends with the part after the last `*` and starts with the part before
+        // the first; the filter gets whichever of the two is fixed
+        let last = rule.rsplit('*').next().unwrap_or("");
+        if !last.is_empty() {
+            key.extend_from_slice(b"s:");
+            key.extend_from_slice(&last.as_bytes()[last.len().saturating_sub(FILTER_AFFIX_LEN)..]);
+        } else {
+            let first = rule.split('*').next().unwrap_or("");
+            key.extend_from_slice(b"p:");
+            key.extend_from_slice(&first.as_bytes()[..first.len().min(FILTER_AFFIX_LEN)]);
+        }
+    } else if let Some(suffix) = rule.strip_prefix("*.") {
+        key.extend_from_slice(b"s:");
+        key.extend_from_slice(&suffix.as_bytes()[suffix.len().saturating_sub(FILTER_AFFIX_LEN)..]);
+    } else if let Some(prefix) = rule.strip_suffix(".*") {
+        key.extend_from_slice(b"p:");
+        key.extend_from_slice(&prefix.as_bytes()[..prefix.len().min(FILTER_AFFIX_LEN)]);
+    } else {
+        key.extend_from_slice(b"e:");
+        key.extend_from_slice(rule.as_bytes());
+    }
+    key
+}
+
+impl Blocklist {
+    pub fn len(&self) -> usize {
+        self.patterns.len() + self.tlds.len() + self.local_records.len() + self.forward_zones.len() + self.passthru.len()
+            + self.compiled.as_ref().map(|c| c.len()).unwrap_or(0)
+    }
+
+    fn insert_entry(&mut self, entry: ListEntry, group: &str, source: &str) {
+        match entry {
+            ListEntry::Pattern(d) => self.insert_pattern(d, group, source),
+            ListEntry::Tld(t) => self.insert_tld(t, group, source),
+            ListEntry::Local(d, ip) => self.insert_local(d, ip, group, source),
+            ListEntry::Forward(z, up) => self.insert_forward(z, up, group, source),
+            ListEntry::Policy(d, action) => {
+                self.insert_pattern(d.clone(), group, source);
+                if let Some(meta) = self.patterns.get_mut(&d) { meta.action = Some(action); }
+            }
+            ListEntry::Passthru(d) => self.passthru.entry(d).or_default().add_origin(group, source),
+        }
+    }
+
+    // Whether an enabled passthru rule exempts `name` (lowercase, no trailing dot).
+    fn is_passthru(&self, name: &str) -> bool {
+        if self.passthru.is_empty() { return false }
+        let active = |key: &str| self.passthru.get(key).map(|m| !self.active_origins(m).is_empty()).unwrap_or(false);
+        if active(name) { return true }
+        let mut cur = name;
+        while let Some((_, parent)) = cur.split_once('.') {
+            if active(&format!("*.{}", parent)) { return true }
+            cur = parent;
+        }
+        false
+    }
+
+    pub fn insert_pattern(&mut self, pattern: String, group: &str, source: &str) {
+        self.add_to_filter(&pattern, false);
+        if is_glob(&pattern) && !self.patterns.contains_key(&pattern) { self.globs.insert(&pattern); }
+        self.patterns.entry(pattern).or_default().add_origin(group, source);
+    }
+
+    pub fn insert_tld(&mut self, tld: String, group: &str, source: &str) {
+        self.add_to_filter(&tld, true);
+        self.tlds.entry(tld).or_default().add_origin(group, source);
+    }
+
+    // Add or update a rule from the API, both among the user rules and in the active set. `types`
+    // replaces the rule's type limit when given.
+    pub fn add_user_rule(&mut self, rule: &str, tld: bool, group: &str, comment: Option<&str>, tags: &[String], types: Option<Vec<RecordType>>) {
+        let user = Arc::make_mut(&mut self.user);
+        let meta = if tld { &mut user.tlds } else { &mut user.patterns }.entry(rule.to_string()).or_default();
+        meta.add_origin(group, API_SOURCE);
+        meta.annotate(comment, tags);
+        if let Some(t) = types { meta.types = t; }
+        let meta = meta.clone();
+        self.merge_user_rule(rule, tld, &meta);
+    }
+
+    // Swap all user rules for `user`, taking the old ones out of the active set first.
+    pub fn replace_user_rules(&mut self, user: UserRules) {
+        let old = std::mem::replace(&mut self.user, Arc::new(user));
+        self.unmerge_user_rules(&old);
+        self.merge_user_rules();
+    }
+
+    // Drop a rule from the user rules and the active set. Returns whether the active set had it.
+    pub fn remove_rule(&mut self, rule: &str, tld: bool) -> bool {
+        let user = Arc::make_mut(&mut self.user);
+        if tld { user.tlds.remove(rule) } else { user.patterns.remove(rule) };
+        if tld { self.tlds.remove(rule).is_some() } else { self.patterns.remove(rule).is_some() }
+    }
+
+    fn merge_user_rule(&mut self, rule: &str, tld: bool, user: &PatternMeta) {
+        for o in &user.origins {
+            if tld { self.insert_tld(rule.to_string(), &o.group, &o.source) } else { self.insert_pattern(rule.to_string(), &o.group, &o.source) }
+        }
+        if let Some(meta) = if tld { self.tlds.get_mut(rule) } else { self.patterns.get_mut(rule) } {
+            meta.annotate(user.comment.as_deref(), &user.tags);
+            meta.types = user.types.clone();
+        }
+    }
+
+    // Merge every user rule into the set, on top of whatever the list files loaded.
+    fn merge_user_rules(&mut self) {
+        let user = self.user.clone();
+        for (rule, meta) in &user.patterns { self.merge_user_rule(rule, false, meta); }
+        for (rule, meta) in &user.tlds { self.merge_user_rule(rule, true, meta); }
+    }
+
+    // Undo `merge_user_rules` for `user`: API origins and annotations go, and rules no list file
+    // has go with them.
+    fn unmerge_user_rules(&mut self, user: &UserRules) {
+        for (map, rules) in [(&mut self.patterns, &user.patterns), (&mut self.tlds, &user.tlds)] {
+            for rule in rules.keys() {
+                let Some(meta) = map.get_mut(rule) else { continue };
+                meta.origins.retain(|o| o.source != API_SOURCE);
+                meta.comment = None;
+                meta.tags.clear();
+                meta.types.clear();
+                if meta.origins.is_empty() { map.remove(rule); }
+            }
+        }
+    }
+
+    // (Re)build the negative-lookup filter from every pattern and TLD rule.
+    pub fn build_filter(&mut self) {
+        let mut f = BloomFilter::with_capacity((self.patterns.len() + self.tlds.len()) * 2);
+        for p in self.patterns.keys() { f.insert(&filter_key(p, false)); }
+        for t in self.tlds.keys() { f.insert(&filter_key(t, true)); }
+        self.filter = Some(f);
+    }
+
+    // Keep the filter in step with runtime additions; removals can stay in it, they only cost a
+    // false positive.
+    fn add_to_filter(&mut self, rule: &str, tld: bool) {
+        let Some(f) = self.filter.as_mut() else { return };
+        f.insert(&filter_key(rule, tld));
+        if f.is_overfull() { self.build_filter(); }
+    }
+
+    // False when no rule can match `name` (lowercase, no trailing dot). Always true before the
+    // filter is built.
+    fn may_match(&self, name: &str) -> bool {
+        let Some(f) = &self.filter else { return true };
+        let b = name.as_bytes();
+        let probe = |tag: &[u8], part: &[u8]| {
+            let mut key = Vec::with_capacity(tag.len() + part.len());
+            key.extend_from_slice(tag);
+            key.extend_from_slice(part);
+            f.may_contain(&key)
+        };
+        if probe(b"e:", b) { return true }
+        if let Some(tld) = name.rsplit('.').next() {
+            if probe(b"t:", tld.as_bytes()) { return true }
+        }
+        (0..=FILTER_AFFIX_LEN.min(b.len())).any(|n| probe(b"s:", &b[b.len() - n..]) || probe(b"p:", &b[..n]))
+    }
+
+    pub fn insert_local(&mut self, name: String, ip: IpAddr, group: &str, source: &str) {
+        let rec = self.local_records.entry(name).or_default();
+        if !rec.ips.contains(&ip) { rec.ips.push(ip); }
+        rec.meta.add_origin(group, source);
+    }
+
+    pub fn insert_forward(&mut self, zone: String, upstream: String, group: &str, source: &str) {
+        let fwd = self.forward_zones.entry(zone).or_default();
+        fwd.upstream = upstream;
+        fwd.meta.add_origin(group, source);
+    }
+
+    // The most specific enabled forward zone covering `name`, as (zone, upstream).
+    pub fn find_forward(&self, name: &str) -> Option<(&str, &str)> {
+        if self.forward_zones.is_empty() { return None }
+        let name = name.trim_end_matches('.').to_lowercase();
+        let mut cur = name.as_str();
+        loop {
+            if let Some((zone, fwd)) = self.forward_zones.get_key_value(cur) {
+                if !self.active_origins(&fwd.meta).is_empty() { return Some((zone.as_str(), fwd.upstream.as_str())) }
+            }
+            cur = cur.split_once('.')?.1;
+        }
+    }
+
+    // Addresses of an enabled local record for `name`, if any.
+    pub fn find_local(&self, name: &str) -> Option<&[IpAddr]> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.local_records.get(&name)
+            .filter(|r| !self.active_origins(&r.meta).is_empty())
+            .map(|r| r.ips.as_slice())
+    }
+
+    // Origins of `meta` whose group is enabled; a rule with none of them is inactive.
+    fn active_origins(&self, meta: &PatternMeta) -> Vec<Origin> {
+        meta.origins.iter().filter(|o| !self.disabled_groups.contains(&o.group)).cloned().collect()
+    }
+
+    // `groups` limits the origins that count, when given.
+    fn matched(&self, rule: &str, kind: &'static str, meta: &PatternMeta, qtype: Option<RecordType>, groups: Option<&BTreeSet<String>>) -> Option<BlockMatch> {
+        if !meta.applies_to(qtype) { return None }
+        let mut origins = self.active_origins(meta);
+        if let Some(groups) = groups { origins.retain(|o| groups.contains(&o.group)); }
+        if origins.is_empty() { return None }
+        let types = meta.types.iter().map(|t| t.to_string()).collect();
+        Some(BlockMatch { rule: rule.to_string(), kind, origins, action: meta.action.clone(), types })
+    }
+
+    // Exact match against the compiled list.
+    fn compiled_match(&self, name: &str, groups: Option<&BTreeSet<String>>) -> Option<BlockMatch> {
+        let origin = self.compiled.as_ref()?.get(name)?;
+        if self.disabled_groups.contains(&origin.group) { return None }
+        if groups.is_some_and(|g| !g.contains(&origin.group)) { return None }
+        Some(BlockMatch { rule: name.to_string(), kind: "exact", origins: vec![origin.clone()], action: None, types: Vec::new() })
+    }
+
+    // Every enabled block rule with its kind (`tld`, `exact`, `wildcard`), sorted by rule.
+    pub fn active_rules(&self) -> Vec<(String, &'static str)> {
+        let active = |meta: &PatternMeta| meta.origins.iter().any(|o| !self.disabled_groups.contains(&o.group));
+        let mut rules: Vec<(String, &'static str)> = self.tlds.iter()
+            .filter(|(_, m)| active(m))
+            .map(|(t, _)| (t.clone(), "tld"))
+            .chain(self.patterns.iter().filter(|(_, m)| active(m)).map(|(p, _)| {
+                (p.clone(), if is_wildcard(p) { "wildcard" } else { "exact" })
+            }))
+            .collect();
+        if let Some(c) = &self.compiled {
+            rules.extend(c.entries().into_iter()
+                .filter(|(_, o)| !self.disabled_groups.contains(&o.group))
+                .map(|(name, _)| (name, "exact")));
+        }
+        rules.sort();
+        rules.dedup();
+        rules
+    }
+
+    // Per-group entry counts across patterns and TLD rules.
+    pub fn group_counts(&self) -> HashMap<String, usize> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let locals = self.local_records.values().map(|r| &r.meta);
+        let forwards = self.forward_zones.values().map(|f| &f.meta);
+        for meta in self.patterns.values().chain(self.tlds.values()).chain(locals).chain(forwards).chain(self.passthru.values()) {
+            let groups: HashSet<&String> = meta.origins.iter().map(|o| &o.group).collect();
+            for g in groups { *counts.entry(g.clone()).or_default() += 1; }
+        }
+        for src in self.compiled.iter().flat_map(|c| &c.manifest.sources) {
+            *counts.entry(src.group.clone()).or_default() += src.names;
+        }
+        counts
+    }
+}
+
+// `*.suffix`, `prefix.*` and glob rules; everything else in `patterns` is an exact name.
+fn is_wildcard(pattern: &str) -> bool {
+    pattern.contains('*')
+}
+
+// Normalize a TLD rule (`zip`, `.zip`, `*.zip`) to its bare label, or None if it isn't a single label.
+pub fn normalize_tld(s: &str) -> Option<String> {
+    let t = s.trim().trim_start_matches("*.").trim_start_matches('.').trim_end_matches('.').to_lowercase();
+    if t.is_empty() || t.contains('.') || t.contains('*') { return None }
+    Some(t)
+}
+
+// Normalize a pattern rule given through the API (trimmed, lowercase, without the final dot), or
+// None if it isn't a valid rule.
+pub fn normalize_pattern(s: &str) -> Option<String> {
+    let p = s.trim().trim_end_matches('.').to_lowercase();
+    valid_rule(&p).then_some(p)
+}
+
+// Loader settings.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LoadOptions {
+    // Treat hosts entries pointing at a real address (not 0.0.0.0 / 127.0.0.1 / :: / ::1) as
+    // local override records answered with that address, instead of blocks.
+    pub hosts_overrides: bool,
+    // Use the compiled list at `compiled_path()` for the list files it covers, when present.
+    pub use_compiled: bool,
+}
+
+impl LoadOptions {
+    // RUSTDNS_HOSTS_OVERRIDES=1 enables hosts overrides.
+    pub fn from_env() -> Self {
+        let flag = |k: &str| crate::envvars::var(k).map(|v| v == "1" || v == "true").unwrap_or(false);
+        LoadOptions { hosts_overrides: flag("RUSTDNS_HOSTS_OVERRIDES"), use_compiled: true }
+    }
+}
+
+// One parsed list line.
+enum ListEntry {
+    Pattern(String),
+    Tld(String),
+    Local(String, IpAddr),
+    Forward(String, String),
+    // block rule with its own answer
+    Policy(String, BlockAction),
+    Passthru(String),
+}
+
+// How a list file is parsed: `.conf` files are dnsmasq configuration, `.rpz` files Response
+// Policy Zones. Other files are sniffed, since lists in every format get published as `.txt`;
+// those that are neither are read line by line as hosts, plain-domain, wildcard or adblock rules,
+// which may be mixed within a file.
+#[derive(Clone, Copy, PartialEq)]
+enum ListFormat {
+    Hosts,
+    Dnsmasq,
+    Rpz,
+}
+
+// dnsmasq directives a list can carry.
+const DNSMASQ_KEYS: [&str; 4] = ["address", "server", "local", "host-record"];
+
+impl ListFormat {
+    fn for_path(path: &Path) -> Option<Self> {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let name = name.trim_end_matches(".gz").trim_end_matches(".zst");
+        if name.ends_with(".conf") {
+            Some(ListFormat::Dnsmasq)
+        } else if name.ends_with(".rpz") {
+            Some(ListFormat::Rpz)
+        } else {
+            None
+        }
+    }
+
+    // The format of a list file, by name or else by its first rules.
+    fn detect(path: &Path, text: &str) -> Self {
+        if let Some(format) = ListFormat::for_path(path) { return format }
+        let sample: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with(['#', '!', ';'])).take(20).collect();
+        if sample.iter().any(|l| l.starts_with("$TTL") || l.starts_with("$ORIGIN") || l.split_whitespace().any(|t| t == "SOA")) {
+            return ListFormat::Rpz;
+        }
+        let dnsmasq = sample.iter().filter(|l| l.split_once('=').is_some_and(|(k, _)| DNSMASQ_KEYS.contains(&k.trim()))).count();
+        if dnsmasq * 2 > sample.len() { ListFormat::Dnsmasq } else { ListFormat::Hosts }
+    }
+}
+
+// One list line that didn't make it into the set.
+#[derive(Clone, Serialize)]
+pub struct LineIssue {
+    pub line: usize,
+    pub text: String,
+    pub reason: &'static str,
+    // invalid rather than skipped
+    pub invalid: bool,
+}
+
+// Line issues kept per file; the counts cover all of them.
+const MAX_LINE_ISSUES: usize = 10;
+
+// How a list file was read. `format` is `hosts`, `domains`, `wildcard` or `adblock` when every
+// rule line was of that kind (`mixed` otherwise), or `dnsmasq` / `rpz`. `skipped` lines are valid
+// in their format but have no DNS meaning (cosmetic or page-level adblock filters, dnsmasq
+// options); `invalid` lines couldn't be read at all, which usually means a broken download or a
+// list in a format not understood here.
+#[derive(Clone, Default, Serialize)]
+pub struct ParseDiagnostics {
+    pub format: &'static str,
+    pub skipped: usize,
+    pub invalid: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub issues: Vec<LineIssue>,
+}
+
+impl ParseDiagnostics {
+    fn issue(&mut self, line: usize, text: &str, reason: &'static str, invalid: bool) {
+        if invalid { self.invalid += 1 } else { self.skipped += 1 }
+        if self.issues.len() < MAX_LINE_ISSUES {
+            self.issues.push(LineIssue { line, text: text.chars().take(200).collect(), reason, invalid });
+        }
+    }
+}
+
+// What a list line turned out to be.
+enum Line {
+    // blank line or comment
+    Empty,
+    // rules, with the kind of line they came from
+    Entries(&'static str, Vec<ListEntry>),
+    Skipped(&'static str),
+    Invalid(&'static str),
+}
+
+// Line kinds of hosts-format files, in the order they're counted.
+const LINE_KINDS: [&str; 4] = ["hosts", "domains", "wildcard", "adblock"];
+
+// Addresses hosts-format blocklists use to mean "blocked" rather than a real destination.
+fn is_sink_ip(ip: &IpAddr) -> bool {
+    ip.is_unspecified() || ip.is_loopback()
+}
+
+// Names the header of hosts files maps to loopback and broadcast addresses; never rules.
+const HOSTS_BOILERPLATE: [&str; 11] = [
+    "localhost", "localhost.localdomain", "local", "broadcasthost", "ip6-localhost", "ip6-loopback",
+    "ip6-localnet", "ip6-mcastprefix", "ip6-allnodes", "ip6-allrouters", "0.0.0.0",
+];
+
+// Whether `name` can be a list rule: dot-separated labels of letters, digits, `-` and `_`, and
+// `*` in wildcard rules. Internationalized names must be in their `xn--` form.
+pub fn valid_rule(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 253
+        && name.split('.').all(|l| !l.is_empty() && l.len() <= 63 && l.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'*')))
+}
+
+// Feed every entry of a list file's text to `f`, and report how its lines were read.
+fn for_each_entry(text: &str, format: ListFormat, opts: &LoadOptions, mut f: impl FnMut(ListEntry)) -> ParseDiagnostics {
+    if format == ListFormat::Rpz {
+        parse_rpz(text).into_iter().map(rpz_entry).for_each(f);
+        return ParseDiagnostics { format: "rpz", ..Default::default() };
+    }
+    let mut diag = ParseDiagnostics::default();
+    let mut kinds = [0usize; LINE_KINDS.len()];
+    for (i, line) in text.lines().enumerate() {
+        let parsed = if format == ListFormat::Dnsmasq { parse_dnsmasq_line(line) } else { parse_line(line, opts) };
+        match parsed {
+            Line::Empty => {}
+            Line::Entries(kind, entries) => {
+                if let Some(k) = LINE_KINDS.iter().position(|k| *k == kind) { kinds[k] += 1; }
+                entries.into_iter().for_each(&mut f);
+            }
+            Line::Skipped(reason) => diag.issue(i + 1, line.trim(), reason, false),
+            Line::Invalid(reason) => diag.issue(i + 1, line.trim(), reason, true),
+        }
+    }
+    diag.format = match (format, kinds.iter().filter(|n| **n > 0).count()) {
+        (ListFormat::Dnsmasq, _) => "dnsmasq",
+        (_, 0) => "empty",
+        (_, 1) => LINE_KINDS[kinds.iter().position(|n| *n > 0).unwrap_or(0)],
+        _ => "mixed",
+    };
+    diag
+}
+
+// RPZ policies in blocklist terms: `CNAME .` blocks with the global blocking mode, NODATA and
+// walled-garden CNAMEs keep their own answer, local data becomes a local record.
+fn rpz_entry(rule: RpzRule) -> ListEntry {
+    match rule {
+        RpzRule::Block(d) => ListEntry::Pattern(d),
+        RpzRule::NoData(d) => ListEntry::Policy(d, BlockAction::NoData),
+        RpzRule::Redirect(d, target) => ListEntry::Policy(d, BlockAction::Redirect(target)),
+        RpzRule::Passthru(d) => ListEntry::Passthru(d),
+        RpzRule::Local(d, ip) => ListEntry::Local(d, ip),
+    }
+}
+
+// Parse a list line: hosts-style `ip name [name...]`, a plain domain, a wildcard or TLD rule, or
+// an adblock rule.
+fn parse_line(line: &str, opts: &LoadOptions) -> Line {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(['#', '!']) || (line.starts_with('[') && line.ends_with(']')) { return Line::Empty }
+    // element hiding and scriptlets (`example.com##.ad`), before `#` is taken for a comment
+    if ["##", "#@#", "#?#", "#$#", "#%#"].iter().any(|m| line.contains(m)) { return Line::Skipped("adblock cosmetic filter") }
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() { return Line::Empty }
+    if line.starts_with(['|', '@', '/']) { return parse_adblock_line(line) }
+    let mut tokens = line.split_whitespace();
+    let first = tokens.next().unwrap_or("");
+    // `fe80::1%lo0 localhost` in hosts headers
+    if let Ok(ip) = first.split('%').next().unwrap_or(first).parse::<IpAddr>() {
+        let local = opts.hosts_overrides && !is_sink_ip(&ip);
+        let mut entries = Vec::new();
+        let mut invalid = false;
+        for d in tokens.map(|t| t.trim_end_matches('.').to_lowercase()) {
+            if HOSTS_BOILERPLATE.contains(&d.as_str()) { continue }
+            if !valid_rule(&d) || d.contains('*') {
+                invalid = true;
+                continue;
+            }
+            entries.push(if local { ListEntry::Local(d, ip) } else { ListEntry::Pattern(d) });
+        }
+        return match (entries.is_empty(), invalid) {
+            (true, true) => Line::Invalid("invalid host name"),
+            _ => Line::Entries("hosts", entries),
+        };
+    }
+    if tokens.next().is_some() { return Line::Invalid("expected a domain or `address name...`") }
+    let d = first.trim_end_matches('.').to_lowercase();
+    if d.starts_with('.') {
+        return match normalize_tld(&d) {
+            Some(t) => Line::Entries("domains", vec![ListEntry::Tld(t)]),
+            None => Line::Invalid("invalid TLD rule"),
+        };
+    }
+    if !valid_rule(&d) { return Line::Invalid("invalid domain") }
+    Line::Entries(if d.contains('*') { "wildcard" } else { "domains" }, vec![ListEntry::Pattern(d)])
+}
+
+// Request options of adblock rules that still make sense for a whole name.
+const ADBLOCK_DNS_OPTIONS: [&str; 3] = ["important", "all", "document"];
+
+// Adblock rules as DNS lists publish them (Hagezi, AdGuard): `||example.com^` blocks the name
+// and its subdomains, `@@||example.com^` exempts them. Rules that need a URL to match (paths,
+// regexes, most `$` options) are skipped.
+fn parse_adblock_line(line: &str) -> Line {
+    if line.starts_with('/') && line.ends_with('/') && line.len() > 1 { return Line::Skipped("adblock regex rule") }
+    let (rule, options) = line.split_once('$').unwrap_or((line, ""));
+    if options.split(',').any(|o| !o.is_empty() && !ADBLOCK_DNS_OPTIONS.contains(&o.trim())) {
+        return Line::Skipped("adblock rule with request options");
+    }
+    let (except, rule) = match rule.strip_prefix("@@") {
+        Some(r) => (true, r),
+        None => (false, rule),
+    };
+    let Some(name) = rule.strip_prefix("||") else { return Line::Skipped("adblock rule not anchored to a domain") };
+    let name = name.trim_end_matches('|').trim_end_matches('^');
+    if name.contains(['/', ':', '^', '?', '=']) { return Line::Skipped("adblock rule with a path") }
+    let name = name.trim_start_matches("*.").trim_end_matches('.').to_lowercase();
+    if !valid_rule(&name) { return Line::Invalid("invalid domain in adblock rule") }
+    let wildcard = format!("*.{}", name);
+    let entries = if except {
+        vec![ListEntry::Passthru(wildcard), ListEntry::Passthru(name)]
+    } else {
+        vec![ListEntry::Pattern(wildcard), ListEntry::Pattern(name)]
+    };
+    Line::Entries("adblock", entries)
+}
+
+// Parse a dnsmasq config line. `address=/d/0.0.0.0` (or an empty address / `#`) blocks d and its
+// subdomains, `address=/d/ip` with a real address and `host-record=name,...,ip` become local
+// records, and `server=/d/ip#port` (or `local=`) a forward zone. Other directives are skipped.
+fn parse_dnsmasq_line(line: &str) -> Line {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') { return Line::Empty }
+    let Some((key, value)) = line.split_once('=') else { return Line::Invalid("expected option=value") };
+    match key.trim() {
+        "address" => {
+            let Some((domains, target)) = dnsmasq_domains(value) else { return Line::Invalid("expected /domain/address") };
+            let ip = match target {
+                "" | "#" => None,
+                t => match t.parse::<IpAddr>() {
+                    Ok(ip) => Some(ip),
+                    Err(_) => return Line::Invalid("invalid address"),
+                },
+            };
+            Line::Entries("dnsmasq", domains.into_iter().flat_map(|d| match ip {
+                Some(ip) if !is_sink_ip(&ip) => vec![ListEntry::Local(d, ip)],
+                _ => vec![ListEntry::Pattern(format!("*.{}", d)), ListEntry::Pattern(d)],
+            }).collect())
+        }
+        "server" | "local" => {
+            let Some((domains, target)) = dnsmasq_domains(value) else { return Line::Invalid("expected /domain/upstream") };
+            // an empty target (local-only) or `#` (use the default servers) needs no zone here
+            let Some(upstream) = dnsmasq_upstream(target) else {
+                return if matches!(target, "" | "#") { Line::Skipped("local-only domain") } else { Line::Invalid("invalid upstream") };
+            };
+            Line::Entries("dnsmasq", domains.into_iter().map(|d| ListEntry::Forward(d, upstream.clone())).collect())
+        }
+        "host-record" => {
+            let fields: Vec<&str> = value.split(',').map(|f| f.trim()).collect();
+            let ips: Vec<IpAddr> = fields.iter().filter_map(|f| f.parse().ok()).collect();
+            if ips.is_empty() { return Line::Invalid("host-record without an address") }
+            Line::Entries("dnsmasq", fields.iter()
+                .filter(|f| f.parse::<IpAddr>().is_err() && f.parse::<u32>().is_err() && !f.is_empty())
+                .flat_map(|name| ips.iter().map(move |ip| ListEntry::Local(name.trim_end_matches('.').to_lowercase(), *ip)))
+                .collect())
+        }
+        _ => Line::Skipped("unsupported dnsmasq option"),
+    }
+}
+
+// Split `/a.com/b.com/target` into its domains and the target after the last slash.
+fn dnsmasq_domains(value: &str) -> Option<(Vec<String>, &str)> {
+    let (domains, target) = value.trim().strip_prefix('/')?.rsplit_once('/')?;
+    let domains: Vec<String> = domains.split('/')
+        .map(|d| d.trim().trim_start_matches("*.").trim_matches('.').to_lowercase())
+        .filter(|d| !d.is_empty() && d != "#")
+        .collect();
+    if domains.is_empty() { return None }
+    Some((domains, target.trim()))
+}
+
+// dnsmasq upstream syntax (`ip`, `ip#port`, optionally `@interface`) as `ip:port`.
+fn dnsmasq_upstream(target: &str) -> Option<String> {
+    let target = target.split('@').next()?;
+    let (ip, port) = match target.split_once('#') {
+        Some((ip, port)) => (ip, port.parse().ok()?),
+        None => (target, 53),
+    };
+    Some(std::net::SocketAddr::new(ip.parse().ok()?, port).to_string())
+}
+
+// Group name for a list file nested one level below the blocklist directory.
+fn group_for(path: &Path) -> String {
+    path.parent()
+        .and_then(|p| p.file_name())
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| DEFAULT_GROUP.to_string())
+}
+
+/// How blocked names are answered: NXDOMAIN, 0.0.0.0, an empty answer, or the block page address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockingMode {
+    Nx,
+    Null,
+    NoData,
+    Redirect,
+}
+
+pub const BLOCK_MODES: &[&str] = &["nx", "null", "nodata", "redirect"];
+
+impl BlockingMode {
+    const ALL: [BlockingMode; 4] = [BlockingMode::Nx, BlockingMode::Null, BlockingMode::NoData, BlockingMode::Redirect];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BlockingMode::Nx => "nx",
+            BlockingMode::Null => "null",
+            BlockingMode::NoData => "nodata",
+            BlockingMode::Redirect => "redirect",
+        }
+    }
+
+    // One of BLOCK_MODES, in any case.
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.as_str().eq_ignore_ascii_case(s.trim()))
+    }
+}
+
+// Block answer of one group, used instead of the global mode when that group's lists produced
+// the match, with `block_ip` in place of the global block page address for `redirect`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupMode {
+    pub mode: BlockingMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_ip: Option<Ipv4Addr>,
+}
+
+impl GroupMode {
+    // None when `block_ip` is given with a mode other than redirect.
+    pub fn new(mode: BlockingMode, block_ip: Option<Ipv4Addr>) -> Option<Self> {
+        if block_ip.is_some() && mode != BlockingMode::Redirect { return None }
+        Some(GroupMode { mode, block_ip })
+    }
+
+    // `mode`, or `redirect:<ipv4>` for a group-specific sinkhole address.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (mode, ip) = match s.trim().split_once(':') {
+            Some((m, ip)) => (m, Some(ip.parse().ok()?)),
+            None => (s.trim(), None),
+        };
+        GroupMode::new(BlockingMode::parse(mode)?, ip)
+    }
+}
+
+// Per-group block answers. RUSTDNS_GROUP_MODES=malware=null,ads=redirect,adult=nx seeds them;
+// POST /groups/{name}/mode changes them at runtime. Categories count as groups here too.
+#[derive(Default)]
+pub struct GroupModes {
+    modes: std::sync::RwLock<HashMap<String, GroupMode>>,
+}
+
+impl GroupModes {
+    pub fn from_env() -> Self {
+        let mut modes = HashMap::new();
+        for item in crate::envvars::var("RUSTDNS_GROUP_MODES").unwrap_or_default().split(',').filter(|i| !i.trim().is_empty()) {
+            match item.split_once('=').and_then(|(g, m)| Some((g.trim().to_lowercase(), GroupMode::parse(m)?))) {
+                Some((group, mode)) => { modes.insert(group, mode); }
+                None => tracing::warn!("ignoring invalid RUSTDNS_GROUP_MODES entry {:?}", item),
+            }
+        }
+        GroupModes { modes: std::sync::RwLock::new(modes) }
+    }
+
+    // Set or, with None, clear the mode of `group`.
+    pub fn set(&self, group: &str, mode: Option<GroupMode>) {
+        let mut modes = self.modes.write().unwrap();
+        match mode {
+            Some(m) => { modes.insert(group.to_lowercase(), m); }
+            None => { modes.remove(&group.to_lowercase()); }
+        }
+    }
+
+    pub fn get(&self, group: &str) -> Option<GroupMode> {
+        self.modes.read().unwrap().get(group).cloned()
+    }
+
+    // The mode for a match: that of the first origin whose group has one.
+    pub fn for_match(&self, m: &BlockMatch) -> Option<(String, GroupMode)> {
+        let modes = self.modes.read().unwrap();
+        if modes.is_empty() { return None }
+        m.origins.iter().find_map(|o| modes.get(&o.group).map(|mode| (o.group.clone(), mode.clone())))
+    }
+
+    pub fn all(&self) -> HashMap<String, GroupMode> {
+        self.modes.read().unwrap().clone()
+    }
+}
+
+// Entries contributed by one list file during a load, and how its lines were read (absent for
+// files served from the compiled list).
+#[derive(Clone, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    pub group: String,
+    pub entries: usize,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<ParseDiagnostics>,
+}
+
+// Outcome of a load compared to the previously active set.
+#[derive(Clone, Serialize)]
+pub struct LoadReport {
+    pub loaded: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub files: Vec<FileReport>,
+    // lines left out across all files; see `ParseDiagnostics`
+    pub skipped_lines: usize,
+    pub invalid_lines: usize,
+    pub timings: LoadTimings,
+    // resident memory of the process once the new set is in place
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resident_bytes: Option<u64>,
+}
+
+// Where the time of a load went, in milliseconds. `parse` covers reading and parsing the list
+// files and indexing their entries, `compile` building the negative-lookup filter queries check
+// first, and `swap` waiting for in-flight lookups and installing the new set. Lists are local
+// files, so there is no download step; RPZ zones are transferred separately (see GET /rpz).
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct LoadTimings {
+    pub parse_ms: u64,
+    pub compile_ms: u64,
+    pub swap_ms: u64,
+    pub total_ms: u64,
+}
+
+// Number of keys in `a` that are missing from `b`.
+fn missing_from<V>(a: &HashMap<String, V>, b: &HashMap<String, V>) -> usize {
+    a.keys().filter(|k| !b.contains_key(*k)).count()
+}
+
+// List file name patterns; compressed lists are decompressed transparently.
+const LIST_GLOBS: [&str; 7] = ["*.txt", "*.txt.gz", "*.txt.zst", "*.conf", "*.rpz", "*.rpz.gz", "*.rpz.zst"];
+
+fn list_files(dir: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for g in LIST_GLOBS {
+        paths.extend(glob(&format!("{}/{}", dir, g))?.flatten());
+    }
+    Ok(paths)
+}
+
+// Read a list file, decompressing `.gz` / `.zst` files by extension. Blocking.
+pub fn read_list_file(path: &Path) -> Result<String> {
+    let raw = std::fs::read(path)?;
+    let mut out = Vec::new();
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => { flate2::read::MultiGzDecoder::new(raw.as_slice()).read_to_end(&mut out)?; }
+        Some("zst") => {
+            let mut src = raw.as_slice();
+            ruzstd::StreamingDecoder::new(&mut src)
+                .map_err(|e| anyhow::anyhow!("zstd: {}", e))?
+                .read_to_end(&mut out)?;
+        }
+        _ => return Ok(String::from_utf8_lossy(&raw).into_owned()),
+    }
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+// A list file read and parsed off the async runtime.
+struct ParsedList {
+    path: PathBuf,
+    group: String,
+    // size and mtime taken before reading, for the compiled list's staleness check
+    stamp: Option<(u64, u64)>,
+    entries: Result<Vec<ListEntry>>,
+    diagnostics: ParseDiagnostics,
+}
+
+// Read and parse `files` on the blocking pool, one file per thread and as many at a time as there
+// are cores, yielding them in the order given so merges stay deterministic. At most that many
+// parsed files are held at once, waiting to be merged.
+fn parse_lists(files: Vec<(PathBuf, String)>, opts: LoadOptions) -> impl Stream<Item = ParsedList> {
+    let parallelism = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    stream::iter(files)
+        .map(move |(path, group)| async move {
+            let task_path = path.clone();
+            let parsed = tokio::task::spawn_blocking(move || {
+                let stamp = file_stamp(&task_path);
+                let text = read_list_file(&task_path)?;
+                let mut entries = Vec::new();
+                let diagnostics = for_each_entry(&text, ListFormat::detect(&task_path, &text), &opts, |entry| entries.push(entry));
+                Ok((stamp, entries, diagnostics))
+            }).await;
+            match parsed {
+                Ok(Ok((stamp, entries, diagnostics))) => ParsedList { path, group, stamp, entries: Ok(entries), diagnostics },
+                Ok(Err(e)) => ParsedList { path, group, stamp: None, entries: Err(e), diagnostics: ParseDiagnostics::default() },
+                Err(e) => ParsedList { path, group, stamp: None, entries: Err(e.into()), diagnostics: ParseDiagnostics::default() },
+            }
+        })
+        .buffered(parallelism)
+}
+
+// Shared handle to the active blocklist. Queries take a cheap snapshot (`Arc` clone) and match
+// against it without holding the lock; writers replace or copy-on-write the inner `Arc`.
+pub type SharedBlocklist = Arc<RwLock<Arc<Blocklist>>>;
+
+// Current blocklist snapshot. The read lock is held only long enough to clone the `Arc`.
+pub async fn snapshot(lists: &SharedBlocklist) -> Arc<Blocklist> {
+    lists.read().await.clone()
+}
+
+// Load all .txt files (and .txt.gz / .txt.zst) from `dir` (and one level of group subdirectories,
+// e.g. `dir/ads/*.txt`) into the provided `lists`. Accepts hosts-style and plain lines; a line
+// consisting of a single dotted label such as `.zip` is a TLD rule. `*.conf` files are read as
+// dnsmasq configuration and `*.rpz` files as Response Policy Zones. Group enable/disable state
+// survives the reload.
+//
+// The files are read and parsed concurrently on the blocking pool (see `parse_lists`) and merged
+// in discovery order. The new set is parsed and diffed without touching the lock; queries keep
+// matching against the previous snapshot until the final swap, which only holds the write lock
+// for a pointer exchange.
+pub async fn load_blocklists_into(dir: &str, lists: &SharedBlocklist, opts: &LoadOptions) -> Result<LoadReport> {
+    let started = Instant::now();
+    let mut bl = Blocklist::default();
+    let mut files = Vec::new();
+    let compiled = if opts.use_compiled { open_compiled(opts) } else { None };
+    let mut pending = Vec::new();
+    for (path, group) in discover_lists(dir)? {
+        if !path.is_file() { continue }
+        let source = path.to_string_lossy().into_owned();
+        if let Some(src) = compiled.as_ref().and_then(|c| c.covers(&source)) {
+            files.push(FileReport { path: source, group: src.group.clone(), entries: src.entries, diagnostics: None });
+            continue;
+        }
+        pending.push((path, group));
+    }
+    let mut parsed = std::pin::pin!(parse_lists(pending, *opts));
+    while let Some(list) = parsed.next().await {
+        let source = list.path.to_string_lossy().into_owned();
+        match list.entries {
+            Ok(entries) => {
+                let diag = &list.diagnostics;
+                if diag.invalid > 0 {
+                    let first = diag.issues.iter().find(|i| i.invalid);
+                    tracing::warn!("list {} ({}): {} invalid lines{}", source, diag.format, diag.invalid,
+                        first.map(|i| format!(", e.g. line {}: {} ({:?})", i.line, i.reason, i.text)).unwrap_or_default());
+                }
+                files.push(FileReport { path: source.clone(), group: list.group.clone(), entries: entries.len(), diagnostics: Some(list.diagnostics) });
+                for entry in entries {
+                    bl.insert_entry(entry, &list.group, &source);
                 }
             }
+            Err(e) => tracing::warn!("skipping list {}: {:?}", source, e),
         }
     }
-    let n = set.len();
+    if let Some(c) = &compiled {
+        for rule in &c.manifest.rules {
+            let (Some(origin), Some(entry)) = (c.origin(rule.source), rule_entry(rule)) else { continue };
+            bl.insert_entry(entry, &origin.group, &origin.source);
+        }
+    }
+    bl.compiled = compiled;
+    let parsed_at = Instant::now();
+    let prev = snapshot(lists).await;
+    bl.user = prev.user.clone();
+    bl.merge_user_rules();
+    let mut report = LoadReport {
+        loaded: bl.len(),
+        added: missing_from(&bl.patterns, &prev.patterns) + missing_from(&bl.tlds, &prev.tlds)
+            + missing_from(&bl.local_records, &prev.local_records) + missing_from(&bl.forward_zones, &prev.forward_zones),
+        removed: missing_from(&prev.patterns, &bl.patterns) + missing_from(&prev.tlds, &bl.tlds)
+            + missing_from(&prev.local_records, &bl.local_records) + missing_from(&prev.forward_zones, &bl.forward_zones),
+        skipped_lines: files.iter().filter_map(|f| f.diagnostics.as_ref()).map(|d| d.skipped).sum(),
+        invalid_lines: files.iter().filter_map(|f| f.diagnostics.as_ref()).map(|d| d.invalid).sum(),
+        files,
+        timings: LoadTimings::default(),
+        resident_bytes: None,
+    };
+    drop(prev);
+    let filter_at = Instant::now();
+    bl.build_filter();
+    let swap_at = Instant::now();
     let mut w = lists.write().await;
-    *w = set;
-    Ok(n)
+    bl.disabled_groups = w.disabled_groups.clone();
+    // rules added or removed through the API while this load ran
+    if !Arc::ptr_eq(&bl.user, &w.user) {
+        let stale = bl.user.clone();
+        bl.unmerge_user_rules(&stale);
+        bl.user = w.user.clone();
+        bl.merge_user_rules();
+    }
+    let old = std::mem::replace(&mut *w, Arc::new(bl));
+    drop(w);
+    let swapped_at = Instant::now();
+    // the old set is freed outside the lock, so lookups don't wait on it
+    drop(old);
+    report.timings = LoadTimings {
+        parse_ms: ms_between(started, parsed_at),
+        compile_ms: ms_between(filter_at, swap_at),
+        swap_ms: ms_between(swap_at, swapped_at),
+        total_ms: started.elapsed().as_millis() as u64,
+    };
+    report.resident_bytes = crate::resources::resident_bytes();
+    Ok(report)
+}
+
+fn ms_between(from: Instant, to: Instant) -> u64 {
+    to.duration_since(from).as_millis() as u64
+}
+
+// List files under `dir` with the group each belongs to.
+pub fn discover_lists(dir: &str) -> Result<Vec<(PathBuf, String)>> {
+    let mut paths: Vec<_> = list_files(dir)?.into_iter().map(|p| (p, DEFAULT_GROUP.to_string())).collect();
+    paths.extend(list_files(&format!("{}/*", dir))?.into_iter().map(|p| { let g = group_for(&p); (p, g) }));
+    Ok(paths)
+}
+
+// The compiled list, if there is one that matches the current list files and options. A list
+// file changed or removed since compiling makes the whole compiled list stale, since its old
+// entries can't be told apart; the lists are then loaded as usual until the next compile.
+fn open_compiled(opts: &LoadOptions) -> Option<Arc<CompiledList>> {
+    let path = compiled_path();
+    if !path.exists() { return None }
+    match CompiledList::open(&path) {
+        Ok(c) if c.manifest.hosts_overrides != opts.hosts_overrides => {
+            tracing::warn!("ignoring compiled list {}: compiled with different hosts override setting", path.display());
+            None
+        }
+        Ok(c) if !c.is_current() => {
+            tracing::warn!("ignoring compiled list {}: list files changed since it was compiled; POST /compile to rebuild", path.display());
+            None
+        }
+        Ok(c) => Some(Arc::new(c)),
+        Err(e) => {
+            tracing::warn!("ignoring compiled list {}: {:#}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn rule_entry(rule: &CompiledRule) -> Option<ListEntry> {
+    let name = rule.name.clone();
+    match rule.kind.as_str() {
+        "pattern" => Some(ListEntry::Pattern(name)),
+        "tld" => Some(ListEntry::Tld(name)),
+        "local" => Some(ListEntry::Local(name, rule.value.as_ref()?.parse().ok()?)),
+        "forward" => Some(ListEntry::Forward(name, rule.value.clone()?)),
+        "policy" => Some(ListEntry::Policy(name, BlockAction::decode(rule.value.as_ref()?)?)),
+        "passthru" => Some(ListEntry::Passthru(name)),
+        _ => None,
+    }
+}
+
+fn compiled_rule(entry: ListEntry, source: usize) -> CompiledRule {
+    let (kind, name, value) = match entry {
+        ListEntry::Pattern(d) => ("pattern", d, None),
+        ListEntry::Tld(t) => ("tld", t, None),
+        ListEntry::Local(d, ip) => ("local", d, Some(ip.to_string())),
+        ListEntry::Forward(z, up) => ("forward", z, Some(up)),
+        ListEntry::Policy(d, action) => ("policy", d, Some(action.encode())),
+        ListEntry::Passthru(d) => ("passthru", d, None),
+    };
+    CompiledRule { kind: kind.to_string(), name, value, source }
+}
+
+// Compile every list file under `dir` into the on-disk format read by `CompiledList`: exact names
+// go into a memory-mapped FST at `path`, the few other rules into its manifest. Peak memory is
+// only needed here, once, instead of on every load.
+pub async fn compile_blocklists(dir: &str, opts: &LoadOptions, path: PathBuf) -> Result<Manifest> {
+    let mut names: Vec<(String, u64)> = Vec::new();
+    let mut sources = Vec::new();
+    let mut rules = Vec::new();
+    let files: Vec<_> = discover_lists(dir)?.into_iter().filter(|(f, _)| f.is_file()).collect();
+    let mut parsed = std::pin::pin!(parse_lists(files, *opts));
+    while let Some(list) = parsed.next().await {
+        let entries = match list.entries {
+            Ok(e) => e,
+            Err(e) => {
+                tracing::warn!("not compiling {}: {:?}", list.path.display(), e);
+                continue;
+            }
+        };
+        let Some((size, modified_ms)) = list.stamp else { continue };
+        let idx = sources.len();
+        let count = entries.len();
+        for entry in entries {
+            match entry {
+                ListEntry::Pattern(d) if !is_wildcard(&d) => names.push((d, idx as u64)),
+                other => rules.push(compiled_rule(other, idx)),
+            }
+        }
+        sources.push(CompiledSource { path: list.path.to_string_lossy().into_owned(), group: list.group, size, modified_ms, entries: count, names: 0 });
+    }
+    let created = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let manifest = Manifest { created, hosts_overrides: opts.hosts_overrides, entries: 0, sources, rules };
+    tokio::task::spawn_blocking(move || write_compiled(&path, &mut names, manifest)).await?
 }
 
-// Very simple matching: exact match or prefix/suffix wildcard patterns used in the lists.
-pub fn is_blocked_domain(name: &str, lists: &HashSet<String>) -> bool {
+// Very simple matching: TLD rule, exact match, prefix/suffix wildcard patterns used in the lists,
+// then globs with a `*` in the middle.
+// Rules limited to other record types than `qtype` are skipped; with no `qtype` every rule counts.
+// Returns the rule that matched and the enabled origins it came from.
+pub fn find_match(name: &str, qtype: Option<RecordType>, lists: &Blocklist) -> Option<BlockMatch> {
+    find_match_in(name, qtype, lists, None)
+}
+
+// find_match counting only rules from `groups` when given, as for a listener with a policy.
+pub fn find_match_in(name: &str, qtype: Option<RecordType>, lists: &Blocklist, groups: Option<&BTreeSet<String>>) -> Option<BlockMatch> {
     let name = name.trim_end_matches('.').to_lowercase();
-    if lists.contains(&name) { return true }
-    for pat in lists.iter() {
-        if pat.starts_with("*.") {
-            let suffix = &pat[2..];
-            if name.ends_with(suffix) { return true }
-        } else if pat.ends_with(".*") {
-            let prefix = &pat[..pat.len()-2];
-            if name.starts_with(prefix) { return true }
+    if lists.is_passthru(&name) { return None }
+    if !lists.may_match(&name) { return lists.compiled_match(&name, groups) }
+    if let Some(tld) = name.rsplit('.').next() {
+        if let Some(m) = lists.tlds.get(tld).and_then(|meta| lists.matched(tld, "tld", meta, qtype, groups)) { return Some(m) }
+    }
+    if let Some(m) = lists.patterns.get(&name).and_then(|meta| lists.matched(&name, "exact", meta, qtype, groups)) { return Some(m) }
+    if let Some(m) = lists.compiled_match(&name, groups) { return Some(m) }
+    for (pat, meta) in lists.patterns.iter() {
+        let hit = if let Some(suffix) = pat.strip_prefix("*.") {
+            name.ends_with(suffix)
+        } else if let Some(prefix) = pat.strip_suffix(".*") {
+            name.starts_with(prefix)
+        } else { false };
+        if hit {
+            if let Some(m) = lists.matched(pat, "wildcard", meta, qtype, groups) { return Some(m) }
+        }
+    }
+    if lists.globs.is_empty() { return None }
+    // removed globs stay in the matcher; only those still in `patterns` count
+    let glob = lists.globs.matches(&name).find_map(|pat| lists.patterns.get(pat).and_then(|meta| lists.matched(pat, "wildcard", meta, qtype, groups)));
+    glob
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // While a reload parses a large list, queries must keep matching against the previous
+    // snapshot and never wait on the reload: a snapshot is an `Arc` of its own, so one held
+    // through the whole reload stays the old set, and the reload builds beside it instead of
+    // in place.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn queries_never_block_on_reload() {
+        let dir = std::env::temp_dir().join(format!("rustdns-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_s = dir.to_string_lossy().into_owned();
+        std::fs::write(dir.join("old.txt"), "old.example\n").unwrap();
+        let lists: SharedBlocklist = Arc::new(RwLock::new(Arc::new(Blocklist::default())));
+        load_blocklists_into(&dir_s, &lists, &LoadOptions::default()).await.unwrap();
+
+        std::fs::remove_file(dir.join("old.txt")).unwrap();
+        let big: String = (0..300_000).map(|i| format!("host{}.example\n", i)).collect();
+        std::fs::write(dir.join("big.txt"), big).unwrap();
+
+        let held = snapshot(&lists).await;
+        let reload = tokio::spawn({
+            let (lists, dir_s) = (lists.clone(), dir_s.clone());
+            async move { load_blocklists_into(&dir_s, &lists, &LoadOptions::default()).await }
+        });
+        let mut reads = 0;
+        while !reload.is_finished() {
+            let snap = snapshot(&lists).await;
+            let old = snap.patterns.contains_key("old.example");
+            let new = snap.patterns.contains_key("host1.example");
+            assert!(old != new, "query observed a partially loaded blocklist");
+            reads += 1;
+            tokio::task::yield_now().await;
+        }
+        let report = reload.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert!(reads > 0, "reload finished before any query ran");
+        // the snapshot taken before the reload is untouched, and nothing else keeps the old set
+        assert!(held.patterns.contains_key("old.example"));
+        assert!(!held.patterns.contains_key("host1.example"));
+        assert_eq!(held.len(), 1);
+        assert_eq!(Arc::strong_count(&held), 1);
+        assert_eq!(report.loaded, 300_000);
+        assert_eq!((report.added, report.removed), (300_000, 1));
+        let snap = snapshot(&lists).await;
+        assert!(find_match("host299999.example", None, &snap).is_some());
+        assert!(!snap.patterns.contains_key("old.example"));
+    }
+
+    // Rules added through the API stay across reloads, on top of whatever the files now hold.
+    #[tokio::test]
+    async fn user_rules_survive_reload() {
+        let dir = std::env::temp_dir().join(format!("rustdns-user-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_s = dir.to_string_lossy().into_owned();
+        std::fs::write(dir.join("a.txt"), "file.example\nboth.example\n").unwrap();
+        let lists: SharedBlocklist = Arc::new(RwLock::new(Arc::new(Blocklist::default())));
+        load_blocklists_into(&dir_s, &lists, &LoadOptions::default()).await.unwrap();
+        {
+            let mut w = lists.write().await;
+            let bl = Arc::make_mut(&mut w);
+            bl.add_user_rule("user.example", false, CUSTOM_GROUP, Some("manual"), &[], None);
+            bl.add_user_rule("both.example", false, CUSTOM_GROUP, None, &[], None);
         }
+        std::fs::write(dir.join("a.txt"), "both.example\n").unwrap();
+        let report = load_blocklists_into(&dir_s, &lists, &LoadOptions::default()).await.unwrap();
+        let snap = snapshot(&lists).await;
+        assert_eq!((report.added, report.removed), (0, 1));
+        assert_eq!(snap.patterns["user.example"].comment.as_deref(), Some("manual"));
+        assert_eq!(snap.patterns["both.example"].origins.len(), 2);
+        assert!(!snap.patterns.contains_key("file.example"));
+
+        Arc::make_mut(&mut *lists.write().await).remove_rule("both.example", false);
+        load_blocklists_into(&dir_s, &lists, &LoadOptions::default()).await.unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        let snap = snapshot(&lists).await;
+        assert_eq!(snap.patterns["both.example"].origins.len(), 1);
+        assert!(snap.patterns.contains_key("user.example"));
+    }
+
+    // /add and /add-bulk take the same patterns.
+    #[test]
+    fn api_patterns_are_normalized_or_refused() {
+        assert_eq!(normalize_pattern(" Ads.Example.COM. ").as_deref(), Some("ads.example.com"));
+        assert_eq!(normalize_pattern("*.tracker.example").as_deref(), Some("*.tracker.example"));
+        assert_eq!(normalize_pattern("xn--bcher-kva.example").as_deref(), Some("xn--bcher-kva.example"));
+        for bad in ["", ".", "a..b", "ads example.com", "/ads\\.example/", "ads.example.com/path", &"a.".repeat(130)] {
+            assert_eq!(normalize_pattern(bad), None, "{:?}", bad);
+        }
+    }
+
+    // The filter may only ever let extra names through to the full matcher, never hide a match.
+    #[test]
+    fn filter_never_hides_a_match() {
+        let mut bl = Blocklist::default();
+        for i in 0..5_000 { bl.insert_pattern(format!("host{}.example", i), DEFAULT_GROUP, "t"); }
+        bl.insert_pattern("*.ads.example.net".to_string(), DEFAULT_GROUP, "t");
+        bl.insert_pattern("*.io".to_string(), DEFAULT_GROUP, "t");
+        bl.insert_pattern("track.*".to_string(), DEFAULT_GROUP, "t");
+        bl.insert_pattern("ads.*.cdn.example".to_string(), DEFAULT_GROUP, "t");
+        bl.insert_pattern("beacon*".to_string(), DEFAULT_GROUP, "t");
+        bl.insert_tld("zip".to_string(), DEFAULT_GROUP, "t");
+        let names = ["host4999.example", "a.b.ads.example.net", "x.io", "trackers.example.org", "file.zip", "ads.eu.cdn.example", "beacons.example.org", "unrelated.example.org", "host5000.example"];
+        let unfiltered: Vec<bool> = names.iter().map(|n| find_match(n, None, &bl).is_some()).collect();
+        bl.build_filter();
+        bl.insert_pattern("late.example".to_string(), CUSTOM_GROUP, API_SOURCE);
+        let filtered: Vec<bool> = names.iter().map(|n| find_match(n, None, &bl).is_some()).collect();
+        assert_eq!(unfiltered, filtered);
+        assert_eq!(filtered, [true, true, true, true, true, true, true, false, false]);
+        assert!(find_match("late.example.", None, &bl).is_some());
+    }
+
+    #[test]
+    fn middle_wildcards_match_as_globs() {
+        let mut bl = Blocklist::default();
+        bl.insert_pattern("ads.*.example.com".to_string(), DEFAULT_GROUP, "t");
+        bl.insert_pattern("*tracker*.example.net".to_string(), DEFAULT_GROUP, "t");
+        bl.insert_pattern("metrics.*.*.example.org".to_string(), DEFAULT_GROUP, "t");
+        bl.build_filter();
+        let hits = |n: &str| find_match(n, None, &bl).map(|m| m.rule);
+        assert_eq!(hits("ads.eu.example.com").as_deref(), Some("ads.*.example.com"));
+        assert_eq!(hits("ads.a.b.example.com").as_deref(), Some("ads.*.example.com"));
+        assert_eq!(hits("ads.example.com"), None);
+        assert_eq!(hits("xads.eu.example.com"), None);
+        assert_eq!(hits("eu.mytracker1.example.net").as_deref(), Some("*tracker*.example.net"));
+        assert_eq!(hits("tracker.example.net.evil"), None);
+        assert_eq!(hits("metrics.a.b.example.org").as_deref(), Some("metrics.*.*.example.org"));
+        assert_eq!(hits("metrics.ab.example.org"), None);
+        bl.patterns.remove("ads.*.example.com");
+        assert!(find_match("ads.eu.example.com", None, &bl).is_none());
+    }
+
+    #[test]
+    fn rpz_policies_map_onto_rules() {
+        let zone = "$TTL 300\n\
+            @ SOA ns.rpz.example. admin.rpz.example. ( 1 3600\n  600 86400 60 )\n\
+            \tNS ns.rpz.example.\n\
+            $ORIGIN rpz.example.\n\
+            bad.example CNAME . ; blocked\n\
+            *.bad.example.rpz.example. IN CNAME .\n\
+            ok.bad.example CNAME rpz-passthru.\n\
+            quiet.example 60 IN CNAME *.\n\
+            garden.example CNAME walled.example.net.\n\
+            local.example A 10.0.0.7\n\
+            32.1.2.0.192.rpz-ip CNAME .\n";
+        let mut bl = Blocklist::default();
+        for_each_entry(zone, ListFormat::Rpz, &LoadOptions::default(), |e| bl.insert_entry(e, "rpz", "t"));
+        bl.build_filter();
+        assert_eq!(find_match("bad.example", None, &bl).map(|m| m.action), Some(None));
+        assert!(find_match("x.bad.example", None, &bl).is_some());
+        assert!(find_match("ok.bad.example", None, &bl).is_none());
+        assert_eq!(find_match("quiet.example", None, &bl).and_then(|m| m.action), Some(BlockAction::NoData));
+        assert_eq!(find_match("garden.example", None, &bl).and_then(|m| m.action), Some(BlockAction::Redirect("walled.example.net".to_string())));
+        assert_eq!(bl.find_local("local.example"), Some(&["10.0.0.7".parse().unwrap()][..]));
+        assert!(find_match("32.1.2.0.192.rpz-ip", None, &bl).is_none());
+        assert_eq!(bl.len(), 6);
+    }
+
+    // Lists mix formats; every rule line is read by its own shape and the rest is accounted for.
+    #[test]
+    fn list_formats_are_detected_per_line() {
+        let text = "[Adblock Plus 2.0]\n! Title: mixed\n\
+            0.0.0.0 hosts.example # trailing\n\
+            127.0.0.1 localhost\n\
+            plain.example\n\
+            *.wild.example\n\
+            ||abp.example^\n\
+            @@||ok.abp.example^\n\
+            ||page.example^$third-party\n\
+            example.com##.banner\n\
+            bad domain here\n\
+            exa$mple.com\n";
+        let mut bl = Blocklist::default();
+        let diag = for_each_entry(text, ListFormat::detect(Path::new("l.txt"), text), &LoadOptions::default(), |e| bl.insert_entry(e, DEFAULT_GROUP, "t"));
+        bl.build_filter();
+        assert_eq!((diag.format, diag.skipped, diag.invalid), ("mixed", 2, 2));
+        assert_eq!(diag.issues.iter().map(|i| (i.line, i.invalid)).collect::<Vec<_>>(), [(9, false), (10, false), (11, true), (12, true)]);
+        for name in ["hosts.example", "plain.example", "a.wild.example", "abp.example", "x.abp.example"] {
+            assert!(find_match(name, None, &bl).is_some(), "{}", name);
+        }
+        for name in ["localhost", "ok.abp.example", "page.example", "example.com"] {
+            assert!(find_match(name, None, &bl).is_none(), "{}", name);
+        }
+        let dnsmasq = "# dnsmasq list\naddress=/ads.example/0.0.0.0\nlocal=/lan/\n";
+        assert!(ListFormat::detect(Path::new("l.txt"), dnsmasq) == ListFormat::Dnsmasq);
+        let diag = for_each_entry(dnsmasq, ListFormat::Dnsmasq, &LoadOptions::default(), |_| {});
+        assert_eq!((diag.format, diag.skipped, diag.invalid), ("dnsmasq", 1, 0));
+    }
+
+    #[test]
+    fn type_limited_rules_only_block_their_types() {
+        let mut bl = Blocklist::default();
+        bl.insert_pattern("tunnel.example".to_string(), CUSTOM_GROUP, API_SOURCE);
+        bl.patterns.get_mut("tunnel.example").unwrap().types = vec![RecordType::TXT, RecordType::NULL];
+        bl.insert_pattern("*.example".to_string(), DEFAULT_GROUP, "t");
+        bl.patterns.get_mut("*.example").unwrap().types = vec![RecordType::AAAA];
+        bl.build_filter();
+        let rule = |qtype| find_match("tunnel.example", qtype, &bl).map(|m| m.rule);
+        assert_eq!(rule(Some(RecordType::TXT)).as_deref(), Some("tunnel.example"));
+        assert_eq!(rule(Some(RecordType::A)), None);
+        // an exact rule for other types doesn't hide a wildcard that applies
+        assert_eq!(rule(Some(RecordType::AAAA)).as_deref(), Some("*.example"));
+        assert!(rule(None).is_some());
+        let m = find_match("tunnel.example", Some(RecordType::NULL), &bl).unwrap();
+        assert_eq!(m.types, vec!["TXT".to_string(), "NULL".to_string()]);
+    }
+
+    #[test]
+    fn group_modes_pick_the_first_matching_group() {
+        assert_eq!(GroupMode::parse("redirect:10.0.0.2").unwrap().block_ip, Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert!(GroupMode::parse("null:10.0.0.2").is_none());
+        assert!(GroupMode::parse("sinkhole").is_none());
+        let modes = GroupModes::default();
+        modes.set("Malware", GroupMode::parse("null"));
+        let origin = |group: &str| Origin { group: group.to_string(), source: "t".to_string() };
+        let m = BlockMatch { rule: "bad.example".to_string(), kind: "exact", origins: vec![origin("ads"), origin("malware")], action: None, types: Vec::new() };
+        assert_eq!(modes.for_match(&m).map(|(g, mode)| (g, mode.mode)), Some(("malware".to_string(), BlockingMode::Null)));
+        modes.set("malware", None);
+        assert!(modes.for_match(&m).is_none());
     }
-    false
 }
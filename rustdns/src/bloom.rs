@@ -0,0 +1,59 @@
+// Bits per key and probes per lookup; about a 1% false-positive rate.
+const BITS_PER_KEY: usize = 10;
+const PROBES: u64 = 7;
+
+// Fixed-size bloom filter over strings. Answers "definitely not present" or "maybe present";
+// there are no false negatives, so a miss can safely skip the exact lookup behind it.
+#[derive(Clone, Default)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    // keys it was sized for; inserting more only raises the false-positive rate
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    pub fn with_capacity(keys: usize) -> Self {
+        let words = (keys.max(64) * BITS_PER_KEY).div_ceil(64);
+        BloomFilter { bits: vec![0; words], capacity: keys.max(64), len: 0 }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        let nbits = self.bits.len() as u64 * 64;
+        for bit in probes(key, nbits) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let nbits = self.bits.len() as u64 * 64;
+        probes(key, nbits).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    // More keys were inserted than it was sized for.
+    pub fn is_overfull(&self) -> bool {
+        self.len > self.capacity
+    }
+}
+
+// Bit positions for `key`: double hashing over the two halves of one 64-bit hash.
+fn probes(key: &[u8], nbits: u64) -> impl Iterator<Item = u64> {
+    let h = hash(key);
+    let (h1, h2) = (h & 0xffff_ffff, (h >> 32) | 1);
+    (0..PROBES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % nbits)
+}
+
+// FNV-1a followed by a splitmix64 finalizer so both halves are well mixed.
+fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in key {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
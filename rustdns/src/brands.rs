@@ -0,0 +1,104 @@
+use std::sync::RwLock;
+
+// Protected brand domains (bank, employer, ...). Queries for names that look like one of them
+// but are not under it raise a `lookalike` alert.
+#[derive(Default)]
+pub struct BrandGuard {
+    brands: RwLock<Vec<String>>,
+}
+
+impl BrandGuard {
+    // Seeded from RUSTDNS_PROTECTED_BRANDS (comma separated, e.g. "mybank.com,example.org").
+    pub fn from_env() -> Self {
+        let guard = BrandGuard::default();
+        if let Ok(v) = crate::envvars::var("RUSTDNS_PROTECTED_BRANDS") {
+            for b in v.split(',') { guard.add(b); }
+        }
+        guard
+    }
+
+    pub fn add(&self, brand: &str) -> Option<String> {
+        let b = brand.trim().trim_end_matches('.').to_lowercase();
+        if !b.contains('.') { return None }
+        let mut brands = self.brands.write().unwrap();
+        if !brands.contains(&b) { brands.push(b.clone()); }
+        Some(b)
+    }
+
+    pub fn remove(&self, brand: &str) -> bool {
+        let b = brand.trim().trim_end_matches('.').to_lowercase();
+        let mut brands = self.brands.write().unwrap();
+        let before = brands.len();
+        brands.retain(|x| *x != b);
+        brands.len() != before
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.brands.read().unwrap().clone()
+    }
+
+    // If `name` imitates a protected brand, return (brand, reason).
+    pub fn check(&self, name: &str) -> Option<(String, String)> {
+        let brands = self.brands.read().unwrap();
+        if brands.is_empty() { return None }
+        let name = name.trim_end_matches('.').to_lowercase();
+        brands.iter().find_map(|b| lookalike(&name, b).map(|r| (b.clone(), r)))
+    }
+}
+
+// The label a brand is recognized by: `mybank` for `mybank.com` / `login.mybank.co.uk`.
+fn brand_label(brand: &str) -> &str {
+    let labels: Vec<&str> = brand.split('.').collect();
+    labels.iter().copied().max_by_key(|l| l.len()).unwrap_or(brand)
+}
+
+fn lookalike(name: &str, brand: &str) -> Option<String> {
+    if name == brand || name.ends_with(&format!(".{}", brand)) { return None }
+    let target = brand_label(brand);
+    if target.len() < 4 { return None }
+    let labels: Vec<&str> = name.split('.').collect();
+    // skip the TLD; every other label is a candidate
+    for label in labels.iter().take(labels.len().saturating_sub(1)) {
+        if *label == target {
+            return Some(format!("uses brand label '{}' outside {}", target, brand));
+        }
+        if unconfuse(label) == target {
+            return Some(format!("'{}' is a homoglyph of '{}'", label, target));
+        }
+        let max = if target.len() <= 6 { 1 } else { 2 };
+        let d = levenshtein(label, target);
+        if d > 0 && d <= max {
+            return Some(format!("'{}' is {} edit(s) from '{}'", label, d, target));
+        }
+        if label.len() > target.len() + 2 && label.split('-').any(|part| part == target) {
+            return Some(format!("'{}' embeds brand label '{}'", label, target));
+        }
+    }
+    None
+}
+
+// Fold common visual substitutions back to the letters they imitate.
+fn unconfuse(s: &str) -> String {
+    s.replace("rn", "m").replace("vv", "w").chars().map(|c| match c {
+        '0' => 'o',
+        '1' | '!' => 'l',
+        '3' => 'e',
+        '5' => 's',
+        '@' => 'a',
+        c => c,
+    }).collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
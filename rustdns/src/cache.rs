@@ -0,0 +1,157 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::{DNSClass, RData, Record, RecordType};
+
+// Question plus the EDNS bits that change what upstream sends back.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: String,
+    qtype: RecordType,
+    qclass: DNSClass,
+    edns: bool,
+    dnssec_ok: bool,
+}
+
+impl CacheKey {
+    fn for_query(msg: &Message) -> Option<Self> {
+        let q = msg.queries().first()?;
+        Some(CacheKey {
+            name: q.name().to_string().to_lowercase(),
+            qtype: q.query_type(),
+            qclass: q.query_class(),
+            edns: msg.extensions().is_some(),
+            dnssec_ok: msg.extensions().as_ref().map(|e| e.dnssec_ok()).unwrap_or(false),
+        })
+    }
+}
+
+struct CacheEntry {
+    response: Message,
+    stored: Instant,
+    ttl: u32,
+}
+
+// Upstream answers kept for their TTL. RUSTDNS_CACHE_SIZE (default 10000 entries, 2000 in the
+// minimal build; 0 disables) bounds it; RUSTDNS_CACHE_MAX_TTL (default 86400) caps how long any answer is kept, and negative
+// answers without an SOA are kept for RUSTDNS_CACHE_NEGATIVE_TTL (default 60) seconds. Served
+// answers have their TTLs counted down by the time spent in the cache.
+pub struct ResponseCache {
+    pub capacity: usize,
+    pub max_ttl: u32,
+    pub negative_ttl: u32,
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// Reported under `cache` in /stats.
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ResponseCache {
+    pub fn from_env() -> Self {
+        let num = |key: &str, default: u64| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        ResponseCache {
+            capacity: num("RUSTDNS_CACHE_SIZE", if cfg!(feature = "minimal") { 2_000 } else { 10_000 }) as usize,
+            max_ttl: num("RUSTDNS_CACHE_MAX_TTL", 86_400) as u32,
+            negative_ttl: num("RUSTDNS_CACHE_NEGATIVE_TTL", 60) as u32,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    // Cached answer to `query` with the query's id and TTLs reduced by its age, plus that remaining
+    // lifetime in seconds.
+    pub fn get(&self, query: &Message) -> Option<(Vec<u8>, u32)> {
+        if !self.enabled() { return None }
+        let key = CacheKey::for_query(query)?;
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(&key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let age = entry.stored.elapsed().as_secs().min(u64::from(u32::MAX)) as u32;
+        if age >= entry.ttl {
+            entries.remove(&key);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let remaining = entry.ttl - age;
+        let mut resp = entry.response.clone();
+        drop(entries);
+        resp.set_id(query.id());
+        let age_records = |records: &mut Vec<Record>| {
+            for rec in records.iter_mut().filter(|r| r.record_type() != RecordType::OPT) {
+                rec.set_ttl(rec.ttl().saturating_sub(age));
+            }
+        };
+        age_records(resp.answers_mut());
+        age_records(resp.name_servers_mut());
+        age_records(resp.additionals_mut());
+        let raw = resp.to_vec().ok()?;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some((raw, remaining))
+    }
+
+    // Store the upstream answer `raw` to `query`, if it is cacheable.
+    pub fn insert(&self, query: &Message, raw: &[u8]) {
+        if !self.enabled() { return }
+        let (Some(key), Ok(resp)) = (CacheKey::for_query(query), Message::from_vec(raw)) else { return };
+        if resp.truncated() || !matches!(resp.response_code(), ResponseCode::NoError | ResponseCode::NXDomain) { return }
+        let ttl = self.ttl_for(&resp).min(self.max_ttl);
+        if ttl == 0 { return }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            evict(&mut entries, self.capacity);
+        }
+        entries.insert(key, CacheEntry { response: resp, stored: Instant::now(), ttl });
+    }
+
+    // Lowest TTL in the answer; for negative answers the SOA's (RFC 2308), else the default.
+    fn ttl_for(&self, resp: &Message) -> u32 {
+        let ttls = |records: &[Record]| records.iter().filter(|r| r.record_type() != RecordType::OPT).map(|r| r.ttl()).min();
+        if let Some(ttl) = ttls(resp.answers()) { return ttl }
+        resp.name_servers().iter()
+            .find_map(|r| match r.data() {
+                Some(RData::SOA(soa)) => Some(r.ttl().min(soa.minimum())),
+                _ => None,
+            })
+            .unwrap_or(self.negative_ttl)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity,
+            entries: self.entries.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Make room: drop expired entries, then the tenth of the rest closest to expiry.
+fn evict(entries: &mut HashMap<CacheKey, CacheEntry>, capacity: usize) {
+    let now = Instant::now();
+    let expires = |e: &CacheEntry| e.stored + Duration::from_secs(u64::from(e.ttl));
+    entries.retain(|_, e| expires(e) > now);
+    if entries.len() < capacity { return }
+    let mut by_expiry: Vec<(Instant, CacheKey)> = entries.iter().map(|(k, e)| (expires(e), k.clone())).collect();
+    by_expiry.sort_by_key(|(at, _)| *at);
+    for (_, key) in by_expiry.into_iter().take((capacity / 10).max(1)) {
+        entries.remove(&key);
+    }
+}
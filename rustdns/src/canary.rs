@@ -0,0 +1,53 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+// Canary (honeypot) names no legitimate device should ever resolve, e.g. a fake internal host
+// seeded in documents. Any query for one, or for a name beneath it, raises a `canary` alert.
+#[derive(Default)]
+pub struct CanarySet {
+    names: RwLock<HashSet<String>>,
+}
+
+impl CanarySet {
+    // Seeded from RUSTDNS_CANARY_DOMAINS (comma separated).
+    pub fn from_env() -> Self {
+        let set = CanarySet::default();
+        if let Ok(v) = crate::envvars::var("RUSTDNS_CANARY_DOMAINS") {
+            for d in v.split(',') { set.add(d); }
+        }
+        set
+    }
+
+    pub fn add(&self, name: &str) -> Option<String> {
+        let n = name.trim().trim_end_matches('.').to_lowercase();
+        if n.is_empty() { return None }
+        self.names.write().unwrap().insert(n.clone());
+        Some(n)
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        let n = name.trim().trim_end_matches('.').to_lowercase();
+        self.names.write().unwrap().remove(&n)
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let mut v: Vec<String> = self.names.read().unwrap().iter().cloned().collect();
+        v.sort();
+        v
+    }
+
+    // The canary `name` falls under, if any.
+    pub fn check(&self, name: &str) -> Option<String> {
+        let names = self.names.read().unwrap();
+        if names.is_empty() { return None }
+        let name = name.trim_end_matches('.').to_lowercase();
+        let mut cur = name.as_str();
+        loop {
+            if names.contains(cur) { return Some(cur.to_string()) }
+            match cur.split_once('.') {
+                Some((_, parent)) => cur = parent,
+                None => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::blocklist::{read_list_file, BlockMatch, Origin};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+// Domains by category, from a local category database. A domain's entry covers its subdomains.
+#[derive(Default)]
+struct CategoryIndex {
+    // category names, indexed by the ids in `domains`
+    names: Vec<String>,
+    sources: Vec<String>,
+    counts: Vec<usize>,
+    domains: HashMap<String, Vec<u16>>,
+}
+
+impl CategoryIndex {
+    fn add_file(&mut self, category: &str, path: &Path) -> Result<()> {
+        let text = read_list_file(path)?;
+        let id = match self.names.iter().position(|n| n == category) {
+            Some(i) => i,
+            None => {
+                self.names.push(category.to_string());
+                self.sources.push(path.to_string_lossy().into_owned());
+                self.counts.push(0);
+                self.names.len() - 1
+            }
+        };
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            // plain domain lists, and hosts-style lines as some feeds ship them
+            let Some(domain) = line.split_whitespace().last() else { continue };
+            let domain = domain.trim_matches('.').to_lowercase();
+            if domain.is_empty() || domain.parse::<std::net::IpAddr>().is_ok() { continue }
+            let ids = self.domains.entry(domain).or_default();
+            if !ids.contains(&(id as u16)) {
+                ids.push(id as u16);
+                self.counts[id] += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // The most specific listed domain covering `name`, with its category ids.
+    fn lookup(&self, name: &str) -> Option<(&str, &[u16])> {
+        let mut cur = name;
+        loop {
+            if let Some((domain, ids)) = self.domains.get_key_value(cur) { return Some((domain.as_str(), ids.as_slice())) }
+            cur = cur.split_once('.')?.1;
+        }
+    }
+}
+
+// A category as reported by GET /categories.
+#[derive(Serialize)]
+pub struct CategoryInfo {
+    pub name: String,
+    pub domains: usize,
+    pub blocked: bool,
+}
+
+// Blocking by category instead of list membership. RUSTDNS_CATEGORY_DIR (default ./categories)
+// holds the category database: `<category>.txt` files (optionally `.gz` / `.zst`), or the
+// `<category>/domains` layout of external feeds such as the UT1 blacklists, one domain per line.
+// RUSTDNS_BLOCK_CATEGORIES=gambling,social blocks categories from the start; /categories toggles
+// them at runtime.
+pub struct Categories {
+    pub dir: PathBuf,
+    index: RwLock<Arc<CategoryIndex>>,
+    blocked: RwLock<HashSet<String>>,
+}
+
+impl Categories {
+    pub fn from_env() -> Self {
+        let dir = PathBuf::from(crate::envvars::var("RUSTDNS_CATEGORY_DIR").unwrap_or_else(|_| "./categories".to_string()));
+        let blocked = crate::envvars::var("RUSTDNS_BLOCK_CATEGORIES").unwrap_or_default()
+            .split(',')
+            .map(|c| c.trim().to_lowercase())
+            .filter(|c| !c.is_empty())
+            .collect();
+        Categories { dir, index: RwLock::new(Arc::new(CategoryIndex::default())), blocked: RwLock::new(blocked) }
+    }
+
+    // (Re)load the category database. Returns the number of categories and domains.
+    pub async fn reload(&self) -> Result<(usize, usize)> {
+        let dir = self.dir.clone();
+        let index = tokio::task::spawn_blocking(move || -> Result<CategoryIndex> {
+            let mut index = CategoryIndex::default();
+            if !dir.is_dir() { return Ok(index) }
+            let mut files: Vec<(String, PathBuf)> = Vec::new();
+            for entry in std::fs::read_dir(&dir)?.flatten() {
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().to_lowercase();
+                if path.is_dir() {
+                    let domains = path.join("domains");
+                    if domains.is_file() { files.push((name, domains)); }
+                } else if let Some(category) = [".txt", ".txt.gz", ".txt.zst"].iter().find_map(|ext| name.strip_suffix(ext)) {
+                    files.push((category.to_string(), path));
+                }
+            }
+            files.sort();
+            for (category, path) in files {
+                if let Err(e) = index.add_file(&category, &path) {
+                    tracing::warn!("skipping category file {}: {:?}", path.display(), e);
+                }
+            }
+            Ok(index)
+        }).await??;
+        let counts = (index.names.len(), index.domains.len());
+        *self.index.write().unwrap() = Arc::new(index);
+        Ok(counts)
+    }
+
+    // Categories of `name`, from its most specific listed domain.
+    pub fn categories_of(&self, name: &str) -> Vec<String> {
+        let index = self.index.read().unwrap().clone();
+        let name = name.trim_end_matches('.').to_lowercase();
+        index.lookup(&name)
+            .map(|(_, ids)| ids.iter().map(|i| index.names[usize::from(*i)].clone()).collect())
+            .unwrap_or_default()
+    }
+
+    // The block for `name` if it is in a blocked category.
+    pub fn check(&self, name: &str) -> Option<BlockMatch> {
+        let blocked = self.blocked.read().unwrap();
+        if blocked.is_empty() { return None }
+        let index = self.index.read().unwrap().clone();
+        let name = name.trim_end_matches('.').to_lowercase();
+        let (domain, ids) = index.lookup(&name)?;
+        let origins: Vec<Origin> = ids.iter()
+            .map(|i| usize::from(*i))
+            .filter(|i| blocked.contains(&index.names[*i]))
+            .map(|i| Origin { group: index.names[i].clone(), source: index.sources[i].clone() })
+            .collect();
+        if origins.is_empty() { return None }
+        Some(BlockMatch { rule: domain.to_string(), kind: "category", origins, action: None, types: Vec::new() })
+    }
+
+    // Block or unblock `category`. False for a category the database doesn't have.
+    pub fn set_blocked(&self, category: &str, block: bool) -> bool {
+        let category = category.to_lowercase();
+        if !self.index.read().unwrap().names.contains(&category) { return false }
+        let mut blocked = self.blocked.write().unwrap();
+        if block { blocked.insert(category); } else { blocked.remove(&category); }
+        true
+    }
+
+    // Names of the blocked categories, sorted.
+    pub fn blocked(&self) -> Vec<String> {
+        let mut v: Vec<String> = self.blocked.read().unwrap().iter().cloned().collect();
+        v.sort();
+        v
+    }
+
+    pub fn list(&self) -> Vec<CategoryInfo> {
+        let index = self.index.read().unwrap().clone();
+        let blocked = self.blocked.read().unwrap();
+        let mut v: Vec<CategoryInfo> = index.names.iter().zip(&index.counts)
+            .map(|(n, c)| CategoryInfo { name: n.clone(), domains: *c, blocked: blocked.contains(n) })
+            .collect();
+        v.sort_by(|a, b| a.name.cmp(&b.name));
+        v
+    }
+}
@@ -0,0 +1,472 @@
+use crate::metrics::{CappedCounter, LabelCount};
+use crate::store::{data_dir, read_json, write_json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often the DHCP lease file is checked for changes.
+const LEASES_RECHECK: Duration = Duration::from_secs(30);
+
+// Seconds in a day, for RUSTDNS_CLIENT_AUTO_DAYS.
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+// Domains GET /clients/{id} lists per client; CappedCounter tracks four times as many.
+const CLIENT_TOP_DOMAINS: usize = 10;
+
+// Identity per-client policy (rate limits, debug tracing) is keyed on: an IPv4 address, an IPv6
+// prefix such as `2001:db8:1:2::/64`, or `duid:<hex>` for a DHCPv6 client.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize)]
+pub struct ClientKey(pub String);
+
+impl std::fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Maps query source addresses to client keys. Devices using IPv6 privacy extensions rotate
+// addresses inside their /64, so IPv6 sources are grouped by prefix (RUSTDNS_CLIENT_V6_PREFIX,
+// default 64; 128 keeps full addresses). When RUSTDNS_DHCP_LEASES names a dnsmasq lease file,
+// addresses with a DHCPv6 lease are identified by the lease's DUID instead.
+pub struct ClientIdentity {
+    pub v6_prefix: u8,
+    pub leases_path: Option<PathBuf>,
+    leases: Mutex<Leases>,
+}
+
+#[derive(Default)]
+struct Leases {
+    checked: Option<Instant>,
+    modified: Option<SystemTime>,
+    duids: HashMap<Ipv6Addr, String>,
+    macs: HashMap<Ipv4Addr, String>,
+}
+
+impl ClientIdentity {
+    pub fn from_env() -> Self {
+        let v6_prefix = crate::envvars::var("RUSTDNS_CLIENT_V6_PREFIX").ok().and_then(|v| v.parse().ok()).unwrap_or(64u8).min(128);
+        ClientIdentity {
+            v6_prefix,
+            leases_path: crate::envvars::var("RUSTDNS_DHCP_LEASES").ok().filter(|p| !p.is_empty()).map(PathBuf::from),
+            leases: Mutex::new(Leases::default()),
+        }
+    }
+
+    pub fn key(&self, ip: IpAddr) -> ClientKey {
+        match ip {
+            IpAddr::V4(v4) => ClientKey(v4.to_string()),
+            IpAddr::V6(v6) => {
+                if let Some(v4) = v6.to_ipv4_mapped() { return ClientKey(v4.to_string()) }
+                if let Some(duid) = self.duid(&v6) { return ClientKey(format!("duid:{}", duid)) }
+                if self.v6_prefix >= 128 { return ClientKey(v6.to_string()) }
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.v6_prefix)).unwrap_or(0);
+                let net = Ipv6Addr::from(u128::from(v6) & mask);
+                ClientKey(format!("{}/{}", net, self.v6_prefix))
+            }
+        }
+    }
+
+    fn duid(&self, ip: &Ipv6Addr) -> Option<String> {
+        self.with_leases(|l| l.duids.get(ip).cloned())
+    }
+
+    // MAC address of the DHCPv4 lease holding `ip`, if any.
+    pub fn mac(&self, ip: &Ipv4Addr) -> Option<String> {
+        self.with_leases(|l| l.macs.get(ip).cloned())
+    }
+
+    fn with_leases<T>(&self, f: impl FnOnce(&Leases) -> Option<T>) -> Option<T> {
+        let path = self.leases_path.as_ref()?;
+        let mut leases = self.leases.lock().unwrap();
+        if leases.checked.map(|t| t.elapsed() >= LEASES_RECHECK).unwrap_or(true) {
+            leases.checked = Some(Instant::now());
+            let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+            if modified != leases.modified {
+                leases.modified = modified;
+                let raw = std::fs::read_to_string(path).unwrap_or_default();
+                (leases.duids, leases.macs) = parse_dnsmasq_leases(&raw);
+            }
+        }
+        f(&leases)
+    }
+}
+
+// Leases in a dnsmasq lease file: `<expiry> <mac> <ipv4> <hostname> <client-id>` for DHCPv4 and
+// `<expiry> <iaid> <ipv6> <hostname> <duid>` for DHCPv6. The server's own `duid` line is skipped.
+fn parse_dnsmasq_leases(s: &str) -> (HashMap<Ipv6Addr, String>, HashMap<Ipv4Addr, String>) {
+    let mut duids = HashMap::new();
+    let mut macs = HashMap::new();
+    for line in s.lines() {
+        let f: Vec<&str> = line.split_whitespace().collect();
+        if f.len() < 5 { continue }
+        match f[2].parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) if f[4] != "*" => { duids.insert(ip, f[4].to_lowercase()); }
+            Ok(IpAddr::V4(ip)) => { macs.insert(ip, f[1].to_lowercase()); }
+            _ => {}
+        }
+    }
+    (duids, macs)
+}
+
+// A known device. Identifiers are addresses, IPv6 prefixes (`2001:db8::/64`), `duid:<hex>` or
+// `mac:<addr>` (matched through DHCP leases); a query from any of them counts as this client.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClientRecord {
+    pub id: u64,
+    pub name: Option<String>,
+    pub identifiers: Vec<String>,
+    pub groups: Vec<String>,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+// Fields accepted by POST /clients and PUT /clients/{id}; absent fields are left unchanged.
+#[derive(Deserialize)]
+pub struct ClientUpdate {
+    pub name: Option<String>,
+    pub identifiers: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+}
+
+// What a client did since the server started, for GET /clients/{id}.
+struct Activity {
+    queries: u64,
+    blocked: u64,
+    audited: u64,
+    domains: CappedCounter,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity { queries: 0, blocked: 0, audited: 0, domains: CappedCounter::new(CLIENT_TOP_DOMAINS) }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ActivityView {
+    pub queries: u64,
+    pub blocked: u64,
+    pub audited: u64,
+    // share of queries that were blocked, 0 to 1
+    pub block_ratio: f64,
+    pub top_domains: Vec<LabelCount>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RegistryFile {
+    next_id: u64,
+    clients: Vec<ClientRecord>,
+}
+
+// Table of known clients, persisted to `clients.json` in the data directory. Clients are added
+// automatically the first time they query; names, identifiers and groups are managed through
+// /clients. Last-seen updates are written back by `flush` rather than on every query. Query
+// counts and top domains per client are kept in memory only and start over with the server.
+// Clients added automatically and never named or grouped are kept up to `auto_max`, making way
+// for new ones seen-longest-ago first, and forgotten after `auto_max_age` without a query.
+pub struct ClientRegistry {
+    path: PathBuf,
+    pub auto_max: usize,
+    pub auto_max_age: Option<Duration>,
+    inner: Mutex<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    next_id: u64,
+    clients: HashMap<u64, ClientRecord>,
+    // identifier -> client id
+    index: HashMap<String, u64>,
+    activity: HashMap<u64, Activity>,
+    dirty: bool,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Canonical form of an identifier, or None if it isn't one.
+pub fn normalize_identifier(s: &str) -> Option<String> {
+    let s = s.trim().to_lowercase();
+    if let Ok(ip) = s.parse::<IpAddr>() { return Some(ip.to_string()) }
+    if let Some((net, len)) = s.split_once('/') {
+        let net: Ipv6Addr = net.parse().ok()?;
+        let len: u8 = len.parse().ok().filter(|l| *l <= 128)?;
+        return Some(format!("{}/{}", net, len));
+    }
+    if (s.starts_with("duid:") || s.starts_with("mac:")) && s.len() > 5 { return Some(s) }
+    None
+}
+
+impl RegistryInner {
+    fn reindex(&mut self) {
+        self.index = self.clients.values()
+            .flat_map(|c| c.identifiers.iter().map(move |i| (i.clone(), c.id)))
+            .collect();
+    }
+
+    // The client a query from `ip` (client key `key`, DHCP identifier `mac_id`) belongs to.
+    fn find(&self, ip: &str, key: &str, mac_id: Option<&String>) -> Option<u64> {
+        [Some(ip), Some(key), mac_id.map(String::as_str)].into_iter().flatten()
+            .find_map(|i| self.index.get(i).copied())
+    }
+
+    // Drop client `id` with its identifiers and activity.
+    fn forget(&mut self, id: u64) {
+        let Some(c) = self.clients.remove(&id) else { return };
+        for i in &c.identifiers {
+            if self.index.get(i) == Some(&id) { self.index.remove(i); }
+        }
+        self.activity.remove(&id);
+        self.dirty = true;
+    }
+
+    // Identifiers already claimed by a client other than `id`.
+    fn taken(&self, identifiers: &[String], id: u64) -> Vec<String> {
+        identifiers.iter().filter(|i| self.index.get(*i).map(|o| *o != id).unwrap_or(false)).cloned().collect()
+    }
+}
+
+impl ClientRegistry {
+    pub fn load(path: PathBuf) -> Self {
+        let file: RegistryFile = match read_json(&path) {
+            Ok(f) => f.unwrap_or_default(),
+            Err(e) => {
+                tracing::warn!("ignoring unreadable client registry {}: {:?}", path.display(), e);
+                RegistryFile::default()
+            }
+        };
+        let mut inner = RegistryInner {
+            next_id: file.next_id.max(file.clients.iter().map(|c| c.id).max().unwrap_or(0)),
+            clients: file.clients.into_iter().map(|c| (c.id, c)).collect(),
+            ..Default::default()
+        };
+        inner.reindex();
+        let auto_max = if cfg!(feature = "minimal") { 250 } else { 1000 };
+        ClientRegistry { path, auto_max, auto_max_age: Some(Duration::from_secs(30 * DAY_SECS)), inner: Mutex::new(inner) }
+    }
+
+    // Reads RUSTDNS_CLIENT_AUTO_MAX (clients registered automatically that are kept; 0 registers
+    // none) and RUSTDNS_CLIENT_AUTO_DAYS (days without a query before one is forgotten; 0 keeps
+    // them).
+    pub fn from_env() -> Self {
+        let mut registry = ClientRegistry::load(data_dir().join("clients.json"));
+        let num = |key: &str| crate::envvars::var(key).ok().and_then(|v| v.parse::<u64>().ok());
+        if let Some(max) = num("RUSTDNS_CLIENT_AUTO_MAX") { registry.auto_max = max as usize; }
+        if let Some(days) = num("RUSTDNS_CLIENT_AUTO_DAYS") {
+            registry.auto_max_age = (days > 0).then(|| Duration::from_secs(days.saturating_mul(DAY_SECS)));
+        }
+        registry
+    }
+
+    // Record a query from `ip` (client key `key`, DHCP `mac` if known), registering the client on
+    // first sight. Returns the client's id, or None for a new client when none are registered
+    // automatically.
+    pub fn touch(&self, ip: IpAddr, key: &ClientKey, mac: Option<&str>) -> Option<u64> {
+        let now = now_secs();
+        let mut inner = self.inner.lock().unwrap();
+        let ip_s = ip.to_string();
+        let mac_id = mac.map(|m| format!("mac:{}", m));
+        let id = match inner.find(&ip_s, &key.0, mac_id.as_ref()) {
+            Some(id) => id,
+            None => {
+                if self.auto_max == 0 { return None }
+                let mut auto: Vec<(u64, u64)> = inner.clients.values().filter(|c| is_auto(c)).map(|c| (c.last_seen, c.id)).collect();
+                if auto.len() >= self.auto_max {
+                    auto.sort_unstable();
+                    for (_, id) in auto.iter().take(auto.len() + 1 - self.auto_max) { inner.forget(*id); }
+                }
+                inner.next_id += 1;
+                let id = inner.next_id;
+                let identifiers: Vec<String> = std::iter::once(key.0.clone()).chain(mac_id).collect();
+                for i in &identifiers { inner.index.insert(i.clone(), id); }
+                inner.clients.insert(id, ClientRecord { id, name: None, identifiers, groups: Vec::new(), first_seen: now, last_seen: now });
+                id
+            }
+        };
+        if let Some(c) = inner.clients.get_mut(&id) {
+            c.last_seen = now;
+        }
+        inner.dirty = true;
+        Some(id)
+    }
+
+    // Forget clients registered automatically that haven't queried within `auto_max_age`.
+    pub fn expire(&self) {
+        let Some(max_age) = self.auto_max_age else { return };
+        let cutoff = now_secs().saturating_sub(max_age.as_secs());
+        let mut inner = self.inner.lock().unwrap();
+        let idle: Vec<u64> = inner.clients.values().filter(|c| is_auto(c) && c.last_seen < cutoff).map(|c| c.id).collect();
+        for id in idle { inner.forget(id); }
+    }
+
+    // Count a query by client `id` for `name` (`action` as in the query log), and return the
+    // client's name so callers can show it alongside the address.
+    pub fn record_query(&self, id: u64, name: &str, action: &str) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let activity = inner.activity.entry(id).or_default();
+        activity.queries += 1;
+        match action {
+            "blocked" => activity.blocked += 1,
+            "audited" => activity.audited += 1,
+            _ => {}
+        }
+        activity.domains.add(name);
+        inner.clients.get(&id).and_then(|c| c.name.clone())
+    }
+
+    // The client an identifier (address, IPv6 prefix, `duid:` or `mac:`) is assigned to.
+    pub fn lookup(&self, identifier: &str) -> Option<u64> {
+        let identifier = normalize_identifier(identifier)?;
+        self.inner.lock().unwrap().index.get(&identifier).copied()
+    }
+
+    // The client queries from `ip` (client key `key`, DHCP `mac` if known) are counted under.
+    pub fn lookup_source(&self, ip: IpAddr, key: &ClientKey, mac: Option<&str>) -> Option<u64> {
+        let mac_id = mac.map(|m| format!("mac:{}", m));
+        self.inner.lock().unwrap().find(&ip.to_string(), &key.0, mac_id.as_ref())
+    }
+
+    // Names of the clients `keys` (client keys, as /stats/top ranks them) belong to.
+    pub fn names(&self, keys: &[&str]) -> Vec<Option<String>> {
+        let inner = self.inner.lock().unwrap();
+        keys.iter()
+            .map(|k| inner.index.get(*k).and_then(|id| inner.clients.get(id)).and_then(|c| c.name.clone()))
+            .collect()
+    }
+
+    pub fn activity(&self, id: u64) -> ActivityView {
+        let inner = self.inner.lock().unwrap();
+        let Some(a) = inner.activity.get(&id) else {
+            return ActivityView { queries: 0, blocked: 0, audited: 0, block_ratio: 0.0, top_domains: Vec::new() };
+        };
+        let mut top_domains = a.domains.counts();
+        top_domains.sort_by(|x, y| y.count.cmp(&x.count).then_with(|| x.label.cmp(&y.label)));
+        top_domains.truncate(CLIENT_TOP_DOMAINS);
+        ActivityView {
+            queries: a.queries,
+            blocked: a.blocked,
+            audited: a.audited,
+            block_ratio: if a.queries > 0 { a.blocked as f64 / a.queries as f64 } else { 0.0 },
+            top_domains,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ClientRecord> {
+        let mut v: Vec<ClientRecord> = self.inner.lock().unwrap().clients.values().cloned().collect();
+        v.sort_by_key(|c| c.id);
+        v
+    }
+
+    pub fn get(&self, id: u64) -> Option<ClientRecord> {
+        self.inner.lock().unwrap().clients.get(&id).cloned()
+    }
+
+    pub fn create(&self, update: ClientUpdate) -> Result<ClientRecord, String> {
+        let identifiers = normalize_all(update.identifiers.unwrap_or_default())?;
+        let mut inner = self.inner.lock().unwrap();
+        let taken = inner.taken(&identifiers, 0);
+        if !taken.is_empty() { return Err(format!("identifiers already assigned: {}", taken.join(", "))) }
+        inner.next_id += 1;
+        let now = now_secs();
+        let rec = ClientRecord {
+            id: inner.next_id,
+            name: update.name,
+            identifiers,
+            groups: update.groups.unwrap_or_default(),
+            first_seen: now,
+            last_seen: now,
+        };
+        inner.clients.insert(rec.id, rec.clone());
+        inner.reindex();
+        drop(inner);
+        self.flush();
+        Ok(rec)
+    }
+
+    pub fn update(&self, id: u64, update: ClientUpdate) -> Result<Option<ClientRecord>, String> {
+        let identifiers = update.identifiers.map(normalize_all).transpose()?;
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(ids) = &identifiers {
+            let taken = inner.taken(ids, id);
+            if !taken.is_empty() { return Err(format!("identifiers already assigned: {}", taken.join(", "))) }
+        }
+        let Some(rec) = inner.clients.get_mut(&id) else { return Ok(None) };
+        if let Some(name) = update.name { rec.name = Some(name).filter(|n| !n.is_empty()); }
+        if let Some(ids) = identifiers { rec.identifiers = ids; }
+        if let Some(groups) = update.groups { rec.groups = groups; }
+        let rec = rec.clone();
+        inner.reindex();
+        drop(inner);
+        self.flush();
+        Ok(Some(rec))
+    }
+
+    pub fn delete(&self, id: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let removed = inner.clients.remove(&id).is_some();
+        inner.activity.remove(&id);
+        inner.reindex();
+        drop(inner);
+        if removed { self.flush(); }
+        removed
+    }
+
+    // Swap the whole table for `clients` (a restored backup) and write it.
+    pub fn replace(&self, clients: Vec<ClientRecord>) -> Result<(), String> {
+        let mut inner = RegistryInner::default();
+        for mut c in clients {
+            c.identifiers = normalize_all(c.identifiers)?;
+            let taken = inner.taken(&c.identifiers, c.id);
+            if !taken.is_empty() { return Err(format!("identifiers assigned twice: {}", taken.join(", "))) }
+            inner.next_id = inner.next_id.max(c.id);
+            for i in &c.identifiers { inner.index.insert(i.clone(), c.id); }
+            inner.clients.insert(c.id, c);
+        }
+        {
+            let mut cur = self.inner.lock().unwrap();
+            inner.next_id = inner.next_id.max(cur.next_id);
+            // clients that are still there keep their counts
+            let mut activity = std::mem::take(&mut cur.activity);
+            activity.retain(|id, _| inner.clients.contains_key(id));
+            inner.activity = activity;
+            *cur = inner;
+        }
+        self.flush();
+        Ok(())
+    }
+
+    // Write the table to disk.
+    pub fn flush(&self) {
+        let file = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.dirty = false;
+            RegistryFile { next_id: inner.next_id, clients: inner.clients.values().cloned().collect() }
+        };
+        if let Err(e) = write_json(&self.path, &file) {
+            tracing::warn!("saving client registry to {} failed: {:?}", self.path.display(), e);
+        }
+    }
+
+    // Write the table if anything changed since the last write.
+    pub fn flush_if_dirty(&self) {
+        if self.inner.lock().unwrap().dirty { self.flush(); }
+    }
+}
+
+// Whether `c` was registered automatically and never named or grouped since.
+fn is_auto(c: &ClientRecord) -> bool {
+    c.name.is_none() && c.groups.is_empty()
+}
+
+fn normalize_all(ids: Vec<String>) -> Result<Vec<String>, String> {
+    let mut out = Vec::new();
+    for raw in ids {
+        let id = normalize_identifier(&raw).ok_or_else(|| format!("invalid identifier '{}'", raw))?;
+        if !out.contains(&id) { out.push(id); }
+    }
+    Ok(out)
+}
@@ -0,0 +1,152 @@
+use crate::blocklist::Origin;
+use crate::store::{data_dir, read_json, write_json};
+use anyhow::{anyhow, Context, Result};
+use fst::{Map, MapBuilder, Streamer};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// Where `POST /compile` writes the compiled blocklist and where loads look for it
+// (RUSTDNS_COMPILED_LIST, default blocklist.fst in the data directory). Its manifest sits next to
+// it as `<file>.json`.
+pub fn compiled_path() -> PathBuf {
+    crate::envvars::var("RUSTDNS_COMPILED_LIST").map(PathBuf::from).unwrap_or_else(|_| data_dir().join("blocklist.fst"))
+}
+
+fn manifest_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".json");
+    path.with_file_name(name)
+}
+
+// A list file that went into the compiled set, with the size and mtime it had at the time.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompiledSource {
+    pub path: String,
+    pub group: String,
+    pub size: u64,
+    pub modified_ms: u64,
+    // entries parsed from the file, and how many of them ended up in the FST after dedup
+    pub entries: usize,
+    pub names: usize,
+}
+
+// A rule that isn't a plain exact name (wildcards, TLD rules, local records, forward zones).
+// These are few, so they stay in the manifest and are loaded into memory as usual.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompiledRule {
+    pub kind: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    pub source: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub created: u64,
+    pub hosts_overrides: bool,
+    // exact names in the FST
+    pub entries: usize,
+    pub sources: Vec<CompiledSource>,
+    pub rules: Vec<CompiledRule>,
+}
+
+// Size and mtime of a list file, for telling whether it changed since it was compiled.
+pub fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64;
+    Some((meta.len(), modified))
+}
+
+// Exact block names compiled into an FST and memory-mapped, so multi-million-entry lists cost
+// page cache rather than heap. Each name maps to the index of the list file it came from (the
+// first one, when several list it).
+pub struct CompiledList {
+    pub path: PathBuf,
+    pub manifest: Manifest,
+    map: Map<Mmap>,
+    origins: Vec<Origin>,
+}
+
+impl CompiledList {
+    pub fn open(path: &Path) -> Result<Self> {
+        let manifest: Manifest = read_json(&manifest_path(path))?.ok_or_else(|| anyhow!("manifest missing"))?;
+        let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        // the file is only ever replaced by rename, never rewritten in place, so the mapping
+        // stays valid for as long as it is held
+        let mmap = unsafe { Mmap::map(&file)? };
+        let map = Map::new(mmap)?;
+        if map.len() != manifest.entries {
+            return Err(anyhow!("manifest lists {} entries but the file holds {}", manifest.entries, map.len()));
+        }
+        let origins = manifest.sources.iter().map(|s| Origin { group: s.group.clone(), source: s.path.clone() }).collect();
+        Ok(CompiledList { path: path.to_path_buf(), manifest, map, origins })
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Origin> {
+        self.map.get(name).and_then(|i| self.origins.get(i as usize))
+    }
+
+    pub fn origin(&self, source: usize) -> Option<&Origin> {
+        self.origins.get(source)
+    }
+
+    // Every compiled name with its origin, in byte order.
+    pub fn entries(&self) -> Vec<(String, &Origin)> {
+        let mut out = Vec::with_capacity(self.map.len());
+        let mut stream = self.map.stream();
+        while let Some((k, v)) = stream.next() {
+            if let Some(o) = self.origins.get(v as usize) {
+                out.push((String::from_utf8_lossy(k).into_owned(), o));
+            }
+        }
+        out
+    }
+
+    // Whether every compiled list file is still on disk unchanged.
+    pub fn is_current(&self) -> bool {
+        self.manifest.sources.iter().all(|s| file_stamp(Path::new(&s.path)) == Some((s.size, s.modified_ms)))
+    }
+
+    pub fn covers(&self, path: &str) -> Option<&CompiledSource> {
+        self.manifest.sources.iter().find(|s| s.path == path)
+    }
+
+    // Reported under `compiled` in /lists.
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "path": self.path.display().to_string(),
+            "entries": self.len(),
+            "created": self.manifest.created,
+            "sources": self.manifest.sources.iter().map(|s| &s.path).collect::<Vec<_>>(),
+        })
+    }
+}
+
+// Write the FST for `names` (sorted by name; the first entry of duplicates wins) and then its
+// manifest, each via a temporary file so a running server never maps a partial file.
+pub fn write_compiled(path: &Path, names: &mut Vec<(String, u64)>, mut manifest: Manifest) -> Result<Manifest> {
+    names.sort_by(|a, b| a.0.cmp(&b.0));
+    names.dedup_by(|a, b| a.0 == b.0);
+    if let Some(dir) = path.parent() { std::fs::create_dir_all(dir)?; }
+    let tmp = path.with_extension("fst.tmp");
+    let mut builder = MapBuilder::new(std::io::BufWriter::new(std::fs::File::create(&tmp)?))?;
+    for (name, source) in names.iter() {
+        builder.insert(name, *source)?;
+    }
+    builder.finish()?;
+    std::fs::rename(&tmp, path)?;
+    manifest.entries = names.len();
+    for s in manifest.sources.iter_mut() { s.names = 0; }
+    for (_, source) in names.iter() {
+        if let Some(s) = manifest.sources.get_mut(*source as usize) { s.names += 1; }
+    }
+    write_json(&manifest_path(path), &manifest)?;
+    Ok(manifest)
+}
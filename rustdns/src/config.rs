@@ -0,0 +1,319 @@
+use crate::auth::Role;
+use crate::blocklist::{BlockingMode, BLOCK_MODES};
+use crate::compiled::compiled_path;
+use crate::listeners::{listeners_from_env, Listener};
+use crate::cors::{parse_methods, parse_origins};
+use crate::state::{ReloadPolicy, ServerState};
+use crate::settings::{parse_upstream, valid_upstream_timeout, UPSTREAM_TIMEOUT_MS};
+use crate::store::data_dir;
+use crate::watcher::{debounce_from_env, watch_lists_enabled};
+use serde_json::Value;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+
+// What the server starts with that must be valid for it to start at all: where it listens and how
+// blocked names are answered. main and the embedding APIs build it, runner seeds the state from
+// it, and the control handlers check changes with the same parsers.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub http_addr: SocketAddr,
+    pub udp_bind: SocketAddr,
+    // DNS listeners besides udp_bind, each with its own policy
+    pub listeners: Vec<Listener>,
+    pub blocking_mode: BlockingMode,
+    pub block_page_ip: Option<Ipv4Addr>,
+    // take sockets systemd passed and notify it of readiness; only the standalone binary does,
+    // an embedding app owns its own service
+    pub systemd: bool,
+}
+
+impl Config {
+    // RUSTDNS_HTTP_ADDR (default 127.0.0.1:9080), RUSTDNS_UDP_BIND (default 0.0.0.0:5353),
+    // RUSTDNS_UDP_LISTENERS, RUSTDNS_BLOCKING_MODE (`nx`, `null`, `nodata` or `redirect`; default `nx`) and
+    // RUSTDNS_BLOCK_PAGE_IP (the IPv4 address `redirect` answers with). Fails on the first
+    // invalid one.
+    pub fn from_env() -> Result<Self, String> {
+        let addr = |name: &str, default: &str| -> Result<SocketAddr, String> {
+            let addr = crate::envvars::var(name).unwrap_or_else(|_| default.to_string());
+            addr.parse().map_err(|_| format!("{} {:?} is not an IP address with a port", name, addr))
+        };
+        Config::with_addrs(addr("RUSTDNS_HTTP_ADDR", "127.0.0.1:9080")?, addr("RUSTDNS_UDP_BIND", "0.0.0.0:5353")?)
+    }
+
+    // As from_env, listening on the given addresses instead of the environment's.
+    pub fn with_addrs(http_addr: SocketAddr, udp_bind: SocketAddr) -> Result<Self, String> {
+        let blocking_mode = match crate::envvars::var("RUSTDNS_BLOCKING_MODE") {
+            Ok(m) => parse_mode(&m).map_err(|e| format!("RUSTDNS_BLOCKING_MODE {:?} {}", m, e))?,
+            Err(_) => BlockingMode::Nx,
+        };
+        let block_page_ip = match crate::envvars::var("RUSTDNS_BLOCK_PAGE_IP") {
+            Ok(ip) => Some(ip.parse().map_err(|_| format!("RUSTDNS_BLOCK_PAGE_IP {:?} is not an IPv4 address", ip))?),
+            Err(_) => None,
+        };
+        let listeners = listeners_from_env()?;
+        let mut addrs = std::collections::HashSet::from([udp_bind]);
+        if let Some(l) = listeners.iter().find(|l| !addrs.insert(l.addr)) {
+            return Err(format!("RUSTDNS_UDP_LISTENERS: {} is listed twice or is RUSTDNS_UDP_BIND", l.addr));
+        }
+        Ok(Config { http_addr, udp_bind, listeners, blocking_mode, block_page_ip, systemd: false })
+    }
+}
+
+// `mode` as a blocking mode, or the error to report for it.
+pub fn parse_mode(mode: &str) -> Result<BlockingMode, String> {
+    BlockingMode::parse(mode).ok_or_else(|| format!("must be one of {}", BLOCK_MODES.join(", ")))
+}
+
+// Shown instead of the value of a secret setting.
+const REDACTED: &str = "<redacted>";
+
+// Settings whose values must never be logged or served.
+fn is_secret(key: &str) -> bool {
+    ["TOKEN", "PASSWORD", "SECRET", "CREDENTIAL"].iter().any(|s| key.contains(s))
+}
+
+// `url` without any `user:password@` part.
+fn redact_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else { return url.to_string() };
+    let host_end = rest.find('/').unwrap_or(rest.len());
+    match rest[..host_end].rsplit_once('@') {
+        Some((_, host)) => format!("{}://{}:{}@{}{}", scheme, REDACTED, REDACTED, host, &rest[host_end..]),
+        None => url.to_string(),
+    }
+}
+
+// Cargo features this binary was built with.
+pub fn build_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "acme") { features.push("acme"); }
+    if cfg!(feature = "rdap") { features.push("rdap"); }
+    if cfg!(feature = "webhook") { features.push("webhook"); }
+    if cfg!(feature = "self-signed") { features.push("self-signed"); }
+    if cfg!(feature = "dashboard") { features.push("dashboard"); }
+    if cfg!(feature = "grpc") { features.push("grpc"); }
+    if cfg!(feature = "graphql") { features.push("graphql"); }
+    if cfg!(feature = "mqtt") { features.push("mqtt"); }
+    if cfg!(feature = "minimal") { features.push("minimal"); }
+    features
+}
+
+// The settings the server is actually running with, every default filled in, as logged at startup
+// and served by GET /config. `environment` lists the RUSTDNS_* variables that were set, with
+// secrets (names containing TOKEN, PASSWORD, SECRET or CREDENTIAL) and URL credentials redacted.
+pub async fn effective_config(state: &ServerState, http_addr: &str, udp_bind: &str) -> Value {
+    let environment: Vec<(String, String)> = crate::envvars::vars()
+        .into_iter()
+        .map(|(k, v)| {
+            let v = if is_secret(&k) { REDACTED.to_string() } else { redact_url(&v) };
+            (k, v)
+        })
+        .collect();
+    let environment: serde_json::Map<String, Value> = environment.into_iter().map(|(k, v)| (k, Value::String(v))).collect();
+    let reload_policy = match state.reload_policy {
+        ReloadPolicy::ServePrevious => "previous",
+        ReloadPolicy::ServFail => "servfail",
+    };
+    let acme = state.acme.config.as_ref().map(|c| serde_json::json!({
+        "domains": c.domains,
+        "email": c.email,
+        "directory": redact_url(&c.directory),
+        "challenge": c.challenge,
+        "renew_after_days": c.renew_after.as_secs() / 86_400,
+        "dir": c.dir.display().to_string(),
+    }));
+    let rpz_feeds: Vec<Value> = state.rpz.feeds.iter()
+        .map(|f| serde_json::json!({ "zone": f.zone, "server": f.server.to_string(), "on_failure": f.on_failure, "pin": f.pin }))
+        .collect();
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": build_features(),
+        "config_file": crate::envvars::var("RUSTDNS_CONFIG").ok(),
+        "log_level": crate::runner::log_level_from_env().as_str().to_lowercase(),
+        "listen": { "http": http_addr, "udp": udp_bind, "udp_listeners": state.listeners.iter().skip(1).collect::<Vec<_>>() },
+        "upstream": state.settings.upstream(),
+        "upstream_timeout_ms": state.settings.upstream_timeout().as_millis() as u64,
+        "data_dir": data_dir().display().to_string(),
+        "blocking": {
+            "mode": *state.mode.read().await,
+            "group_modes": state.group_modes.all(),
+            "group_schedules": state.schedules.all(),
+            "schedule_timezone": state.schedules.zone.name,
+            "block_page_ip": *state.block_page_ip.read().await,
+            "audit": state.audit.load(Ordering::Relaxed),
+            "response_padding": state.response_padding,
+        },
+        "lists": {
+            "dir": state.lists_dir,
+            "hosts_overrides": state.load_options.hosts_overrides,
+            "compiled_list": compiled_path().display().to_string(),
+            "reload_policy": reload_policy,
+            "watch": watch_lists_enabled(),
+            "watch_debounce_ms": debounce_from_env().as_millis() as u64,
+        },
+        "rpz": { "zones": rpz_feeds, "refresh_secs": state.rpz.refresh.as_secs() },
+        "cache": {
+            "size": state.cache.capacity,
+            "max_ttl": state.cache.max_ttl,
+            "negative_ttl": state.cache.negative_ttl,
+        },
+        "prewarm": state.prewarm.top,
+        "review_size": state.review.capacity,
+        "query_log_size": state.query_log.capacity,
+        "query_log_store": state.query_log.store.as_ref().map(|s| &s.config),
+        "rate_limit": {
+            "per_window": state.limiter.max_per_window,
+            "window_secs": state.limiter.window.as_secs(),
+            "ban_secs": state.limiter.ban_for.as_secs(),
+        },
+        "resources": state.resources.limits,
+        "api_limits": state.api_limits.limits,
+        "longterm": { "days": state.longterm.keep_days, "weeks": state.longterm.keep_weeks, "timezone": state.longterm.zone.name },
+        "metrics": { "top_clients": state.labels.clients.top, "top_domains": state.labels.domains.top, "top_window_hours": state.top.window_hours },
+        "clients": {
+            "v6_prefix": state.identity.v6_prefix,
+            "dhcp_leases": state.identity.leases_path.as_ref().map(|p| p.display().to_string()),
+        },
+        "dga": { "mode": state.dga.mode, "threshold": state.dga.threshold },
+        "tunnel": {
+            "enabled": state.tunnel.enabled,
+            "max_label_len": state.tunnel.max_label_len,
+            "max_txt_per_min": state.tunnel.max_txt_per_min,
+            "max_subdomains_per_min": state.tunnel.max_subdomains_per_min,
+            "clamp_qps": state.tunnel.clamp_qps,
+            "clamp_secs": state.tunnel.clamp_for.as_secs(),
+        },
+        "self_names": {
+            "names": state.self_names.names,
+            "mode": state.self_names.mode,
+            "addresses": state.self_names.addrs,
+        },
+        "categories": {
+            "dir": state.categories.dir.display().to_string(),
+            "blocked": state.categories.blocked(),
+        },
+        "allow_presets": state.allowlist.enabled_presets(),
+        "protected_brands": state.brands.list(),
+        "canary_domains": state.canaries.list(),
+        "rdap": { "base": redact_url(&state.rdap.base), "cache_secs": state.rdap.ttl.as_secs() },
+        "tls": {
+            "cert": state.certs.cert_path.display().to_string(),
+            "key": state.certs.key_path.display().to_string(),
+        },
+        "acme": acme,
+        "http_tls": state.http_tls,
+        "cors": { "origins": state.cors.origins(), "methods": state.cors.methods() },
+        "api_auth": { "enabled": state.auth.enabled(), "admin_tokens": state.auth.token_count(Role::Admin), "read_tokens": state.auth.token_count(Role::Read) },
+        "lifecycle": {
+            "hook": state.lifecycle.command,
+            "webhook": state.lifecycle.webhook.as_deref().map(redact_url),
+            "timeout_secs": state.lifecycle.timeout.as_secs(),
+        },
+        "environment": environment,
+    })
+}
+
+// A validated change to one of the settings PUT /config can change while running.
+enum Change {
+    Upstream(String),
+    UpstreamTimeout(u64),
+    Mode(BlockingMode),
+    BlockPageIp(Option<Ipv4Addr>),
+    Audit(bool),
+    CorsOrigins(Vec<String>),
+    CorsMethods(Vec<String>),
+}
+
+// `value` as a list of strings: an array, or one comma-separated string as in the environment.
+fn string_list(value: &Value) -> Option<Vec<&str>> {
+    match value {
+        Value::String(s) => Some(s.split(',').collect()),
+        Value::Array(items) => items.iter().map(Value::as_str).collect(),
+        _ => None,
+    }
+}
+
+fn change(path: &str, value: &Value) -> Result<Change, String> {
+    match path {
+        "upstream" => value.as_str().and_then(parse_upstream).map(Change::Upstream)
+            .ok_or_else(|| "must be an IP address with an optional port".to_string()),
+        "upstream_timeout_ms" => value.as_u64().filter(|ms| valid_upstream_timeout(*ms)).map(Change::UpstreamTimeout)
+            .ok_or_else(|| format!("must be a number of milliseconds from {} to {}", UPSTREAM_TIMEOUT_MS.0, UPSTREAM_TIMEOUT_MS.1)),
+        "blocking.mode" => parse_mode(value.as_str().unwrap_or_default()).map(Change::Mode),
+        "blocking.block_page_ip" => match value {
+            Value::Null => Ok(Change::BlockPageIp(None)),
+            Value::String(ip) => ip.parse().map(|ip| Change::BlockPageIp(Some(ip))).map_err(|_| "must be an IPv4 address or null".to_string()),
+            _ => Err("must be an IPv4 address or null".to_string()),
+        },
+        "blocking.audit" => value.as_bool().map(Change::Audit).ok_or_else(|| "must be true or false".to_string()),
+        "cors.origins" => string_list(value).ok_or_else(|| "must be a list of origins".to_string())
+            .and_then(|o| parse_origins(&o)).map(Change::CorsOrigins),
+        "cors.methods" => match string_list(value).map(|m| parse_methods(&m)) {
+            Some(Ok(m)) if !m.is_empty() => Ok(Change::CorsMethods(m)),
+            Some(Ok(_)) => Err("must name at least one method".to_string()),
+            Some(Err(e)) => Err(e),
+            None => Err("must be a list of methods".to_string()),
+        },
+        _ => Err("can't be changed while running; set it in the environment or config file and restart".to_string()),
+    }
+}
+
+// Every leaf of `value` with its path, keys joined with dots.
+fn leaves<'a>(path: &mut Vec<&'a str>, value: &'a Value, out: &mut Vec<(Vec<&'a str>, &'a Value)>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (k, v) in map {
+                path.push(k);
+                leaves(path, v, out);
+                path.pop();
+            }
+        }
+        _ => out.push((path.clone(), value)),
+    }
+}
+
+// Apply `update`, a document shaped like `current` (the effective configuration) or any part of
+// it, as PUT /config does. Values equal to the current ones are ignored, so a client can send
+// back a whole GET /config response with a few values edited. Every change is validated before
+// any is applied; on error nothing changes and the errors are returned keyed by setting. Returns
+// the settings that changed.
+pub async fn apply_config(state: &ServerState, current: &Value, update: &Value) -> Result<Vec<String>, serde_json::Map<String, Value>> {
+    if !update.is_object() {
+        let mut errors = serde_json::Map::new();
+        errors.insert(String::new(), Value::from("the body must be a JSON object"));
+        return Err(errors);
+    }
+    let mut all = Vec::new();
+    leaves(&mut Vec::new(), update, &mut all);
+    let mut changes = Vec::new();
+    let mut errors = serde_json::Map::new();
+    for (path, value) in all {
+        let pointer: String = path.iter().map(|k| format!("/{}", k.replace('~', "~0").replace('/', "~1"))).collect();
+        let dotted = path.join(".");
+        match current.pointer(&pointer) {
+            Some(cur) if cur == value => continue,
+            None if current.get(path[0]).is_none() => {
+                errors.insert(dotted, Value::from("unknown setting"));
+                continue;
+            }
+            _ => {}
+        }
+        match change(&dotted, value) {
+            Ok(c) => changes.push((dotted, c, value)),
+            Err(e) => { errors.insert(dotted, Value::from(e)); }
+        }
+    }
+    if !errors.is_empty() { return Err(errors) }
+    for (path, c, value) in &changes {
+        match c {
+            Change::Upstream(up) => state.settings.set_upstream(up.clone()),
+            Change::UpstreamTimeout(ms) => state.settings.set_upstream_timeout(*ms),
+            Change::Mode(m) => *state.mode.write().await = *m,
+            Change::BlockPageIp(ip) => *state.block_page_ip.write().await = *ip,
+            Change::Audit(on) => state.audit.store(*on, Ordering::Relaxed),
+            Change::CorsOrigins(o) => state.cors.set_origins(o.clone()),
+            Change::CorsMethods(m) => state.cors.set_methods(m.clone()),
+        }
+        tracing::info!("configuration: {} set to {}", path, value);
+    }
+    Ok(changes.into_iter().map(|(p, _, _)| p).collect())
+}
@@ -0,0 +1,518 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+// Looked for in the working directory when no config file is named.
+pub const DEFAULT_FILE: &str = "piblock.toml";
+
+pub const USAGE: &str = "usage: rustdns [--config <path>]";
+
+// The config file to load: `--config <path>` (or `--config=<path>`),
+// then RUSTDNS_CONFIG (passed in as `env`), then piblock.toml in the working directory if there is
+// one. `args` are the command-line arguments after the program name.
+pub fn config_path(args: impl IntoIterator<Item = String>, env: Option<OsString>) -> Result<Option<PathBuf>, String> {
+    let mut args = args.into_iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            let p = args.next().ok_or_else(|| format!("{} needs a path\n{}", arg, USAGE))?;
+            path = Some(PathBuf::from(p));
+        } else if let Some(p) = arg.strip_prefix("--config=") {
+            path = Some(PathBuf::from(p));
+        } else {
+            return Err(format!("unknown argument {:?}\n{}", arg, USAGE));
+        }
+    }
+    if path.is_some() { return Ok(path) }
+    if let Some(p) = env { return Ok(Some(PathBuf::from(p))) }
+    let default = PathBuf::from(DEFAULT_FILE);
+    Ok(default.is_file().then_some(default))
+}
+
+// Settings from a config file, as the RUSTDNS_* variables they stand for, sorted by name.
+// `[section] key = value` is RUSTDNS_SECTION_KEY and a key outside any section RUSTDNS_KEY, so
+// every environment variable in the README can be set from the file:
+//
+//   upstream = "9.9.9.9"
+//   [http]
+//   addr = "0.0.0.0:9080"
+//   [api]
+//   tokens = ["admin:secret", "read:dashboard"]
+//
+// Arrays become comma-separated lists and booleans `true` / `false`.
+pub fn parse(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut p = Parser { chars: text.chars().collect(), pos: 0, line: 1 };
+    let mut table: Vec<String> = Vec::new();
+    let mut vars = BTreeMap::new();
+    loop {
+        p.skip_blank();
+        let Some(c) = p.peek() else { break };
+        if c == '[' {
+            p.pos += 1;
+            if p.peek() == Some('[') { return Err(p.error("arrays of tables are not supported")) }
+            table = p.key()?;
+            p.skip_space();
+            if !p.eat(']') { return Err(p.error("expected `]` after the table name")) }
+        } else {
+            let key = p.key()?;
+            p.skip_space();
+            if !p.eat('=') { return Err(p.error("expected `=` after the key")) }
+            p.skip_space();
+            let value = p.value()?;
+            let name = env_name(table.iter().chain(&key));
+            if vars.insert(name.clone(), value).is_some() {
+                return Err(p.error(&format!("{} is set twice", name)));
+            }
+        }
+        p.skip_space();
+        p.skip_comment();
+        match p.peek() {
+            None => break,
+            Some('\n') | Some('\r') => {}
+            Some(c) => return Err(p.error(&format!("unexpected {:?}", c))),
+        }
+    }
+    Ok(vars)
+}
+
+fn env_name<'a>(parts: impl Iterator<Item = &'a String>) -> String {
+    let parts: Vec<String> = parts.map(|p| p.replace(['-', '.'], "_").to_uppercase()).collect();
+    format!("RUSTDNS_{}", parts.join("_"))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> String {
+        format!("line {}: {}", self.line, msg)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) { self.pos += 1; true } else { false }
+    }
+
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) { self.pos += 1 }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) { self.pos += 1 }
+        }
+    }
+
+    // Whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_space();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') => { self.pos += 1; self.line += 1 }
+                Some('\r') => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    // A dotted key: bare (`addr`, `max-bytes`) or quoted parts.
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_space();
+            let part = match self.peek() {
+                Some('"') | Some('\'') => self.string()?,
+                _ => {
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') { self.pos += 1 }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            if part.is_empty() { return Err(self.error("expected a key")) }
+            parts.push(part);
+            self.skip_space();
+            if !self.eat('.') { return Ok(parts) }
+        }
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some('"') | Some('\'') => self.string(),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank();
+                    if self.eat(']') { break }
+                    match self.peek() {
+                        Some('[') => return Err(self.error("nested arrays are not supported")),
+                        None => return Err(self.error("unterminated array")),
+                        _ => items.push(self.value()?),
+                    }
+                    self.skip_blank();
+                    if self.eat(']') { break }
+                    if self.peek().is_none() { return Err(self.error("unterminated array")) }
+                    if !self.eat(',') { return Err(self.error("expected `,` or `]` in the array")) }
+                }
+                Ok(items.join(","))
+            }
+            Some('{') => Err(self.error("inline tables are not supported; use a [table]")),
+            _ => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '.' | ':')) { self.pos += 1 }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                match word.as_str() {
+                    "true" | "false" => Ok(word),
+                    "" => Err(self.error("expected a value")),
+                    _ => {
+                        let number = word.replace('_', "");
+                        if number.parse::<i64>().is_ok() || number.parse::<f64>().is_ok() {
+                            Ok(number.trim_start_matches('+').to_string())
+                        } else {
+                            Err(self.error(&format!("{:?} is not a value; quote strings", word)))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // A basic ("...", with escapes) or literal ('...') string on one line.
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.peek().unwrap_or('"');
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some(c) => c,
+            };
+            self.pos += 1;
+            if c == quote { return Ok(s) }
+            if c != '\\' || quote == '\'' {
+                s.push(c);
+                continue;
+            }
+            let escaped = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match escaped {
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                'r' => s.push('\r'),
+                '"' => s.push('"'),
+                '\\' => s.push('\\'),
+                'u' | 'U' => {
+                    let len = if escaped == 'u' { 4 } else { 8 };
+                    let hex: String = self.chars.iter().skip(self.pos).take(len).take_while(|c| c.is_ascii_hexdigit()).collect();
+                    let ch = u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == len).and_then(char::from_u32)
+                        .ok_or_else(|| self.error(&format!("invalid escape \\{}{}", escaped, hex)))?;
+                    self.pos += len;
+                    s.push(ch);
+                }
+                other => return Err(self.error(&format!("invalid escape \\{}", other))),
+            }
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// A config file that was applied, for reading it again on SIGHUP.
+pub struct ConfigFile {
+    pub path: PathBuf,
+    // values taken from the file
+    applied: BTreeMap<String, String>,
+    // RUSTDNS_* variables the environment set before the file was read, which the file can't change
+    from_env: BTreeSet<String>,
+}
+
+// Read the config file at `path` and set the variables it stands for, except those already in the
+// environment, which take precedence over the file. RUSTDNS_CONFIG is set to the path so /config
+// shows where settings came from.
+//
+// Must run before any other thread exists, since it changes the process environment.
+pub fn apply(path: &Path) -> Result<ConfigFile, String> {
+    let vars = read(path)?;
+    if std::env::var_os("RUSTDNS_CONFIG").is_none() {
+        std::env::set_var("RUSTDNS_CONFIG", path);
+    }
+    let from_env: BTreeSet<String> = std::env::vars_os().filter_map(|(k, _)| k.into_string().ok()).filter(|k| k.starts_with("RUSTDNS_")).collect();
+    let applied: BTreeMap<String, String> = vars.into_iter().filter(|(name, _)| !from_env.contains(name)).collect();
+    for (name, value) in &applied {
+        std::env::set_var(name, value);
+    }
+    Ok(ConfigFile { path: path.to_path_buf(), applied, from_env })
+}
+
+// Settings PUT /config can change while running, by variable and /config path.
+const RUNTIME_SETTINGS: &[(&str, &str)] = &[
+    ("RUSTDNS_UPSTREAM", "upstream"),
+    ("RUSTDNS_UPSTREAM_TIMEOUT_MS", "upstream_timeout_ms"),
+    ("RUSTDNS_BLOCKING_MODE", "blocking.mode"),
+    ("RUSTDNS_BLOCK_PAGE_IP", "blocking.block_page_ip"),
+    ("RUSTDNS_AUDIT", "blocking.audit"),
+    ("RUSTDNS_CORS_ORIGINS", "cors.origins"),
+    ("RUSTDNS_CORS_METHODS", "cors.methods"),
+];
+
+// What changed in a config file read again.
+#[derive(Default)]
+pub struct FileChanges {
+    // the changed settings that can be applied while running, as a PUT /config document
+    pub update: serde_json::Map<String, serde_json::Value>,
+    // changed variables that only take effect on a restart
+    pub need_restart: Vec<String>,
+    // the new values of the settings in `update`, None for ones removed from the file, recorded
+    // by `commit` once they are applied
+    pending: BTreeMap<String, Option<String>>,
+}
+
+impl ConfigFile {
+    // Read the file again and report what changed since the settings were last applied.
+    // Variables the environment set are left to it, and a setting removed from the file is only
+    // reset where that is unambiguous (no block page address, audit off); the others need a
+    // restart, and are reported again on the next read until then.
+    pub fn reread(&self) -> Result<FileChanges, String> {
+        let mut vars = read(&self.path)?;
+        vars.retain(|name, _| !self.from_env.contains(name));
+        let names: BTreeSet<&String> = vars.keys().chain(self.applied.keys()).collect();
+        let mut changes = FileChanges::default();
+        for name in names {
+            let new = vars.get(name);
+            if new == self.applied.get(name) { continue }
+            let runtime = RUNTIME_SETTINGS.iter().find(|(n, _)| n == name).map(|(_, path)| *path);
+            let value = match (name.as_str(), new) {
+                ("RUSTDNS_UPSTREAM_TIMEOUT_MS", Some(v)) => v.parse::<u64>().map(serde_json::Value::from).unwrap_or_else(|_| v.as_str().into()),
+                ("RUSTDNS_AUDIT", Some(v)) => serde_json::Value::Bool(v == "1" || v == "true"),
+                ("RUSTDNS_AUDIT", None) => serde_json::Value::Bool(false),
+                ("RUSTDNS_BLOCK_PAGE_IP", None) => serde_json::Value::Null,
+                (_, Some(v)) if runtime.is_some() => v.as_str().into(),
+                _ => {
+                    changes.need_restart.push(name.clone());
+                    continue;
+                }
+            };
+            let Some(path) = runtime else { continue };
+            changes.pending.insert(name.clone(), new.cloned());
+            match path.split_once('.') {
+                Some((section, key)) => {
+                    let section = changes.update.entry(section.to_string()).or_insert_with(|| serde_json::json!({}));
+                    if let Some(section) = section.as_object_mut() { section.insert(key.to_string(), value); }
+                }
+                None => { changes.update.insert(path.to_string(), value); }
+            }
+        }
+        Ok(changes)
+    }
+
+    // Record the settings of `changes` as applied, once PUT /config has taken them; until then a
+    // later read reports them again.
+    pub fn commit(&mut self, changes: FileChanges) {
+        for (name, value) in changes.pending {
+            match value {
+                Some(v) => self.applied.insert(name, v),
+                None => self.applied.remove(&name),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(text: &str) -> Vec<(String, String)> {
+        parse(text).unwrap().into_iter().collect()
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn tables_and_dotted_keys_map_to_variable_names() {
+        let text = "upstream = \"9.9.9.9\"\n\
+                    cache.size = 5000\n\
+                    [http]\n\
+                    addr = \"0.0.0.0:9080\"\n\
+                    tls.max-bytes = 1_024\n\
+                    [ api . \"rate\" ]\n\
+                    limit = +600\n\
+                    'burst' = 120\n";
+        assert_eq!(vars(text), [
+            pair("RUSTDNS_API_RATE_BURST", "120"),
+            pair("RUSTDNS_API_RATE_LIMIT", "600"),
+            pair("RUSTDNS_CACHE_SIZE", "5000"),
+            pair("RUSTDNS_HTTP_ADDR", "0.0.0.0:9080"),
+            pair("RUSTDNS_HTTP_TLS_MAX_BYTES", "1024"),
+            pair("RUSTDNS_UPSTREAM", "9.9.9.9"),
+        ]);
+    }
+
+    #[test]
+    fn values_become_variable_text() {
+        let text = "audit = true\nstrict = false\nratio = 0.25\nneg = -3\nempty = \"\"\n";
+        assert_eq!(vars(text), [
+            pair("RUSTDNS_AUDIT", "true"),
+            pair("RUSTDNS_EMPTY", ""),
+            pair("RUSTDNS_NEG", "-3"),
+            pair("RUSTDNS_RATIO", "0.25"),
+            pair("RUSTDNS_STRICT", "false"),
+        ]);
+    }
+
+    #[test]
+    fn arrays_may_span_lines_with_comments() {
+        let text = "[api]\n\
+                    tokens = [\n\
+                    \x20   \"admin:secret\", # the phone\n\
+                    \n\
+                    \x20   'read:dashboard',\n\
+                    ]\n\
+                    ports = [53, 5353]\n\
+                    none = []\n\
+                    after = 1\n";
+        assert_eq!(vars(text), [
+            pair("RUSTDNS_API_AFTER", "1"),
+            pair("RUSTDNS_API_NONE", ""),
+            pair("RUSTDNS_API_PORTS", "53,5353"),
+            pair("RUSTDNS_API_TOKENS", "admin:secret,read:dashboard"),
+        ]);
+    }
+
+    #[test]
+    fn comments_after_values_and_tables() {
+        let text = "# settings\n\
+                    upstream = \"1.1.1.1\"   # primary\n\
+                    size = 10# no space\n\
+                    [http] # control API\n\
+                    \t# indented comment\n\
+                    addr = \"127.0.0.1:9080\"\r\n";
+        assert_eq!(vars(text), [
+            pair("RUSTDNS_HTTP_ADDR", "127.0.0.1:9080"),
+            pair("RUSTDNS_SIZE", "10"),
+            pair("RUSTDNS_UPSTREAM", "1.1.1.1"),
+        ]);
+    }
+
+    #[test]
+    fn strings_keep_hashes_and_decode_escapes() {
+        let text = r#"
+            basic = "a # not a comment"
+            escapes = "tab\there \"quoted\" back\\slash\nnew"
+            unicode = "caf\u00e9 \U0001F600"
+            literal = 'C:\lists\# raw'
+        "#;
+        assert_eq!(vars(text), [
+            pair("RUSTDNS_BASIC", "a # not a comment"),
+            pair("RUSTDNS_ESCAPES", "tab\there \"quoted\" back\\slash\nnew"),
+            pair("RUSTDNS_LITERAL", "C:\\lists\\# raw"),
+            pair("RUSTDNS_UNICODE", "café 😀"),
+        ]);
+    }
+
+    #[test]
+    fn a_variable_set_twice_is_an_error() {
+        assert_eq!(parse("size = 1\nsize = 2\n").unwrap_err(), "line 2: RUSTDNS_SIZE is set twice");
+        // the same variable through a dotted key and a table
+        assert_eq!(parse("http.addr = \"a\"\n[http]\naddr = \"b\"\n").unwrap_err(), "line 3: RUSTDNS_HTTP_ADDR is set twice");
+        // and through dashes and underscores
+        assert_eq!(parse("max-bytes = 1\nmax_bytes = 2\n").unwrap_err(), "line 2: RUSTDNS_MAX_BYTES is set twice");
+        // a table may be opened again
+        assert_eq!(vars("[a]\nx = 1\n[b]\ny = 2\n[a]\nz = 3\n").len(), 3);
+    }
+
+    #[test]
+    fn unsupported_syntax_is_reported_with_its_line() {
+        let cases = [
+            ("[[listeners]]\n", "line 1: arrays of tables are not supported"),
+            ("a = 1\nb = { x = 1 }\n", "line 2: inline tables are not supported; use a [table]"),
+            ("a = [[1], [2]]\n", "line 1: nested arrays are not supported"),
+            ("a = [1, 2\n", "line 2: unterminated array"),
+            ("a = [1 2]\n", "line 1: expected `,` or `]` in the array"),
+            ("a = 9.9.9.9\n", "line 1: \"9.9.9.9\" is not a value; quote strings"),
+            ("a = \"open\nb = 1\n", "line 1: unterminated string"),
+            ("a = \"\\q\"\n", "line 1: invalid escape \\q"),
+            ("a = \"\\u12\"\n", "line 1: invalid escape \\u12"),
+            ("a = \"\\uD800\"\n", "line 1: invalid escape \\uD800"),
+            ("a =\n", "line 1: expected a value"),
+            ("a 1\n", "line 1: expected `=` after the key"),
+            ("= 1\n", "line 1: expected a key"),
+            ("[http\n", "line 1: expected `]` after the table name"),
+            ("a = 1 b = 2\n", "line 1: unexpected 'b'"),
+        ];
+        for (text, error) in cases {
+            assert_eq!(parse(text).unwrap_err(), error, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn config_path_from_arguments() {
+        assert_eq!(config_path(args(&["--config", "/etc/piblock.toml"]), None), Ok(Some(PathBuf::from("/etc/piblock.toml"))));
+        assert_eq!(config_path(args(&["-c", "a.toml"]), None), Ok(Some(PathBuf::from("a.toml"))));
+        assert_eq!(config_path(args(&["--config=b.toml"]), None), Ok(Some(PathBuf::from("b.toml"))));
+        // the last one given wins
+        assert_eq!(config_path(args(&["-c", "a.toml", "--config=b.toml"]), None), Ok(Some(PathBuf::from("b.toml"))));
+        assert_eq!(config_path(args(&["--config"]), None), Err(format!("--config needs a path\n{}", USAGE)));
+        assert_eq!(config_path(args(&["--verbose"]), None), Err(format!("unknown argument \"--verbose\"\n{}", USAGE)));
+    }
+
+    #[test]
+    fn config_path_falls_back_to_the_environment_and_default_file() {
+        let env = || Some(OsString::from("/env/piblock.toml"));
+        assert_eq!(config_path(Vec::new(), env()), Ok(Some(PathBuf::from("/env/piblock.toml"))));
+        assert_eq!(config_path(args(&["-c", "a.toml"]), env()), Ok(Some(PathBuf::from("a.toml"))));
+        let default = Path::new(DEFAULT_FILE).is_file().then(|| PathBuf::from(DEFAULT_FILE));
+        assert_eq!(config_path(Vec::new(), None), Ok(default));
+    }
+
+    // A change only counts as applied once committed; one that wasn't is reported again.
+    #[test]
+    fn reread_reports_changes_until_committed() {
+        let path = std::env::temp_dir().join(format!("rustdns-reread-{}.toml", std::process::id()));
+        std::fs::write(&path, "blocking_mode = \"nx\"\nlog_level = \"info\"\n").unwrap();
+        let mut file = ConfigFile { path: path.clone(), applied: read(&path).unwrap(), from_env: BTreeSet::new() };
+        assert!(file.reread().unwrap().update.is_empty());
+
+        std::fs::write(&path, "blocking_mode = \"null\"\naudit = true\nlog_level = \"debug\"\n").unwrap();
+        let changes = file.reread().unwrap();
+        assert_eq!(serde_json::Value::Object(changes.update), serde_json::json!({ "blocking": { "mode": "null", "audit": true } }));
+        assert_eq!(changes.need_restart, ["RUSTDNS_LOG_LEVEL"]);
+        // not committed, as when PUT /config rejected it
+        let changes = file.reread().unwrap();
+        assert_eq!(changes.update.len(), 1);
+        file.commit(changes);
+        let changes = file.reread().unwrap();
+        assert!(changes.update.is_empty());
+        assert_eq!(changes.need_restart, ["RUSTDNS_LOG_LEVEL"]);
+
+        // removed from the file
+        std::fs::write(&path, "blocking_mode = \"null\"\nlog_level = \"info\"\n").unwrap();
+        let changes = file.reread().unwrap();
+        assert_eq!(serde_json::Value::Object(changes.update.clone()), serde_json::json!({ "blocking": { "audit": false } }));
+        file.commit(changes);
+        assert!(file.reread().unwrap().update.is_empty());
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn the_example_config_parses() {
+        let text = include_str!("../piblock.toml");
+        let vars = parse(text).unwrap();
+        assert!(vars.keys().all(|k| k.starts_with("RUSTDNS_")));
+    }
+}
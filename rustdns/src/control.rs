@@ -1,14 +1,56 @@
-use crate::state::{ServerState, Stats};
-use crate::blocklist::load_blocklists_into;
-use axum::{Json};
+use crate::state::{ServerState, Stats, MAX_PAUSE};
+use crate::allowlist::MAX_ALLOW_TTL;
+use crate::server::{block_mode, forward_udp_to_upstream, query_message, resolve_query};
+use crate::trace::Trace;
+use crate::clients::ClientUpdate;
+use crate::review::ReviewStatus;
+use crate::rpz::approve_pending;
+use crate::hits::RuleHit;
+use crate::schedule::apply_schedules;
+use crate::metrics::Exposition;
+use crate::health::Check;
+use crate::history::HISTORY_RANGES;
+use crate::longterm::{parse_date, LONGTERM_PERIODS};
+use crate::top::{TOP_DEFAULT, TOP_KINDS, TOP_MAX};
+use crate::querylog::{QueryFilter, ACTIONS, QUERIES_MAX_PAGE, QUERIES_PAGE};
+use crate::watcher::watch_lists_enabled;
+use crate::listeners::ListenerPolicy;
+use crate::state::Event;
+use std::str::FromStr;
+use std::time::Instant;
+use trust_dns_proto::op::Message;
+use trust_dns_proto::rr::{Name, Record, RecordType};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::body::{Bytes, StreamBody};
+use crate::backup::{create_backup, decode_backup, encode_backup, restore_backup};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::Stream;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio_stream::{wrappers::{errors::BroadcastStreamRecvError, BroadcastStream}, StreamExt};
+use crate::compiled::compiled_path;
+use crate::config::{apply_config, effective_config, parse_mode};
+use crate::blocklist::{compile_blocklists, find_match, Annotation, BlockingMode, GroupMode, normalize_pattern, normalize_tld, snapshot, CUSTOM_GROUP};
+use axum::{extract::{Path, Query}, Json};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 pub async fn http_reload(state: Arc<ServerState>) -> Json<Value> {
-    match load_blocklists_into("./blocklist", &state.lists).await {
-        Ok(n) => {
-            tracing::info!("reloaded {} domains", n);
-            Json(serde_json::json!({ "loaded": n }))
+    match state.reload_lists(&state.lists_dir).await {
+        Ok(report) => {
+            let t = report.timings;
+            tracing::info!("reloaded {} domains (+{} / -{}) in {}ms (parse {}ms, compile {}ms, swap {}ms)",
+                report.loaded, report.added, report.removed, t.total_ms, t.parse_ms, t.compile_ms, t.swap_ms);
+            for f in &report.files {
+                match &f.diagnostics {
+                    Some(d) => tracing::info!("  {} [{}]: {} entries ({}, {} skipped, {} invalid lines)", f.path, f.group, f.entries, d.format, d.skipped, d.invalid),
+                    None => tracing::info!("  {} [{}]: {} entries (compiled)", f.path, f.group, f.entries),
+                }
+            }
+            Json(serde_json::json!(report))
         }
         Err(e) => {
             tracing::warn!("reload failed: {:?}", e);
@@ -17,50 +59,1359 @@ pub async fn http_reload(state: Arc<ServerState>) -> Json<Value> {
     }
 }
 
+// Compile the list files into the memory-mapped on-disk format and reload so it takes effect.
+pub async fn http_compile(state: Arc<ServerState>) -> Json<Value> {
+    let path = compiled_path();
+    let started = Instant::now();
+    let manifest = match compile_blocklists(&state.lists_dir, &state.load_options, path.clone()).await {
+        Ok(m) => m,
+        Err(e) => {
+            tracing::warn!("compiling lists failed: {:?}", e);
+            return Json(serde_json::json!({ "ok": false, "error": format!("{:#}", e) }));
+        }
+    };
+    tracing::info!("compiled {} names from {} files into {}", manifest.entries, manifest.sources.len(), path.display());
+    let reload = state.reload_lists(&state.lists_dir).await.map_err(|e| format!("{:#}", e));
+    Json(serde_json::json!({
+        "ok": reload.is_ok(),
+        "path": path.display().to_string(),
+        "entries": manifest.entries,
+        "rules": manifest.rules.len(),
+        "files": manifest.sources.len(),
+        "bytes": std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+        "elapsed_ms": started.elapsed().as_millis() as u64,
+        "error": reload.err(),
+    }))
+}
+
 pub async fn http_stats(state: Arc<ServerState>) -> Json<Stats> {
     let q = state.queries.load(std::sync::atomic::Ordering::Relaxed);
     let b = state.blocked.load(std::sync::atomic::Ordering::Relaxed);
-    Json(Stats { queries: q, blocked: b })
+    let rl = state.rate_limited.load(std::sync::atomic::Ordering::Relaxed);
+    Json(Stats {
+        queries: q,
+        blocked: b,
+        uptime_seconds: state.totals.uptime_secs(),
+        lifetime: state.totals.current(q, b),
+        rate_limited: rl,
+        blocking_enabled: state.blocking.is_enabled(),
+        seconds_remaining: state.blocking.seconds_remaining(),
+        audit: state.audit.load(std::sync::atomic::Ordering::Relaxed),
+        audited: state.audited.load(std::sync::atomic::Ordering::Relaxed),
+        dga: state.dga.stats(),
+        tunnel: state.tunnel.stats(),
+        labels: state.labels.stats(),
+        cache: state.cache.stats(),
+        resources: state.resources.usage(),
+        api: state.api_limits.usage(),
+        udp_errors: state.udp_errors.stats(),
+    })
+}
+
+// GET /stats/top?kind=blocked|allowed|clients&n=10 — the most blocked names, the most queried
+// names that weren't blocked, or the busiest clients over the rolling window, with the number of
+// queries the ranking was drawn from.
+pub async fn http_stats_top(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    if !state.top.enabled() {
+        return Json(serde_json::json!({ "ok": false, "error": "top lists are off (RUSTDNS_TOP_WINDOW_HOURS=0)" }));
+    }
+    let kind = params.get("kind").map(|k| k.to_lowercase()).unwrap_or_else(|| "blocked".to_string());
+    let n = match params.get("n").map(|n| n.parse::<usize>()) {
+        None => TOP_DEFAULT,
+        Some(Ok(n)) => n.min(TOP_MAX),
+        Some(Err(_)) => return Json(serde_json::json!({ "ok": false, "error": "n must be a number" })),
+    };
+    match state.top.ranking(&kind, n) {
+        Some(r) => {
+            let mut top = serde_json::json!(r.top);
+            // clients are ranked by key; show the names they have in the registry
+            if kind == "clients" {
+                let keys: Vec<&str> = r.top.iter().map(|c| c.label.as_str()).collect();
+                for (entry, name) in top.as_array_mut().unwrap().iter_mut().zip(state.clients.names(&keys)) {
+                    if let Some(name) = name { entry["name"] = Value::String(name); }
+                }
+            }
+            Json(serde_json::json!({
+                "ok": true,
+                "kind": kind,
+                "window_hours": state.top.window_hours,
+                "since": r.since,
+                "total": r.total,
+                "top": top,
+            }))
+        }
+        None => Json(serde_json::json!({ "ok": false, "error": format!("kind must be one of {}", TOP_KINDS.join(", ")) })),
+    }
+}
+
+// GET /stats/history?range=24h|30d — queries and blocked queries per 10 minutes over the last
+// day, or per hour over the last 30 days, oldest first.
+pub async fn http_stats_history(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    // include what was counted since the last sample
+    state.sample_history();
+    let range = params.get("range").map(String::as_str).unwrap_or("24h");
+    match state.history.series(range) {
+        Some((interval, buckets)) => Json(serde_json::json!({ "ok": true, "range": range, "interval": interval, "buckets": buckets })),
+        None => {
+            let ranges: Vec<&str> = HISTORY_RANGES.iter().map(|(r, _, _)| *r).collect();
+            Json(serde_json::json!({ "ok": false, "error": format!("range must be one of {}", ranges.join(", ")) }))
+        }
+    }
+}
+
+// GET /stats/longterm?period=day|week&from=2026-01-01&to=2026-03-31 — query totals per day or
+// week from the persistent rollups, oldest first, with their sum.
+pub async fn http_stats_longterm(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    if !state.longterm.enabled() {
+        return Json(serde_json::json!({ "ok": false, "error": "long-term stats are off (RUSTDNS_LONGTERM_DAYS=0 and RUSTDNS_LONGTERM_WEEKS=0)" }));
+    }
+    let mut dates = [None, None];
+    for (date, key) in dates.iter_mut().zip(["from", "to"]) {
+        if let Some(v) = params.get(key) {
+            match parse_date(v) {
+                Some(d) => *date = Some(d),
+                None => return Json(serde_json::json!({ "ok": false, "error": format!("{} must be a date like 2026-01-31", key) })),
+            }
+        }
+    }
+    state.sample_history();
+    let period = params.get("period").map(String::as_str).unwrap_or("day");
+    match state.longterm.view(period, dates[0], dates[1]) {
+        Some(view) => {
+            let mut v = serde_json::json!(view);
+            v["ok"] = Value::Bool(true);
+            Json(v)
+        }
+        None => {
+            let periods: Vec<&str> = LONGTERM_PERIODS.iter().map(|(p, _)| *p).collect();
+            Json(serde_json::json!({ "ok": false, "error": format!("period must be one of {}", periods.join(", ")) }))
+        }
+    }
+}
+
+// GET /metrics — counters, cache, upstream latency and list sizes in the Prometheus text format.
+pub async fn http_metrics(state: Arc<ServerState>) -> Response {
+    let load = |c: &std::sync::atomic::AtomicU64| c.load(std::sync::atomic::Ordering::Relaxed);
+    let mut out = Exposition::default();
+    out.single("rustdns_queries_total", "counter", "Client queries since start.", load(&state.queries));
+    out.single("rustdns_blocked_total", "counter", "Blocked queries since start.", load(&state.blocked));
+    out.single("rustdns_audited_total", "counter", "Queries audit mode would have blocked.", load(&state.audited));
+    out.single("rustdns_rate_limited_total", "counter", "Queries refused by a rate limit.", load(&state.rate_limited));
+    let udp_errors = state.udp_errors.stats();
+    out.family("rustdns_udp_errors_total", "counter", "Packets the DNS listeners lost: failed receives, answers that could not be sent, non-DNS packets.");
+    for (kind, n) in [("receive", udp_errors.receive), ("send", udp_errors.send), ("unparsable", udp_errors.unparsable)] {
+        out.sample("rustdns_udp_errors_total", &[("kind", kind)], n);
+    }
+    state.counters.write(&mut out);
+    let cache = state.cache.stats();
+    out.single("rustdns_cache_hits_total", "counter", "Queries answered from the response cache.", cache.hits);
+    out.single("rustdns_cache_misses_total", "counter", "Cache lookups that found nothing.", cache.misses);
+    out.single("rustdns_cache_entries", "gauge", "Answers in the response cache.", cache.entries);
+    out.single("rustdns_cache_capacity", "gauge", "Most answers the response cache keeps.", cache.capacity);
+    let labels = state.labels.stats();
+    out.labelled("rustdns_client_queries_total", "counter", "Queries of the busiest clients; the rest under \"other\".", "client", &labels.clients);
+    out.labelled("rustdns_domain_queries_total", "counter", "Queries for the busiest names; the rest under \"other\".", "domain", &labels.domains);
+    {
+        let lists = snapshot(&state.lists).await;
+        out.family("rustdns_list_rules", "gauge", "Active rules by kind.");
+        let compiled = lists.compiled.as_ref().map(|c| c.len()).unwrap_or(0);
+        for (kind, n) in [("patterns", lists.patterns.len()), ("tlds", lists.tlds.len()), ("compiled", compiled), ("local_records", lists.local_records.len()), ("forward_zones", lists.forward_zones.len()), ("passthru", lists.passthru.len()), ("api", lists.user.len())] {
+            out.sample("rustdns_list_rules", &[("kind", kind)], n);
+        }
+    }
+    out.family("rustdns_list_file_entries", "gauge", "Entries read from each list file by the last load.");
+    if let Some(report) = state.last_load.lock().unwrap().as_ref() {
+        for f in &report.files {
+            out.sample("rustdns_list_file_entries", &[("file", &f.path), ("group", &f.group)], f.entries);
+        }
+    }
+    out.single("rustdns_blocking_enabled", "gauge", "1 unless blocking is paused.", u8::from(state.blocking.is_enabled()));
+    out.single("rustdns_audit_mode", "gauge", "1 while audit (dry-run) mode is on.", u8::from(state.audit.load(std::sync::atomic::Ordering::Relaxed)));
+    out.single("rustdns_uptime_seconds", "gauge", "Seconds since start.", state.totals.uptime_secs());
+    if let Some(rss) = state.resources.usage().resident_bytes {
+        out.single("rustdns_resident_memory_bytes", "gauge", "Resident memory of the process.", rss);
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], out.finish()).into_response()
+}
+
+// GET /healthz — liveness: answers as long as the process is up and serving the API, with the
+// status of each supervised component; 503 while one of them is being restarted.
+pub async fn http_healthz(state: Arc<ServerState>) -> Response {
+    let components = state.health.components();
+    let ok = components.values().all(|c| c.state == "running");
+    let status = if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(serde_json::json!({ "ok": ok, "uptime_seconds": state.totals.uptime_secs(), "components": components }))).into_response()
+}
+
+// GET /readyz — readiness: 200 once the lists are loaded, the UDP listener is bound and the
+// upstream answers, 503 with the failing checks otherwise.
+pub async fn http_readyz(state: Arc<ServerState>) -> Response {
+    let loaded = state.last_load.lock().unwrap().as_ref().map(|r| r.loaded);
+    let lists = Check { ok: loaded.is_some(), detail: Some(match loaded { Some(n) => format!("{} entries loaded", n), None => "initial list load not finished".to_string() }) };
+    let udp = state.health.udp();
+    let upstream = state.health.probe_upstream(&state, &state.settings.upstream()).await;
+    let ok = lists.ok && udp.ok && upstream.ok;
+    let status = if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(serde_json::json!({ "ok": ok, "checks": { "lists": lists, "udp": udp, "upstream": upstream } }))).into_response()
+}
+
+// GET /config — the effective configuration, as logged at startup (secrets redacted).
+pub async fn http_config(state: Arc<ServerState>, listen: (String, String)) -> Json<Value> {
+    Json(effective_config(&state, &listen.0, &listen.1).await)
+}
+
+// PUT /config — change settings while running: `upstream`, `upstream_timeout_ms` and
+// `blocking.mode`, `block_page_ip` and `audit`, in the shape GET /config returns (see
+// `apply_config`). Returns the settings that changed and the configuration now in effect.
+pub async fn http_config_update(state: Arc<ServerState>, listen: (String, String), Json(payload): Json<Value>) -> Json<Value> {
+    let current = effective_config(&state, &listen.0, &listen.1).await;
+    match apply_config(&state, &current, &payload).await {
+        Ok(changed) => Json(serde_json::json!({ "ok": true, "changed": changed, "config": effective_config(&state, &listen.0, &listen.1).await })),
+        Err(errors) => Json(serde_json::json!({ "ok": false, "error": "invalid configuration; nothing was changed", "errors": errors })),
+    }
+}
+
+// GET /backup — all user state as one gzip-compressed JSON archive (see `Backup`), for POST
+// /restore on this or another server.
+pub async fn http_backup(state: Arc<ServerState>) -> Response {
+    match create_backup(&state).await.and_then(|b| Ok((b.created, encode_backup(&b)?))) {
+        Ok((created, archive)) => (
+            [
+                (header::CONTENT_TYPE, "application/gzip".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"rustdns-backup-{}.json.gz\"", created)),
+            ],
+            archive,
+        ).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "ok": false, "error": format!("{:#}", e) }))).into_response(),
+    }
+}
+
+// POST /restore — import an archive from GET /backup (gzipped or plain JSON). Each section in it
+// replaces the current state; `restored` counts what came back and `errors` names what didn't.
+pub async fn http_restore(state: Arc<ServerState>, body: Bytes) -> Json<Value> {
+    let backup = match decode_backup(&body) {
+        Ok(b) => b,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "error": format!("invalid backup: {:#}", e) })),
+    };
+    tracing::info!("restoring backup from {} (rustdns {})", backup.created, backup.server_version);
+    let report = restore_backup(&state, backup).await;
+    Json(serde_json::json!({ "ok": report.errors.is_empty(), "restored": report.restored, "errors": report.errors }))
+}
+
+// Body: {"enabled": false, "seconds": 300} pauses blocking (indefinitely without "seconds"),
+// {"enabled": true} resumes it.
+pub async fn http_blocking(state: Arc<ServerState>, Json(payload): Json<Value>) -> Response {
+    match payload.get("enabled").and_then(|v| v.as_bool()) {
+        Some(true) => state.resume_blocking(),
+        Some(false) => {
+            let secs = payload.get("seconds").and_then(|v| v.as_u64());
+            if let Some(e) = pause_too_long(secs) { return e }
+            state.pause_blocking(secs.map(Duration::from_secs));
+        }
+        None => return Json(serde_json::json!({ "ok": false, "error": "missing enabled" })).into_response(),
+    }
+    Json(serde_json::json!({
+        "ok": true,
+        "blocking_enabled": state.blocking.is_enabled(),
+        "seconds_remaining": state.blocking.seconds_remaining(),
+    })).into_response()
+}
+
+// 400 for a pause longer than MAX_PAUSE.
+fn pause_too_long(secs: Option<u64>) -> Option<Response> {
+    secs.filter(|s| *s > MAX_PAUSE.as_secs()).map(|_| (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+        "ok": false, "error": format!("a pause lasts at most {} seconds", MAX_PAUSE.as_secs()),
+    }))).into_response())
+}
+
+// Body: {"enabled": true} switches to audit mode: list matches are logged and counted under
+// `audited` in /stats, but queries are forwarded as if nothing matched.
+pub async fn http_audit(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    match payload.get("enabled").and_then(|v| v.as_bool()) {
+        Some(enabled) => {
+            state.audit.store(enabled, std::sync::atomic::Ordering::Relaxed);
+            tracing::info!("audit mode {}", if enabled { "on" } else { "off" });
+            Json(serde_json::json!({ "ok": true, "audit": enabled }))
+        }
+        None => Json(serde_json::json!({ "ok": false, "error": "missing enabled" })),
+    }
+}
+
+// Blocking state as the pause endpoints report it, with Pi-hole's `status` alongside.
+fn blocking_status(state: &ServerState) -> Json<Value> {
+    let enabled = state.blocking.is_enabled();
+    Json(serde_json::json!({
+        "ok": true,
+        "status": if enabled { "enabled" } else { "disabled" },
+        "blocking_enabled": enabled,
+        "seconds_remaining": state.blocking.seconds_remaining(),
+    }))
+}
+
+// POST /pause?seconds=N (or ?minutes=N) — suspend all blocking, resuming automatically once the
+// time is up. Without a duration blocking stays off until POST /resume. Also served as
+// POST /disable?duration=N, Pi-hole's name for it; 0 means until resumed there too. Pauses longer
+// than MAX_PAUSE (a year) are rejected.
+pub async fn http_pause(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Response {
+    let num = |k: &str| params.get(k).and_then(|v| v.parse::<u64>().ok());
+    let secs = num("duration").or_else(|| num("seconds"))
+        // saturates, so a minute count too big to convert is still rejected as too long
+        .or_else(|| num("minutes").map(|m| m.saturating_mul(60)))
+        .filter(|s| *s > 0);
+    if let Some(e) = pause_too_long(secs) { return e }
+    state.pause_blocking(secs.map(Duration::from_secs));
+    tracing::info!("blocking paused for {}", secs.map(|s| format!("{}s", s)).unwrap_or_else(|| "an unlimited time".to_string()));
+    blocking_status(&state).into_response()
+}
+
+// POST /resume, or POST /enable.
+pub async fn http_resume(state: Arc<ServerState>) -> Json<Value> {
+    state.resume_blocking();
+    tracing::info!("blocking resumed");
+    blocking_status(&state)
+}
+
+// Server-sent event stream of state changes (e.g. blocking paused/resumed).
+pub async fn http_events(state: Arc<ServerState>) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(|ev| {
+        ev.ok().and_then(|ev| SseEvent::default().json_data(ev).ok()).map(Ok)
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// Rules per /lists page unless `limit` says otherwise, and the most a page may hold.
+const LISTS_PAGE: usize = 1000;
+const LISTS_MAX_PAGE: usize = 10_000;
+
+// GET /lists — loaded rules, a page at a time so a multi-million-entry set is never serialized in
+// one go. `q` keeps rules containing a substring, `offset` / `limit` page through the patterns
+// (sorted by name, or by hits with `sort=hits`: most first, then by name, so dead rules end up
+// last). Annotations, types and hits cover the returned rules only. `format=ndjson` instead
+// streams every matching rule, one JSON object per line, in no particular order.
+pub async fn http_lists(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Response {
+    let filter = params.get("q").map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
+    match params.get("format").map(String::as_str) {
+        None | Some("json") => {}
+        Some("ndjson") => return lists_ndjson(state, filter).await,
+        Some(_) => return Json(serde_json::json!({ "ok": false, "error": "format must be json or ndjson" })).into_response(),
+    }
+    let page_param = |key: &str, default: usize| match params.get(key) {
+        None => Ok(default),
+        Some(v) => v.parse::<usize>().map_err(|_| format!("{} must be a number", key)),
+    };
+    let (offset, limit) = match (page_param("offset", 0), page_param("limit", LISTS_PAGE)) {
+        (Ok(o), Ok(l)) => (o, l.min(LISTS_MAX_PAGE)),
+        (Err(e), _) | (_, Err(e)) => return Json(serde_json::json!({ "ok": false, "error": e })).into_response(),
+    };
+    let lists = snapshot(&state.lists).await;
+    let keep = |rule: &str| filter.as_deref().map(|q| rule.contains(q)).unwrap_or(true);
+    let mut v: Vec<&String> = lists.patterns.keys().filter(|k| keep(k)).collect();
+    let mut tlds: Vec<&String> = lists.tlds.keys().filter(|k| keep(k)).collect();
+    let (pattern_hits, tld_hits) = state.hits.snapshot();
+    match params.get("sort").map(String::as_str) {
+        None | Some("name") => {
+            v.sort_unstable();
+            tlds.sort_unstable();
+        }
+        Some("hits") => {
+            sort_by_hits(&mut v, &pattern_hits);
+            sort_by_hits(&mut tlds, &tld_hits);
+        }
+        Some(_) => return Json(serde_json::json!({ "ok": false, "error": "sort must be name or hits" })).into_response(),
+    }
+    let total = v.len();
+    let page: Vec<&String> = v.into_iter().skip(offset).take(limit).collect();
+    let next_offset = (offset + page.len() < total).then_some(offset + page.len());
+    let locals: HashMap<&String, &Vec<IpAddr>> = lists.local_records.iter().filter(|(k, _)| keep(k)).map(|(k, r)| (k, &r.ips)).collect();
+    let forwards: HashMap<&String, &String> = lists.forward_zones.iter().filter(|(k, _)| keep(k)).map(|(k, f)| (k, &f.upstream)).collect();
+    let returned = || page.iter().map(|k| (*k, &lists.patterns[*k])).chain(tlds.iter().map(|k| (*k, &lists.tlds[*k])));
+    let annotations: HashMap<&String, Annotation> = returned().filter_map(|(k, m)| m.annotation().map(|a| (k, a))).collect();
+    let types: HashMap<&String, Vec<String>> = returned()
+        .filter(|(_, m)| !m.types.is_empty())
+        .map(|(k, m)| (k, m.types.iter().map(|t| t.to_string()).collect()))
+        .collect();
+    let pattern_hits: HashMap<&String, RuleHit> = page.iter().filter_map(|k| pattern_hits.get(*k).map(|h| (*k, *h))).collect();
+    Json(serde_json::json!({
+        "count": lists.patterns.len(),
+        "user_rule_count": lists.user.len(),
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "next_offset": next_offset,
+        "patterns": page,
+        "tld_count": lists.tlds.len(),
+        "tlds": tlds,
+        "local_record_count": lists.local_records.len(),
+        "local_records": locals,
+        "forward_zone_count": lists.forward_zones.len(),
+        "forward_zones": forwards,
+        "annotations": annotations,
+        "types": types,
+        "hits": { "patterns": pattern_hits, "tlds": tld_hits },
+        "hits_since": state.hits.since,
+        "compiled": lists.compiled.as_ref().map(|c| c.summary()),
+    })).into_response()
+}
+
+fn sort_by_hits(rules: &mut [&String], hits: &HashMap<String, RuleHit>) {
+    let count = |r: &String| hits.get(r).map(|h| h.count).unwrap_or(0);
+    rules.sort_by(|a, b| count(b).cmp(&count(a)).then_with(|| a.cmp(b)));
+}
+
+// Every pattern and TLD rule matching `filter` as NDJSON. A task walks the snapshot and hands
+// lines over in chunks through a small channel, so only a few chunks are in memory at a time and
+// a slow reader holds the walk back instead of buffering the whole set. The task ends with the
+// response: the control API's graceful shutdown waits for the body, and a dropped connection
+// stops the walk at the next rule.
+async fn lists_ndjson(state: Arc<ServerState>, filter: Option<String>) -> Response {
+    let lists = snapshot(&state.lists).await;
+    let (pattern_hits, tld_hits) = state.hits.snapshot();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<String, Infallible>>(4);
+    tokio::spawn(async move {
+        let rules = lists.patterns.iter().map(|(k, m)| ("pattern", k, m, pattern_hits.get(k)))
+            .chain(lists.tlds.iter().map(|(k, m)| ("tld", k, m, tld_hits.get(k))))
+            .filter(|(_, k, _, _)| filter.as_deref().map(|q| k.contains(q)).unwrap_or(true));
+        let mut chunk = String::new();
+        for (kind, rule, meta, hits) in rules {
+            if tx.is_closed() { return }
+            let line = serde_json::json!({
+                "rule": rule,
+                "kind": kind,
+                "origins": meta.origins,
+                "comment": meta.comment,
+                "tags": meta.tags,
+                "types": meta.types.iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+                "hits": hits.map(|h| h.count).unwrap_or(0),
+            });
+            chunk.push_str(&line.to_string());
+            chunk.push('\n');
+            if chunk.len() >= 64 * 1024 && tx.send(Ok(std::mem::take(&mut chunk))).await.is_err() { return }
+        }
+        if !chunk.is_empty() { let _ = tx.send(Ok(chunk)).await; }
+    });
+    let body = StreamBody::new(tokio_stream::wrappers::ReceiverStream::new(rx));
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
+}
+
+// GET /export?format=hosts|abp|plain — the effective blocklist (enabled groups only, duplicates
+// merged, allowlisted names removed) for other resolvers and routers to consume. Rules a format
+// can't express are left out: hosts files have no wildcards or TLD rules, and `prefix.*` rules
+// only exist in plain output.
+pub async fn http_export(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Response {
+    let format = params.get("format").map(|f| f.to_lowercase()).unwrap_or_else(|| "plain".to_string());
+    let header_line = match format.as_str() {
+        "hosts" => "# PiBlock blocklist export (hosts)",
+        "abp" => "[Adblock Plus 2.0]\n! PiBlock blocklist export",
+        "plain" => "# PiBlock blocklist export",
+        _ => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "ok": false, "error": "format must be hosts, abp or plain" }))).into_response(),
+    };
+    let lists = snapshot(&state.lists).await;
+    let mut lines: Vec<String> = lists.active_rules().into_iter()
+        .filter(|(rule, kind)| *kind == "tld" || state.allowlist.check(rule).is_none())
+        .filter_map(|(rule, kind)| export_line(&format, &rule, kind))
+        .collect();
+    lines.sort();
+    lines.dedup();
+    // the lines have to be held to sort them; the body is joined from them a chunk at a time as
+    // it is sent, rather than as one more copy of the whole export
+    let header = std::iter::once(format!("{}\n", header_line));
+    let mut rest = lines.into_iter().peekable();
+    let chunks = std::iter::from_fn(move || {
+        rest.peek()?;
+        let mut chunk = rest.by_ref().take(1000).collect::<Vec<_>>().join("\n");
+        chunk.push('\n');
+        Some(chunk)
+    });
+    let body = StreamBody::new(futures_util::stream::iter(header.chain(chunks).map(Ok::<_, Infallible>)));
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response()
+}
+
+fn export_line(format: &str, rule: &str, kind: &str) -> Option<String> {
+    match format {
+        "hosts" => (kind == "exact").then(|| format!("0.0.0.0 {}", rule)),
+        // `||d^` covers d and its subdomains, so `d` and `*.d` collapse into one line
+        "abp" => match kind {
+            "wildcard" => rule.strip_prefix("*.").map(|d| format!("||{}^", d)),
+            _ => Some(format!("||{}^", rule)),
+        },
+        _ => Some(if kind == "tld" { format!(".{}", rule) } else { rule.to_string() }),
+    }
+}
+
+// GET /rpz — configured Response Policy Zone feeds with their failure policy, last transfer and
+// consecutive failure count.
+pub async fn http_rpz(state: Arc<ServerState>) -> Json<Value> {
+    let feeds = state.rpz.status();
+    Json(serde_json::json!({ "refresh_secs": state.rpz.refresh.as_secs(), "feeds": feeds }))
+}
+
+// POST /rpz/{zone}/approve — body {"sha256": "..."}, the hash GET /rpz reports under `pending`.
+// Puts the transfer of an approve-pinned feed into use.
+pub async fn http_rpz_approve(state: Arc<ServerState>, zone: String, Json(payload): Json<Value>) -> Json<Value> {
+    let Some(sha256) = payload.get("sha256").and_then(|s| s.as_str()) else {
+        return Json(serde_json::json!({ "ok": false, "error": "missing sha256" }));
+    };
+    match approve_pending(&state.rpz, &zone, sha256, &state.lists_dir).await {
+        Ok(serial) => {
+            if !watch_lists_enabled() {
+                if let Err(e) = state.reload_lists(&state.lists_dir).await {
+                    tracing::warn!("reload after RPZ approval failed: {:?}", e);
+                }
+            }
+            Json(serde_json::json!({ "ok": true, "zone": zone, "serial": serial }))
+        }
+        Err(e) => Json(serde_json::json!({ "ok": false, "error": format!("{:#}", e) })),
+    }
+}
+
+// GET /check?domain=foo.example.com — report whether a query for the name would be blocked right
+// now, without sending one: the rule and list that match, the answer mode that would apply, and
+// whatever would keep it from being blocked (an allowlist entry, a local record, paused blocking
+// or audit mode). Also its categories. With &type=TXT, rules limited to other record types don't
+// count.
+pub async fn http_check(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    let Some(domain) = params.get("domain") else {
+        return Json(serde_json::json!({ "ok": false, "error": "missing domain" }));
+    };
+    if state.self_names.contains(domain) {
+        return Json(serde_json::json!({ "ok": true, "domain": domain, "blocked": false, "self_name": true }));
+    }
+    let qtype = match params.get("type").map(|t| RecordType::from_str(&t.to_uppercase())) {
+        None => None,
+        Some(Ok(t)) => Some(t),
+        Some(Err(_)) => return Json(serde_json::json!({ "ok": false, "error": "unknown record type" })),
+    };
+    let lists = snapshot(&state.lists).await;
+    let categories = state.categories.categories_of(domain);
+    if let Some(ips) = lists.find_local(domain) {
+        return Json(serde_json::json!({ "ok": true, "domain": domain, "blocked": false, "local_record": ips, "categories": categories }));
+    }
+    let m = find_match(domain, qtype, &lists).or_else(|| state.categories.check(domain));
+    let allowed_by = state.allowlist.check(domain);
+    let blocking_enabled = state.blocking.is_enabled();
+    let audit = state.audit.load(std::sync::atomic::Ordering::Relaxed);
+    let mode = match &m {
+        Some(m) if m.action.is_none() => Some(block_mode(&state, m).await),
+        _ => None,
+    };
+    Json(serde_json::json!({
+        "ok": true,
+        "domain": domain,
+        "blocked": m.is_some() && allowed_by.is_none() && blocking_enabled && !audit,
+        "match": m,
+        "mode": mode,
+        "allowed_by": allowed_by,
+        "blocking_enabled": blocking_enabled,
+        "audit": audit,
+        "categories": categories,
+    }))
+}
+
+// GET /lookup/rdap?domain=... — registration date and registrar for a domain, cached for a day.
+pub async fn http_rdap(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    let Some(domain) = params.get("domain") else {
+        return Json(serde_json::json!({ "ok": false, "error": "missing domain" }));
+    };
+    match state.rdap.lookup(domain).await {
+        Ok((info, cached)) => Json(serde_json::json!({ "ok": true, "cached": cached, "rdap": info })),
+        Err(e) => {
+            tracing::warn!("rdap lookup failed: {:?}", e);
+            Json(serde_json::json!({ "ok": false, "error": format!("{}", e) }))
+        }
+    }
+}
+
+fn records_json(records: &[Record]) -> Vec<Value> {
+    records.iter().map(|r| serde_json::json!({
+        "name": r.name().to_string(),
+        "type": r.record_type().to_string(),
+        "class": r.dns_class().to_string(),
+        "ttl": r.ttl(),
+        "data": r.data().map(|d| d.to_string()),
+    })).collect()
+}
+
+// Render a parsed DNS message (header flags and all sections) as JSON.
+fn message_json(msg: &Message) -> Value {
+    serde_json::json!({
+        "id": msg.id(),
+        "rcode": msg.response_code().to_string(),
+        "authoritative": msg.authoritative(),
+        "truncated": msg.truncated(),
+        "recursion_desired": msg.recursion_desired(),
+        "recursion_available": msg.recursion_available(),
+        "questions": msg.queries().iter().map(|q| serde_json::json!({
+            "name": q.name().to_string(),
+            "type": q.query_type().to_string(),
+        })).collect::<Vec<_>>(),
+        "answers": records_json(msg.answers()),
+        "authority": records_json(msg.name_servers()),
+        "additional": records_json(msg.additionals()),
+    })
+}
+
+// GET /resolve?name=example.com&type=A[&upstream=9.9.9.9:53][&bypass_cache=true][&debug=true]
+// Runs the name through the server's own pipeline (blocklist, cache, then upstream), or straight
+// to the given upstream when one is passed, and returns the parsed response with timing. With
+// debug=true the response also carries the pipeline trace.
+pub async fn http_resolve(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    let Some(name) = params.get("name") else {
+        return Json(serde_json::json!({ "ok": false, "error": "missing name" }));
+    };
+    let qtype = params.get("type").map(|t| t.to_uppercase()).unwrap_or_else(|| "A".to_string());
+    let Ok(rtype) = RecordType::from_str(&qtype) else {
+        return Json(serde_json::json!({ "ok": false, "error": format!("unknown record type {}", qtype) }));
+    };
+    let Ok(qname) = Name::from_str(name) else {
+        return Json(serde_json::json!({ "ok": false, "error": "invalid name" }));
+    };
+    let msg = query_message(rand_id(), qname, rtype);
+    let packet = match msg.to_vec() {
+        Ok(p) => p,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "error": format!("{}", e) })),
+    };
+    let flag = |k: &str| params.get(k).map(|v| v == "true" || v == "1").unwrap_or(false);
+    let bypass_cache = flag("bypass_cache");
+    let mut trace = flag("debug").then(|| Trace::new(name, &qtype));
+
+    let started = Instant::now();
+    let result = match params.get("upstream") {
+        Some(up) => {
+            if let Some(t) = trace.as_mut() {
+                t.step("upstream_override", serde_json::json!({ "upstream": up, "pipeline": "skipped" }));
+            }
+            forward_udp_to_upstream(&state.resources, &packet, up, state.settings.upstream_timeout()).await.map(|r| (r, None, None, Some(up.clone()), false, false))
+        }
+        None => resolve_query(&state, &msg, &packet, &state.settings.upstream(), bypass_cache, &ListenerPolicy::All, trace.as_mut()).await.map(|r| (r.response, r.blocked, r.audited, r.upstream, r.local, r.cached)),
+    };
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let mut out = match result {
+        Ok((raw, blocked, audited, upstream, local, cached)) => match Message::from_vec(&raw) {
+            Ok(resp) => serde_json::json!({
+                "ok": true,
+                "path": if blocked.is_some() { "blocked" } else if local { "local" } else if cached { "cache" } else { "upstream" },
+                "upstream": upstream,
+                "blocked_by": blocked,
+                "would_block": audited,
+                "bypass_cache": bypass_cache,
+                "elapsed_ms": elapsed_ms,
+                "response": message_json(&resp),
+            }),
+            Err(e) => serde_json::json!({ "ok": false, "error": format!("unparsable response: {}", e), "elapsed_ms": elapsed_ms }),
+        },
+        Err(e) => serde_json::json!({ "ok": false, "error": format!("{}", e), "elapsed_ms": elapsed_ms }),
+    };
+    if let Some(t) = trace {
+        out["trace"] = serde_json::json!(t);
+    }
+    Json(out)
+}
+
+// POST /debug/resolve {"name": "example.com", "type": "AAAA", "bypass_cache": true} — run a
+// name through the whole pipeline as a client query would go (canaries, local records, allowlist,
+// blocklist, categories, cache, upstream) and return every step it took with the final
+// `decision`, the rule that matched or the allowlist entry that let it through, and the answer.
+// Canary names stop at the canary check as they do for clients, but raise no alert here.
+// `"listener": "192.168.2.2:53"` applies that DNS listener's policy instead of blocking with every
+// group.
+pub async fn http_debug_resolve(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    let Some(name) = payload.get("name").and_then(|v| v.as_str()) else {
+        return Json(serde_json::json!({ "ok": false, "error": "missing name" }));
+    };
+    let qtype = payload.get("type").and_then(|v| v.as_str()).unwrap_or("A").to_uppercase();
+    let Ok(rtype) = RecordType::from_str(&qtype) else {
+        return Json(serde_json::json!({ "ok": false, "error": format!("unknown record type {}", qtype) }));
+    };
+    let Ok(qname) = Name::from_str(name) else {
+        return Json(serde_json::json!({ "ok": false, "error": "invalid name" }));
+    };
+    let bypass_cache = payload.get("bypass_cache").and_then(|v| v.as_bool()).unwrap_or(false);
+    let policy = match payload.get("listener").and_then(|v| v.as_str()) {
+        Some(addr) => match state.listeners.iter().find(|l| l.addr.to_string() == addr) {
+            Some(l) => l.policy.clone(),
+            None => return Json(serde_json::json!({ "ok": false, "error": format!("no DNS listener on {}", addr) })),
+        },
+        None => ListenerPolicy::All,
+    };
+    let msg = query_message(rand_id(), qname.clone(), rtype);
+    let packet = match msg.to_vec() {
+        Ok(p) => p,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "error": format!("{}", e) })),
+    };
+    let mut trace = Trace::new(name, &qtype);
+    let started = Instant::now();
+    if let Some(canary) = state.canaries.check(&qname.to_string()) {
+        trace.step("canary", serde_json::json!({ "canary": canary, "rcode": "NXDOMAIN", "alert": "raised for client queries" }));
+        return Json(serde_json::json!({
+            "ok": true,
+            "decision": "canary",
+            "elapsed_ms": started.elapsed().as_secs_f64() * 1000.0,
+            "trace": trace,
+        }));
+    }
+    let allowed_by = if state.blocking.is_enabled() { state.allowlist.check(&qname.to_string()) } else { None };
+    let result = resolve_query(&state, &msg, &packet, &state.settings.upstream(), bypass_cache, &policy, Some(&mut trace)).await;
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let res = match result {
+        Ok(res) => res,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "decision": "error", "error": format!("{}", e), "elapsed_ms": elapsed_ms, "trace": trace })),
+    };
+    let decision = match (&res.blocked, &res.audited) {
+        (Some(_), _) => "blocked",
+        (_, Some(_)) => "audited",
+        _ if res.local => "local",
+        _ if res.cached => "cache",
+        _ if res.upstream.is_some() => "upstream",
+        _ => "servfail",
+    };
+    let response = match Message::from_vec(&res.response) {
+        Ok(resp) => message_json(&resp),
+        Err(e) => serde_json::json!({ "error": format!("unparsable response: {}", e) }),
+    };
+    Json(serde_json::json!({
+        "ok": true,
+        "decision": decision,
+        "matched": res.blocked.as_ref().or(res.audited.as_ref()),
+        "allowed_by": allowed_by,
+        "upstream": res.upstream,
+        "elapsed_ms": elapsed_ms,
+        "response": response,
+        "trace": trace,
+    }))
+}
+
+// Query id for diagnostic lookups; uniqueness only matters per upstream socket.
+fn rand_id() -> u16 {
+    (std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0) & 0xffff) as u16
+}
+
+// POST /clients/{ip}/debug {"enabled": true} — trace every query from this client.
+pub async fn http_client_debug(state: Arc<ServerState>, Path(ip): Path<String>, Json(payload): Json<Value>) -> Json<Value> {
+    let Ok(addr) = ip.parse::<IpAddr>() else {
+        return Json(serde_json::json!({ "ok": false, "error": "invalid client ip" }));
+    };
+    let enabled = payload.get("enabled").and_then(|v| v.as_bool()).unwrap_or(true);
+    let client = state.identity.key(addr);
+    state.debug_clients.set(&client, enabled);
+    tracing::info!("query tracing for {} {}", client, if enabled { "enabled" } else { "disabled" });
+    Json(serde_json::json!({ "ok": true, "client": client, "debug": enabled }))
+}
+
+// GET /clients/{ip}/debug — the most recent traces recorded for a debug-enabled client.
+pub async fn http_client_traces(state: Arc<ServerState>, Path(ip): Path<String>) -> Json<Value> {
+    let Ok(addr) = ip.parse::<IpAddr>() else {
+        return Json(serde_json::json!({ "ok": false, "error": "invalid client ip" }));
+    };
+    let client = state.identity.key(addr);
+    match state.debug_clients.traces(&client) {
+        Some(traces) => Json(serde_json::json!({ "ok": true, "client": client, "debug": true, "traces": traces })),
+        None => Json(serde_json::json!({ "ok": true, "client": client, "debug": false, "traces": [] })),
+    }
+}
+
+// GET /alerts?since=<id>&kind=<kind> — recent alerts, oldest first.
+pub async fn http_alerts(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    let since = params.get("since").and_then(|v| v.parse().ok()).unwrap_or(0);
+    let alerts = state.alerts.list(since, params.get("kind").map(|s| s.as_str()));
+    Json(serde_json::json!({ "count": alerts.len(), "alerts": alerts }))
+}
+
+// GET /queries?client=10.0.0.5&domain=example&action=blocked&since=..&until=..&before=..&limit=..
+// — recent client queries, newest first. `client` is an address, client key or client name, `domain` a
+// substring of the name, `since` / `until` Unix times. Pass `next_before` back as `before` for the
+// next page. With the query log persisted, paging goes on past the in-memory entries into those
+// on disk; `matching` only counts the ones in memory.
+pub async fn http_queries(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    let num = |key: &str| params.get(key).and_then(|v| v.parse::<u64>().ok());
+    let action = params.get("action").map(|a| a.to_lowercase());
+    if let Some(a) = &action {
+        if !ACTIONS.contains(&a.as_str()) {
+            return Json(serde_json::json!({ "ok": false, "error": format!("action must be one of {}", ACTIONS.join(", ")) }));
+        }
+    }
+    let filter = QueryFilter {
+        client: params.get("client").map(|c| c.trim().to_string()).filter(|c| !c.is_empty()),
+        domain: params.get("domain").map(|d| d.trim().trim_end_matches('.').to_lowercase()).filter(|d| !d.is_empty()),
+        action,
+        since: num("since"),
+        until: num("until"),
+        before: num("before"),
+    };
+    let limit = num("limit").map(|l| l as usize).unwrap_or(QUERIES_PAGE).clamp(1, QUERIES_MAX_PAGE);
+    let (mut queries, matching) = state.query_log.query(&filter, limit);
+    let mut more = matching > queries.len();
+    // a page the in-memory log can't fill carries on into the entries on disk
+    if !more && state.query_log.store.is_some() {
+        let log = state.query_log.clone();
+        let want = limit - queries.len();
+        let stored = tokio::task::spawn_blocking(move || {
+            let below = filter.before.unwrap_or(u64::MAX).min(log.oldest_id());
+            log.store.as_ref().map(|s| s.search(&filter, below, want))
+        }).await;
+        if let Ok(Some((older, more_stored))) = stored {
+            queries.extend(older);
+            more = more_stored;
+        }
+    }
+    let next_before = if more { queries.last().map(|q| q.id) } else { None };
+    Json(serde_json::json!({
+        "count": queries.len(),
+        "matching": matching,
+        "next_before": next_before,
+        "capacity": state.query_log.capacity,
+        // entries not written to disk because the writer fell behind
+        "persisted": state.query_log.store.as_ref().map(|s| serde_json::json!({ "dropped": s.dropped() })),
+        "queries": queries,
+    }))
+}
+
+// GET /queries/stream?client=..&domain=..&action=blocked — new client queries as server-sent
+// events, filtered like /queries so a live view only receives what it shows. A subscriber that
+// falls too far behind gets a `lagged` event with the number of entries it missed.
+pub async fn http_queries_stream(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Response {
+    let action = params.get("action").map(|a| a.to_lowercase());
+    if let Some(a) = &action {
+        if !ACTIONS.contains(&a.as_str()) {
+            return Json(serde_json::json!({ "ok": false, "error": format!("action must be one of {}", ACTIONS.join(", ")) })).into_response();
+        }
+    }
+    let filter = QueryFilter {
+        client: params.get("client").map(|c| c.trim().to_string()).filter(|c| !c.is_empty()),
+        domain: params.get("domain").map(|d| d.trim().trim_end_matches('.').to_lowercase()).filter(|d| !d.is_empty()),
+        action,
+        ..Default::default()
+    };
+    let stream = BroadcastStream::new(state.query_log.subscribe()).filter_map(move |entry| match entry {
+        Ok(e) if filter.matches(&e) => SseEvent::default().event("query").json_data(e).ok().map(Ok::<_, Infallible>),
+        Ok(_) => None,
+        Err(BroadcastStreamRecvError::Lagged(n)) => Some(Ok(SseEvent::default().event("lagged").data(n.to_string()))),
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
+
+// GET /review?status=requested — recently blocked domains per client, oldest first.
+pub async fn http_review(state: Arc<ServerState>, Query(params): Query<HashMap<String, String>>) -> Json<Value> {
+    let status = match params.get("status").map(|s| ReviewStatus::parse(&s.to_lowercase())) {
+        None => None,
+        Some(Some(s)) => Some(s),
+        Some(None) => return Json(serde_json::json!({ "ok": false, "error": "status must be pending, requested, approved or confirmed" })),
+    };
+    let items = state.review.list(status);
+    Json(serde_json::json!({ "count": items.len(), "items": items }))
+}
+
+// POST /review/{id}/request — ask for a block to be lifted; body {"note": "homework site"}.
+pub async fn http_review_request(state: Arc<ServerState>, id: String, Json(payload): Json<Value>) -> Json<Value> {
+    let note = payload.get("note").and_then(|n| n.as_str());
+    match id.parse().ok().and_then(|id| state.review.request(id, note)) {
+        Some(item) => {
+            tracing::info!("{} asked to unblock {}", item.client, item.domain);
+            let _ = state.events.send(Event::UnblockRequested(item.clone()));
+            Json(serde_json::json!({ "ok": true, "item": item }))
+        }
+        None => Json(serde_json::json!({ "ok": false, "error": "unknown or closed review item" })),
+    }
+}
+
+// POST /review/{id}/approve — allowlist the item's domain, for `seconds` / `minutes` when given
+// (as with /allow), and close its open items.
+pub async fn http_review_approve(state: Arc<ServerState>, id: String, Json(payload): Json<Value>) -> Response {
+    let secs = match allow_secs(&payload) {
+        Ok(s) => s,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "ok": false, "error": e }))).into_response(),
+    };
+    match id.parse().ok().and_then(|id| state.review.resolve(id, ReviewStatus::Approved)) {
+        Some(item) => {
+            state.allowlist.add(&item.domain, secs.map(Duration::from_secs));
+            tracing::info!("unblock of {} approved", item.domain);
+            Json(serde_json::json!({ "ok": true, "item": item, "allowed": item.domain, "seconds": secs })).into_response()
+        }
+        None => Json(serde_json::json!({ "ok": false, "error": "unknown or closed review item" })).into_response(),
+    }
+}
+
+// POST /review/{id}/confirm — keep the block and close the domain's open items.
+pub async fn http_review_confirm(state: Arc<ServerState>, id: String) -> Json<Value> {
+    match id.parse().ok().and_then(|id| state.review.resolve(id, ReviewStatus::Confirmed)) {
+        Some(item) => Json(serde_json::json!({ "ok": true, "item": item })),
+        None => Json(serde_json::json!({ "ok": false, "error": "unknown or closed review item" })),
+    }
 }
 
-pub async fn http_lists(state: Arc<ServerState>) -> Json<Value> {
-    let lists = state.lists.read().await;
-    let v: Vec<String> = lists.iter().cloned().collect();
-    Json(serde_json::json!({ "count": v.len(), "patterns": v }))
+pub async fn http_brands(state: Arc<ServerState>) -> Json<Value> {
+    Json(serde_json::json!({ "brands": state.brands.list() }))
+}
+
+pub async fn http_brand_add(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    match payload.get("brand").and_then(|s| s.as_str()).and_then(|b| state.brands.add(b)) {
+        Some(b) => Json(serde_json::json!({ "ok": true, "added": b })),
+        None => Json(serde_json::json!({ "ok": false, "error": "missing or invalid brand" })),
+    }
+}
+
+pub async fn http_brand_remove(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    if let Some(b) = payload.get("brand").and_then(|s| s.as_str()) {
+        Json(serde_json::json!({ "ok": state.brands.remove(b) }))
+    } else {
+        Json(serde_json::json!({ "ok": false, "error": "missing brand" }))
+    }
+}
+
+pub async fn http_canaries(state: Arc<ServerState>) -> Json<Value> {
+    Json(serde_json::json!({ "canaries": state.canaries.list() }))
+}
+
+pub async fn http_canary_add(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    match payload.get("domain").and_then(|s| s.as_str()).and_then(|d| state.canaries.add(d)) {
+        Some(d) => Json(serde_json::json!({ "ok": true, "added": d })),
+        None => Json(serde_json::json!({ "ok": false, "error": "missing domain" })),
+    }
+}
+
+pub async fn http_canary_remove(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    if let Some(d) = payload.get("domain").and_then(|s| s.as_str()) {
+        Json(serde_json::json!({ "ok": state.canaries.remove(d) }))
+    } else {
+        Json(serde_json::json!({ "ok": false, "error": "missing domain" }))
+    }
 }
 
+pub async fn http_allowlist(state: Arc<ServerState>) -> Json<Value> {
+    let entries = state.allowlist.list();
+    Json(serde_json::json!({ "count": entries.len(), "entries": entries, "presets": state.allowlist.enabled_presets() }))
+}
+
+pub async fn http_allow_presets(state: Arc<ServerState>) -> Json<Value> {
+    Json(serde_json::json!({ "presets": state.allowlist.presets() }))
+}
+
+// POST /allowlist/presets/{name}/enable and .../disable
+pub async fn http_allow_preset_toggle(state: Arc<ServerState>, name: String, enabled: bool) -> Json<Value> {
+    if !state.allowlist.set_preset(&name, enabled) {
+        return Json(serde_json::json!({ "ok": false, "error": "unknown preset" }));
+    }
+    tracing::info!("allowlist preset {} {}", name, if enabled { "enabled" } else { "disabled" });
+    Json(serde_json::json!({ "ok": true, "preset": name.to_lowercase(), "enabled": enabled }))
+}
+
+// POST /allow {"domain": "tracker.example.com", "seconds": 900} — allow a name, optionally
+// only for a while ("seconds" or "minutes"); without a duration the entry is permanent.
+pub async fn http_allow(state: Arc<ServerState>, Json(payload): Json<Value>) -> Response {
+    let secs = match allow_secs(&payload) {
+        Ok(s) => s,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "ok": false, "error": e }))).into_response(),
+    };
+    match payload.get("domain").and_then(|s| s.as_str()).and_then(|d| state.allowlist.add(d, secs.map(Duration::from_secs))) {
+        Some(d) => Json(serde_json::json!({ "ok": true, "allowed": d, "seconds": secs })).into_response(),
+        None => Json(serde_json::json!({ "ok": false, "error": "missing domain" })).into_response(),
+    }
+}
+
+// How long an allow given as `seconds` or `minutes` lasts; an error past MAX_ALLOW_TTL.
+fn allow_secs(payload: &Value) -> Result<Option<u64>, String> {
+    let secs = match (payload.get("seconds").and_then(|v| v.as_u64()), payload.get("minutes").and_then(|v| v.as_u64())) {
+        (Some(s), _) => Some(s),
+        (None, Some(m)) => Some(m.checked_mul(60).ok_or("minutes is out of range")?),
+        (None, None) => None,
+    };
+    match secs {
+        Some(s) if s > MAX_ALLOW_TTL.as_secs() => Err(format!("an allowlist entry lasts at most {} seconds", MAX_ALLOW_TTL.as_secs())),
+        s => Ok(s),
+    }
+}
+
+pub async fn http_allow_remove(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    if let Some(d) = payload.get("domain").and_then(|s| s.as_str()) {
+        Json(serde_json::json!({ "ok": state.allowlist.remove(d) }))
+    } else {
+        Json(serde_json::json!({ "ok": false, "error": "missing domain" }))
+    }
+}
+
+// Group, comment, tags and record types of rules added through the API.
+struct RuleOptions {
+    group: String,
+    comment: Option<String>,
+    tags: Vec<String>,
+    types: Option<Vec<RecordType>>,
+}
+
+fn rule_options(payload: &Value) -> Result<RuleOptions, &'static str> {
+    let tags: Vec<String> = match payload.get("tags") {
+        None => Vec::new(),
+        Some(Value::Array(tags)) if tags.iter().all(|t| t.is_string()) => {
+            tags.iter().filter_map(|t| t.as_str()).map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()).collect()
+        }
+        Some(_) => return Err("tags must be an array of strings"),
+    };
+    let types: Option<Vec<RecordType>> = match payload.get("types") {
+        None => None,
+        Some(Value::Array(types)) => {
+            let parsed: Option<Vec<RecordType>> = types.iter()
+                .map(|t| t.as_str().and_then(|t| RecordType::from_str(&t.trim().to_uppercase()).ok()))
+                .collect();
+            Some(parsed.ok_or("types must be an array of record types")?)
+        }
+        Some(_) => return Err("types must be an array of record types"),
+    };
+    Ok(RuleOptions {
+        group: payload.get("group").and_then(|s| s.as_str()).unwrap_or(CUSTOM_GROUP).to_lowercase(),
+        comment: payload.get("comment").and_then(|s| s.as_str()).map(str::to_string),
+        tags,
+        types,
+    })
+}
+
+// Body: {"pattern": "ads.example.com"} or {"tld": "zip"}, optionally with "group", a "comment"
+// and "tags" (e.g. {"comment": "kid's game ads", "tags": ["kids", "temporary"]}). "types" limits
+// the rule to those record types (e.g. ["TXT", "NULL"]), replacing any earlier limit; an empty
+// array makes it block every type again.
 pub async fn http_add(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    let RuleOptions { group, comment, tags, types } = match rule_options(&payload) {
+        Ok(o) => o,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "error": e })),
+    };
+    if let Some(t) = payload.get("tld").and_then(|s| s.as_str()) {
+        return match normalize_tld(t) {
+            Some(tld) => {
+                let mut lists = state.lists.write().await;
+                Arc::make_mut(&mut lists).add_user_rule(&tld, true, &group, comment.as_deref(), &tags, types);
+                Json(serde_json::json!({ "ok": true, "added_tld": tld }))
+            }
+            None => Json(serde_json::json!({ "ok": false, "error": "invalid tld" })),
+        };
+    }
     if let Some(p) = payload.get("pattern").and_then(|s| s.as_str()) {
+        let Some(pattern) = normalize_pattern(p) else {
+            return Json(serde_json::json!({ "ok": false, "error": "invalid pattern" }));
+        };
         let mut lists = state.lists.write().await;
-        lists.insert(p.to_lowercase());
-        Json(serde_json::json!({ "ok": true, "added": p }))
+        Arc::make_mut(&mut lists).add_user_rule(&pattern, false, &group, comment.as_deref(), &tags, types);
+        Json(serde_json::json!({ "ok": true, "added": pattern, "group": group }))
     } else {
         Json(serde_json::json!({ "ok": false, "error": "missing pattern" }))
     }
 }
 
+// Rules of a bulk request: a JSON array of patterns, an object with `patterns` and `tlds` arrays
+// (plus the options /add takes, applied to all of them), or a text body with one pattern per
+// line, `#` starting a comment and `.zip`-style lines naming TLDs. Returns (rule, is TLD) pairs
+// and the options.
+fn bulk_rules(headers: &HeaderMap, body: &str) -> Result<(Vec<(String, bool)>, Value), String> {
+    let json = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(|t| t.starts_with("application/json"));
+    if !json {
+        let rules = body.lines()
+            .map(|l| l.split('#').next().unwrap_or("").trim())
+            .filter(|l| !l.is_empty())
+            .map(|l| match l.strip_prefix('.') {
+                Some(tld) if !tld.contains('.') => (tld.to_string(), true),
+                _ => (l.to_string(), false),
+            })
+            .collect();
+        return Ok((rules, Value::Null));
+    }
+    let payload: Value = serde_json::from_str(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    let strings = |v: Option<&Value>, what: &str| -> Result<Vec<String>, String> {
+        match v {
+            None => Ok(Vec::new()),
+            Some(Value::Array(a)) => a.iter().map(|s| s.as_str().map(str::to_string)).collect::<Option<_>>()
+                .ok_or_else(|| format!("{} must be an array of strings", what)),
+            Some(_) => Err(format!("{} must be an array of strings", what)),
+        }
+    };
+    if payload.is_array() {
+        let patterns = strings(Some(&payload), "the body")?;
+        return Ok((patterns.into_iter().map(|p| (p, false)).collect(), Value::Null));
+    }
+    let mut rules: Vec<(String, bool)> = strings(payload.get("patterns"), "patterns")?.into_iter().map(|p| (p, false)).collect();
+    rules.extend(strings(payload.get("tlds"), "tlds")?.into_iter().map(|t| (t, true)));
+    Ok((rules, payload))
+}
+
+// Outcome of one entry of a bulk request.
+fn bulk_result(rule: &str, tld: bool, status: &str, error: Option<&str>) -> Value {
+    let mut r = serde_json::json!({ "ok": error.is_none(), "status": status });
+    r[if tld { "tld" } else { "pattern" }] = Value::from(rule);
+    if let Some(e) = error { r["error"] = Value::from(e); }
+    r
+}
+
+// POST /add-bulk — add many rules at once (see `bulk_rules` for the body). Invalid entries are
+// reported and skipped; the rest are applied together under one write lock, so lookups see
+// either none or all of them. `results` has one entry per rule, in request order.
+pub async fn http_add_bulk(state: Arc<ServerState>, headers: HeaderMap, body: String) -> Json<Value> {
+    let (rules, payload) = match bulk_rules(&headers, &body) {
+        Ok(r) => r,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "error": e })),
+    };
+    let RuleOptions { group, comment, tags, types } = match rule_options(&payload) {
+        Ok(o) => o,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "error": e })),
+    };
+    let mut results = Vec::with_capacity(rules.len());
+    let mut added = 0;
+    let mut lists = state.lists.write().await;
+    let lists = Arc::make_mut(&mut lists);
+    for (rule, tld) in rules {
+        let normalized = if tld { normalize_tld(&rule) } else { normalize_pattern(&rule) };
+        let Some(r) = normalized else {
+            results.push(bulk_result(&rule, tld, "invalid", Some(if tld { "invalid tld" } else { "invalid pattern" })));
+            continue;
+        };
+        let existed = if tld { lists.user.tlds.contains_key(&r) } else { lists.user.patterns.contains_key(&r) };
+        lists.add_user_rule(&r, tld, &group, comment.as_deref(), &tags, types.clone());
+        if !existed { added += 1; }
+        results.push(bulk_result(&r, tld, if existed { "updated" } else { "added" }, None));
+    }
+    let invalid = results.iter().filter(|r| r["ok"] == false).count();
+    tracing::info!("bulk add: {} added, {} updated, {} invalid (group {})", added, results.len() - added - invalid, invalid, group);
+    Json(serde_json::json!({ "ok": invalid == 0, "group": group, "added": added, "invalid": invalid, "results": results }))
+}
+
+// POST /remove-bulk — remove many rules at once, under one write lock, with a result per rule:
+// `removed`, `not_found`, `invalid`, or `compiled` for names only the compiled list has.
+pub async fn http_remove_bulk(state: Arc<ServerState>, headers: HeaderMap, body: String) -> Json<Value> {
+    let rules = match bulk_rules(&headers, &body) {
+        Ok((rules, _)) => rules,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "error": e })),
+    };
+    let mut results = Vec::with_capacity(rules.len());
+    let mut lists = state.lists.write().await;
+    let lists = Arc::make_mut(&mut lists);
+    for (rule, tld) in rules {
+        let normalized = if tld { normalize_tld(&rule) } else { Some(rule.trim().trim_end_matches('.').to_lowercase()) };
+        let Some(r) = normalized else {
+            results.push(bulk_result(&rule, tld, "invalid", Some("invalid tld")));
+            continue;
+        };
+        let present = if tld { lists.tlds.contains_key(&r) || lists.user.tlds.contains_key(&r) } else { lists.patterns.contains_key(&r) || lists.user.patterns.contains_key(&r) };
+        if present && lists.remove_rule(&r, tld) {
+            results.push(bulk_result(&r, tld, "removed", None));
+        } else if !tld && lists.compiled.as_ref().and_then(|c| c.get(&r)).is_some() {
+            results.push(bulk_result(&r, tld, "compiled", Some("pattern is in the compiled list; edit its list file and recompile")));
+        } else {
+            results.push(bulk_result(&r, tld, "not_found", Some("no such rule")));
+        }
+    }
+    let removed = results.iter().filter(|r| r["status"] == "removed").count();
+    tracing::info!("bulk remove: {} of {} rules removed", removed, results.len());
+    Json(serde_json::json!({ "ok": removed == results.len(), "removed": removed, "results": results }))
+}
+
 pub async fn http_remove(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    if let Some(t) = payload.get("tld").and_then(|s| s.as_str()) {
+        let removed = match normalize_tld(t) {
+            Some(tld) => {
+                let mut lists = state.lists.write().await;
+                (lists.tlds.contains_key(&tld) || lists.user.tlds.contains_key(&tld)) && Arc::make_mut(&mut lists).remove_rule(&tld, true)
+            }
+            None => false,
+        };
+        return Json(serde_json::json!({ "ok": removed }));
+    }
     if let Some(p) = payload.get("pattern").and_then(|s| s.as_str()) {
+        let p = p.to_lowercase();
         let mut lists = state.lists.write().await;
-        let removed = lists.remove(&p.to_lowercase());
+        let removed = (lists.patterns.contains_key(&p) || lists.user.patterns.contains_key(&p)) && Arc::make_mut(&mut lists).remove_rule(&p, false);
+        if !removed && lists.compiled.as_ref().and_then(|c| c.get(&p)).is_some() {
+            return Json(serde_json::json!({ "ok": false, "error": "pattern is in the compiled list; edit its list file and recompile" }));
+        }
         Json(serde_json::json!({ "ok": removed }))
     } else {
         Json(serde_json::json!({ "ok": false, "error": "missing pattern" }))
     }
 }
 
-pub async fn http_mode(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
-    if let Some(m) = payload.get("mode").and_then(|s| s.as_str()) {
-        let mut mode = state.mode.write().await;
-        *mode = m.to_string();
-        if m == "redirect" {
-            if let Some(ip) = payload.get("block_ip").and_then(|s| s.as_str()) {
-                let mut bip = state.block_page_ip.write().await;
-                *bip = Some(ip.to_string());
+pub async fn http_categories(state: Arc<ServerState>) -> Json<Value> {
+    let categories = state.categories.list();
+    Json(serde_json::json!({ "dir": state.categories.dir.display().to_string(), "categories": categories }))
+}
+
+pub async fn http_category_toggle(state: Arc<ServerState>, name: String, block: bool) -> Json<Value> {
+    if !state.categories.set_blocked(&name, block) {
+        return Json(serde_json::json!({ "ok": false, "error": "unknown category" }));
+    }
+    tracing::info!("category {} {}", name, if block { "blocked" } else { "unblocked" });
+    Json(serde_json::json!({ "ok": true, "category": name.to_lowercase(), "blocked": block }))
+}
+
+pub async fn http_categories_reload(state: Arc<ServerState>) -> Json<Value> {
+    match state.categories.reload().await {
+        Ok((categories, domains)) => Json(serde_json::json!({ "ok": true, "categories": categories, "domains": domains })),
+        Err(e) => Json(serde_json::json!({ "ok": false, "error": format!("{:#}", e) })),
+    }
+}
+
+pub async fn http_groups(state: Arc<ServerState>) -> Json<Value> {
+    let lists = snapshot(&state.lists).await;
+    let mut groups: Vec<Value> = lists.group_counts().into_iter().map(|(name, count)| {
+        let enabled = !lists.disabled_groups.contains(&name);
+        let mode = state.group_modes.get(&name);
+        let schedule = state.schedules.get(&name);
+        serde_json::json!({ "name": name, "count": count, "enabled": enabled, "mode": mode, "schedule": schedule })
+    }).collect();
+    groups.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Json(serde_json::json!({ "groups": groups }))
+}
+
+pub async fn http_group_toggle(state: Arc<ServerState>, name: String, enabled: bool) -> Json<Value> {
+    let name = name.to_lowercase();
+    let mut lists = state.lists.write().await;
+    let lists = Arc::make_mut(&mut lists);
+    if enabled {
+        lists.disabled_groups.remove(&name);
+    } else {
+        lists.disabled_groups.insert(name.clone());
+    }
+    tracing::info!("group {} {}", name, if enabled { "enabled" } else { "disabled" });
+    Json(serde_json::json!({ "ok": true, "group": name, "enabled": enabled }))
+}
+
+// POST /groups/{name}/mode {"mode": "null"} or {"mode": "redirect", "block_ip": "10.0.0.2"};
+// {"mode": null} goes back to the global mode.
+pub async fn http_group_mode(state: Arc<ServerState>, name: String, Json(payload): Json<Value>) -> Json<Value> {
+    let name = name.to_lowercase();
+    let mode = match payload.get("mode") {
+        Some(Value::Null) => None,
+        Some(Value::String(m)) => {
+            let spec = match payload.get("block_ip").and_then(|v| v.as_str()) {
+                Some(ip) => format!("{}:{}", m, ip),
+                None => m.clone(),
+            };
+            match GroupMode::parse(&spec) {
+                Some(mode) => Some(mode),
+                None => return Json(serde_json::json!({ "ok": false, "error": "mode must be nx, null, nodata or redirect (block_ip only with redirect, as IPv4)" })),
             }
         }
-        Json(serde_json::json!({ "ok": true, "mode": *mode }))
-    } else {
-        Json(serde_json::json!({ "ok": false, "error": "missing mode" }))
+        _ => return Json(serde_json::json!({ "ok": false, "error": "missing mode" })),
+    };
+    tracing::info!("group {} mode {}", name, mode.as_ref().map(|m| m.mode.as_str()).unwrap_or("global"));
+    state.group_modes.set(&name, mode.clone());
+    Json(serde_json::json!({ "ok": true, "group": name, "mode": mode }))
+}
+
+// POST /groups/{name}/schedule {"windows": ["mon-fri 08:00-17:00"], "timezone": "Europe/Berlin"}
+// — the group is enabled during its windows and disabled outside them; {"windows": null} removes
+// the schedule and leaves the group as it is.
+pub async fn http_group_schedule(state: Arc<ServerState>, name: String, Json(payload): Json<Value>) -> Json<Value> {
+    let name = name.to_lowercase();
+    let windows: Option<Vec<String>> = match payload.get("windows") {
+        Some(Value::Null) => None,
+        Some(Value::Array(w)) if w.iter().all(|w| w.is_string()) => Some(w.iter().filter_map(|w| w.as_str()).map(str::to_string).collect()),
+        _ => return Json(serde_json::json!({ "ok": false, "error": "windows must be an array of strings or null" })),
+    };
+    let timezone = payload.get("timezone").and_then(|t| t.as_str());
+    if let Err(e) = state.schedules.set(&name, windows.as_deref(), timezone) {
+        return Json(serde_json::json!({ "ok": false, "error": e }));
+    }
+    apply_schedules(&state).await;
+    tracing::info!("group {} schedule {}", name, windows.map(|w| w.join(" + ")).unwrap_or_else(|| "removed".to_string()));
+    Json(serde_json::json!({ "ok": true, "group": name, "schedule": state.schedules.get(&name) }))
+}
+
+// POST /mode {"mode": "nx"} or {"mode": "redirect", "block_ip": "10.0.0.2"}; `block_ip` is only
+// taken with redirect. Nothing changes unless both are valid.
+pub async fn http_mode(state: Arc<ServerState>, Json(payload): Json<Value>) -> Json<Value> {
+    let Some(m) = payload.get("mode").and_then(|s| s.as_str()) else {
+        return Json(serde_json::json!({ "ok": false, "error": "missing mode" }));
+    };
+    let mode = match parse_mode(m) {
+        Ok(mode) => mode,
+        Err(e) => return Json(serde_json::json!({ "ok": false, "error": format!("mode {}", e) })),
+    };
+    let block_ip = match payload.get("block_ip").and_then(|s| s.as_str()) {
+        Some(ip) if mode == BlockingMode::Redirect => match ip.parse::<std::net::Ipv4Addr>() {
+            Ok(ip) => Some(ip),
+            Err(_) => return Json(serde_json::json!({ "ok": false, "error": "block_ip must be an IPv4 address" })),
+        },
+        _ => None,
+    };
+    *state.mode.write().await = mode;
+    if let Some(ip) = block_ip {
+        *state.block_page_ip.write().await = Some(ip);
+    }
+    tracing::info!("blocking mode {}", mode.as_str());
+    Json(serde_json::json!({ "ok": true, "mode": mode }))
+}
+
+pub async fn http_client_unban(state: Arc<ServerState>, Path(ip): Path<String>) -> Json<Value> {
+    match ip.parse::<IpAddr>() {
+        Ok(addr) => {
+            let client = state.identity.key(addr);
+            let was_banned = state.limiter.unban(&client);
+            tracing::info!("cleared rate-limit state for {}", client);
+            Json(serde_json::json!({ "ok": true, "client": client, "was_banned": was_banned }))
+        }
+        Err(_) => Json(serde_json::json!({ "ok": false, "error": "invalid client ip" })),
+    }
+}
+
+pub async fn http_clients(state: Arc<ServerState>) -> Json<Value> {
+    Json(serde_json::json!({ "clients": state.clients.list() }))
+}
+
+pub async fn http_client_create(state: Arc<ServerState>, Json(body): Json<ClientUpdate>) -> Json<Value> {
+    match state.clients.create(body) {
+        Ok(client) => {
+            tracing::info!("registered client {}", client.id);
+            Json(serde_json::json!({ "ok": true, "client": client }))
+        }
+        Err(e) => Json(serde_json::json!({ "ok": false, "error": e })),
+    }
+}
+
+// The registry id `/clients/{id}` refers to: the id itself, an address of the client (resolved
+// the way its queries are) or another of its identifiers.
+fn client_id(state: &ServerState, id: &str) -> Option<u64> {
+    if let Ok(id) = id.parse() { return Some(id) }
+    match id.parse::<IpAddr>() {
+        Ok(ip) => {
+            let mac = match ip { IpAddr::V4(v4) => state.identity.mac(&v4), IpAddr::V6(_) => None };
+            state.clients.lookup_source(ip, &state.identity.key(ip), mac.as_deref())
+        }
+        Err(_) => state.clients.lookup(id),
+    }
+}
+
+// GET /clients/{id} — a client (by id, address or identifier) with what it did since the server
+// started: query counts, the share blocked and its most queried names.
+pub async fn http_client_get(state: Arc<ServerState>, id: String) -> Json<Value> {
+    match client_id(&state, &id).and_then(|id| state.clients.get(id)) {
+        Some(client) => {
+            let activity = state.clients.activity(client.id);
+            Json(serde_json::json!({ "ok": true, "client": client, "activity": activity }))
+        }
+        None => Json(serde_json::json!({ "ok": false, "error": "unknown client" })),
+    }
+}
+
+pub async fn http_client_update(state: Arc<ServerState>, id: String, Json(body): Json<ClientUpdate>) -> Json<Value> {
+    let Some(id) = client_id(&state, &id) else { return Json(serde_json::json!({ "ok": false, "error": "unknown client" })) };
+    match state.clients.update(id, body) {
+        Ok(Some(client)) => Json(serde_json::json!({ "ok": true, "client": client })),
+        Ok(None) => Json(serde_json::json!({ "ok": false, "error": "unknown client" })),
+        Err(e) => Json(serde_json::json!({ "ok": false, "error": e })),
+    }
+}
+
+pub async fn http_client_delete(state: Arc<ServerState>, id: String) -> Json<Value> {
+    let removed = client_id(&state, &id).map(|id| state.clients.delete(id)).unwrap_or(false);
+    if removed { tracing::info!("removed client {}", id); }
+    Json(serde_json::json!({ "ok": removed }))
+}
+
+pub async fn http_tls(state: Arc<ServerState>) -> Json<Value> {
+    Json(serde_json::json!(state.certs.status()))
+}
+
+pub async fn http_acme(state: Arc<ServerState>) -> Json<Value> {
+    Json(serde_json::json!(state.acme.status()))
+}
+
+// http-01 challenge responses; only answers while an ACME order is in progress.
+pub async fn http_acme_challenge(state: Arc<ServerState>, token: String) -> (StatusCode, String) {
+    match state.acme.http_token(&token) {
+        Some(key_auth) => (StatusCode::OK, key_auth),
+        None => (StatusCode::NOT_FOUND, String::new()),
     }
 }
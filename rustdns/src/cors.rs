@@ -0,0 +1,127 @@
+use crate::state::ServerState;
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::{Arc, RwLock};
+
+// Request headers a cross-origin dashboard may send, and response headers it may read.
+const ALLOW_HEADERS: &str = "authorization, content-type";
+const EXPOSE_HEADERS: &str = "api-version, deprecation, link, content-disposition";
+
+// How long browsers may cache a preflight answer, in seconds.
+const PREFLIGHT_MAX_AGE: &str = "600";
+
+// Which other origins may call the control API from a browser, for dashboards hosted elsewhere.
+// RUSTDNS_CORS_ORIGINS (comma separated `https://dash.example:8443` origins, or `*` for any) and
+// RUSTDNS_CORS_METHODS (default GET,POST,PUT,DELETE) seed it, and PUT /config changes it
+// (`cors.origins`, `cors.methods`). With no origins, no CORS headers are sent and browsers keep
+// refusing cross-origin requests. Tokens are sent as an Authorization header, not cookies, so the
+// policy never allows credentials.
+pub struct CorsPolicy {
+    origins: RwLock<Vec<String>>,
+    methods: RwLock<Vec<String>>,
+}
+
+// `origins` as an origin list: `*`, or scheme://host[:port] values without a path.
+pub fn parse_origins(origins: &[&str]) -> Result<Vec<String>, String> {
+    origins.iter().map(|o| o.trim()).filter(|o| !o.is_empty()).map(|o| {
+        let valid = o == "*" || o.split_once("://").is_some_and(|(scheme, rest)| {
+            matches!(scheme, "http" | "https") && !rest.is_empty() && !rest.contains('/')
+        });
+        if valid { Ok(o.trim_end_matches('/').to_lowercase()) } else { Err(format!("invalid origin {:?}; expected e.g. https://dash.example.com or *", o)) }
+    }).collect()
+}
+
+pub fn parse_methods(methods: &[&str]) -> Result<Vec<String>, String> {
+    methods.iter().map(|m| m.trim().to_uppercase()).filter(|m| !m.is_empty()).map(|m| {
+        if ["GET", "POST", "PUT", "DELETE"].contains(&m.as_str()) { Ok(m) } else { Err(format!("invalid method {:?}; the API uses GET, POST, PUT and DELETE", m)) }
+    }).collect()
+}
+
+fn env_list(name: &str) -> Vec<String> {
+    crate::envvars::var(name).unwrap_or_default().split(',').map(str::to_string).collect()
+}
+
+impl CorsPolicy {
+    pub fn from_env() -> Self {
+        let origins = parse_origins(&env_list("RUSTDNS_CORS_ORIGINS").iter().map(String::as_str).collect::<Vec<_>>()).unwrap_or_else(|e| {
+            tracing::warn!("RUSTDNS_CORS_ORIGINS: {}; cross-origin requests stay disabled", e);
+            Vec::new()
+        });
+        let methods = match crate::envvars::var("RUSTDNS_CORS_METHODS") {
+            Ok(v) => parse_methods(&v.split(',').collect::<Vec<_>>()).unwrap_or_else(|e| {
+                tracing::warn!("RUSTDNS_CORS_METHODS: {}; using the default", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        let methods = if methods.is_empty() { ["GET", "POST", "PUT", "DELETE"].map(String::from).to_vec() } else { methods };
+        CorsPolicy { origins: RwLock::new(origins), methods: RwLock::new(methods) }
+    }
+
+    pub fn origins(&self) -> Vec<String> {
+        self.origins.read().unwrap().clone()
+    }
+
+    pub fn methods(&self) -> Vec<String> {
+        self.methods.read().unwrap().clone()
+    }
+
+    pub fn set_origins(&self, origins: Vec<String>) {
+        *self.origins.write().unwrap() = origins;
+    }
+
+    pub fn set_methods(&self, methods: Vec<String>) {
+        *self.methods.write().unwrap() = methods;
+    }
+
+    // The Access-Control-Allow-Origin value for a request from `origin`, if it is allowed.
+    fn allow_origin(&self, origin: &str) -> Option<HeaderValue> {
+        let origins = self.origins.read().unwrap();
+        if origins.iter().any(|o| o == "*") { return Some(HeaderValue::from_static("*")) }
+        let origin = origin.trim_end_matches('/').to_lowercase();
+        origins.contains(&origin).then(|| HeaderValue::from_str(&origin).ok()).flatten()
+    }
+}
+
+// Middleware answering CORS preflight requests and adding the allow headers to responses for
+// allowed origins. It runs before the token check, since browsers send preflights without one.
+pub async fn cors<B>(State(state): State<Arc<ServerState>>, req: Request<B>, next: Next<B>) -> Response {
+    let Some(origin) = req.headers().get(header::ORIGIN).and_then(|v| v.to_str().ok()).map(str::to_string) else {
+        return next.run(req).await;
+    };
+    let policy = &state.cors;
+    let preflight = req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight {
+        let method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD).and_then(|v| v.to_str().ok()).unwrap_or("").to_uppercase();
+        let methods = policy.methods();
+        return match policy.allow_origin(&origin) {
+            Some(allow) if methods.contains(&method) => {
+                let mut resp = StatusCode::NO_CONTENT.into_response();
+                let h = resp.headers_mut();
+                h.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow);
+                if let Ok(m) = HeaderValue::from_str(&methods.join(", ")) { h.insert(header::ACCESS_CONTROL_ALLOW_METHODS, m); }
+                h.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static(ALLOW_HEADERS));
+                h.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(PREFLIGHT_MAX_AGE));
+                h.insert(header::VARY, HeaderValue::from_static("origin"));
+                resp
+            }
+            _ => {
+                tracing::debug!("refused CORS preflight from {} for {}", origin, method);
+                StatusCode::FORBIDDEN.into_response()
+            }
+        };
+    }
+    let allow = policy.allow_origin(&origin);
+    let mut resp = next.run(req).await;
+    if let Some(allow) = allow {
+        let h = resp.headers_mut();
+        h.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow);
+        h.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(EXPOSE_HEADERS));
+        h.insert(header::VARY, HeaderValue::from_static("origin"));
+    }
+    resp
+}
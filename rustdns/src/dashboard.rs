@@ -0,0 +1,41 @@
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+
+// The built-in web UI: a static page under /ui/ that drives the control API from the browser, so
+// a phone can manage the server without an app. The files in `dashboard/` are compiled into the
+// binary with the `dashboard` feature; without it /ui/ answers 404.
+#[cfg(feature = "dashboard")]
+const ASSETS: &[(&str, &str, &str)] = &[
+    ("index.html", "text/html; charset=utf-8", include_str!("../dashboard/index.html")),
+    ("app.js", "text/javascript; charset=utf-8", include_str!("../dashboard/app.js")),
+    ("style.css", "text/css; charset=utf-8", include_str!("../dashboard/style.css")),
+];
+
+#[cfg(not(feature = "dashboard"))]
+const ASSETS: &[(&str, &str, &str)] = &[];
+
+fn asset(name: &str) -> Response {
+    match ASSETS.iter().find(|(n, _, _)| *n == name) {
+        Some((_, content_type, body)) => ([(header::CONTENT_TYPE, *content_type), (header::CACHE_CONTROL, "no-cache")], *body).into_response(),
+        None if ASSETS.is_empty() => (StatusCode::NOT_FOUND, "this build has no dashboard (feature \"dashboard\")").into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// GET / and /ui
+pub async fn http_dashboard_redirect() -> Redirect {
+    Redirect::temporary("/ui/")
+}
+
+// GET /ui/
+pub async fn http_dashboard_index() -> Response {
+    asset("index.html")
+}
+
+// GET /ui/{file}
+pub async fn http_dashboard_asset(Path(file): Path<String>) -> Response {
+    asset(&file)
+}
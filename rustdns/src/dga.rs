@@ -0,0 +1,127 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use once_cell::sync::Lazy;
+
+// The ~250 most frequent English letter pairs. Human-chosen names are mostly built from these;
+// algorithmically generated ones are not.
+const COMMON_BIGRAMS: &str = "th he in er an re on at en nd ti es or te of ed is it al ar st to nt ng se ha as ou io le ve co me de hi ri ro ic ne ea ra ce li ch ll be ma si om ur ca el ta la ns di fo ho pe ec pr no ct us ac ot il tr ly nc et ut ss so rs un lo wa ge ie wh ee wi em ad ol rt po we na ul ni ts mo ow pa im mi ai sh ir su id os iv ia am fi ci vi pl ig tu ev ld ry mp fe bl ab gh ty op wo sa ay ex ke fr oo av ag if ap gr od bo sp rd do uc bu ei ov by rm ep tt oc fa ef cu rn sc gi da yo cr cl du ga qu ue ff ba ey ls va um pp ua up lu go ht ru ug ds lt pi rc rr eg au ck ew mu br bi pt ak pu ui rg ib tl ny ki rk ys ob mm fu ph og ms ye ud mb ip ub oi rl gu dr hr cc tw ft wn nu";
+
+static BIGRAMS: Lazy<HashSet<&'static str>> = Lazy::new(|| COMMON_BIGRAMS.split(' ').collect());
+
+// Labels shorter than this are never scored; short names are too ambiguous to judge.
+const MIN_LABEL_LEN: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DgaMode {
+    Off,
+    // raise a `dga` alert but answer normally
+    Flag,
+    // answer like any other blocked name
+    Block,
+}
+
+// Scores query names for algorithmically generated domain traits: character entropy, share of
+// uncommon letter pairs, label length and digits. RUSTDNS_DGA=flag|block enables it and
+// RUSTDNS_DGA_THRESHOLD (0..1, default 0.6) sets the score at which it acts.
+pub struct DgaDetector {
+    pub mode: DgaMode,
+    pub threshold: f64,
+    scored: AtomicU64,
+    flagged: AtomicU64,
+    blocked: AtomicU64,
+    // scored names per 0.1-wide score bucket, for picking a threshold
+    histogram: [AtomicU64; 10],
+}
+
+// Reported under `dga` in /stats.
+#[derive(Serialize)]
+pub struct DgaStats {
+    pub mode: DgaMode,
+    pub threshold: f64,
+    pub scored: u64,
+    pub flagged: u64,
+    pub blocked: u64,
+    pub score_histogram: Vec<u64>,
+}
+
+impl DgaDetector {
+    pub fn from_env() -> Self {
+        let mode = match crate::envvars::var("RUSTDNS_DGA").map(|v| v.to_lowercase()).as_deref() {
+            Ok("flag") => DgaMode::Flag,
+            Ok("block") => DgaMode::Block,
+            _ => DgaMode::Off,
+        };
+        let threshold = crate::envvars::var("RUSTDNS_DGA_THRESHOLD").ok().and_then(|v| v.parse().ok()).unwrap_or(0.6);
+        DgaDetector {
+            mode,
+            threshold,
+            scored: AtomicU64::new(0),
+            flagged: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            histogram: Default::default(),
+        }
+    }
+
+    // Score `name` when the detector is in `mode`; returns the score if it reaches the threshold,
+    // counting it as flagged or blocked accordingly.
+    pub fn check(&self, name: &str, mode: DgaMode) -> Option<f64> {
+        if self.mode != mode || mode == DgaMode::Off { return None }
+        let s = score(name)?;
+        self.scored.fetch_add(1, Ordering::Relaxed);
+        self.histogram[((s * 10.0) as usize).min(9)].fetch_add(1, Ordering::Relaxed);
+        if s < self.threshold { return None }
+        let counter = if mode == DgaMode::Block { &self.blocked } else { &self.flagged };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Some(s)
+    }
+
+    pub fn stats(&self) -> DgaStats {
+        DgaStats {
+            mode: self.mode,
+            threshold: self.threshold,
+            scored: self.scored.load(Ordering::Relaxed),
+            flagged: self.flagged.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+            score_histogram: self.histogram.iter().map(|c| c.load(Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+// The registrable part of `name` (`example.com` for `a.b.example.com`). Two-letter second-level
+// labels under a ccTLD (`example.co.uk`) are treated as part of the suffix.
+pub fn registrable_domain(name: &str) -> Option<&str> {
+    let labels: Vec<&str> = name.split('.').collect();
+    let n = labels.len();
+    if n < 2 || labels.iter().any(|l| l.is_empty()) { return None }
+    let keep = if n > 2 && labels[n - 2].len() <= 3 && labels[n - 1].len() == 2 { 3 } else { 2 };
+    let skip: usize = labels[..n - keep].iter().map(|l| l.len() + 1).sum();
+    Some(&name[skip..])
+}
+
+// The label a DGA would generate: the one left of the public suffix.
+fn registrable_label(name: &str) -> Option<&str> {
+    registrable_domain(name)?.split('.').next()
+}
+
+// 0..1 likelihood that `name` was generated by an algorithm; None when the name is too short to
+// judge.
+pub fn score(name: &str) -> Option<f64> {
+    let name = name.trim_end_matches('.').to_lowercase();
+    let label = registrable_label(&name)?;
+    let chars: Vec<char> = label.chars().filter(|c| *c != '-').collect();
+    if chars.len() < MIN_LABEL_LEN { return None }
+
+    let mut counts = std::collections::HashMap::new();
+    for c in &chars { *counts.entry(*c).or_insert(0usize) += 1; }
+    let n = chars.len() as f64;
+    let entropy: f64 = counts.values().map(|&c| { let p = c as f64 / n; -p * p.log2() }).sum();
+
+    let pairs: Vec<String> = chars.windows(2).map(|w| w.iter().collect()).collect();
+    let rare = pairs.iter().filter(|p| !BIGRAMS.contains(p.as_str())).count() as f64 / pairs.len() as f64;
+    let digits = chars.iter().filter(|c| c.is_ascii_digit()).count() as f64 / n;
+    let length = ((n - MIN_LABEL_LEN as f64) / 16.0).clamp(0.0, 1.0);
+
+    Some(0.5 * rare + 0.25 * (entropy / 4.0).min(1.0) + 0.15 * length + 0.1 * (digits * 2.0).min(1.0))
+}
@@ -0,0 +1,351 @@
+use crate::blocklist::BlockingMode;
+use crate::config::Config;
+use crate::envvars::OVERRIDES;
+use crate::settings::{parse_upstream, valid_upstream_timeout, UPSTREAM_TIMEOUT_MS};
+use crate::state::ServerState;
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// Set while a server started through this API runs: its settings are process-wide, so there can
+// be one at a time.
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+// Make `settings` the ones the server about to start reads (kept for its lifetime, since some are
+// read after startup). Fails while another server's are in use.
+pub(crate) fn claim_settings(settings: BTreeMap<String, String>) -> Result<()> {
+    if IN_USE.swap(true, Ordering::AcqRel) {
+        bail!("another server with settings given in code is running; shut it down first");
+    }
+    *OVERRIDES.write().unwrap_or_else(|e| e.into_inner()) = settings;
+    Ok(())
+}
+
+pub(crate) fn release_settings() {
+    OVERRIDES.write().unwrap_or_else(|e| e.into_inner()).clear();
+    IN_USE.store(false, Ordering::Release);
+}
+
+/// The server for Rust programs that embed it, configured in code instead of through RUSTDNS_*
+/// environment variables and run on the caller's tokio runtime. It logs through `tracing` and
+/// leaves installing a subscriber to the program.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let server = rustdns::PiBlockServer::builder()
+///     .udp_bind("127.0.0.1:5353".parse()?)
+///     .upstream("9.9.9.9")
+///     .blocking_mode(rustdns::BlockingMode::Nx)
+///     .build()?;
+/// let handle = server.start().await?;
+/// println!("{} queries", handle.stats().queries);
+/// handle.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PiBlockServer {
+    http_addr: SocketAddr,
+    udp_bind: SocketAddr,
+    settings: BTreeMap<String, String>,
+}
+
+/// Settings for a `PiBlockServer`. Anything not set keeps its RUSTDNS_* environment value, or the
+/// default when that isn't set either.
+#[derive(Debug, Clone, Default)]
+pub struct PiBlockServerBuilder {
+    http_addr: Option<SocketAddr>,
+    udp_bind: Option<SocketAddr>,
+    upstream: Option<String>,
+    upstream_timeout: Option<Duration>,
+    blocklist_dir: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    blocking_mode: Option<BlockingMode>,
+    block_page_ip: Option<Ipv4Addr>,
+    api_tokens: Option<Vec<String>>,
+    settings: BTreeMap<String, String>,
+}
+
+impl PiBlockServerBuilder {
+    /// Address of the control API (default 127.0.0.1:9080).
+    pub fn http_addr(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Address the DNS server listens on (default 0.0.0.0:5353).
+    pub fn udp_bind(mut self, addr: SocketAddr) -> Self {
+        self.udp_bind = Some(addr);
+        self
+    }
+
+    /// Upstream resolver: an IP address with an optional port, 53 by default.
+    pub fn upstream(mut self, upstream: impl Into<String>) -> Self {
+        self.upstream = Some(upstream.into());
+        self
+    }
+
+    /// How long to wait for the upstream resolver, 100 ms to 30 s.
+    pub fn upstream_timeout(mut self, timeout: Duration) -> Self {
+        self.upstream_timeout = Some(timeout);
+        self
+    }
+
+    /// Directory of blocklist files (default ./blocklist).
+    pub fn blocklist_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.blocklist_dir = Some(dir.into());
+        self
+    }
+
+    /// Directory for state kept across restarts (default ./data).
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// How blocked names are answered (default `BlockingMode::Nx`).
+    pub fn blocking_mode(mut self, mode: BlockingMode) -> Self {
+        self.blocking_mode = Some(mode);
+        self
+    }
+
+    /// Address blocked names resolve to in redirect mode.
+    pub fn block_page_ip(mut self, ip: Ipv4Addr) -> Self {
+        self.block_page_ip = Some(ip);
+        self
+    }
+
+    /// Bearer tokens the control API accepts; without any it accepts every request.
+    pub fn api_tokens<I, S>(mut self, tokens: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.api_tokens = Some(tokens.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Any other setting, by its environment variable name (e.g. `RUSTDNS_CACHE_SIZE`), with the
+    /// value the variable would have. The settings above take precedence over ones given here.
+    pub fn setting(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.settings.insert(name.into(), value.into());
+        self
+    }
+
+    /// Check the settings and make the server.
+    pub fn build(self) -> Result<PiBlockServer> {
+        let mut settings = self.settings;
+        if let Some(name) = settings.keys().find(|k| !k.starts_with("RUSTDNS_")) {
+            bail!("{} is not a RUSTDNS_* setting", name);
+        }
+        if let Some(upstream) = self.upstream {
+            let addr = parse_upstream(&upstream).ok_or_else(|| anyhow!("upstream {:?} is not an IP address with an optional port", upstream))?;
+            settings.insert("RUSTDNS_UPSTREAM".into(), addr);
+        }
+        if let Some(timeout) = self.upstream_timeout {
+            let ms = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+            if !valid_upstream_timeout(ms) {
+                bail!("upstream timeout must be {} to {} ms", UPSTREAM_TIMEOUT_MS.0, UPSTREAM_TIMEOUT_MS.1);
+            }
+            settings.insert("RUSTDNS_UPSTREAM_TIMEOUT_MS".into(), ms.to_string());
+        }
+        if let Some(mode) = self.blocking_mode { settings.insert("RUSTDNS_BLOCKING_MODE".into(), mode.as_str().to_string()); }
+        let paths = [("RUSTDNS_BLOCKLIST_DIR", self.blocklist_dir), ("RUSTDNS_DATA_DIR", self.data_dir)];
+        for (name, dir) in paths {
+            if let Some(dir) = dir {
+                let dir = dir.into_os_string().into_string().map_err(|d| anyhow!("{} {:?} is not valid UTF-8", name, d))?;
+                settings.insert(name.into(), dir);
+            }
+        }
+        if let Some(ip) = self.block_page_ip { settings.insert("RUSTDNS_BLOCK_PAGE_IP".into(), ip.to_string()); }
+        if let Some(tokens) = self.api_tokens { settings.insert("RUSTDNS_API_TOKENS".into(), tokens.join(",")); }
+
+        let addr = |given: Option<SocketAddr>, name: &str, default: &str| -> Result<SocketAddr> {
+            match given {
+                Some(addr) => Ok(addr),
+                None => {
+                    let addr = settings.get(name).cloned().or_else(|| std::env::var(name).ok()).unwrap_or_else(|| default.to_string());
+                    addr.parse().map_err(|_| anyhow!("{} {:?} is not an address with a port", name, addr))
+                }
+            }
+        };
+        let http_addr = addr(self.http_addr, "RUSTDNS_HTTP_ADDR", "127.0.0.1:9080")?;
+        let udp_bind = addr(self.udp_bind, "RUSTDNS_UDP_BIND", "0.0.0.0:5353")?;
+        settings.insert("RUSTDNS_HTTP_ADDR".into(), http_addr.to_string());
+        settings.insert("RUSTDNS_UDP_BIND".into(), udp_bind.to_string());
+        Ok(PiBlockServer { http_addr, udp_bind, settings })
+    }
+}
+
+impl PiBlockServer {
+    pub fn builder() -> PiBlockServerBuilder {
+        PiBlockServerBuilder::default()
+    }
+
+    // The addresses to listen on and the settings, for rustdns_start_with_config.
+    pub(crate) fn into_parts(self) -> (SocketAddr, SocketAddr, BTreeMap<String, String>) {
+        (self.http_addr, self.udp_bind, self.settings)
+    }
+
+    /// Start the server on the current tokio runtime and return once the lists are loaded and
+    /// the control API and DNS listeners are bound. Fails outside a runtime, when a RUSTDNS_*
+    /// environment setting it starts with is invalid (e.g. RUSTDNS_BLOCK_PAGE_IP), when a
+    /// listener can't bind, and while another server with settings given in code runs (started
+    /// this way or by rustdns_start_with_config).
+    pub async fn start(self) -> Result<ServerHandle> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| anyhow!("PiBlockServer::start must be called within a tokio runtime"))?;
+        claim_settings(self.settings)?;
+        // the claimed settings include the addresses
+        let config = match Config::from_env() {
+            Ok(config) => config,
+            Err(e) => {
+                release_settings();
+                bail!(e);
+            }
+        };
+        let state = crate::runner::build_state(&config);
+        let (tx, rx) = watch::channel(false);
+        let mut task = runtime.spawn(crate::runner::run_server(state.clone(), config, rx));
+        // the control API binds before the DNS listener, so the server is up once that is bound
+        loop {
+            if state.health.udp_bound.load(Ordering::Relaxed) { break }
+            if task.is_finished() {
+                let result = (&mut task).await;
+                release_settings();
+                return Err(match result {
+                    Ok(Err(e)) => e,
+                    Ok(Ok(())) => anyhow!("the server stopped while starting"),
+                    Err(e) => anyhow!("the server stopped while starting: {}", e),
+                });
+            }
+            let udp_error = state.health.udp_error.lock().unwrap().clone();
+            if let Some(e) = udp_error {
+                let _ = tx.send(true);
+                drop(state);
+                let _ = task.await;
+                release_settings();
+                bail!(e);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        Ok(ServerHandle { http_addr: self.http_addr, udp_bind: self.udp_bind, state, shutdown: tx, task })
+    }
+}
+
+/// A running server. Dropping it leaves the server running until the runtime shuts down, and no
+/// other can be started meanwhile; call `shutdown` to stop it.
+pub struct ServerHandle {
+    http_addr: SocketAddr,
+    udp_bind: SocketAddr,
+    state: Arc<ServerState>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<Result<()>>,
+}
+
+/// Counters of a running server, as in GET /stats.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerStats {
+    pub queries: u64,
+    pub blocked: u64,
+    pub audited: u64,
+    pub rate_limited: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_entries: usize,
+    pub uptime_seconds: u64,
+    pub blocking_enabled: bool,
+    /// Seconds until a timed pause ends; None when blocking or paused until resumed.
+    pub seconds_remaining: Option<u64>,
+}
+
+impl ServerHandle {
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http_addr
+    }
+
+    pub fn udp_bind(&self) -> SocketAddr {
+        self.udp_bind
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        ServerStats::of(&self.state)
+    }
+
+    /// Pause blocking for `duration`, or until resumed when None. Durations over a year are
+    /// cut to a year.
+    pub fn pause_blocking(&self, duration: Option<Duration>) {
+        self.state.pause_blocking(duration);
+    }
+
+    pub fn resume_blocking(&self) {
+        self.state.resume_blocking();
+    }
+
+    /// Stop the server and wait until it has: the listeners close, DNS queries in flight get up
+    /// to the upstream timeout to be answered, and state is saved. Fails if the server had
+    /// stopped on an error.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown.send(true);
+        drop(self.state);
+        let result = self.task.await;
+        release_settings();
+        result.map_err(|e| anyhow!("server stopped on an error: {}", e))?
+    }
+}
+
+impl ServerStats {
+    pub(crate) fn of(state: &ServerState) -> Self {
+        let cache = state.cache.stats();
+        ServerStats {
+            queries: state.queries.load(Ordering::Relaxed),
+            blocked: state.blocked.load(Ordering::Relaxed),
+            audited: state.audited.load(Ordering::Relaxed),
+            rate_limited: state.rate_limited.load(Ordering::Relaxed),
+            cache_hits: cache.hits,
+            cache_misses: cache.misses,
+            cache_entries: cache.entries,
+            uptime_seconds: state.totals.uptime_secs(),
+            blocking_enabled: state.blocking.is_enabled(),
+            seconds_remaining: state.blocking.seconds_remaining(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Everything the first server spawned holds its state, so once shutdown returns nothing may
+    // still hold it, and a second server can take the same addresses.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn shutdown_stops_everything_before_the_next_start() {
+        let dir = std::env::temp_dir().join(format!("rustdns-restart-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lists")).unwrap();
+        std::fs::write(dir.join("lists/a.txt"), "ads.example\n").unwrap();
+        let http_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let udp_bind = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = || PiBlockServer::builder()
+            .http_addr(http_addr)
+            .udp_bind(udp_bind)
+            .blocklist_dir(dir.join("lists"))
+            .data_dir(dir.join("data"))
+            .build()
+            .unwrap();
+
+        let first = server().start().await.unwrap();
+        let state = Arc::downgrade(&first.state);
+        first.shutdown().await.unwrap();
+        assert_eq!(state.strong_count(), 0, "a task of the first server is still running");
+
+        let second = server().start().await.unwrap();
+        second.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
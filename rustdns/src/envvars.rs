@@ -0,0 +1,23 @@
+use std::collections::BTreeMap;
+use std::env::VarError;
+use std::sync::RwLock;
+
+// Settings given in code by a program embedding the server (see `PiBlockServer`), by variable
+// name. They are consulted before the process environment, which can't safely be changed once
+// the host's threads run.
+pub static OVERRIDES: RwLock<BTreeMap<String, String>> = RwLock::new(BTreeMap::new());
+
+// The RUSTDNS_* setting `name`, as std::env::var reads it. Every setting is read through here.
+pub fn var(name: &str) -> Result<String, VarError> {
+    if let Some(v) = OVERRIDES.read().unwrap_or_else(|e| e.into_inner()).get(name) {
+        return Ok(v.clone());
+    }
+    std::env::var(name)
+}
+
+// Every RUSTDNS_* setting given, by name.
+pub fn vars() -> BTreeMap<String, String> {
+    let mut vars: BTreeMap<String, String> = std::env::vars().filter(|(k, _)| k.starts_with("RUSTDNS_")).collect();
+    vars.extend(OVERRIDES.read().unwrap_or_else(|e| e.into_inner()).iter().map(|(k, v)| (k.clone(), v.clone())));
+    vars
+}
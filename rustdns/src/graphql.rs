@@ -0,0 +1,761 @@
+use crate::control::{
+    http_alerts, http_allowlist, http_brands, http_canaries, http_categories, http_client_get, http_clients, http_groups,
+    http_lists, http_queries, http_review, http_rpz, http_stats, http_stats_history, http_stats_longterm, http_stats_top,
+};
+use crate::state::ServerState;
+use axum::{
+    extract::Query,
+    http::Method,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Longest query document accepted, and how deep and wide a query may go.
+const MAX_QUERY_LEN: usize = 16 * 1024;
+const MAX_DEPTH: usize = 12;
+const MAX_ROOT_FIELDS: usize = 32;
+
+// Root fields and the arguments each takes. Every one runs the matching GET route with the
+// arguments as its query parameters and returns that route's JSON.
+const ROOT_FIELDS: [(&str, &[&str]); 16] = [
+    ("stats", &[]),
+    ("top", &["kind", "n"]),
+    ("history", &["range"]),
+    ("longterm", &["period", "from", "to"]),
+    ("queries", &["client", "domain", "action", "since", "until", "before", "limit"]),
+    ("clients", &[]),
+    ("client", &["id"]),
+    ("lists", &["q", "offset", "limit", "sort"]),
+    ("allowlist", &[]),
+    ("groups", &[]),
+    ("categories", &[]),
+    ("alerts", &["since", "kind"]),
+    ("review", &["status"]),
+    ("rpz", &[]),
+    ("brands", &[]),
+    ("canaries", &[]),
+];
+
+// POST /graphql (`{"query": "...", "variables": {..}, "operationName": ".."}`) or
+// GET /graphql?query=..&variables=.. — read-only GraphQL over the dashboard data, so one request
+// can fetch stats, recent queries, clients and lists together. There is no schema: each root
+// field (see ROOT_FIELDS) returns its route's JSON, and selections pick fields out of it, with
+// fields missing from the data coming back as null. Queries with variables, aliases and
+// @skip / @include are supported; mutations, fragments and introspection are not.
+pub async fn http_graphql(state: Arc<ServerState>, method: Method, Query(params): Query<HashMap<String, String>>, body: Option<Json<Value>>) -> Json<Value> {
+    let request = match (method, body) {
+        (Method::POST, Some(Json(body))) => body,
+        (Method::POST, None) => return errors(vec![GqlError::new("expected a JSON body with `query`")]),
+        (_, _) => {
+            let variables = match params.get("variables").map(|v| serde_json::from_str::<Value>(v)) {
+                None => Value::Null,
+                Some(Ok(v)) => v,
+                Some(Err(_)) => return errors(vec![GqlError::new("`variables` is not valid JSON")]),
+            };
+            serde_json::json!({ "query": params.get("query"), "variables": variables, "operationName": params.get("operationName") })
+        }
+    };
+    let Some(query) = request.get("query").and_then(Value::as_str) else {
+        return errors(vec![GqlError::new("missing `query`")]);
+    };
+    if query.len() > MAX_QUERY_LEN {
+        return errors(vec![GqlError::new(format!("query is longer than {} bytes", MAX_QUERY_LEN))]);
+    }
+    let variables = match request.get("variables") {
+        None | Some(Value::Null) => Map::new(),
+        Some(Value::Object(m)) => m.clone(),
+        Some(_) => return errors(vec![GqlError::new("`variables` must be an object")]),
+    };
+    let operation_name = request.get("operationName").and_then(Value::as_str);
+    let operation = match parse(query).and_then(|ops| pick_operation(ops, operation_name)) {
+        Ok(op) => op,
+        Err(e) => return errors(vec![e]),
+    };
+    let variables = match bind_variables(&operation, variables) {
+        Ok(v) => v,
+        Err(e) => return errors(vec![e]),
+    };
+    let (data, errs) = execute(&state, &operation.selection, &variables).await;
+    let mut out = serde_json::json!({ "data": data });
+    if !errs.is_empty() {
+        out["errors"] = errs.into_iter().map(GqlError::into_json).collect();
+    }
+    Json(out)
+}
+
+fn errors(errs: Vec<GqlError>) -> Json<Value> {
+    Json(serde_json::json!({ "errors": errs.into_iter().map(GqlError::into_json).collect::<Vec<_>>() }))
+}
+
+struct GqlError {
+    message: String,
+    path: Vec<Value>,
+}
+
+impl GqlError {
+    fn new(message: impl Into<String>) -> Self {
+        GqlError { message: message.into(), path: Vec::new() }
+    }
+
+    fn at(message: impl Into<String>, path: &[Value]) -> Self {
+        GqlError { message: message.into(), path: path.to_vec() }
+    }
+
+    fn into_json(self) -> Value {
+        if self.path.is_empty() {
+            serde_json::json!({ "message": self.message })
+        } else {
+            serde_json::json!({ "message": self.message, "path": self.path })
+        }
+    }
+}
+
+// --- execution ---
+
+async fn execute(state: &Arc<ServerState>, selection: &[Field], variables: &Map<String, Value>) -> (Value, Vec<GqlError>) {
+    let mut data = Map::new();
+    let mut errs = Vec::new();
+    for field in selection {
+        let path = [Value::String(field.key().to_string())];
+        match field.included(variables) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(e) => {
+                errs.push(GqlError::at(e, &path));
+                continue;
+            }
+        }
+        if field.name == "__typename" {
+            data.insert(field.key().to_string(), "Query".into());
+            continue;
+        }
+        let value = match resolve(state, field, variables).await {
+            Ok(v) => select(&v, &field.selection, variables, &path).map_err(|e| vec![e]),
+            Err(e) => Err(vec![GqlError::at(e, &path)]),
+        };
+        match value {
+            Ok(v) => { data.insert(field.key().to_string(), v); }
+            Err(e) => {
+                data.insert(field.key().to_string(), Value::Null);
+                errs.extend(e);
+            }
+        }
+    }
+    (Value::Object(data), errs)
+}
+
+// Run the route behind root field `field` and return its JSON, or its error.
+async fn resolve(state: &Arc<ServerState>, field: &Field, variables: &Map<String, Value>) -> Result<Value, String> {
+    let Some((_, allowed)) = ROOT_FIELDS.iter().find(|(name, _)| *name == field.name) else {
+        let names: Vec<&str> = ROOT_FIELDS.iter().map(|(n, _)| *n).collect();
+        return Err(format!("unknown field `{}`; query one of {}", field.name, names.join(", ")));
+    };
+    let mut params = HashMap::new();
+    for (name, value) in &field.args {
+        if !allowed.contains(&name.as_str()) {
+            return Err(format!("`{}` takes no argument `{}`", field.name, name));
+        }
+        match value.resolve(variables)? {
+            Value::Null => {}
+            Value::String(s) => { params.insert(name.clone(), s); }
+            v @ (Value::Number(_) | Value::Bool(_)) => { params.insert(name.clone(), v.to_string()); }
+            _ => return Err(format!("argument `{}` must be a string, number or boolean", name)),
+        }
+    }
+    let state = state.clone();
+    let q = Query(params);
+    let value = match field.name.as_str() {
+        "stats" => Json(serde_json::json!(http_stats(state).await.0)),
+        "top" => http_stats_top(state, q).await,
+        "history" => http_stats_history(state, q).await,
+        "longterm" => http_stats_longterm(state, q).await,
+        "queries" => http_queries(state, q).await,
+        "clients" => http_clients(state).await,
+        "client" => {
+            let Some(id) = q.0.get("id").cloned() else { return Err("`client` needs an `id`".to_string()) };
+            http_client_get(state, id).await
+        }
+        "lists" => {
+            let mut q = q;
+            q.0.insert("format".to_string(), "json".to_string());
+            let body = hyper::body::to_bytes(http_lists(state, q).await.into_response().into_body()).await.map_err(|e| e.to_string())?;
+            Json(serde_json::from_slice(&body).map_err(|e| e.to_string())?)
+        }
+        "allowlist" => http_allowlist(state).await,
+        "groups" => http_groups(state).await,
+        "categories" => http_categories(state).await,
+        "alerts" => http_alerts(state, q).await,
+        "review" => http_review(state, q).await,
+        "rpz" => http_rpz(state).await,
+        "brands" => http_brands(state).await,
+        "canaries" => http_canaries(state).await,
+        _ => unreachable!("every ROOT_FIELDS entry is resolved"),
+    }.0;
+    if value.get("ok") == Some(&Value::Bool(false)) {
+        return Err(value.get("error").and_then(Value::as_str).unwrap_or("request failed").to_string());
+    }
+    Ok(value)
+}
+
+// Pick the fields `selection` asks for out of `value`; lists are selected element by element,
+// and an empty selection takes the value whole.
+fn select(value: &Value, selection: &[Field], variables: &Map<String, Value>, path: &[Value]) -> Result<Value, GqlError> {
+    if selection.is_empty() { return Ok(value.clone()) }
+    match value {
+        Value::Null => Ok(Value::Null),
+        Value::Array(items) => items.iter().enumerate().map(|(i, item)| {
+            let mut p = path.to_vec();
+            p.push(i.into());
+            select(item, selection, variables, &p)
+        }).collect::<Result<Vec<_>, _>>().map(Value::Array),
+        Value::Object(obj) => {
+            let mut out = Map::new();
+            for field in selection {
+                let mut p = path.to_vec();
+                p.push(field.key().into());
+                if !field.included(variables).map_err(|e| GqlError::at(e, &p))? { continue }
+                if !field.args.is_empty() {
+                    return Err(GqlError::at(format!("only top-level fields take arguments, not `{}`", field.name), &p));
+                }
+                let v = match field.name.as_str() {
+                    "__typename" => "Object".into(),
+                    name => select(obj.get(name).unwrap_or(&Value::Null), &field.selection, variables, &p)?,
+                };
+                out.insert(field.key().to_string(), v);
+            }
+            Ok(Value::Object(out))
+        }
+        _ => Err(GqlError::at("this field is a scalar and has no fields to select", path)),
+    }
+}
+
+// --- documents ---
+
+struct Operation {
+    name: Option<String>,
+    // variable names and their defaults
+    variables: Vec<(String, Option<InputValue>)>,
+    selection: Vec<Field>,
+}
+
+struct Field {
+    alias: Option<String>,
+    name: String,
+    args: Vec<(String, InputValue)>,
+    directives: Vec<(String, Vec<(String, InputValue)>)>,
+    selection: Vec<Field>,
+}
+
+impl Field {
+    // The key the field's value is returned under.
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    // False when @skip(if: true) or @include(if: false) leaves the field out.
+    fn included(&self, variables: &Map<String, Value>) -> Result<bool, String> {
+        for (name, args) in &self.directives {
+            let cond = match args.iter().find(|(a, _)| a == "if") {
+                Some((_, v)) => match v.resolve(variables)? {
+                    Value::Bool(b) => b,
+                    _ => return Err(format!("@{}(if:) must be a boolean", name)),
+                },
+                None => return Err(format!("@{} needs an `if` argument", name)),
+            };
+            match name.as_str() {
+                "skip" if cond => return Ok(false),
+                "include" if !cond => return Ok(false),
+                "skip" | "include" => {}
+                other => return Err(format!("unknown directive @{}", other)),
+            }
+        }
+        Ok(true)
+    }
+}
+
+enum InputValue {
+    Variable(String),
+    Const(Value),
+    List(Vec<InputValue>),
+    Object(Vec<(String, InputValue)>),
+}
+
+impl InputValue {
+    fn resolve(&self, variables: &Map<String, Value>) -> Result<Value, String> {
+        Ok(match self {
+            InputValue::Variable(name) => variables.get(name).cloned().ok_or_else(|| format!("variable ${} is not defined", name))?,
+            InputValue::Const(v) => v.clone(),
+            InputValue::List(items) => Value::Array(items.iter().map(|i| i.resolve(variables)).collect::<Result<_, _>>()?),
+            InputValue::Object(fields) => Value::Object(fields.iter().map(|(k, v)| Ok((k.clone(), v.resolve(variables)?))).collect::<Result<_, String>>()?),
+        })
+    }
+}
+
+fn pick_operation(mut ops: Vec<Operation>, name: Option<&str>) -> Result<Operation, GqlError> {
+    match name {
+        Some(name) => ops.into_iter().find(|o| o.name.as_deref() == Some(name))
+            .ok_or_else(|| GqlError::new(format!("no operation named `{}`", name))),
+        None if ops.len() == 1 => Ok(ops.remove(0)),
+        None => Err(GqlError::new("the document has several operations; name one in `operationName`")),
+    }
+}
+
+// The operation's variables: those passed in, then the declared defaults; undeclared ones are errors.
+fn bind_variables(op: &Operation, mut passed: Map<String, Value>) -> Result<Map<String, Value>, GqlError> {
+    if let Some(extra) = passed.keys().find(|k| !op.variables.iter().any(|(name, _)| name == *k)) {
+        return Err(GqlError::new(format!("variable ${} is not declared by the operation", extra)));
+    }
+    for (name, default) in &op.variables {
+        if !passed.contains_key(name) {
+            if let Some(d) = default {
+                passed.insert(name.clone(), d.resolve(&Map::new()).map_err(GqlError::new)?);
+            }
+        }
+    }
+    Ok(passed)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Number(String),
+    Str(String),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, GqlError> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}' => { chars.next(); }
+            '#' => { while chars.next_if(|c| *c != '\n').is_some() {} }
+            '{' | '}' | '(' | ')' | ':' | '$' | '!' | '=' | '@' | '[' | ']' | '|' | '&' => {
+                chars.next();
+                tokens.push(Token::Punct(c));
+            }
+            '.' => {
+                let dots: String = std::iter::from_fn(|| chars.next_if_eq(&'.')).take(3).collect();
+                if dots != "..." { return Err(GqlError::new("unexpected `.`")) }
+                tokens.push(Token::Spread);
+            }
+            '"' => tokens.push(Token::Str(string(&mut chars)?)),
+            c if c == '-' || c.is_ascii_digit() => {
+                let mut n = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')) { n.push(c); }
+                tokens.push(Token::Number(n));
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let mut n = String::new();
+                while let Some(c) = chars.next_if(|c| *c == '_' || c.is_ascii_alphanumeric()) { n.push(c); }
+                tokens.push(Token::Name(n));
+            }
+            c => return Err(GqlError::new(format!("unexpected character {:?}", c))),
+        }
+    }
+    Ok(tokens)
+}
+
+// A quoted string (the opening quote not yet taken), or a `"""` block string.
+fn string(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> Result<String, GqlError> {
+    let unterminated = || GqlError::new("unterminated string");
+    chars.next();
+    if chars.next_if_eq(&'"').is_some() {
+        if chars.next_if_eq(&'"').is_none() { return Ok(String::new()) }
+        let mut s = String::new();
+        loop {
+            let c = chars.next().ok_or_else(unterminated)?;
+            if c == '"' && s.ends_with("\"\"") && !s.ends_with("\\\"\"") {
+                s.truncate(s.len() - 2);
+                return Ok(s.replace("\\\"\"\"", "\"\"\""));
+            }
+            s.push(c);
+        }
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next().ok_or_else(unterminated)? {
+            '"' => return Ok(s),
+            '\n' => return Err(unterminated()),
+            '\\' => match chars.next().ok_or_else(unterminated)? {
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                'r' => s.push('\r'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).ok_or_else(|| GqlError::new("bad \\u escape"))?;
+                    s.push(c);
+                }
+                c @ ('"' | '\\' | '/') => s.push(c),
+                c => return Err(GqlError::new(format!("bad escape \\{}", c))),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+fn parse(src: &str) -> Result<Vec<Operation>, GqlError> {
+    let mut p = Parser { tokens: tokenize(src)?, pos: 0 };
+    let mut ops = Vec::new();
+    while p.pos < p.tokens.len() {
+        ops.push(p.operation()?);
+    }
+    if ops.is_empty() { return Err(GqlError::new("the document has no operation")) }
+    Ok(ops)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, GqlError> {
+        let t = self.tokens.get(self.pos).cloned().ok_or_else(|| GqlError::new("unexpected end of query"))?;
+        self.pos += 1;
+        Ok(t)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), GqlError> {
+        match self.next()? {
+            Token::Punct(p) if p == c => Ok(()),
+            t => Err(GqlError::new(format!("expected `{}`, found {}", c, describe(&t)))),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, GqlError> {
+        match self.next()? {
+            Token::Name(n) => Ok(n),
+            t => Err(GqlError::new(format!("expected a name, found {}", describe(&t)))),
+        }
+    }
+
+    fn operation(&mut self) -> Result<Operation, GqlError> {
+        if self.peek() == Some(&Token::Punct('{')) {
+            return Ok(Operation { name: None, variables: Vec::new(), selection: self.selection(0)? });
+        }
+        match self.name()?.as_str() {
+            "query" => {}
+            "mutation" | "subscription" => return Err(GqlError::new("only queries are supported; use the REST routes to make changes")),
+            "fragment" => return Err(GqlError::new("fragments are not supported")),
+            other => return Err(GqlError::new(format!("expected `query` or `{{`, found `{}`", other))),
+        }
+        let name = match self.peek() {
+            Some(Token::Name(_)) => Some(self.name()?),
+            _ => None,
+        };
+        let mut variables = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                self.expect('$')?;
+                let var = self.name()?;
+                self.expect(':')?;
+                self.skip_type(0)?;
+                let default = if self.eat('=') { Some(self.value(true, 0)?) } else { None };
+                variables.push((var, default));
+            }
+        }
+        if self.peek() == Some(&Token::Punct('@')) {
+            return Err(GqlError::new("directives on operations are not supported"));
+        }
+        Ok(Operation { name, variables, selection: self.selection(0)? })
+    }
+
+    // A variable's type, such as `Int`, `String!` or `[String!]`; types aren't checked.
+    fn skip_type(&mut self, depth: usize) -> Result<(), GqlError> {
+        if depth >= MAX_DEPTH { return Err(too_deep()) }
+        if self.eat('[') {
+            self.skip_type(depth + 1)?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat('!');
+        Ok(())
+    }
+
+    fn selection(&mut self, depth: usize) -> Result<Vec<Field>, GqlError> {
+        if depth >= MAX_DEPTH { return Err(too_deep()) }
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            if self.peek() == Some(&Token::Spread) { return Err(GqlError::new("fragments are not supported")) }
+            fields.push(self.field(depth)?);
+            if depth == 0 && fields.len() > MAX_ROOT_FIELDS {
+                return Err(GqlError::new(format!("at most {} top-level fields per query", MAX_ROOT_FIELDS)));
+            }
+        }
+        if fields.is_empty() { return Err(GqlError::new("empty selection")) }
+        Ok(fields)
+    }
+
+    fn field(&mut self, depth: usize) -> Result<Field, GqlError> {
+        let mut name = self.name()?;
+        let mut alias = None;
+        if self.eat(':') {
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let args = self.arguments()?;
+        let mut directives = Vec::new();
+        while self.eat('@') {
+            directives.push((self.name()?, self.arguments()?));
+        }
+        let selection = if self.peek() == Some(&Token::Punct('{')) { self.selection(depth + 1)? } else { Vec::new() };
+        Ok(Field { alias, name, args, directives, selection })
+    }
+
+    fn arguments(&mut self) -> Result<Vec<(String, InputValue)>, GqlError> {
+        let mut args = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let name = self.name()?;
+                self.expect(':')?;
+                args.push((name, self.value(false, 0)?));
+            }
+        }
+        Ok(args)
+    }
+
+    // A value; variables aren't allowed in `constant` ones (defaults). Lists and objects nest
+    // no deeper than selections do, so a hostile document can't recurse the parser off its stack.
+    fn value(&mut self, constant: bool, depth: usize) -> Result<InputValue, GqlError> {
+        if depth >= MAX_DEPTH { return Err(too_deep()) }
+        Ok(match self.next()? {
+            Token::Punct('$') if !constant => InputValue::Variable(self.name()?),
+            Token::Punct('[') => {
+                let mut items = Vec::new();
+                while !self.eat(']') { items.push(self.value(constant, depth + 1)?); }
+                InputValue::List(items)
+            }
+            Token::Punct('{') => {
+                let mut fields = Vec::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value(constant, depth + 1)?));
+                }
+                InputValue::Object(fields)
+            }
+            Token::Str(s) => InputValue::Const(Value::String(s)),
+            Token::Number(n) => InputValue::Const(serde_json::from_str::<serde_json::Number>(&n).map(Value::Number).map_err(|_| GqlError::new(format!("bad number {}", n)))?),
+            Token::Name(n) => InputValue::Const(match n.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // enum values are passed on as strings
+                _ => Value::String(n),
+            }),
+            t => return Err(GqlError::new(format!("expected a value, found {}", describe(&t)))),
+        })
+    }
+}
+
+fn too_deep() -> GqlError {
+    GqlError::new(format!("queries may nest at most {} levels", MAX_DEPTH))
+}
+
+fn describe(t: &Token) -> String {
+    match t {
+        Token::Punct(c) => format!("`{}`", c),
+        Token::Spread => "`...`".to_string(),
+        Token::Name(n) => format!("`{}`", n),
+        Token::Number(n) => n.clone(),
+        Token::Str(_) => "a string".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse_err(src: &str) -> String {
+        match parse(src) {
+            Ok(_) => panic!("{:?} parsed", src),
+            Err(e) => e.message,
+        }
+    }
+
+    fn parse_one(src: &str) -> Operation {
+        let mut ops = parse(src).unwrap_or_else(|e| panic!("{:?}: {}", src, e.message));
+        assert_eq!(ops.len(), 1);
+        ops.remove(0)
+    }
+
+    #[test]
+    fn tokenize_splits_punctuation_names_numbers_and_strings() {
+        let tokens = tokenize("query Q($n: Int = -1.5e3) { top(kind: \"a\\\"b\", n: $n) ... } # comment").unwrap_or_else(|e| panic!("{}", e.message));
+        assert_eq!(tokens, vec![
+            Token::Name("query".into()), Token::Name("Q".into()), Token::Punct('('), Token::Punct('$'), Token::Name("n".into()),
+            Token::Punct(':'), Token::Name("Int".into()), Token::Punct('='), Token::Number("-1.5e3".into()), Token::Punct(')'),
+            Token::Punct('{'), Token::Name("top".into()), Token::Punct('('), Token::Name("kind".into()), Token::Punct(':'),
+            Token::Str("a\"b".into()), Token::Name("n".into()), Token::Punct(':'), Token::Punct('$'), Token::Name("n".into()),
+            Token::Punct(')'), Token::Spread, Token::Punct('}'),
+        ]);
+    }
+
+    #[test]
+    fn tokenize_reads_escapes_and_block_strings() {
+        let strings = |src: &str| tokenize(src).unwrap_or_else(|e| panic!("{}", e.message));
+        assert_eq!(strings(r#""é\n\t\/""#), vec![Token::Str("é\n\t/".into())]);
+        assert_eq!(strings(r#""""#), vec![Token::Str(String::new())]);
+        assert_eq!(strings("\"\"\"two\nlines \\\"\"\" \"\"\""), vec![Token::Str("two\nlines \"\"\" ".into())]);
+    }
+
+    #[test]
+    fn tokenize_rejects_malformed_input() {
+        let err = |src: &str| tokenize(src).err().map(|e| e.message);
+        assert_eq!(err("\"open").as_deref(), Some("unterminated string"));
+        assert_eq!(err("\"line\nbreak\"").as_deref(), Some("unterminated string"));
+        assert_eq!(err("\"\"\"never closed").as_deref(), Some("unterminated string"));
+        assert_eq!(err(r#""\q""#).as_deref(), Some("bad escape \\q"));
+        assert_eq!(err(r#""\uZZZZ""#).as_deref(), Some("bad \\u escape"));
+        assert_eq!(err("{ a..b }").as_deref(), Some("unexpected `.`"));
+        assert_eq!(err("{ a % b }").as_deref(), Some("unexpected character '%'"));
+    }
+
+    #[test]
+    fn parses_aliases_arguments_and_nested_selections() {
+        let op = parse_one("{ s: stats { total blocked } top(kind: domains, n: 5) { name } }");
+        assert!(op.name.is_none());
+        assert_eq!(op.selection.len(), 2);
+        let s = &op.selection[0];
+        assert_eq!((s.key(), s.name.as_str()), ("s", "stats"));
+        assert_eq!(s.selection.iter().map(|f| f.key()).collect::<Vec<_>>(), ["total", "blocked"]);
+        let top = &op.selection[1];
+        let args: Vec<_> = top.args.iter().map(|(k, v)| (k.as_str(), v.resolve(&Map::new()).ok())).collect();
+        assert_eq!(args, [("kind", Some(json!("domains"))), ("n", Some(json!(5)))]);
+    }
+
+    #[test]
+    fn parses_list_and_object_values() {
+        let op = parse_one("{ queries(client: [\"a\", 1, true, null], domain: {x: {y: [false]}}) }");
+        let resolved: Vec<_> = op.selection[0].args.iter().map(|(_, v)| v.resolve(&Map::new()).ok()).collect();
+        assert_eq!(resolved, [Some(json!(["a", 1, true, null])), Some(json!({"x": {"y": [false]}}))]);
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        assert_eq!(parse_err(""), "the document has no operation");
+        assert_eq!(parse_err("{ }"), "empty selection");
+        assert_eq!(parse_err("{ stats"), "unexpected end of query");
+        assert_eq!(parse_err("{ top(n 5) }"), "expected `:`, found 5");
+        assert_eq!(parse_err("{ top(n: ) }"), "expected a value, found `)`");
+        assert_eq!(parse_err("{ top(n: 1x) }"), "bad number 1x");
+        assert_eq!(parse_err("query Q($n: Int = $m) { stats }"), "expected a value, found `$`");
+        assert_eq!(parse_err("mutation { stats }"), "only queries are supported; use the REST routes to make changes");
+        assert_eq!(parse_err("subscription { stats }"), "only queries are supported; use the REST routes to make changes");
+        assert_eq!(parse_err("query @live { stats }"), "directives on operations are not supported");
+        assert_eq!(parse_err("stats"), "expected `query` or `{`, found `stats`");
+    }
+
+    #[test]
+    fn rejects_fragments() {
+        assert_eq!(parse_err("{ stats { ...F } }"), "fragments are not supported");
+        assert_eq!(parse_err("{ stats { ... on Stats { total } } }"), "fragments are not supported");
+        assert_eq!(parse_err("{ stats } fragment F on Stats { total }"), "fragments are not supported");
+    }
+
+    #[test]
+    fn selections_nest_at_most_max_depth() {
+        let nested = |levels: usize| format!("{{ {}b{}", "a { ".repeat(levels - 1), " }".repeat(levels));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(parse_err(&nested(MAX_DEPTH + 1)), format!("queries may nest at most {} levels", MAX_DEPTH));
+    }
+
+    #[test]
+    fn values_nest_at_most_max_depth() {
+        let list = |levels: usize| format!("{{ stats(x: {}1{}) }}", "[".repeat(levels), "]".repeat(levels));
+        assert!(parse(&list(MAX_DEPTH - 1)).is_ok());
+        assert_eq!(parse_err(&list(MAX_DEPTH)), format!("queries may nest at most {} levels", MAX_DEPTH));
+        let object = |levels: usize| format!("{{ stats(x: {}1{}) }}", "{a: ".repeat(levels), "}".repeat(levels));
+        assert!(parse(&object(MAX_DEPTH - 1)).is_ok());
+        assert_eq!(parse_err(&object(MAX_DEPTH)), format!("queries may nest at most {} levels", MAX_DEPTH));
+        // As deep as the body size allows: an error, not a stack overflow.
+        let hostile = format!("{{stats(x:{}{})}}", "[".repeat(MAX_QUERY_LEN / 2), "]".repeat(MAX_QUERY_LEN / 2));
+        assert_eq!(parse_err(&hostile), format!("queries may nest at most {} levels", MAX_DEPTH));
+        let default = format!("query Q($x: Int = {}1{}) {{ stats }}", "[".repeat(MAX_QUERY_LEN / 2), "]".repeat(MAX_QUERY_LEN / 2));
+        assert_eq!(parse_err(&default), format!("queries may nest at most {} levels", MAX_DEPTH));
+        let ty = format!("query Q($x: {}Int{}) {{ stats }}", "[".repeat(MAX_QUERY_LEN / 2), "]".repeat(MAX_QUERY_LEN / 2));
+        assert_eq!(parse_err(&ty), format!("queries may nest at most {} levels", MAX_DEPTH));
+    }
+
+    #[test]
+    fn at_most_max_root_fields() {
+        let fields = |n: usize| format!("{{ {} }}", (0..n).map(|i| format!("f{}: stats", i)).collect::<Vec<_>>().join(" "));
+        assert!(parse(&fields(MAX_ROOT_FIELDS)).is_ok());
+        assert_eq!(parse_err(&fields(MAX_ROOT_FIELDS + 1)), format!("at most {} top-level fields per query", MAX_ROOT_FIELDS));
+    }
+
+    #[test]
+    fn variables_take_passed_values_then_defaults() {
+        let op = parse_one("query Q($n: Int! = 10, $kinds: [String!], $c: String) { top(n: $n) }");
+        assert_eq!(op.name.as_deref(), Some("Q"));
+        let passed = json!({ "kinds": ["a"] }).as_object().cloned().unwrap();
+        let vars = bind_variables(&op, passed).unwrap_or_else(|e| panic!("{}", e.message));
+        assert_eq!(Value::Object(vars.clone()), json!({ "n": 10, "kinds": ["a"] }));
+        assert_eq!(op.selection[0].args[0].1.resolve(&vars), Ok(json!(10)));
+        let missing = parse_one("{ top(n: $m) }");
+        assert_eq!(missing.selection[0].args[0].1.resolve(&vars), Err("variable $m is not defined".to_string()));
+    }
+
+    #[test]
+    fn undeclared_variables_are_rejected() {
+        let op = parse_one("query ($n: Int) { top(n: $n) }");
+        let passed = json!({ "n": 1, "other": 2 }).as_object().cloned().unwrap();
+        assert_eq!(bind_variables(&op, passed).err().map(|e| e.message).as_deref(), Some("variable $other is not declared by the operation"));
+    }
+
+    #[test]
+    fn directives_skip_and_include_fields() {
+        let op = parse_one("query ($on: Boolean) { a @skip(if: true) b @include(if: $on) c @include(if: false) @skip(if: false) d @live e @skip }");
+        let vars = json!({ "on": true }).as_object().cloned().unwrap();
+        let included: Vec<_> = op.selection.iter().map(|f| f.included(&vars)).collect();
+        assert_eq!(included, [
+            Ok(false), Ok(true), Ok(false),
+            Err("@live needs an `if` argument".to_string()), Err("@skip needs an `if` argument".to_string()),
+        ]);
+        let op = parse_one("{ a @skip(if: 1) b @live(if: true) }");
+        let included: Vec<_> = op.selection.iter().map(|f| f.included(&Map::new())).collect();
+        assert_eq!(included, [Err("@skip(if:) must be a boolean".to_string()), Err("unknown directive @live".to_string())]);
+    }
+
+    #[test]
+    fn operations_are_picked_by_name() {
+        let name = |src: &str, pick: Option<&str>| pick_operation(parse(src).ok().unwrap(), pick).map(|o| o.name).map_err(|e| e.message);
+        assert_eq!(name("{ stats }", None), Ok(None));
+        assert_eq!(name("query A { stats } query B { clients }", Some("B")), Ok(Some("B".to_string())));
+        assert_eq!(name("query A { stats } query B { clients }", None), Err("the document has several operations; name one in `operationName`".to_string()));
+        assert_eq!(name("query A { stats }", Some("C")), Err("no operation named `C`".to_string()));
+    }
+
+    #[test]
+    fn select_picks_fields_and_reports_paths() {
+        let data = json!({ "total": 3, "clients": [{ "ip": "10.0.0.1", "n": 2 }, { "ip": "10.0.0.2" }] });
+        let op = parse_one("{ x { t: total clients { ip n } missing } }");
+        let picked = select(&data, &op.selection[0].selection, &Map::new(), &[]).map_err(|e| e.message);
+        assert_eq!(picked, Ok(json!({ "t": 3, "clients": [{ "ip": "10.0.0.1", "n": 2 }, { "ip": "10.0.0.2", "n": null }], "missing": null })));
+        let op = parse_one("{ x { total { deeper } } }");
+        let err = select(&data, &op.selection[0].selection, &Map::new(), &[json!("x")]).err().unwrap();
+        assert_eq!(err.path, [json!("x"), json!("total")]);
+    }
+}
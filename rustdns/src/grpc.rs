@@ -0,0 +1,864 @@
+use crate::api::API_PREFIX;
+use crate::apilimits::{ApiConn, LimitedIncoming};
+use crate::querylog::{QueryEntry, QueryFilter, ACTIONS};
+use crate::state::ServerState;
+use axum::{extract::ConnectInfo, Router};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{self, HeaderMap, HeaderValue},
+    server::conn::{AddrIncoming, AddrStream},
+    service::{make_service_fn, service_fn, Service},
+    Body, Method, Request, Response, StatusCode,
+};
+use serde_json::Value;
+use std::{convert::Infallible, net::SocketAddr, sync::{Arc, Mutex}};
+use tokio::sync::{broadcast::error::RecvError, mpsc, watch};
+
+// gRPC control plane: the service in proto/piblock.proto, served over cleartext HTTP/2 on
+// RUSTDNS_GRPC_ADDR (off unless set) for the companion app and other services that want typed
+// messages and a bidirectional query stream. Unary RPCs are run as requests through the control
+// API router, with the caller's `authorization` metadata, so tokens, roles, validation and
+// behaviour are exactly those of the HTTP API. Messages are encoded by hand below, rather than
+// generated by tonic and prost, which need protoc at build time; field numbers follow the .proto
+// file, and the tests check them against it.
+
+const SERVICE_PATH: &str = "/piblock.v1.Control/";
+
+const UNARY: [&str; 12] = ["GetStats", "Pause", "Resume", "SetMode", "AddRule", "RemoveRule", "Allow", "RemoveAllow", "Check", "Reload", "Resolve", "Call"];
+
+// The largest request message accepted.
+const MAX_MESSAGE: usize = 4 << 20;
+
+// gRPC status codes used here.
+const OK: u32 = 0;
+const CANCELLED: u32 = 1;
+const INVALID_ARGUMENT: u32 = 3;
+const NOT_FOUND: u32 = 5;
+const PERMISSION_DENIED: u32 = 7;
+const RESOURCE_EXHAUSTED: u32 = 8;
+const UNIMPLEMENTED: u32 = 12;
+const INTERNAL: u32 = 13;
+const UNAVAILABLE: u32 = 14;
+const UNAUTHENTICATED: u32 = 16;
+
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Status { code, message: message.into() }
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        Status::new(INVALID_ARGUMENT, message)
+    }
+}
+
+// The address from RUSTDNS_GRPC_ADDR, if set and valid.
+fn grpc_addr() -> Option<SocketAddr> {
+    let v = crate::envvars::var("RUSTDNS_GRPC_ADDR").ok().filter(|v| !v.trim().is_empty())?;
+    match v.trim().parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            tracing::warn!("RUSTDNS_GRPC_ADDR={:?} is not an address like 127.0.0.1:50051; gRPC stays off", v);
+            None
+        }
+    }
+}
+
+// Start the gRPC listener when RUSTDNS_GRPC_ADDR is set. It stops with the rest of the server;
+// open WatchQueries streams end with UNAVAILABLE.
+pub fn spawn_grpc(app: Router, state: Arc<ServerState>, shutdown: watch::Receiver<bool>) -> Option<tokio::task::JoinHandle<()>> {
+    let addr = grpc_addr()?;
+    if state.auth.enabled() && !addr.ip().is_loopback() {
+        tracing::warn!("gRPC tokens cross the network in cleartext; keep RUSTDNS_GRPC_ADDR on loopback or behind a TLS proxy");
+    } else if !state.auth.enabled() && !addr.ip().is_loopback() {
+        tracing::warn!("gRPC on {} accepts requests without authentication; set RUSTDNS_API_TOKENS", addr);
+    }
+    let grpc = Grpc { app: Arc::new(Mutex::new(app)), state, shutdown: shutdown.clone() };
+    Some(tokio::spawn(async move {
+        let incoming = match AddrIncoming::bind(&addr) {
+            Ok(incoming) => incoming,
+            Err(e) => {
+                tracing::warn!("binding gRPC to {} failed: {}", addr, e);
+                return;
+            }
+        };
+        tracing::info!("gRPC listening on {}", addr);
+        // connections count against the control API's connection caps, and calls against its rate limit
+        let incoming = LimitedIncoming::new(incoming, grpc.state.api_limits.clone());
+        let make = make_service_fn(move |conn: &ApiConn<AddrStream>| {
+            let (grpc, peer) = (grpc.clone(), conn.peer());
+            async move { Ok::<_, Infallible>(service_fn(move |req| grpc.clone().handle(peer, req))) }
+        });
+        let mut stop = shutdown;
+        let served = hyper::Server::builder(incoming).http2_only(true).serve(make).with_graceful_shutdown(async move { let _ = stop.changed().await; }).await;
+        if let Err(e) = served { tracing::warn!("gRPC server failed: {}", e); }
+    }))
+}
+
+// Who made a call: the `authorization` metadata and the peer address the rate limit applies to.
+struct Caller {
+    auth: Option<HeaderValue>,
+    peer: SocketAddr,
+}
+
+#[derive(Clone)]
+struct Grpc {
+    // a Router isn't Sync; each call clones its own
+    app: Arc<Mutex<Router>>,
+    state: Arc<ServerState>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Grpc {
+    async fn handle(self, peer: SocketAddr, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let (parts, body) = req.into_parts();
+        let caller = Caller { auth: parts.headers.get(header::AUTHORIZATION).cloned(), peer };
+        let grpc = parts.headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(|c| c.starts_with("application/grpc"));
+        if parts.method != Method::POST || !grpc {
+            return Ok(Response::builder().status(StatusCode::UNSUPPORTED_MEDIA_TYPE).body(Body::empty()).unwrap());
+        }
+        let method = parts.uri.path().strip_prefix(SERVICE_PATH).unwrap_or("").to_string();
+        if method == "WatchQueries" {
+            return Ok(self.watch_queries(&caller, body).await);
+        }
+        if !UNARY.contains(&method.as_str()) {
+            return Ok(error_response(Status::new(UNIMPLEMENTED, format!("unknown method {}", parts.uri.path()))));
+        }
+        let msg = match Frames::new(body).next().await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Ok(error_response(Status::invalid("missing request message"))),
+            Err(s) => return Ok(error_response(s)),
+        };
+        Ok(match self.unary(&caller, &method, &msg).await {
+            Ok(reply) => unary_response(reply),
+            Err(s) => error_response(s),
+        })
+    }
+
+    // Run `method path` through the control API router as the caller.
+    async fn api(&self, caller: &Caller, method: Method, path: &str, body: Option<Value>) -> Result<Response<Bytes>, Status> {
+        let mut req = Request::builder().method(method).uri(format!("{}{}", API_PREFIX, path));
+        if let Some(auth) = &caller.auth { req = req.header(header::AUTHORIZATION, auth); }
+        let mut req = match body {
+            Some(body) => req.header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+            None => req.body(Body::empty()),
+        }.map_err(|e| Status::invalid(format!("invalid request: {}", e)))?;
+        req.extensions_mut().insert(ConnectInfo(caller.peer));
+        let mut app = self.app.lock().unwrap().clone();
+        let _ = std::future::poll_fn(|cx| app.poll_ready(cx)).await;
+        let resp = match app.call(req).await { Ok(resp) => resp, Err(e) => match e {} };
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await.map_err(|e| Status::new(INTERNAL, e.to_string()))?;
+        Ok(Response::from_parts(parts, body))
+    }
+
+    // `api` for the typed RPCs: the JSON response, or the status its HTTP status or error maps to.
+    async fn api_json(&self, caller: &Caller, method: Method, path: &str, body: Option<Value>) -> Result<Value, Status> {
+        let resp = self.api(caller, method, path, body).await?;
+        let value: Value = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+        let error = value.get("error").and_then(Value::as_str).unwrap_or("");
+        let code = match resp.status().as_u16() {
+            401 => UNAUTHENTICATED,
+            403 => PERMISSION_DENIED,
+            404 => NOT_FOUND,
+            413 | 429 => RESOURCE_EXHAUSTED,
+            503 => UNAVAILABLE,
+            s if s >= 500 => INTERNAL,
+            s if s >= 400 => INVALID_ARGUMENT,
+            _ if !error.is_empty() && value.get("ok") != Some(&Value::Bool(true)) => INVALID_ARGUMENT,
+            _ => return Ok(value),
+        };
+        let message = if error.is_empty() { resp.status().canonical_reason().unwrap_or("request failed") } else { error };
+        Err(Status::new(code, message))
+    }
+
+    async fn unary(&self, caller: &Caller, method: &str, msg: &[u8]) -> Result<Vec<u8>, Status> {
+        let req = Message::decode(msg)?;
+        let mut out = Encoder::default();
+        match method {
+            "GetStats" => {
+                let v = self.api_json(caller, Method::GET, "/stats", None).await?;
+                out.uint(1, uint(&v["queries"]));
+                out.uint(2, uint(&v["blocked"]));
+                out.uint(3, uint(&v["uptime_seconds"]));
+                out.uint(4, uint(&v["rate_limited"]));
+                out.bool(5, v["blocking_enabled"].as_bool().unwrap_or(false));
+                out.optional_uint(6, v["seconds_remaining"].as_u64());
+                out.bool(7, v["audit"].as_bool().unwrap_or(false));
+                out.uint(8, uint(&v["audited"]));
+                out.uint(9, uint(&v["cache"]["hits"]));
+                out.uint(10, uint(&v["cache"]["misses"]));
+            }
+            "Pause" | "Resume" => {
+                let path = if method == "Pause" { format!("/disable?duration={}", req.uint(1)?) } else { "/enable".to_string() };
+                let v = self.api_json(caller, Method::POST, &path, None).await?;
+                out.bool(1, v["blocking_enabled"].as_bool().unwrap_or(false));
+                out.optional_uint(2, v["seconds_remaining"].as_u64());
+            }
+            "SetMode" => {
+                let mut body = serde_json::json!({ "mode": req.string(1)? });
+                let block_ip = req.string(2)?;
+                if !block_ip.is_empty() { body["block_ip"] = Value::from(block_ip); }
+                let v = self.api_json(caller, Method::POST, "/mode", Some(body)).await?;
+                out.string(1, &text(&v["mode"]));
+            }
+            "AddRule" | "RemoveRule" => {
+                let (pattern, tld) = (req.string(1)?, req.string(2)?);
+                let mut body = serde_json::json!({});
+                if !pattern.is_empty() { body["pattern"] = Value::from(pattern); }
+                if !tld.is_empty() { body["tld"] = Value::from(tld); }
+                if method == "AddRule" {
+                    let group = req.string(3)?;
+                    if !group.is_empty() { body["group"] = Value::from(group); }
+                    let comment = req.string(4)?;
+                    if !comment.is_empty() { body["comment"] = Value::from(comment); }
+                    let tags = req.strings(5)?;
+                    if !tags.is_empty() { body["tags"] = Value::from(tags); }
+                    let types = req.strings(6)?;
+                    if !types.is_empty() { body["types"] = Value::from(types); }
+                }
+                let path = if method == "AddRule" { "/add" } else { "/remove" };
+                let v = self.api_json(caller, Method::POST, path, Some(body.clone())).await?;
+                out.bool(1, v["ok"].as_bool().unwrap_or(false));
+                let rule = [&v["added"], &v["added_tld"], &body["pattern"], &body["tld"]].into_iter().find(|r| r.is_string()).map(text).unwrap_or_default();
+                out.string(2, &rule);
+                out.string(3, &text(&v["group"]));
+            }
+            "Allow" | "RemoveAllow" => {
+                let domain = req.string(1)?;
+                let mut body = serde_json::json!({ "domain": domain });
+                let seconds = req.uint(2)?;
+                if method == "Allow" && seconds > 0 { body["seconds"] = Value::from(seconds); }
+                let path = if method == "Allow" { "/allow" } else { "/allow/remove" };
+                let v = self.api_json(caller, Method::POST, path, Some(body)).await?;
+                out.bool(1, v["ok"].as_bool().unwrap_or(false));
+                out.string(2, v["allowed"].as_str().unwrap_or(&domain));
+            }
+            "Check" => {
+                let params: Vec<String> = [("domain", req.string(1)?), ("type", req.string(2)?)].into_iter()
+                    .filter(|(_, v)| !v.is_empty()).map(|(k, v)| format!("{}={}", k, percent_encode(&v))).collect();
+                let path = format!("/check?{}", params.join("&"));
+                let v = self.api_json(caller, Method::GET, &path, None).await?;
+                out.string(1, &text(&v["domain"]));
+                out.bool(2, v["blocked"].as_bool().unwrap_or(false));
+                out.string(3, &text(&v["match"]["rule"]));
+                out.string(4, &text(&v["match"]["kind"]));
+                for origin in v["match"]["origins"].as_array().into_iter().flatten() {
+                    let mut o = Encoder::default();
+                    o.string(1, &text(&origin["group"]));
+                    o.string(2, &text(&origin["source"]));
+                    out.message(5, o);
+                }
+                out.string(6, &text(&v["mode"]["mode"]));
+                out.string(7, &text(&v["allowed_by"]));
+                out.bool(8, v["blocking_enabled"].as_bool().unwrap_or(false));
+                out.bool(9, v["audit"].as_bool().unwrap_or(false));
+                out.strings(10, &texts(&v["categories"]));
+                out.strings(11, &texts(&v["local_record"]));
+            }
+            "Reload" => {
+                let v = self.api_json(caller, Method::POST, "/reload", None).await?;
+                out.uint(1, uint(&v["loaded"]));
+                out.uint(2, uint(&v["added"]));
+                out.uint(3, uint(&v["removed"]));
+                out.uint(4, uint(&v["timings"]["total_ms"]));
+            }
+            "Resolve" => {
+                let mut body = serde_json::json!({ "name": req.string(1)?, "bypass_cache": req.bool(3)? });
+                let qtype = req.string(2)?;
+                if !qtype.is_empty() { body["type"] = Value::from(qtype); }
+                let v = self.api_json(caller, Method::POST, "/debug/resolve", Some(body)).await?;
+                out.string(1, &text(&v["decision"]));
+                out.string(2, &text(&v["response"]["rcode"]));
+                let answers: Vec<String> = v["response"]["answers"].as_array().into_iter().flatten().map(|r| {
+                    format!("{} {} {} {} {}", text(&r["name"]), uint(&r["ttl"]), text(&r["class"]), text(&r["type"]), text(&r["data"]))
+                }).collect();
+                out.strings(3, &answers);
+                out.string(4, &text(&v["upstream"]));
+                out.double(5, v["elapsed_ms"].as_f64().unwrap_or(0.0));
+                out.string(6, &text(&v["matched"]["rule"]));
+                out.string(7, &text(&v["allowed_by"]));
+                if !v["trace"].is_null() { out.string(8, &v["trace"].to_string()); }
+            }
+            "Call" => {
+                let method = match req.string(1)?.to_uppercase().as_str() {
+                    "" | "GET" => Method::GET,
+                    "POST" => Method::POST,
+                    "PUT" => Method::PUT,
+                    "DELETE" => Method::DELETE,
+                    m => return Err(Status::invalid(format!("method must be GET, POST, PUT or DELETE, not {}", m))),
+                };
+                let path = req.string(2)?;
+                if !path.starts_with('/') { return Err(Status::invalid("path must start with /, e.g. /groups")) }
+                let body = req.string(3)?;
+                let body = if body.trim().is_empty() { None } else {
+                    Some(serde_json::from_str(&body).map_err(|e| Status::invalid(format!("body is not JSON: {}", e)))?)
+                };
+                let resp = self.api(caller, method, &path, body).await?;
+                out.uint(1, resp.status().as_u16() as u64);
+                out.bytes(2, resp.body());
+                out.string(3, resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or(""));
+            }
+            _ => return Err(Status::new(UNIMPLEMENTED, format!("unknown method {}", method))),
+        }
+        Ok(out.0)
+    }
+
+    // WatchQueries: live query log entries matching the latest filter the client sent.
+    async fn watch_queries(&self, caller: &Caller, body: Body) -> Response<Body> {
+        // the same permission as GET /queries/stream
+        if let Err(s) = self.api_json(caller, Method::GET, "/queries?limit=0", None).await {
+            return error_response(s);
+        }
+        let (filters_tx, mut filters) = mpsc::channel::<Result<QueryFilter, Status>>(4);
+        tokio::spawn(async move {
+            let mut frames = Frames::new(body);
+            loop {
+                let next = match frames.next().await {
+                    Ok(Some(msg)) => query_filter(&msg),
+                    Ok(None) => break,
+                    Err(s) => Err(s),
+                };
+                let failed = next.is_err();
+                if filters_tx.send(next).await.is_err() || failed { break }
+            }
+        });
+        let mut live = self.state.query_log.subscribe();
+        let mut shutdown = self.shutdown.clone();
+        let (mut tx, resp_body) = Body::channel();
+        tokio::spawn(async move {
+            let mut filter = QueryFilter::default();
+            let mut reading = true;
+            let status = loop {
+                tokio::select! {
+                    f = filters.recv(), if reading => match f {
+                        Some(Ok(f)) => filter = f,
+                        Some(Err(s)) => break s,
+                        // the client finished sending filters; keep streaming
+                        None => reading = false,
+                    },
+                    entry = live.recv() => {
+                        let msg = match entry {
+                            Ok(e) if filter.matches(&e) => query_event(&e),
+                            Ok(_) => continue,
+                            Err(RecvError::Lagged(n)) => {
+                                let mut out = Encoder::default();
+                                out.uint(13, n);
+                                out.0
+                            }
+                            Err(RecvError::Closed) => break Status::new(OK, ""),
+                        };
+                        // the client went away
+                        if tx.send_data(frame(&msg)).await.is_err() { return }
+                    }
+                    _ = shutdown.changed() => break Status::new(UNAVAILABLE, "server shutting down"),
+                }
+            };
+            let _ = tx.send_trailers(status_trailers(&status)).await;
+        });
+        grpc_response(resp_body)
+    }
+}
+
+fn query_filter(msg: &[u8]) -> Result<QueryFilter, Status> {
+    let m = Message::decode(msg)?;
+    let client = m.string(1)?.trim().to_string();
+    let domain = m.string(2)?.trim().trim_end_matches('.').to_lowercase();
+    let action = m.string(3)?.trim().to_lowercase();
+    if !action.is_empty() && !ACTIONS.contains(&action.as_str()) {
+        return Err(Status::invalid(format!("action must be one of {}", ACTIONS.join(", "))));
+    }
+    let some = |s: String| Some(s).filter(|s| !s.is_empty());
+    Ok(QueryFilter { client: some(client), domain: some(domain), action: some(action), ..Default::default() })
+}
+
+fn query_event(e: &QueryEntry) -> Vec<u8> {
+    let mut out = Encoder::default();
+    out.uint(1, e.id);
+    out.uint(2, e.time);
+    out.string(3, &e.client);
+    out.string(4, &e.client_key);
+    out.string(5, &e.name);
+    out.string(6, &e.qtype);
+    out.string(7, e.action);
+    out.string(8, e.rule.as_deref().unwrap_or(""));
+    out.strings(9, &e.lists);
+    out.string(10, e.upstream.as_deref().unwrap_or(""));
+    out.string(11, e.rcode.as_deref().unwrap_or(""));
+    out.double(12, e.elapsed_ms);
+    out.string(14, e.client_name.as_deref().unwrap_or(""));
+    out.0
+}
+
+// JSON value helpers for filling replies: strings as they are, other values as JSON, null as "".
+fn text(v: &Value) -> String {
+    match v {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn texts(v: &Value) -> Vec<String> {
+    v.as_array().into_iter().flatten().map(text).collect()
+}
+
+fn uint(v: &Value) -> u64 {
+    v.as_u64().unwrap_or(0)
+}
+
+// Percent-encode everything but unreserved characters, for query strings.
+fn percent_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect()
+}
+
+// --- gRPC framing ---
+
+// A length-prefixed message: an uncompressed flag byte, then the length as 4 big-endian bytes.
+fn frame(msg: &[u8]) -> Bytes {
+    let mut out = Vec::with_capacity(5 + msg.len());
+    out.push(0);
+    out.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    out.extend_from_slice(msg);
+    Bytes::from(out)
+}
+
+// Messages read off a request body as they arrive.
+struct Frames {
+    body: Body,
+    buf: Vec<u8>,
+}
+
+impl Frames {
+    fn new(body: Body) -> Self {
+        Frames { body, buf: Vec::new() }
+    }
+
+    async fn next(&mut self) -> Result<Option<Vec<u8>>, Status> {
+        loop {
+            if self.buf.len() >= 5 {
+                if self.buf[0] != 0 { return Err(Status::new(UNIMPLEMENTED, "compressed messages are not supported")) }
+                let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+                if len > MAX_MESSAGE { return Err(Status::new(RESOURCE_EXHAUSTED, format!("message larger than {} bytes", MAX_MESSAGE))) }
+                if self.buf.len() >= 5 + len {
+                    let msg = self.buf[5..5 + len].to_vec();
+                    self.buf.drain(..5 + len);
+                    return Ok(Some(msg));
+                }
+            }
+            match self.body.data().await {
+                Some(Ok(chunk)) => self.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => return Err(Status::new(CANCELLED, e.to_string())),
+                None if self.buf.is_empty() => return Ok(None),
+                None => return Err(Status::invalid("truncated message")),
+            }
+        }
+    }
+}
+
+fn grpc_response(body: Body) -> Response<Body> {
+    Response::builder().header(header::CONTENT_TYPE, "application/grpc").body(body).unwrap()
+}
+
+fn status_headers(status: &Status, headers: &mut HeaderMap) {
+    headers.insert("grpc-status", HeaderValue::from(status.code));
+    if !status.message.is_empty() {
+        // printable ASCII except `%` goes as is; everything else is percent-encoded
+        let message: String = status.message.bytes().map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        }).collect();
+        if let Ok(v) = HeaderValue::from_str(&message) { headers.insert("grpc-message", v); }
+    }
+}
+
+fn status_trailers(status: &Status) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    status_headers(status, &mut trailers);
+    trailers
+}
+
+// A failed call as a trailers-only response: the status goes in the headers, with no body.
+fn error_response(status: Status) -> Response<Body> {
+    let mut resp = grpc_response(Body::empty());
+    status_headers(&status, resp.headers_mut());
+    resp
+}
+
+fn unary_response(msg: Vec<u8>) -> Response<Body> {
+    let (mut tx, body) = Body::channel();
+    tokio::spawn(async move {
+        if tx.send_data(frame(&msg)).await.is_ok() {
+            let _ = tx.send_trailers(status_trailers(&Status::new(OK, ""))).await;
+        }
+    });
+    grpc_response(body)
+}
+
+// --- protobuf wire format ---
+
+// Writes fields in proto3 style: scalars at their default value are left out.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64);
+    }
+
+    fn uint(&mut self, field: u32, v: u64) {
+        if v != 0 { self.optional_uint(field, Some(v)); }
+    }
+
+    // A field with presence (`optional`), written even when zero.
+    fn optional_uint(&mut self, field: u32, v: Option<u64>) {
+        if let Some(v) = v {
+            self.key(field, 0);
+            self.varint(v);
+        }
+    }
+
+    fn bool(&mut self, field: u32, v: bool) {
+        self.uint(field, v as u64);
+    }
+
+    fn double(&mut self, field: u32, v: f64) {
+        if v != 0.0 {
+            self.key(field, 1);
+            self.0.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    fn bytes(&mut self, field: u32, v: &[u8]) {
+        if !v.is_empty() { self.len_delimited(field, v); }
+    }
+
+    fn string(&mut self, field: u32, v: &str) {
+        self.bytes(field, v.as_bytes());
+    }
+
+    // A repeated string field; every element is written, empty or not.
+    fn strings(&mut self, field: u32, vs: &[String]) {
+        for v in vs { self.len_delimited(field, v.as_bytes()); }
+    }
+
+    fn message(&mut self, field: u32, m: Encoder) {
+        self.len_delimited(field, &m.0);
+    }
+
+    fn len_delimited(&mut self, field: u32, v: &[u8]) {
+        self.key(field, 2);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    // fixed32 and fixed64 values; no request message has them
+    Fixed,
+}
+
+// A decoded message: its fields in wire order. Unknown fields are kept and ignored, and a field
+// that appears more than once takes its last value, as protobuf parsers do.
+struct Message<'a> {
+    fields: Vec<(u32, Field<'a>)>,
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *buf.get(*pos)?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b < 0x80 { return Some(v) }
+    }
+    None
+}
+
+impl<'a> Message<'a> {
+    fn decode(buf: &'a [u8]) -> Result<Self, Status> {
+        let malformed = || Status::invalid("malformed protobuf message");
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let key = read_varint(buf, &mut pos).ok_or_else(malformed)?;
+            let field = (key >> 3) as u32;
+            let value = match key & 7 {
+                0 => Field::Varint(read_varint(buf, &mut pos).ok_or_else(malformed)?),
+                1 | 5 => {
+                    pos += if key & 7 == 1 { 8 } else { 4 };
+                    Field::Fixed
+                }
+                2 => {
+                    let len = read_varint(buf, &mut pos).ok_or_else(malformed)? as usize;
+                    let end = pos.checked_add(len).filter(|end| *end <= buf.len()).ok_or_else(malformed)?;
+                    let bytes = &buf[pos..end];
+                    pos = end;
+                    Field::Bytes(bytes)
+                }
+                _ => return Err(malformed()),
+            };
+            if pos > buf.len() { return Err(malformed()) }
+            fields.push((field, value));
+        }
+        Ok(Message { fields })
+    }
+
+    fn last(&self, field: u32) -> Option<&Field<'a>> {
+        self.fields.iter().rev().find(|(f, _)| *f == field).map(|(_, v)| v)
+    }
+
+    fn uint(&self, field: u32) -> Result<u64, Status> {
+        match self.last(field) {
+            None => Ok(0),
+            Some(Field::Varint(v)) => Ok(*v),
+            Some(_) => Err(Status::invalid(format!("field {} should be an integer", field))),
+        }
+    }
+
+    fn bool(&self, field: u32) -> Result<bool, Status> {
+        self.uint(field).map(|v| v != 0)
+    }
+
+    fn string(&self, field: u32) -> Result<String, Status> {
+        match self.last(field) {
+            None => Ok(String::new()),
+            Some(Field::Bytes(b)) => utf8(field, b),
+            Some(_) => Err(Status::invalid(format!("field {} should be a string", field))),
+        }
+    }
+
+    fn strings(&self, field: u32) -> Result<Vec<String>, Status> {
+        self.fields.iter().filter(|(f, _)| *f == field).map(|(_, v)| match v {
+            Field::Bytes(b) => utf8(field, b),
+            _ => Err(Status::invalid(format!("field {} should be a string", field))),
+        }).collect()
+    }
+}
+
+fn utf8(field: u32, b: &[u8]) -> Result<String, Status> {
+    String::from_utf8(b.to_vec()).map_err(|_| Status::invalid(format!("field {} is not valid UTF-8", field)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Field numbers of `message` in proto/piblock.proto, by field name.
+    fn proto_fields(message: &str) -> HashMap<String, u32> {
+        let proto = include_str!("../proto/piblock.proto");
+        let start = proto.find(&format!("message {} {{", message)).expect("message in the .proto");
+        let body = &proto[start..start + proto[start..].find('}').unwrap()];
+        body.lines().skip(1)
+            .filter_map(|l| l.split("//").next()?.trim().strip_suffix(';')?.split_once('='))
+            .map(|(decl, n)| (decl.split_whitespace().last().unwrap().to_string(), n.trim().parse().unwrap()))
+            .collect()
+    }
+
+    // Request body made of `chunks`, sent as they are; an error ends it early when `fail` is set.
+    fn body(chunks: Vec<Vec<u8>>, fail: bool) -> Body {
+        let (mut tx, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                if tx.send_data(Bytes::from(chunk)).await.is_err() { return }
+            }
+            if fail { tx.abort(); }
+        });
+        body
+    }
+
+    fn status_code<T>(r: Result<T, Status>) -> Option<u32> {
+        r.err().map(|s| s.code)
+    }
+
+    #[test]
+    fn read_varint_decodes_and_rejects_truncation() {
+        let mut pos = 0;
+        assert_eq!(read_varint(&[0x00], &mut pos), Some(0));
+        let mut pos = 0;
+        assert_eq!(read_varint(&[0xac, 0x02, 0xff], &mut pos), Some(300));
+        assert_eq!(pos, 2);
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        let mut pos = 0;
+        assert_eq!(read_varint(&max, &mut pos), Some(u64::MAX));
+        assert_eq!(pos, 10);
+        // continuation bit on the last byte available
+        let mut pos = 0;
+        assert_eq!(read_varint(&[0x80, 0x80], &mut pos), None);
+        // longer than any 64-bit value
+        let mut pos = 0;
+        assert_eq!(read_varint(&[0x80; 11], &mut pos), None);
+    }
+
+    #[test]
+    fn decode_reads_every_wire_type_and_keeps_the_last_value() {
+        let mut e = Encoder::default();
+        e.uint(1, 7);
+        e.string(2, "first");
+        e.double(3, 1.5);
+        e.string(2, "second");
+        e.strings(4, &["a".to_string(), String::new(), "c".to_string()]);
+        e.key(5, 5);
+        e.0.extend_from_slice(&[1, 2, 3, 4]);
+        e.bool(6, true);
+        let m = Message::decode(&e.0).ok().expect("valid message");
+        assert_eq!(m.uint(1).ok(), Some(7));
+        assert_eq!(m.string(2).ok().as_deref(), Some("second"));
+        assert!(matches!(m.last(3), Some(Field::Fixed)));
+        assert!(matches!(m.last(5), Some(Field::Fixed)));
+        assert_eq!(m.strings(4).ok(), Some(vec!["a".to_string(), String::new(), "c".to_string()]));
+        assert_eq!(m.bool(6).ok(), Some(true));
+        // absent fields read as their default
+        assert_eq!(m.uint(9).ok(), Some(0));
+        assert_eq!(m.string(9).ok().as_deref(), Some(""));
+        // a field of the wrong type
+        assert_eq!(status_code(m.uint(2)), Some(INVALID_ARGUMENT));
+        assert_eq!(status_code(m.string(1)), Some(INVALID_ARGUMENT));
+    }
+
+    #[test]
+    fn decode_rejects_malformed_messages() {
+        let cases: [&[u8]; 6] = [
+            // key without a value
+            &[0x08],
+            // length past the end
+            &[0x12, 0x05, b'a'],
+            // fixed64 and fixed32 cut short
+            &[0x19, 1, 2, 3],
+            &[0x1d, 1, 2],
+            // wire types 3 and 4 (groups) aren't supported
+            &[0x0b],
+            // a length that overflows the position
+            &[0x12, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        ];
+        for buf in cases {
+            assert_eq!(status_code(Message::decode(buf)), Some(INVALID_ARGUMENT), "{:?}", buf);
+        }
+        assert_eq!(status_code(Message::decode(&[0x12, 0x02, 0xc3, 0x28]).and_then(|m| m.string(2))), Some(INVALID_ARGUMENT));
+        assert!(Message::decode(&[]).is_ok());
+    }
+
+    #[test]
+    fn query_event_uses_the_proto_field_numbers() {
+        let f = proto_fields("QueryEvent");
+        let mut e = QueryEntry::new("10.0.0.5:5353".into(), "10.0.0.5".into(), "Ads.Example.", "AAAA".into(), "blocked", 2.5);
+        e.id = 42;
+        e.time = 1_700_000_000;
+        e.client_name = Some("kids-tablet".into());
+        e.rule = Some("*.example".into());
+        e.lists = vec!["ads.txt".into(), "extra.txt".into()];
+        e.upstream = Some("9.9.9.9:53".into());
+        e.rcode = Some("NXDomain".into());
+        let buf = query_event(&e);
+        let m = Message::decode(&buf).ok().expect("valid message");
+        assert_eq!(m.uint(f["id"]).ok(), Some(42));
+        assert_eq!(m.uint(f["time"]).ok(), Some(1_700_000_000));
+        assert_eq!(m.string(f["client"]).ok().as_deref(), Some("10.0.0.5:5353"));
+        assert_eq!(m.string(f["client_key"]).ok().as_deref(), Some("10.0.0.5"));
+        assert_eq!(m.string(f["name"]).ok().as_deref(), Some("ads.example"));
+        assert_eq!(m.string(f["qtype"]).ok().as_deref(), Some("AAAA"));
+        assert_eq!(m.string(f["action"]).ok().as_deref(), Some("blocked"));
+        assert_eq!(m.string(f["rule"]).ok().as_deref(), Some("*.example"));
+        assert_eq!(m.strings(f["lists"]).ok(), Some(vec!["ads.txt".to_string(), "extra.txt".to_string()]));
+        assert_eq!(m.string(f["upstream"]).ok().as_deref(), Some("9.9.9.9:53"));
+        assert_eq!(m.string(f["rcode"]).ok().as_deref(), Some("NXDomain"));
+        assert!(matches!(m.last(f["elapsed_ms"]), Some(Field::Fixed)));
+        let elapsed = [&[(f["elapsed_ms"] << 3) as u8 | 1][..], &2.5f64.to_le_bytes()].concat();
+        assert!(buf.windows(elapsed.len()).any(|w| w == elapsed));
+        assert_eq!(m.string(f["client_name"]).ok().as_deref(), Some("kids-tablet"));
+        assert_eq!(m.uint(f["lagged"]).ok(), Some(0));
+        // every field written is one the .proto declares
+        let known: Vec<u32> = f.values().copied().collect();
+        assert!(m.fields.iter().all(|(n, _)| known.contains(n)));
+    }
+
+    #[test]
+    fn query_filter_reads_the_proto_field_numbers() {
+        let f = proto_fields("QueryFilter");
+        let mut e = Encoder::default();
+        e.string(f["client"], " 10.0.0.5 ");
+        e.string(f["domain"], "Example.COM.");
+        e.string(f["action"], "Blocked");
+        let filter = query_filter(&e.0).ok().expect("valid filter");
+        assert_eq!(filter.client.as_deref(), Some("10.0.0.5"));
+        assert_eq!(filter.domain.as_deref(), Some("example.com"));
+        assert_eq!(filter.action.as_deref(), Some("blocked"));
+        let empty = query_filter(&[]).ok().expect("valid filter");
+        assert!(empty.client.is_none() && empty.domain.is_none() && empty.action.is_none());
+        let mut e = Encoder::default();
+        e.string(f["action"], "nonsense");
+        assert_eq!(status_code(query_filter(&e.0)), Some(INVALID_ARGUMENT));
+    }
+
+    #[test]
+    fn encoder_leaves_out_defaults_but_not_optional_zeroes() {
+        let mut e = Encoder::default();
+        e.uint(1, 0);
+        e.bool(2, false);
+        e.string(3, "");
+        e.double(4, 0.0);
+        e.bytes(5, &[]);
+        assert!(e.0.is_empty());
+        e.optional_uint(6, Some(0));
+        e.optional_uint(7, None);
+        assert_eq!(e.0, [0x30, 0x00]);
+        // field numbers past 15 take a two-byte key
+        let mut e = Encoder::default();
+        e.uint(16, 1);
+        assert_eq!(e.0, [0x80, 0x01, 0x01]);
+        let mut inner = Encoder::default();
+        inner.string(1, "g");
+        let mut e = Encoder::default();
+        e.message(5, inner);
+        assert_eq!(e.0, [0x2a, 0x03, 0x0a, 0x01, b'g']);
+    }
+
+    #[tokio::test]
+    async fn frames_are_reassembled_across_chunks() {
+        let (a, b) = (frame(b"hello"), frame(b""));
+        let mut all = a.to_vec();
+        all.extend_from_slice(&b);
+        all.extend_from_slice(&frame(&[7; 300]));
+        // split inside the length prefix, inside a message, and between messages
+        let chunks = vec![all[..2].to_vec(), all[2..7].to_vec(), all[7..12].to_vec(), all[12..100].to_vec(), all[100..].to_vec()];
+        let mut frames = Frames::new(body(chunks, false));
+        assert_eq!(frames.next().await.ok(), Some(Some(b"hello".to_vec())));
+        assert_eq!(frames.next().await.ok(), Some(Some(Vec::new())));
+        assert_eq!(frames.next().await.ok(), Some(Some(vec![7; 300])));
+        assert_eq!(frames.next().await.ok(), Some(None));
+    }
+
+    #[tokio::test]
+    async fn frames_reject_oversized_compressed_and_truncated_messages() {
+        let oversized = [&[0u8][..], &((MAX_MESSAGE + 1) as u32).to_be_bytes()].concat();
+        assert_eq!(status_code(Frames::new(body(vec![oversized], false)).next().await), Some(RESOURCE_EXHAUSTED));
+        // a message of exactly the limit is still read
+        let at_limit = [&[0u8][..], &(MAX_MESSAGE as u32).to_be_bytes()].concat();
+        let mut frames = Frames::new(body(vec![at_limit, vec![0; MAX_MESSAGE]], false));
+        assert_eq!(frames.next().await.ok().flatten().map(|m| m.len()), Some(MAX_MESSAGE));
+
+        let compressed = [1, 0, 0, 0, 1, 0].to_vec();
+        assert_eq!(status_code(Frames::new(body(vec![compressed], false)).next().await), Some(UNIMPLEMENTED));
+        let truncated = frame(b"hello")[..7].to_vec();
+        assert_eq!(status_code(Frames::new(body(vec![truncated], false)).next().await), Some(INVALID_ARGUMENT));
+        assert_eq!(status_code(Frames::new(body(vec![vec![0, 0]], true)).next().await), Some(CANCELLED));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::server::{forward_udp_to_upstream, query_message};
+use crate::state::ServerState;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trust_dns_proto::op::Message;
+use trust_dns_proto::rr::{Name, RecordType};
+
+// How long an upstream probe result is reused, so frequent probes don't each send a query.
+const PROBE_TTL: Duration = Duration::from_secs(10);
+
+// Longest an upstream probe waits for an answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Outcome of one readiness check.
+#[derive(Clone, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+// How a supervised component (the control API, the DNS listeners) is doing, as GET /healthz
+// reports it.
+#[derive(Clone, Serialize)]
+pub struct ComponentStatus {
+    // `running`, `restarting` (waiting out the backoff after a failure) or `stopped`
+    pub state: &'static str,
+    pub restarts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+// What GET /readyz looks at besides the loaded lists: whether the UDP listener is bound (and why
+// not, if it failed), and whether the upstream answered a probe recently.
+#[derive(Default)]
+pub struct Health {
+    pub udp_bound: AtomicBool,
+    pub udp_error: Mutex<Option<String>>,
+    probe: Mutex<Option<(Instant, String, Check)>>,
+    components: Mutex<BTreeMap<&'static str, ComponentStatus>>,
+}
+
+impl Health {
+    // Whether `upstream` answers a query for the root NS records, reusing the last answer for
+    // PROBE_TTL. Any response counts, whatever its code: the upstream is reachable.
+    pub async fn probe_upstream(&self, state: &ServerState, upstream: &str) -> Check {
+        if let Some((at, up, check)) = self.probe.lock().unwrap().as_ref() {
+            if up == upstream && at.elapsed() < PROBE_TTL { return check.clone() }
+        }
+        let started = Instant::now();
+        let id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u16).unwrap_or(0);
+        let msg = query_message(id, Name::root(), RecordType::NS);
+        let check = match msg.to_vec() {
+            Ok(packet) => match forward_udp_to_upstream(&state.resources, &packet, upstream, PROBE_TIMEOUT.min(state.settings.upstream_timeout())).await {
+                Ok(raw) if Message::from_vec(&raw).is_ok() => Check { ok: true, detail: Some(format!("{} answered in {} ms", upstream, started.elapsed().as_millis())) },
+                Ok(_) => Check { ok: false, detail: Some(format!("{} sent an unparsable answer", upstream)) },
+                Err(e) => Check { ok: false, detail: Some(format!("{}: {}", upstream, e)) },
+            },
+            Err(e) => Check { ok: false, detail: Some(e.to_string()) },
+        };
+        *self.probe.lock().unwrap() = Some((Instant::now(), upstream.to_string(), check.clone()));
+        check
+    }
+
+    pub fn set_component(&self, name: &'static str, state: &'static str, error: Option<String>) {
+        let mut components = self.components.lock().unwrap();
+        let status = components.entry(name).or_insert(ComponentStatus { state, restarts: 0, last_error: None });
+        if state == "restarting" { status.restarts += 1; }
+        status.state = state;
+        if error.is_some() { status.last_error = error; }
+    }
+
+    pub fn components(&self) -> BTreeMap<&'static str, ComponentStatus> {
+        self.components.lock().unwrap().clone()
+    }
+
+    pub fn udp(&self) -> Check {
+        let ok = self.udp_bound.load(Ordering::Relaxed);
+        let error = self.udp_error.lock().unwrap().clone();
+        Check { ok, detail: (!ok).then(|| error.unwrap_or_else(|| "UDP listener not bound".to_string())) }
+    }
+}
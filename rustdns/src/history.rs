@@ -0,0 +1,107 @@
+use crate::store::{data_dir, read_json, write_json};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Resolutions GET /stats/history serves: (range, bucket length in seconds, buckets kept).
+pub const HISTORY_RANGES: [(&str, u64, usize); 2] = [("24h", 600, 144), ("30d", 3600, 720)];
+
+// Queries and blocked queries in one interval starting at `start` (unix time).
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Bucket {
+    pub start: u64,
+    pub queries: u64,
+    pub blocked: u64,
+}
+
+// Buckets of every resolution in HISTORY_RANGES, oldest first, as saved in `history.json`.
+#[derive(Default, Serialize, Deserialize)]
+struct HistoryData {
+    series: Vec<VecDeque<Bucket>>,
+}
+
+// Query and blocked counts over time for charting: 10-minute buckets for the last day and hourly
+// ones for the last 30 days. The since-start counters are sampled every minute and the increase
+// is added to the current bucket of each series, so the queries hot path stays untouched. The
+// buckets are saved to `history.json` in the data directory every 10 minutes and on shutdown,
+// and loaded back at startup.
+pub struct StatsHistory {
+    path: PathBuf,
+    data: Mutex<HistoryData>,
+    // counters at the last sample
+    sampled: Mutex<(u64, u64)>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl StatsHistory {
+    pub fn from_env() -> Self {
+        let path = data_dir().join("history.json");
+        let mut data = match read_json::<HistoryData>(&path) {
+            Ok(Some(d)) => d,
+            Ok(None) => HistoryData::default(),
+            Err(e) => {
+                tracing::warn!("ignoring unreadable stats history {}: {:?}", path.display(), e);
+                HistoryData::default()
+            }
+        };
+        data.series.resize_with(HISTORY_RANGES.len(), VecDeque::new);
+        StatsHistory { path, data: Mutex::new(data), sampled: Mutex::new((0, 0)) }
+    }
+
+    // Add what the since-start counters went up by since the last sample.
+    pub fn sample(&self, queries: u64, blocked: u64) {
+        let mut sampled = self.sampled.lock().unwrap();
+        let (dq, db) = (queries.saturating_sub(sampled.0), blocked.saturating_sub(sampled.1));
+        *sampled = (queries, blocked);
+        let now = now_secs();
+        let mut data = self.data.lock().unwrap();
+        for ((_, secs, keep), series) in HISTORY_RANGES.iter().zip(data.series.iter_mut()) {
+            let start = now - now % secs;
+            // after the clock went back, counts go to the latest bucket
+            if series.back().is_none_or(|b| b.start < start) {
+                series.push_back(Bucket { start, ..Default::default() });
+            }
+            while series.front().is_some_and(|b| b.start + secs * *keep as u64 <= now) {
+                series.pop_front();
+            }
+            let bucket = series.back_mut().unwrap();
+            bucket.queries += dq;
+            bucket.blocked += db;
+        }
+    }
+
+    // Every bucket of `range` (see HISTORY_RANGES) up to now, oldest first, with empty intervals
+    // filled in, and the bucket length in seconds.
+    pub fn series(&self, range: &str) -> Option<(u64, Vec<Bucket>)> {
+        let i = HISTORY_RANGES.iter().position(|(r, _, _)| *r == range)?;
+        let (_, secs, keep) = HISTORY_RANGES[i];
+        let now = now_secs();
+        let last = now - now % secs;
+        let first = last.saturating_sub(secs * (keep as u64 - 1));
+        let data = self.data.lock().unwrap();
+        let mut stored = data.series[i].iter().filter(|b| b.start >= first).peekable();
+        let mut out = Vec::with_capacity(keep);
+        let mut start = first;
+        while start <= last {
+            while stored.next_if(|b| b.start < start).is_some() {}
+            match stored.next_if(|b| b.start == start) {
+                Some(b) => out.push(*b),
+                None => out.push(Bucket { start, ..Default::default() }),
+            }
+            start += secs;
+        }
+        Some((secs, out))
+    }
+
+    pub fn save(&self) {
+        let data = self.data.lock().unwrap();
+        if let Err(e) = write_json(&self.path, &*data) {
+            tracing::warn!("saving stats history to {} failed: {:?}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use crate::blocklist::{BlockMatch, Blocklist};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Serialize)]
+pub struct RuleHit {
+    pub count: u64,
+    pub last_hit: u64,
+}
+
+// How often each list rule has matched (blocked or, in audit mode, would have blocked), so dead
+// rules can be pruned and the rule behind an unwanted block found. Counts live as long as the
+// rule stays loaded: a reload keeps those of rules that are still in the lists.
+pub struct RuleHits {
+    pub since: u64,
+    // keyed by TLD rule or not, then the rule as stored in the blocklist
+    counts: Mutex<HashMap<(bool, String), RuleHit>>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl RuleHits {
+    pub fn new() -> Self {
+        RuleHits { since: now_secs(), counts: Mutex::new(HashMap::new()) }
+    }
+
+    // Count a match. Blocks that didn't come from a list rule (detectors, categories) are skipped.
+    pub fn record(&self, m: &BlockMatch) {
+        if !matches!(m.kind, "exact" | "wildcard" | "tld") { return }
+        let now = now_secs();
+        let mut counts = self.counts.lock().unwrap();
+        let hit = counts.entry((m.kind == "tld", m.rule.clone())).or_insert(RuleHit { count: 0, last_hit: now });
+        hit.count += 1;
+        hit.last_hit = now;
+    }
+
+    // Hits of pattern rules and of TLD rules.
+    pub fn snapshot(&self) -> (HashMap<String, RuleHit>, HashMap<String, RuleHit>) {
+        let counts = self.counts.lock().unwrap();
+        let (mut patterns, mut tlds) = (HashMap::new(), HashMap::new());
+        for ((tld, rule), hit) in counts.iter() {
+            if *tld { tlds.insert(rule.clone(), *hit); } else { patterns.insert(rule.clone(), *hit); }
+        }
+        (patterns, tlds)
+    }
+
+    // Forget rules `lists` no longer has. Rules from the compiled list aren't kept in memory, so
+    // theirs are kept while a compiled list is in use.
+    pub fn retain_loaded(&self, lists: &Blocklist) {
+        if lists.compiled.is_some() { return }
+        self.counts.lock().unwrap().retain(|(tld, rule), _| {
+            if *tld { lists.tlds.contains_key(rule) } else { lists.patterns.contains_key(rule) }
+        });
+    }
+}
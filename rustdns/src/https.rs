@@ -0,0 +1,115 @@
+use crate::tls::CertStore;
+use hyper::server::accept::Accept;
+use rustls::ServerConfig;
+use serde::Serialize;
+#[cfg(feature = "self-signed")]
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+// Longest a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Whether the control API is served over HTTPS (RUSTDNS_HTTP_TLS). `on` uses the certificate of
+// the encrypted listeners (RUSTDNS_TLS_CERT / RUSTDNS_TLS_KEY, renewed by ACME and reloaded when
+// the files change); `self-signed` does the same but first writes a self-signed certificate
+// there when none exists, so the API token never crosses the network in cleartext even without
+// a real certificate.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HttpTls {
+    Off,
+    On,
+    SelfSigned,
+}
+
+impl HttpTls {
+    pub fn from_env() -> Self {
+        match crate::envvars::var("RUSTDNS_HTTP_TLS").unwrap_or_default().trim().to_lowercase().as_str() {
+            "" | "0" | "off" | "false" => HttpTls::Off,
+            "1" | "on" | "true" => HttpTls::On,
+            "self-signed" | "selfsigned" => HttpTls::SelfSigned,
+            other => {
+                tracing::warn!("unknown RUSTDNS_HTTP_TLS {:?}; serving the control API over plain HTTP", other);
+                HttpTls::Off
+            }
+        }
+    }
+}
+
+// rustls settings for the control API, resolving the certificate through `certs` on every
+// handshake so renewals apply to new connections.
+pub fn server_config(certs: Arc<CertStore>) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certs);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Arc::new(config)
+}
+
+// Connections accepted on a TCP listener, handed to hyper once their TLS handshake is done.
+// Handshakes run in their own tasks, so a slow or stalled client doesn't hold up the others.
+pub struct TlsIncoming {
+    conns: mpsc::Receiver<TlsStream<TcpStream>>,
+}
+
+impl TlsIncoming {
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> Self {
+        let (tx, conns) = mpsc::channel(32);
+        let acceptor = TlsAcceptor::from(config);
+        // ends, releasing the listener, once the server stops taking connections
+        tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    _ = tx.closed() => return,
+                    a = listener.accept() => a,
+                };
+                let (stream, peer) = match accepted {
+                    Ok(c) => c,
+                    Err(e) => {
+                        tracing::warn!("control API accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(tls)) => { let _ = tx.send(tls).await; }
+                        Ok(Err(e)) => tracing::debug!("control API TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => tracing::debug!("control API TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+        TlsIncoming { conns }
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<TcpStream>;
+    type Error = std::io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.conns.poll_recv(cx).map(|c| c.map(Ok))
+    }
+}
+
+// Names the self-signed certificate is issued for: localhost, the machine's own names and
+// addresses, and the control API address when it is bound to one.
+#[cfg(feature = "self-signed")]
+pub fn self_signed_names(addr: &SocketAddr, own_names: &[String], own_addrs: &[std::net::IpAddr]) -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    names.extend(own_names.iter().cloned());
+    names.extend(own_addrs.iter().map(|a| a.to_string()));
+    if !addr.ip().is_unspecified() { names.push(addr.ip().to_string()); }
+    names.sort();
+    names.dedup();
+    names
+}
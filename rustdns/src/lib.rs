@@ -1,75 +1,748 @@
+mod acme;
+mod alerts;
+mod allowlist;
+mod api;
+mod apilimits;
+mod backup;
+mod auth;
 mod blocklist;
+mod bloom;
+mod brands;
+mod cache;
+mod canary;
+mod categories;
+mod clients;
+mod compiled;
+mod config;
+mod cors;
+mod dashboard;
 mod control;
+mod dga;
+mod embed;
+mod envvars;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod health;
+mod history;
+mod hits;
+mod https;
+mod lifecycle;
+mod listeners;
+mod logging;
+mod longterm;
+mod metrics;
+#[cfg(feature = "uniffi")]
+mod mobile;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod openapi;
+mod prewarm;
+mod querylog;
+mod querystore;
 mod server;
 mod state;
+mod store;
+mod supervisor;
+mod ratelimit;
+mod rdap;
+mod resources;
+mod review;
+mod savedstate;
+mod schedule;
+mod rpz;
+mod selfnames;
+mod settings;
+mod systemd;
+mod tls;
+mod top;
+mod totals;
+mod trace;
+mod tunnel;
+mod tz;
+mod watcher;
+mod wildcard;
 mod runner;
 
-use std::ffi::CStr;
-use std::os::raw::c_char;
-use std::sync::{Mutex, Once};
-use once_cell::sync::OnceCell;
+pub use blocklist::BlockingMode;
+pub use embed::{PiBlockServer, PiBlockServerBuilder, ServerHandle, ServerStats};
+pub use logging::RustdnsLogCallback;
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::net::SocketAddr;
+use std::os::raw::{c_char, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-static START_ONCE: Once = Once::new();
-static THREAD_HANDLE: OnceCell<Mutex<Option<JoinHandle<()>>>> = OnceCell::new();
-static SHUTDOWN_TX: OnceCell<tokio::sync::watch::Sender<bool>> = OnceCell::new();
+/// Success.
+pub const RUSTDNS_OK: i32 = 0;
+/// rustdns_start*: a server is already running.
+pub const RUSTDNS_ERR_ALREADY_RUNNING: i32 = 1;
+/// An argument is missing or invalid, or the server refused the change.
+pub const RUSTDNS_ERR_INVALID_ARGUMENT: i32 = 2;
+/// rustdns_start*: an address isn't an IP address with a port.
+pub const RUSTDNS_ERR_INVALID_ADDRESS: i32 = 3;
+/// rustdns_start*: the control API or DNS address couldn't be bound, e.g. because it is in use.
+pub const RUSTDNS_ERR_BIND: i32 = 4;
+/// rustdns_start*: the runtime or server thread couldn't be created, or the server stopped
+/// while starting.
+pub const RUSTDNS_ERR_STARTUP: i32 = 5;
+/// rustdns_start*: the configuration isn't valid JSON or has an invalid setting, such as a
+/// RUSTDNS_BLOCKING_MODE that isn't a blocking mode.
+pub const RUSTDNS_ERR_INVALID_CONFIG: i32 = 6;
+/// rustdns_init_logging, rustdns_set_log_callback: the host installed a tracing subscriber of its
+/// own, which gets the server's logs instead.
+pub const RUSTDNS_ERR_LOGGER: i32 = 7;
+/// Calls that act on the server: no server is running.
+pub const RUSTDNS_ERR_NOT_RUNNING: i32 = 8;
 
-fn init_cells() {
-    START_ONCE.call_once(|| {
-        THREAD_HANDLE.set(Mutex::new(None)).ok();
-    });
+thread_local! {
+    // message for the last call on this thread that failed (see rustdns_last_error_message)
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+// Record `message` as the reason for `code` and return `code`.
+fn fail(code: i32, message: impl Into<String>) -> i32 {
+    let message = CString::new(message.into().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+    code
+}
+
+fn not_running() -> i32 {
+    fail(RUSTDNS_ERR_NOT_RUNNING, "the server is not running")
+}
+
+// A server started over FFI: the thread running it, the sender that stops it, its state and
+// runtime for FFI calls that act on it directly, and whether its queries are being passed to a
+// query callback (see rustdns_set_query_callback), with the flag that ends that at stop. A server
+// started by rustdns_start_with_config holds the settings given in code until it stops. It is
+// recorded while it starts too, so the slot isn't locked through startup and rustdns_stop can
+// abort it.
+struct Running {
+    starting: bool,
+    thread: JoinHandle<anyhow::Result<()>>,
+    shutdown: tokio::sync::watch::Sender<bool>,
+    state: Arc<state::ServerState>,
+    runtime: tokio::runtime::Handle,
+    dispatching: bool,
+    stopped: Arc<AtomicBool>,
+    owns_settings: bool,
+}
+
+// The running or starting server, if any. Taken out on stop, so the server can be started again
+// after.
+static SERVER: Mutex<Option<Running>> = Mutex::new(None);
+
+// The server slot, also when a thread panicked while holding it. A started server whose thread
+// has ended on its own (e.g. it panicked) is cleared, so it doesn't block the next start; one
+// still starting is left to the start that reports why.
+fn server() -> MutexGuard<'static, Option<Running>> {
+    let mut server = SERVER.lock().unwrap_or_else(|e| e.into_inner());
+    if server.as_ref().is_some_and(|r| !r.starting && r.thread.is_finished()) && server.take().is_some_and(|r| r.owns_settings) {
+        embed::release_settings();
+    }
+    server
+}
+
+// The server once it has started.
+fn started(server: &Option<Running>) -> Option<&Running> {
+    server.as_ref().filter(|r| !r.starting)
+}
+
+// State of the running server.
+fn current_state() -> Option<Arc<state::ServerState>> {
+    started(&server()).map(|r| r.state.clone())
+}
+
+// Run a control API handler on the running server's runtime and return its JSON answer, or None
+// if not running.
+fn call<F, Fut>(handler: F) -> Option<serde_json::Value>
+where
+    F: FnOnce(Arc<state::ServerState>) -> Fut,
+    Fut: std::future::Future<Output = axum::Json<serde_json::Value>>,
+{
+    let (state, runtime) = started(&server()).map(|r| (r.state.clone(), r.runtime.clone()))?;
+    Some(runtime.block_on(handler(state)).0)
+}
+
+// The string `s` points to, or None for null or invalid UTF-8.
+unsafe fn c_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() { None } else { CStr::from_ptr(s).to_str().ok() }
 }
 
+// RUSTDNS_OK if the handler's answer has `"ok": true`, else the error it gives.
+fn status(answer: Option<serde_json::Value>) -> i32 {
+    match answer {
+        Some(v) if v.get("ok").and_then(|ok| ok.as_bool()) == Some(true) => RUSTDNS_OK,
+        Some(v) => fail(RUSTDNS_ERR_INVALID_ARGUMENT, v.get("error").and_then(|e| e.as_str()).unwrap_or("no such rule")),
+        None => not_running(),
+    }
+}
+
+/// Start the DNS server and control API on a background thread, and return once both listen (the
+/// lists load first). Returns RUSTDNS_OK, or an RUSTDNS_ERR_* code with the reason in
+/// rustdns_last_error_message. Null addresses mean 127.0.0.1:9080 and 0.0.0.0:5353; others must
+/// point to a valid NUL-terminated string.
+// A safe function, as it always was, so Rust code calling it through the rlib keeps building;
+// the entry points added after it are `unsafe` instead.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn rustdns_start(http_addr: *const c_char, udp_bind: *const c_char) -> i32 {
-    init_cells();
-    let http = if http_addr.is_null() { "127.0.0.1:9080".to_string() } else {
-        unsafe { CStr::from_ptr(http_addr).to_string_lossy().into_owned() }
-    };
-    let udp = if udp_bind.is_null() { "0.0.0.0:5353".to_string() } else {
-        unsafe { CStr::from_ptr(udp_bind).to_string_lossy().into_owned() }
-    };
+    // SAFETY: the caller passes null or NUL-terminated strings, as documented above
+    unsafe { start(http_addr, udp_bind, None) }
+}
 
-    // if already started, return 1
-    if THREAD_HANDLE.get().and_then(|m| m.lock().ok()).and_then(|g| g.as_ref().map(|_| ())).is_some() {
-        return 1;
+/// Scheduling options for the server threads, for hosts that need DNS latency to stay low while
+/// the rest of the app is busy. Applied on Linux and Android only.
+#[repr(C)]
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RustdnsThreadOptions {
+    /// Nice value (-20..19) for the server threads; 0 keeps the inherited priority. Negative
+    /// values need CAP_SYS_NICE.
+    pub nice: i32,
+    /// CPUs the server threads may run on (bit n = CPU n); 0 keeps the inherited affinity.
+    pub cpu_affinity: u64,
+}
+
+/// Like `rustdns_start`, with thread priority and CPU affinity for the server threads.
+///
+/// # Safety
+///
+/// `http_addr` and `udp_bind` must each be null or point to a valid NUL-terminated string, and
+/// `options` must be null or point to a valid `RustdnsThreadOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn rustdns_start_with_options(http_addr: *const c_char, udp_bind: *const c_char, options: *const RustdnsThreadOptions) -> i32 {
+    start(http_addr, udp_bind, options.as_ref().copied())
+}
+
+// The configuration rustdns_start_with_config takes. Unset values keep their RUSTDNS_* environment
+// value or default, as with PiBlockServerBuilder.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartConfig {
+    http_addr: Option<String>,
+    udp_bind: Option<String>,
+    upstream: Option<String>,
+    upstream_timeout_ms: Option<u64>,
+    blocklist_dir: Option<String>,
+    data_dir: Option<String>,
+    blocking_mode: Option<BlockingMode>,
+    block_page_ip: Option<String>,
+    api_tokens: Option<Vec<String>>,
+    log_level: Option<String>,
+    thread_options: Option<RustdnsThreadOptions>,
+    // any other RUSTDNS_* setting; numbers and booleans are taken as their text
+    #[serde(default)]
+    settings: BTreeMap<String, serde_json::Value>,
+}
+
+impl StartConfig {
+    // The server it describes, or the error code and message for what is wrong with it.
+    fn server(self) -> Result<PiBlockServer, (i32, String)> {
+        let invalid = |e: String| (RUSTDNS_ERR_INVALID_CONFIG, e);
+        let mut builder = PiBlockServer::builder();
+        for (addr, what) in [(&self.http_addr, "http_addr"), (&self.udp_bind, "udp_bind")] {
+            let Some(addr) = addr else { continue };
+            let parsed = addr.parse().map_err(|_| (RUSTDNS_ERR_INVALID_ADDRESS, format!("{} {:?} is not an IP address with a port", what, addr)))?;
+            builder = if what == "http_addr" { builder.http_addr(parsed) } else { builder.udp_bind(parsed) };
+        }
+        if let Some(upstream) = self.upstream { builder = builder.upstream(upstream); }
+        if let Some(ms) = self.upstream_timeout_ms { builder = builder.upstream_timeout(Duration::from_millis(ms)); }
+        if let Some(dir) = self.blocklist_dir { builder = builder.blocklist_dir(dir); }
+        if let Some(dir) = self.data_dir { builder = builder.data_dir(dir); }
+        if let Some(mode) = self.blocking_mode { builder = builder.blocking_mode(mode); }
+        if let Some(ip) = self.block_page_ip {
+            builder = builder.block_page_ip(ip.parse().map_err(|_| invalid(format!("block_page_ip {:?} is not an IPv4 address", ip)))?);
+        }
+        if let Some(tokens) = self.api_tokens { builder = builder.api_tokens(tokens); }
+        if let Some(level) = self.log_level {
+            level.parse::<tracing::Level>().map_err(|_| invalid(format!("log_level {:?} must be one of error, warn, info, debug, trace", level)))?;
+            builder = builder.setting("RUSTDNS_LOG_LEVEL", level);
+        }
+        for (name, value) in self.settings {
+            let value = match value {
+                serde_json::Value::String(v) => v,
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => value.to_string(),
+                _ => return Err(invalid(format!("settings.{} must be a string, number or boolean", name))),
+            };
+            builder = builder.setting(name, value);
+        }
+        builder.build().map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// Start the server with its configuration given as a JSON object, in place of RUSTDNS_*
+/// environment variables: `http_addr`, `udp_bind`, `upstream`, `upstream_timeout_ms`,
+/// `blocklist_dir`, `data_dir`, `blocking_mode`, `block_page_ip`, `api_tokens` (an array),
+/// `log_level`, `thread_options` (`nice`, `cpu_affinity`) and `settings`, an object of any other
+/// RUSTDNS_* variable and its value. Unset values keep their environment value or default.
+/// `log_level` installs rustdns' logger at that level as rustdns_init_logging does, unless the host
+/// has a tracing subscriber of its own. Returns
+/// like rustdns_start, or RUSTDNS_ERR_INVALID_CONFIG (RUSTDNS_ERR_INVALID_ADDRESS for the
+/// addresses) with the reason in rustdns_last_error_message.
+///
+/// # Safety
+///
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustdns_start_with_config(config_json: *const c_char) -> i32 {
+    let Some(json) = c_str(config_json) else { return fail(RUSTDNS_ERR_INVALID_CONFIG, "the configuration must be a UTF-8 string") };
+    match serde_json::from_str(json) {
+        Ok(config) => start_with_config(config),
+        Err(e) => fail(RUSTDNS_ERR_INVALID_CONFIG, format!("invalid configuration: {}", e)),
+    }
+}
+
+fn start_with_config(config: StartConfig) -> i32 {
+    let options = config.thread_options;
+    let log_level = config.log_level.as_deref().and_then(|l| l.parse::<tracing::Level>().ok());
+    match config.server() {
+        Ok(server) => {
+            // a host with a logger of its own gets the logs through that
+            if let Some(level) = log_level { let _ = logging::install(level); }
+            let (http, udp, settings) = server.into_parts();
+            launch(http, udp, options, Some(settings))
+        }
+        Err((code, message)) => fail(code, message),
     }
+}
 
-    // create shutdown channel
+unsafe fn start(http_addr: *const c_char, udp_bind: *const c_char, options: Option<RustdnsThreadOptions>) -> i32 {
+    let mut addrs = Vec::new();
+    for (ptr, what, default) in [(http_addr, "control API", "127.0.0.1:9080"), (udp_bind, "DNS", "0.0.0.0:5353")] {
+        let addr = if ptr.is_null() { default.to_string() } else { CStr::from_ptr(ptr).to_string_lossy().into_owned() };
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => addrs.push(addr),
+            Err(_) => return fail(RUSTDNS_ERR_INVALID_ADDRESS, format!("{} address {:?} is not an IP address with a port", what, addr)),
+        }
+    }
+    launch(addrs[0], addrs[1], options, None)
+}
+
+// Start the server with `settings` given in code, if any, in place of the environment's.
+fn launch(http: SocketAddr, udp: SocketAddr, options: Option<RustdnsThreadOptions>, settings: Option<BTreeMap<String, String>>) -> i32 {
+    // held until the starting server is recorded, so concurrent starts can't both go ahead
+    let mut slot = server();
+    if let Some(other) = slot.as_ref() {
+        let what = if other.starting { "starting" } else { "running" };
+        return fail(RUSTDNS_ERR_ALREADY_RUNNING, format!("the server is already {}", what));
+    }
+    let owns_settings = settings.is_some();
+    if let Some(settings) = settings {
+        if let Err(e) = embed::claim_settings(settings) { return fail(RUSTDNS_ERR_ALREADY_RUNNING, e.to_string()) }
+    }
+    // settings given in code are released again if the server doesn't start
+    let failed = |code: i32, message: String| {
+        if owns_settings { embed::release_settings(); }
+        fail(code, message)
+    };
+
+    let config = match config::Config::with_addrs(http, udp) {
+        Ok(config) => config,
+        Err(e) => return failed(RUSTDNS_ERR_INVALID_CONFIG, e),
+    };
     let (tx, rx) = tokio::sync::watch::channel(false);
-    let _ = SHUTDOWN_TX.set(tx.clone());
+    let state = crate::runner::build_state(&config);
+
+    // built here so FFI calls can run on it; the worker and blocking threads get the same
+    // scheduling as the thread that runs the server
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if cfg!(feature = "minimal") { builder.worker_threads(1); }
+    let rt = match builder
+        .enable_all()
+        .on_thread_start(move || { if let Some(opts) = options { apply_thread_options(&opts); } })
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(e) => return failed(RUSTDNS_ERR_STARTUP, format!("creating the runtime failed: {}", e)),
+    };
+    let runtime = rt.handle().clone();
 
     // spawn thread that runs tokio runtime
-    let handle = thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let st_thread = state.clone();
+    let spawned = thread::Builder::new().name("rustdns".to_string()).spawn(move || {
+        if let Some(opts) = options { apply_thread_options(&opts); }
         rt.block_on(async move {
                 // call into runner::run_server
-                crate::runner::run_server(http, udp, rx.clone()).await;
-            });
+                crate::runner::run_server(st_thread, config, rx).await
+            })
     });
+    let handle = match spawned {
+        Ok(handle) => handle,
+        Err(e) => return failed(RUSTDNS_ERR_STARTUP, format!("starting the server thread failed: {}", e)),
+    };
+    let stopped = Arc::new(AtomicBool::new(false));
+    *slot = Some(Running { starting: true, thread: handle, shutdown: tx, state, runtime, dispatching: false, stopped: stopped.clone(), owns_settings });
+    drop(slot);
 
-    if let Some(m) = THREAD_HANDLE.get() {
-        if let Ok(mut guard) = m.lock() {
-            *guard = Some(handle);
+    // the control API binds before the DNS listener: the server is up once that is bound, and
+    // failed if its thread ended (binding the control API fails the server) or the DNS listener
+    // failed. The slot is only locked to look, so rustdns_stop can take the server meanwhile.
+    loop {
+        let mut server = server();
+        let Some(running) = server.as_mut().filter(|r| Arc::ptr_eq(&r.stopped, &stopped)) else {
+            // rustdns_stop stopped it and released its settings
+            return fail(RUSTDNS_ERR_STARTUP, "the server was stopped while starting");
+        };
+        if running.state.health.udp_bound.load(Ordering::Relaxed) {
+            running.starting = false;
+            if QUERY_CALLBACK.lock().unwrap_or_else(|e| e.into_inner()).is_some() {
+                start_dispatch(running);
+            }
+            return RUSTDNS_OK;
+        }
+        let udp_error = running.state.health.udp_error.lock().unwrap().clone();
+        if running.thread.is_finished() || udp_error.is_some() {
+            let Running { thread, shutdown, .. } = server.take().expect("checked above");
+            drop(server);
+            let _ = shutdown.send(true);
+            let result = thread.join();
+            if let Some(e) = udp_error { return failed(RUSTDNS_ERR_BIND, e) }
+            let (code, message) = match result {
+                Err(panic) => (RUSTDNS_ERR_STARTUP, panic.downcast_ref::<String>().cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "the server thread panicked".to_string())),
+                Ok(Err(e)) if e.is::<runner::BindError>() => (RUSTDNS_ERR_BIND, format!("{:#}", e)),
+                Ok(Err(e)) => (RUSTDNS_ERR_STARTUP, format!("{:#}", e)),
+                Ok(Ok(())) => (RUSTDNS_ERR_STARTUP, "the server stopped while starting".to_string()),
+            };
+            return failed(code, message);
         }
+        drop(server);
+        thread::sleep(Duration::from_millis(20));
     }
-    0
 }
 
-#[no_mangle]
-pub extern "C" fn rustdns_stop() -> i32 {
-    if let Some(tx) = SHUTDOWN_TX.get() {
-        if tx.send(true).is_ok() {
-            // try to join thread
-            if let Some(m) = THREAD_HANDLE.get() {
-                if let Ok(mut guard) = m.lock() {
-                    if let Some(h) = guard.take() {
-                        let _ = h.join();
-                    }
-                }
+// Apply nice value and CPU affinity to the calling thread. Failures (e.g. missing CAP_SYS_NICE)
+// are logged and otherwise ignored.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn apply_thread_options(opts: &RustdnsThreadOptions) {
+    // SAFETY: plain syscalls on the calling thread; the cpu_set_t is zero-initialized and only
+    // manipulated through the libc helpers.
+    unsafe {
+        if opts.nice != 0 {
+            let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+            if libc::setpriority(libc::PRIO_PROCESS, tid, opts.nice) != 0 {
+                tracing::warn!("setting nice {} failed: {}", opts.nice, std::io::Error::last_os_error());
             }
-            return 0;
         }
+        if opts.cpu_affinity != 0 {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for cpu in (0..64).filter(|c| opts.cpu_affinity & (1u64 << c) != 0) {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                tracing::warn!("setting CPU affinity {:#x} failed: {}", opts.cpu_affinity, std::io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn apply_thread_options(_opts: &RustdnsThreadOptions) {}
+
+/// Pause blocking for `seconds` (0 = until resumed). Returns 0 on success,
+/// RUSTDNS_ERR_NOT_RUNNING if not running.
+#[no_mangle]
+pub extern "C" fn rustdns_pause(seconds: u32) -> i32 {
+    let state = current_state();
+    match state {
+        Some(st) => {
+            let dur = (seconds > 0).then(|| std::time::Duration::from_secs(u64::from(seconds)));
+            st.pause_blocking(dur);
+            RUSTDNS_OK
+        }
+        None => not_running(),
+    }
+}
+
+/// Resume blocking after rustdns_pause (or a pause made over the API). Returns 0 on success,
+/// RUSTDNS_ERR_NOT_RUNNING if not running.
+#[no_mangle]
+pub extern "C" fn rustdns_resume() -> i32 {
+    let state = current_state();
+    match state {
+        Some(st) => {
+            st.resume_blocking();
+            RUSTDNS_OK
+        }
+        None => not_running(),
+    }
+}
+
+/// Block `pattern` for `group`, or the `custom` group if null, as POST /add does. `pattern` is an
+/// exact name (`ads.example.com`, not its subdomains), `*.example.com` for the subdomains,
+/// `ads.*` for names starting with `ads.`, or a glob with `*` elsewhere (`ads.*.example.com`);
+/// it is lowercased and a final dot dropped. Regular expressions aren't supported. Returns 0 on
+/// success, RUSTDNS_ERR_NOT_RUNNING if not running, 2 if an argument is invalid, including a
+/// pattern that isn't one of these.
+///
+/// # Safety
+///
+/// `pattern` and `group` must each be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustdns_add_pattern(pattern: *const c_char, group: *const c_char) -> i32 {
+    let Some(pattern) = c_str(pattern) else { return fail(RUSTDNS_ERR_INVALID_ARGUMENT, "pattern must be a UTF-8 string") };
+    let mut body = serde_json::json!({ "pattern": pattern });
+    if !group.is_null() {
+        let Some(group) = c_str(group) else { return fail(RUSTDNS_ERR_INVALID_ARGUMENT, "group must be a UTF-8 string") };
+        body["group"] = group.into();
+    }
+    status(call(|st| control::http_add(st, axum::Json(body))))
+}
+
+/// Remove a rule added with rustdns_add_pattern or POST /add, or read from a list file. Returns 0
+/// if it was removed, RUSTDNS_ERR_NOT_RUNNING if not running, 2 if there was no such rule.
+///
+/// # Safety
+///
+/// `pattern` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustdns_remove_pattern(pattern: *const c_char) -> i32 {
+    let Some(pattern) = c_str(pattern) else { return fail(RUSTDNS_ERR_INVALID_ARGUMENT, "pattern must be a UTF-8 string") };
+    status(call(|st| control::http_remove(st, axum::Json(serde_json::json!({ "pattern": pattern })))))
+}
+
+/// Reload the list files and return once they are in use. Returns 0 on success,
+/// RUSTDNS_ERR_NOT_RUNNING if not running, 2 if the lists couldn't be read (the previous ones stay
+/// in use).
+#[no_mangle]
+pub extern "C" fn rustdns_reload_lists() -> i32 {
+    match call(control::http_reload) {
+        Some(v) => match v.get("error") {
+            Some(e) => fail(RUSTDNS_ERR_INVALID_ARGUMENT, e.as_str().unwrap_or("reloading the lists failed")),
+            None => RUSTDNS_OK,
+        },
+        None => not_running(),
+    }
+}
+
+/// Set how blocked names are answered: "nx", "null", "nodata" or "redirect". `block_ip` is the
+/// IPv4 address redirect answers with; null keeps the current one, and it is ignored for the
+/// other modes. Returns 0 on success, RUSTDNS_ERR_NOT_RUNNING if not running, 2 if an argument is
+/// invalid.
+///
+/// # Safety
+///
+/// `mode` and `block_ip` must each be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustdns_set_mode(mode: *const c_char, block_ip: *const c_char) -> i32 {
+    let mode = match c_str(mode).map(config::parse_mode) {
+        Some(Ok(mode)) => mode,
+        Some(Err(e)) => return fail(RUSTDNS_ERR_INVALID_ARGUMENT, format!("mode {}", e)),
+        None => return fail(RUSTDNS_ERR_INVALID_ARGUMENT, "mode must be a UTF-8 string"),
+    };
+    let mut body = serde_json::json!({ "mode": mode });
+    if !block_ip.is_null() && mode == BlockingMode::Redirect {
+        let Some(ip) = c_str(block_ip).filter(|ip| ip.parse::<std::net::Ipv4Addr>().is_ok()) else {
+            return fail(RUSTDNS_ERR_INVALID_ARGUMENT, "block_ip must be an IPv4 address");
+        };
+        body["block_ip"] = ip.into();
+    }
+    status(call(|st| control::http_mode(st, axum::Json(body))))
+}
+
+/// The server's counters as a JSON object (the fields of `ServerStats`: queries, blocked, cache
+/// hits/misses/entries, uptime and the blocking state), or null if not running. Free the string
+/// with `rustdns_free_string`.
+#[no_mangle]
+pub extern "C" fn rustdns_stats_json() -> *mut c_char {
+    let Some(st) = current_state() else { return std::ptr::null_mut() };
+    let json = serde_json::to_string(&ServerStats::of(&st)).unwrap_or_default();
+    CString::new(json).map(CString::into_raw).unwrap_or(std::ptr::null_mut())
+}
+
+/// Free a string returned by rustdns. Null is ignored.
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by rustdns that hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rustdns_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// 1 if the server is running, else 0 (also while a rustdns_start* call is still starting it).
+#[no_mangle]
+pub extern "C" fn rustdns_is_running() -> i32 {
+    i32::from(current_state().is_some())
+}
+
+/// 1 if blocking is on, 0 if it is paused, -1 if not running.
+#[no_mangle]
+pub extern "C" fn rustdns_blocking_enabled() -> i32 {
+    match current_state() {
+        Some(st) => i32::from(st.blocking.is_enabled()),
+        None => -1,
     }
-    1
+}
+
+/// Log to stderr at `max_level`: 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace, or 0 for
+/// RUSTDNS_LOG_LEVEL (default info). The library doesn't log anywhere until this,
+/// rustdns_set_log_callback or a `log_level` in rustdns_start_with_config; called again, it changes
+/// the level and sends the logs back to stderr. Returns 0, RUSTDNS_ERR_INVALID_ARGUMENT for an
+/// unknown level, or RUSTDNS_ERR_LOGGER if the process has a tracing subscriber of its own.
+#[no_mangle]
+pub extern "C" fn rustdns_init_logging(max_level: i32) -> i32 {
+    // SAFETY: no callback, so the user data is never used
+    unsafe { rustdns_set_log_callback(None, std::ptr::null_mut(), max_level) }
+}
+
+/// Like rustdns_init_logging, with each log line passed to `callback` (with `user_data`) instead
+/// of written to stderr; null goes back to stderr. Once this returns the previous callback isn't
+/// called again. What rustdns logs while a callback runs goes to stderr, and calling this from
+/// the callback fails with RUSTDNS_ERR_INVALID_ARGUMENT.
+///
+/// # Safety
+///
+/// `callback` must be safe to call from any thread with `user_data` until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn rustdns_set_log_callback(callback: Option<RustdnsLogCallback>, user_data: *mut c_void, max_level: i32) -> i32 {
+    let Some(level) = logging::level_from_number(max_level) else {
+        return fail(RUSTDNS_ERR_INVALID_ARGUMENT, "max_level must be 0 to 5");
+    };
+    if logging::in_callback() {
+        return fail(RUSTDNS_ERR_INVALID_ARGUMENT, "the log callback can't be changed from within it");
+    }
+    if let Err(e) = logging::install(level) {
+        return fail(RUSTDNS_ERR_LOGGER, e);
+    }
+    logging::set_callback(callback, user_data);
+    RUSTDNS_OK
+}
+
+/// A query as passed to the query callback. The strings are valid during the call only.
+#[repr(C)]
+pub struct RustdnsQueryEvent {
+    /// Query log id, as in GET /queries.
+    pub id: u64,
+    /// Unix time, in seconds.
+    pub time: u64,
+    /// Queried name, lowercase and without the final dot.
+    pub name: *const c_char,
+    /// Record type, e.g. "A" or "AAAA".
+    pub qtype: *const c_char,
+    /// Address the query came from.
+    pub client: *const c_char,
+    /// What became of it: "blocked", "audited", "cached", "forwarded", "local", "refused",
+    /// "canary", "servfail" or "error".
+    pub action: *const c_char,
+    /// Time taken to answer, in milliseconds.
+    pub latency_ms: f64,
+}
+
+/// Function the host gets query events through, with the `user_data` it was registered with.
+pub type RustdnsQueryCallback = extern "C" fn(event: *const RustdnsQueryEvent, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct QueryCallback {
+    func: RustdnsQueryCallback,
+    user_data: *mut c_void,
+}
+
+// SAFETY: user_data is only handed back to the host's callback, which rustdns_set_query_callback
+// requires to be callable from any thread.
+unsafe impl Send for QueryCallback {}
+
+static QUERY_CALLBACK: Mutex<Option<QueryCallback>> = Mutex::new(None);
+
+// Held while the callback runs, so rustdns_set_query_callback and rustdns_stop can wait for a
+// call in progress to end.
+static DELIVERING: Mutex<()> = Mutex::new(());
+
+const DISPATCH_THREAD: &str = "rustdns-query-callback";
+
+// Start passing the server's queries to the query callback, on a thread of its own so a slow
+// callback never holds up DNS answers. It ends when the server does.
+fn start_dispatch(running: &mut Running) {
+    if running.dispatching { return }
+    let mut queries = running.state.query_log.subscribe();
+    let stopped = running.stopped.clone();
+    let spawned = thread::Builder::new().name(DISPATCH_THREAD.to_string()).spawn(move || loop {
+        let entry = match queries.blocking_recv() {
+            Ok(entry) => entry,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                tracing::warn!("query callback fell behind; {} query events skipped", n);
+                continue;
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+        };
+        let _delivering = DELIVERING.lock().unwrap_or_else(|e| e.into_inner());
+        if stopped.load(Ordering::Acquire) { return }
+        let Some(callback) = *QUERY_CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) else { continue };
+        let text = |s: &str| CString::new(s).unwrap_or_default();
+        let (name, qtype, client, action) = (text(&entry.name), text(&entry.qtype), text(&entry.client), text(entry.action));
+        let event = RustdnsQueryEvent {
+            id: entry.id,
+            time: entry.time,
+            name: name.as_ptr(),
+            qtype: qtype.as_ptr(),
+            client: client.as_ptr(),
+            action: action.as_ptr(),
+            latency_ms: entry.elapsed_ms,
+        };
+        (callback.func)(&event, callback.user_data);
+    });
+    match spawned {
+        Ok(_) => running.dispatching = true,
+        Err(e) => tracing::warn!("starting the query callback thread failed: {}", e),
+    }
+}
+
+/// Have `callback` called with each query the server answers, with `user_data` passed through,
+/// or stop the calls with a null `callback`. Calls come one at a time from a thread of rustdns'
+/// own; a callback that falls more than 1024 queries behind misses some. The setting outlives
+/// rustdns_stop and applies to servers started later. Once this returns the previous callback
+/// won't be called again, unless this is called from within it. Returns 0.
+///
+/// # Safety
+///
+/// `callback` must be safe to call from another thread with `user_data` until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn rustdns_set_query_callback(callback: Option<RustdnsQueryCallback>, user_data: *mut c_void) -> i32 {
+    *QUERY_CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) = callback.map(|func| QueryCallback { func, user_data });
+    if callback.is_some() {
+        if let Some(running) = server().as_mut() { start_dispatch(running); }
+    }
+    // wait for a call of the previous callback to end; the dispatch thread can't wait for itself
+    if thread::current().name() != Some(DISPATCH_THREAD) {
+        drop(DELIVERING.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+    RUSTDNS_OK
+}
+
+/// Stop the server and wait until it has: the listeners close, DNS queries in flight get up to
+/// the upstream timeout to be answered, and state is saved. A server still starting is stopped
+/// too, and its rustdns_start* call fails with RUSTDNS_ERR_STARTUP. Returns 0 on success,
+/// RUSTDNS_ERR_NOT_RUNNING if not running.
+#[no_mangle]
+pub extern "C" fn rustdns_stop() -> i32 {
+    // taken out first, so calls made while it stops see no server and a new start can follow
+    let Some(running) = SERVER.lock().unwrap_or_else(|e| e.into_inner()).take() else { return not_running() };
+    let _ = running.shutdown.send(true);
+    drop(running.state);
+    let _ = running.thread.join();
+    // no query callback after this returns
+    let _delivering = (thread::current().name() != Some(DISPATCH_THREAD)).then(|| DELIVERING.lock().unwrap_or_else(|e| e.into_inner()));
+    running.stopped.store(true, Ordering::Release);
+    if running.owns_settings { embed::release_settings(); }
+    RUSTDNS_OK
+}
+
+/// Why the last rustdns call on this thread that failed did, or null if none has. Valid until the
+/// next call that fails on this thread; don't free it.
+#[no_mangle]
+pub extern "C" fn rustdns_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(std::ptr::null(), |m| m.as_ptr()))
 }
@@ -0,0 +1,93 @@
+use std::time::Duration;
+#[cfg(feature = "webhook")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Announces the resolver coming up and going down, so automation can point the router's DHCP DNS
+// option at a fallback resolver while PiBlock is away and back once it returns.
+// RUSTDNS_LIFECYCLE_HOOK is a shell command run with the event (`start` or `stop`) as `$1` and in
+// RUSTDNS_LIFECYCLE_EVENT; RUSTDNS_LIFECYCLE_WEBHOOK is a URL that gets a JSON POST
+// `{"event":..,"version":..,"dns":..,"time":..}`. Each gets RUSTDNS_LIFECYCLE_TIMEOUT_SECS
+// (default 10) before it is abandoned, so a hung hook can't hold up shutdown.
+pub struct LifecycleHooks {
+    pub command: Option<String>,
+    pub webhook: Option<String>,
+    pub timeout: Duration,
+    #[cfg(feature = "webhook")]
+    http: reqwest::Client,
+}
+
+impl LifecycleHooks {
+    pub fn from_env() -> Self {
+        let var = |key: &str| crate::envvars::var(key).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let timeout = Duration::from_secs(var("RUSTDNS_LIFECYCLE_TIMEOUT_SECS").and_then(|v| v.parse().ok()).unwrap_or(10));
+        let webhook = var("RUSTDNS_LIFECYCLE_WEBHOOK");
+        #[cfg(not(feature = "webhook"))]
+        if webhook.is_some() {
+            tracing::warn!("RUSTDNS_LIFECYCLE_WEBHOOK is set but this build has no webhook support; ignoring it");
+        }
+        LifecycleHooks {
+            command: var("RUSTDNS_LIFECYCLE_HOOK"),
+            webhook,
+            timeout,
+            #[cfg(feature = "webhook")]
+            http: reqwest::Client::builder().timeout(timeout).build().unwrap_or_default(),
+        }
+    }
+
+    // Run the hook and send the webhook for `event`, waiting for both. `dns` is the DNS listen
+    // address. Failures are logged and otherwise ignored.
+    pub async fn announce(&self, event: &str, dns: &str) {
+        if self.command.is_none() && self.webhook.is_none() { return }
+        tracing::info!("announcing resolver {}", event);
+        tokio::join!(self.run_command(event, dns), self.send_webhook(event, dns));
+    }
+
+    async fn run_command(&self, event: &str, dns: &str) {
+        let Some(cmd) = &self.command else { return };
+        #[cfg(unix)]
+        let mut command = {
+            let mut c = tokio::process::Command::new("sh");
+            c.arg("-c").arg(cmd).arg("rustdns").arg(event);
+            c
+        };
+        #[cfg(not(unix))]
+        let mut command = {
+            let mut c = tokio::process::Command::new("cmd");
+            c.arg("/C").arg(cmd).arg(event);
+            c
+        };
+        command.env("RUSTDNS_LIFECYCLE_EVENT", event).env("RUSTDNS_LIFECYCLE_DNS", dns).kill_on_drop(true);
+        let mut child = match command.spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::warn!("lifecycle hook for {} failed to start: {}", event, e);
+                return;
+            }
+        };
+        match tokio::time::timeout(self.timeout, child.wait()).await {
+            Ok(Ok(status)) if status.success() => {}
+            Ok(Ok(status)) => tracing::warn!("lifecycle hook for {} exited with {}", event, status),
+            Ok(Err(e)) => tracing::warn!("lifecycle hook for {} failed: {}", event, e),
+            Err(_) => tracing::warn!("lifecycle hook for {} timed out after {}s; killed", event, self.timeout.as_secs()),
+        }
+    }
+
+    #[cfg(feature = "webhook")]
+    async fn send_webhook(&self, event: &str, dns: &str) {
+        let Some(url) = &self.webhook else { return };
+        let body = serde_json::json!({
+            "event": event,
+            "version": env!("CARGO_PKG_VERSION"),
+            "dns": dns,
+            "time": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        });
+        match self.http.post(url).json(&body).send().await {
+            Ok(resp) if resp.status().is_success() => {}
+            Ok(resp) => tracing::warn!("lifecycle webhook for {} returned {}", event, resp.status()),
+            Err(e) => tracing::warn!("lifecycle webhook for {} failed: {}", event, e),
+        }
+    }
+
+    #[cfg(not(feature = "webhook"))]
+    async fn send_webhook(&self, _event: &str, _dns: &str) {}
+}
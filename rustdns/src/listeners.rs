@@ -0,0 +1,91 @@
+use crate::blocklist::BlockMatch;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+
+// What the queries a DNS listener receives are blocked by. Listeners share everything else: the
+// lists, allowlist, cache and upstreams.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ListenerPolicy {
+    // every enabled group, as on RUSTDNS_UDP_BIND
+    #[default]
+    All,
+    // only rules of these groups or categories; matches that come from no list (DGA) still block
+    Groups(BTreeSet<String>),
+    // nothing is blocked
+    Off,
+}
+
+impl ListenerPolicy {
+    // `all`, `none`, or group names joined with `+` (`ads+malware`).
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "all" => Some(ListenerPolicy::All),
+            "none" => Some(ListenerPolicy::Off),
+            groups => {
+                let groups: BTreeSet<String> = groups.split('+').map(|g| g.trim().to_string()).collect();
+                if groups.iter().any(String::is_empty) { return None }
+                Some(ListenerPolicy::Groups(groups))
+            }
+        }
+    }
+
+    pub fn blocks(&self) -> bool {
+        *self != ListenerPolicy::Off
+    }
+
+    // The groups whose rules apply, None for every group.
+    pub fn groups(&self) -> Option<&BTreeSet<String>> {
+        match self {
+            ListenerPolicy::Groups(groups) => Some(groups),
+            _ => None,
+        }
+    }
+
+    // `m` with only the origins this policy applies; None when none is left.
+    pub fn narrow(&self, mut m: BlockMatch) -> Option<BlockMatch> {
+        if let Some(groups) = self.groups() {
+            m.origins.retain(|o| groups.contains(&o.group));
+            if m.origins.is_empty() { return None }
+        }
+        Some(m)
+    }
+}
+
+impl std::fmt::Display for ListenerPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenerPolicy::All => f.write_str("all"),
+            ListenerPolicy::Off => f.write_str("none"),
+            ListenerPolicy::Groups(groups) => f.write_str(&groups.iter().cloned().collect::<Vec<_>>().join("+")),
+        }
+    }
+}
+
+impl Serialize for ListenerPolicy {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+// A DNS address the server listens on, with the policy for what it receives.
+#[derive(Clone, Debug, Serialize)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub policy: ListenerPolicy,
+}
+
+// RUSTDNS_UDP_LISTENERS: DNS listeners besides RUSTDNS_UDP_BIND, comma separated, each an address
+// with an optional `=<policy>`, e.g. `192.168.2.2:53=ads+malware,192.168.3.2:53=none`.
+pub fn listeners_from_env() -> Result<Vec<Listener>, String> {
+    let Ok(spec) = crate::envvars::var("RUSTDNS_UDP_LISTENERS") else { return Ok(Vec::new()) };
+    let mut listeners = Vec::new();
+    for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let (addr, policy) = item.split_once('=').unwrap_or((item, ""));
+        let addr = addr.trim().parse().map_err(|_| format!("RUSTDNS_UDP_LISTENERS: {:?} is not an IP address with a port", addr.trim()))?;
+        let policy = ListenerPolicy::parse(policy)
+            .ok_or_else(|| format!("RUSTDNS_UDP_LISTENERS: policy {:?} of {} must be all, none or groups joined with +", policy, addr))?;
+        listeners.push(Listener { addr, policy });
+    }
+    Ok(listeners)
+}
@@ -0,0 +1,140 @@
+use std::cell::Cell;
+use std::ffi::CString;
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_void};
+use std::sync::{Mutex, RwLock};
+use tracing::level_filters::LevelFilter;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, Registry};
+
+// Logging for programs that embed the library. The library doesn't install a tracing subscriber
+// on its own: Rust hosts see its events through their own subscriber, and FFI hosts opt in with
+// rustdns_init_logging (stderr) or rustdns_set_log_callback, which install this one.
+
+/// Function the host gets log lines through: the level (1 = error .. 5 = trace), the formatted
+/// line without its newline (valid during the call only), and the `user_data` it was registered
+/// with.
+pub type RustdnsLogCallback = extern "C" fn(level: i32, message: *const c_char, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct LogCallback {
+    func: RustdnsLogCallback,
+    user_data: *mut c_void,
+}
+
+// SAFETY: user_data is only handed back to the host's callback, which rustdns_set_log_callback
+// requires to be callable from any thread.
+unsafe impl Send for LogCallback {}
+unsafe impl Sync for LogCallback {}
+
+// Where log lines go: the host's callback, or stderr when there is none. Read-locked while the
+// callback runs, so replacing it waits for calls in progress.
+static SINK: RwLock<Option<LogCallback>> = RwLock::new(None);
+
+// Level filter of the installed subscriber, once installed.
+static FILTER: Mutex<Option<reload::Handle<LevelFilter, Registry>>> = Mutex::new(None);
+
+thread_local! {
+    // set while this thread runs the log callback; what it logs meanwhile goes to stderr
+    static IN_CALLBACK: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn in_callback() -> bool {
+    IN_CALLBACK.with(Cell::get)
+}
+
+// Level for the C API's numbering: 1 = error .. 5 = trace; 0 is RUSTDNS_LOG_LEVEL (default info).
+pub fn level_from_number(n: i32) -> Option<Level> {
+    match n {
+        0 => Some(crate::runner::log_level_from_env()),
+        1 => Some(Level::ERROR),
+        2 => Some(Level::WARN),
+        3 => Some(Level::INFO),
+        4 => Some(Level::DEBUG),
+        5 => Some(Level::TRACE),
+        _ => None,
+    }
+}
+
+fn level_number(level: Level) -> i32 {
+    match level {
+        Level::ERROR => 1,
+        Level::WARN => 2,
+        Level::INFO => 3,
+        Level::DEBUG => 4,
+        Level::TRACE => 5,
+    }
+}
+
+// Install the subscriber, or set its level if it already is. Fails if the host installed another.
+pub fn install(level: Level) -> Result<(), String> {
+    let mut filter = FILTER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(handle) = filter.as_ref() {
+        return handle.reload(LevelFilter::from_level(level)).map_err(|e| e.to_string());
+    }
+    let (layer, handle) = reload::Layer::new(LevelFilter::from_level(level));
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(tracing_subscriber::fmt::layer().with_ansi(false).with_writer(HostWriter))
+        .try_init()
+        .map_err(|_| "another logger is installed in this process".to_string())?;
+    *filter = Some(handle);
+    Ok(())
+}
+
+// Send log lines to `callback`, or to stderr if None. Waits for calls of the previous callback
+// in progress to end.
+pub fn set_callback(callback: Option<RustdnsLogCallback>, user_data: *mut c_void) {
+    *SINK.write().unwrap_or_else(|e| e.into_inner()) = callback.map(|func| LogCallback { func, user_data });
+}
+
+struct HostWriter;
+
+// One formatted event, delivered when the formatter is done with it.
+struct Line {
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl<'a> MakeWriter<'a> for HostWriter {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Line {
+        Line { level: Level::INFO, buf: Vec::new() }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Line {
+        Line { level: *meta.level(), buf: Vec::new() }
+    }
+}
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        if self.buf.is_empty() { return }
+        if !in_callback() {
+            let sink = SINK.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(callback) = *sink {
+                let text = String::from_utf8_lossy(&self.buf);
+                let message = CString::new(text.trim_end().replace('\0', " ")).unwrap_or_default();
+                IN_CALLBACK.with(|c| c.set(true));
+                (callback.func)(level_number(self.level), message.as_ptr(), callback.user_data);
+                IN_CALLBACK.with(|c| c.set(false));
+                return;
+            }
+        }
+        let _ = io::stderr().write_all(&self.buf);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::store::{data_dir, read_json, write_json_synced};
+use crate::tz::{civil_from_days, days_from_civil, weekday, Zone, DAY};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Periods GET /stats/longterm serves, and their length in days.
+pub const LONGTERM_PERIODS: [(&str, i64); 2] = [("day", 1), ("week", 7)];
+
+// Counts over one day or week, starting on `day` (days since 1970-01-01, in the stats zone).
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Rollup {
+    pub day: i64,
+    pub queries: u64,
+    pub blocked: u64,
+    pub audited: u64,
+    pub rate_limited: u64,
+}
+
+impl Rollup {
+    fn add(&mut self, other: &Counts) {
+        self.queries += other.queries;
+        self.blocked += other.blocked;
+        self.audited += other.audited;
+        self.rate_limited += other.rate_limited;
+    }
+}
+
+// The since-start counters the rollups are built from.
+#[derive(Clone, Copy, Default)]
+pub struct Counts {
+    pub queries: u64,
+    pub blocked: u64,
+    pub audited: u64,
+    pub rate_limited: u64,
+}
+
+// Daily and weekly rollups, oldest first, as saved in `longterm.json`.
+#[derive(Default, Serialize, Deserialize)]
+struct LongTermData {
+    days: VecDeque<Rollup>,
+    weeks: VecDeque<Rollup>,
+}
+
+// A period's rollups for GET /stats/longterm.
+#[derive(Serialize)]
+pub struct LongTermView {
+    pub period: &'static str,
+    pub timezone: String,
+    pub buckets: Vec<RollupView>,
+    pub total: RollupView,
+}
+
+#[derive(Serialize)]
+pub struct RollupView {
+    // first day of the period, YYYY-MM-DD
+    pub date: String,
+    pub queries: u64,
+    pub blocked: u64,
+    pub audited: u64,
+    pub rate_limited: u64,
+}
+
+pub fn date_string(day: i64) -> String {
+    let (y, m, d) = civil_from_days(day);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+// `YYYY-MM-DD` as days since 1970-01-01.
+pub fn parse_date(s: &str) -> Option<i64> {
+    let mut parts = s.trim().splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (y, m, d) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) { return None }
+    let days = days_from_civil(y, m, d);
+    // rejects dates such as 2026-02-30
+    (civil_from_days(days) == (y, m, d)).then_some(days)
+}
+
+// Query totals per day and per week kept for months, so a reboot or power cut doesn't erase the
+// history the way it would the in-memory counters. Days and weeks are in RUSTDNS_STATS_TZ (an
+// IANA zone, `+02:00` or `UTC`; default the system zone), weeks start on Monday. The counters are
+// sampled every minute along with the stats history, and the rollups are written to
+// `longterm.json` in the data directory every 5 minutes and on shutdown, synced to disk so at
+// most those 5 minutes are lost. RUSTDNS_LONGTERM_DAYS (default 400) and RUSTDNS_LONGTERM_WEEKS
+// (default 520) bound how many are kept; 0 for both turns it off.
+//
+// A period takes under 140 bytes of JSON (about 85 with a few million queries a day), so the file
+// stays under 130 KB at the defaults and grows linearly with the two settings. That is small
+// enough to hold in memory and rewrite whole, atomically through `store` like the rest of the
+// state, which keeps SQLite's C build and a database file format out of the server.
+pub struct LongTermStats {
+    path: PathBuf,
+    pub zone: Zone,
+    pub keep_days: usize,
+    pub keep_weeks: usize,
+    data: Mutex<LongTermData>,
+    // counters at the last sample
+    sampled: Mutex<Counts>,
+}
+
+impl LongTermStats {
+    pub fn from_env() -> Self {
+        let num = |key: &str, default: usize| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        let zone = match crate::envvars::var("RUSTDNS_STATS_TZ") {
+            Ok(name) => Zone::parse(&name).unwrap_or_else(|e| {
+                tracing::warn!("RUSTDNS_STATS_TZ: {}; using the system zone", e);
+                Zone::local()
+            }),
+            Err(_) => Zone::local(),
+        };
+        let path = data_dir().join("longterm.json");
+        let data = match read_json::<LongTermData>(&path) {
+            Ok(d) => d.unwrap_or_default(),
+            Err(e) => {
+                // keep the damaged file for recovery instead of overwriting it on the next save
+                let aside = path.with_extension(format!("json.damaged-{}", now_secs()));
+                tracing::warn!("unreadable long-term stats {} ({:?}); moved to {} and starting over", path.display(), e, aside.display());
+                let _ = std::fs::rename(&path, &aside);
+                LongTermData::default()
+            }
+        };
+        LongTermStats {
+            path,
+            zone,
+            keep_days: num("RUSTDNS_LONGTERM_DAYS", 400),
+            keep_weeks: num("RUSTDNS_LONGTERM_WEEKS", 520),
+            data: Mutex::new(data),
+            sampled: Mutex::new(Counts::default()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.keep_days > 0 || self.keep_weeks > 0
+    }
+
+    // Today in the stats zone, as days since 1970-01-01.
+    fn today(&self) -> i64 {
+        let now = now_secs() as i64;
+        (now + self.zone.offset_at(now)).div_euclid(DAY)
+    }
+
+    // Add what the since-start counters went up by since the last sample.
+    pub fn sample(&self, counts: Counts) {
+        if !self.enabled() { return }
+        let mut sampled = self.sampled.lock().unwrap();
+        let delta = Counts {
+            queries: counts.queries.saturating_sub(sampled.queries),
+            blocked: counts.blocked.saturating_sub(sampled.blocked),
+            audited: counts.audited.saturating_sub(sampled.audited),
+            rate_limited: counts.rate_limited.saturating_sub(sampled.rate_limited),
+        };
+        *sampled = counts;
+        let today = self.today();
+        let mut data = self.data.lock().unwrap();
+        let LongTermData { days, weeks } = &mut *data;
+        for (series, start, len, keep) in [(days, today, 1, self.keep_days), (weeks, today - weekday(today) as i64, 7, self.keep_weeks)] {
+            if keep == 0 { series.clear(); continue }
+            // after the clock went back, counts go to the latest period
+            if series.back().is_none_or(|r| r.day < start) {
+                series.push_back(Rollup { day: start, ..Default::default() });
+            }
+            while series.front().is_some_and(|r| r.day <= start - len * keep as i64) { series.pop_front(); }
+            series.back_mut().unwrap().add(&delta);
+        }
+    }
+
+    // The rollups of `period` (see LONGTERM_PERIODS) whose period overlaps `from`..=`to` (days),
+    // oldest first, with periods without data filled in as zeros. `from` defaults to the oldest
+    // stored period and `to` to today.
+    pub fn view(&self, period: &str, from: Option<i64>, to: Option<i64>) -> Option<LongTermView> {
+        let (period, len) = LONGTERM_PERIODS.iter().copied().find(|(p, _)| *p == period)?;
+        let align = |day: i64| if len == 7 { day - weekday(day) as i64 } else { day };
+        let data = self.data.lock().unwrap();
+        let (series, keep) = if len == 7 { (&data.weeks, self.keep_weeks) } else { (&data.days, self.keep_days) };
+        let last = align(to.unwrap_or_else(|| self.today()));
+        // never more periods than are kept
+        let oldest = last - len * (keep as i64).saturating_sub(1);
+        let first = align(from.or_else(|| series.front().map(|r| r.day)).unwrap_or(last)).max(oldest);
+        let mut stored = series.iter().filter(|r| r.day >= first).peekable();
+        let mut buckets = Vec::new();
+        let mut total = Rollup::default();
+        let mut day = first;
+        while day <= last {
+            while stored.next_if(|r| r.day < day).is_some() {}
+            let r = stored.next_if(|r| r.day == day).copied().unwrap_or(Rollup { day, ..Default::default() });
+            total.add(&Counts { queries: r.queries, blocked: r.blocked, audited: r.audited, rate_limited: r.rate_limited });
+            buckets.push(rollup_view(&r));
+            day += len;
+        }
+        Some(LongTermView {
+            period,
+            timezone: self.zone.name.clone(),
+            total: RollupView { date: date_string(first), ..rollup_view(&total) },
+            buckets,
+        })
+    }
+
+    pub fn save(&self) {
+        if !self.enabled() { return }
+        let data = self.data.lock().unwrap();
+        if let Err(e) = write_json_synced(&self.path, &*data) {
+            tracing::warn!("saving long-term stats to {} failed: {:?}", self.path.display(), e);
+        }
+    }
+}
+
+fn rollup_view(r: &Rollup) -> RollupView {
+    RollupView { date: date_string(r.day), queries: r.queries, blocked: r.blocked, audited: r.audited, rate_limited: r.rate_limited }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
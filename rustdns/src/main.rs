@@ -1,20 +1,98 @@
+mod acme;
+mod alerts;
+mod allowlist;
+mod api;
+mod apilimits;
+mod backup;
+mod auth;
 mod blocklist;
+mod bloom;
+mod brands;
+mod cache;
+mod canary;
+mod categories;
+mod clients;
+mod compiled;
+mod config;
+mod configfile;
+mod cors;
+mod dashboard;
 mod control;
+mod dga;
+mod envvars;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod health;
+mod history;
+mod hits;
+mod https;
+mod lifecycle;
+mod listeners;
+mod longterm;
+mod metrics;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod openapi;
+mod prewarm;
+mod querylog;
+mod querystore;
 mod server;
 mod state;
+mod store;
+mod supervisor;
+mod ratelimit;
+mod rdap;
+mod resources;
+mod review;
+mod savedstate;
+mod schedule;
+mod rpz;
+mod selfnames;
+mod settings;
+mod signals;
+mod systemd;
+mod tls;
+mod top;
+mod totals;
+mod trace;
+mod tunnel;
+mod tz;
+mod watcher;
+mod wildcard;
 mod runner;
 
 use anyhow::Result;
 use std::env;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let http_addr = env::var("RUSTDNS_HTTP_ADDR").unwrap_or_else(|_| "127.0.0.1:9080".to_string());
-    let udp_bind = env::var("RUSTDNS_UDP_BIND").unwrap_or_else(|_| "0.0.0.0:5353".to_string());
+fn main() -> Result<()> {
+    // settings from the config file become environment variables, so this runs before the
+    // runtime starts any thread
+    let config = match crate::configfile::config_path(env::args().skip(1), env::var_os("RUSTDNS_CONFIG")).map_err(anyhow::Error::msg)? {
+        Some(path) => Some(crate::configfile::apply(&path).map_err(anyhow::Error::msg)?),
+        None => None,
+    };
 
-    // create shutdown channel (unused for simple binary run)
-    let (_tx, rx) = tokio::sync::watch::channel(false);
+    // after the config file, which may set RUSTDNS_LOG_LEVEL
+    tracing_subscriber::fmt().with_max_level(crate::runner::log_level_from_env()).init();
+    let server_config = crate::config::Config { systemd: true, ..crate::config::Config::from_env().map_err(anyhow::Error::msg)? };
 
-    crate::runner::run_server(http_addr, udp_bind, rx).await;
-    Ok(())
+    // the minimal build runs everything on one thread
+    let mut builder = if cfg!(feature = "minimal") {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    builder.enable_all().build()?.block_on(serve(server_config, config))
+}
+
+async fn serve(server_config: crate::config::Config, config: Option<crate::configfile::ConfigFile>) -> Result<()> {
+    // SIGTERM / Ctrl-C shut down through this channel
+    let (tx, rx) = tokio::sync::watch::channel(false);
+
+    let state = crate::runner::build_state(&server_config);
+    let listen = (server_config.http_addr.to_string(), server_config.udp_bind.to_string());
+    tokio::spawn(crate::signals::handle_signals(state.clone(), config, listen, tx));
+    crate::runner::run_server(state, server_config, rx).await
 }
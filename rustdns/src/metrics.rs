@@ -0,0 +1,228 @@
+use crate::querylog::ACTIONS;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Extra label values tracked beyond the reported top N, so a label climbing into the top N has
+// an accurate count by the time it gets there.
+const TRACK_FACTOR: usize = 4;
+
+// Label value that everything outside the top N is folded into.
+pub const OTHER_LABEL: &str = "other";
+
+// Per-label counter with bounded cardinality. Only the `top` busiest label values are reported;
+// the rest are summed under "other". Memory stays fixed no matter how many distinct values are
+// seen (e.g. a scan of random subdomains): at most `top * TRACK_FACTOR` values are tracked, and
+// when that fills up the least-counted one is evicted and the newcomer inherits its count
+// (space-saving), so heavy hitters are never lost. Counts of late arrivals are upper bounds.
+pub struct CappedCounter {
+    pub top: usize,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct LabelCount {
+    pub label: String,
+    pub count: u64,
+}
+
+impl CappedCounter {
+    pub fn new(top: usize) -> Self {
+        CappedCounter { top, counts: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn add(&self, label: &str) {
+        self.add_n(label, 1);
+    }
+
+    pub fn add_n(&self, label: &str, n: u64) {
+        if self.top == 0 || n == 0 { return }
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(c) = counts.get_mut(label) {
+            *c += n;
+            return;
+        }
+        let mut start = 0;
+        if counts.len() >= self.top * TRACK_FACTOR {
+            if let Some((min_label, min)) = counts.iter().min_by_key(|(_, c)| **c).map(|(l, c)| (l.clone(), *c)) {
+                counts.remove(&min_label);
+                start = min;
+            }
+        }
+        counts.insert(label.to_string(), start + n);
+    }
+
+    // Every tracked label value and its count, in no particular order.
+    pub fn counts(&self) -> Vec<LabelCount> {
+        self.counts.lock().unwrap().iter().map(|(l, c)| LabelCount { label: l.clone(), count: *c }).collect()
+    }
+
+    // The top N label values by count, followed by "other" when anything was folded into it.
+    // Tracked counts always sum to the number of increments, so "other" is simply the remainder.
+    pub fn snapshot(&self) -> Vec<LabelCount> {
+        let counts = self.counts.lock().unwrap();
+        let mut all: Vec<(&String, &u64)> = counts.iter().collect();
+        all.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let mut out: Vec<LabelCount> = all.iter().take(self.top)
+            .map(|(l, c)| LabelCount { label: l.to_string(), count: **c })
+            .collect();
+        let other: u64 = all.iter().skip(self.top).map(|(_, c)| **c).sum();
+        if other > 0 {
+            out.push(LabelCount { label: OTHER_LABEL.to_string(), count: other });
+        }
+        out
+    }
+}
+
+// Per-client and per-domain query counters. RUSTDNS_METRICS_TOP_CLIENTS (default 20, 10 in the
+// minimal build) and RUSTDNS_METRICS_TOP_DOMAINS (default 50, 20 in the minimal build) cap how
+// many label values are reported; 0 turns the breakdown off.
+pub struct QueryLabels {
+    pub clients: CappedCounter,
+    pub domains: CappedCounter,
+}
+
+// Reported under `labels` in /stats.
+#[derive(Serialize)]
+pub struct LabelStats {
+    pub clients: Vec<LabelCount>,
+    pub domains: Vec<LabelCount>,
+}
+
+impl QueryLabels {
+    pub fn from_env() -> Self {
+        let num = |key: &str, default: usize| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        QueryLabels {
+            clients: CappedCounter::new(num("RUSTDNS_METRICS_TOP_CLIENTS", if cfg!(feature = "minimal") { 10 } else { 20 })),
+            domains: CappedCounter::new(num("RUSTDNS_METRICS_TOP_DOMAINS", if cfg!(feature = "minimal") { 20 } else { 50 })),
+        }
+    }
+
+    pub fn record(&self, client: &str, name: &str) {
+        self.clients.add(client);
+        self.domains.add(name.trim_end_matches('.'));
+    }
+
+    pub fn stats(&self) -> LabelStats {
+        LabelStats { clients: self.clients.snapshot(), domains: self.domains.snapshot() }
+    }
+}
+
+// Upper bounds, in seconds, of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Default)]
+struct Histogram {
+    // per bucket, not cumulative; the +Inf bucket is `count`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| secs <= *b) { self.buckets[i] += 1; }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+// Counters only GET /metrics reports: queries by what became of them (see querylog::ACTIONS),
+// blocked queries by the blocking mode that answered them, and latency and failures per upstream.
+#[derive(Default)]
+pub struct QueryCounters {
+    actions: [AtomicU64; ACTIONS.len()],
+    modes: Mutex<BTreeMap<String, u64>>,
+    upstreams: Mutex<BTreeMap<String, Histogram>>,
+    upstream_errors: Mutex<BTreeMap<String, u64>>,
+}
+
+impl QueryCounters {
+    pub fn action(&self, action: &str) {
+        if let Some(i) = ACTIONS.iter().position(|a| *a == action) { self.actions[i].fetch_add(1, Ordering::Relaxed); }
+    }
+
+    pub fn blocked_with(&self, mode: &str) {
+        *self.modes.lock().unwrap().entry(mode.to_string()).or_default() += 1;
+    }
+
+    pub fn upstream(&self, upstream: &str, elapsed: Duration) {
+        self.upstreams.lock().unwrap().entry(upstream.to_string()).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    pub fn upstream_error(&self, upstream: &str) {
+        *self.upstream_errors.lock().unwrap().entry(upstream.to_string()).or_default() += 1;
+    }
+
+    pub fn write(&self, out: &mut Exposition) {
+        out.family("rustdns_queries_by_action_total", "counter", "Client queries by what became of them.");
+        for (action, n) in ACTIONS.iter().zip(&self.actions) {
+            out.sample("rustdns_queries_by_action_total", &[("action", action)], n.load(Ordering::Relaxed));
+        }
+        out.family("rustdns_blocked_by_mode_total", "counter", "Blocked queries by the blocking mode that answered them.");
+        for (mode, n) in self.modes.lock().unwrap().iter() {
+            out.sample("rustdns_blocked_by_mode_total", &[("mode", mode)], n);
+        }
+        out.family("rustdns_upstream_latency_seconds", "histogram", "Time until an upstream answered a forwarded query.");
+        for (upstream, h) in self.upstreams.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (le, n) in LATENCY_BUCKETS.iter().zip(h.buckets) {
+                cumulative += n;
+                out.sample("rustdns_upstream_latency_seconds_bucket", &[("upstream", upstream), ("le", &le.to_string())], cumulative);
+            }
+            out.sample("rustdns_upstream_latency_seconds_bucket", &[("upstream", upstream), ("le", "+Inf")], h.count);
+            out.sample("rustdns_upstream_latency_seconds_sum", &[("upstream", upstream)], h.sum);
+            out.sample("rustdns_upstream_latency_seconds_count", &[("upstream", upstream)], h.count);
+        }
+        out.family("rustdns_upstream_errors_total", "counter", "Forwarded queries an upstream failed to answer in time.");
+        for (upstream, n) in self.upstream_errors.lock().unwrap().iter() {
+            out.sample("rustdns_upstream_errors_total", &[("upstream", upstream)], n);
+        }
+    }
+}
+
+// Prometheus text exposition format (version 0.0.4), as served by GET /metrics.
+#[derive(Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    // Start a metric family: its HELP and TYPE lines.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v))).collect();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    // A family with a single unlabelled sample.
+    pub fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+
+    pub fn labelled(&mut self, name: &str, kind: &str, help: &str, label: &str, counts: &[LabelCount]) {
+        self.family(name, kind, help);
+        for c in counts {
+            self.sample(name, &[(label, &c.label)], c.count);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
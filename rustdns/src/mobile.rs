@@ -0,0 +1,299 @@
+// The interface Swift and Kotlin apps get, with bindings generated from it by uniffi
+// (bindings/generate.sh). It drives the same server as the C API, so the two can be mixed, and
+// reports failures as PiBlockError where the C API returns codes.
+use crate::{BlockingMode, RustdnsThreadOptions, ServerStats, StartConfig};
+use std::collections::HashMap;
+use std::ffi::{c_void, CString};
+use std::sync::{Arc, Mutex};
+
+/// Why a call failed, with the reason rustdns_last_error_message gives. One case per
+/// RUSTDNS_ERR_* code.
+#[derive(Debug, uniffi::Error)]
+pub enum PiBlockError {
+    AlreadyRunning { message: String },
+    InvalidArgument { message: String },
+    InvalidAddress { message: String },
+    Bind { message: String },
+    Startup { message: String },
+    InvalidConfig { message: String },
+    Logger { message: String },
+    NotRunning { message: String },
+}
+
+impl std::fmt::Display for PiBlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PiBlockError::AlreadyRunning { message }
+            | PiBlockError::InvalidArgument { message }
+            | PiBlockError::InvalidAddress { message }
+            | PiBlockError::Bind { message }
+            | PiBlockError::Startup { message }
+            | PiBlockError::InvalidConfig { message }
+            | PiBlockError::Logger { message }
+            | PiBlockError::NotRunning { message } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for PiBlockError {}
+
+/// How blocked names are answered, as `BlockingMode`.
+#[derive(Clone, Copy, uniffi::Enum)]
+pub enum PiBlockMode {
+    Nx,
+    Null,
+    NoData,
+    Redirect,
+}
+
+impl From<PiBlockMode> for BlockingMode {
+    fn from(mode: PiBlockMode) -> Self {
+        match mode {
+            PiBlockMode::Nx => BlockingMode::Nx,
+            PiBlockMode::Null => BlockingMode::Null,
+            PiBlockMode::NoData => BlockingMode::NoData,
+            PiBlockMode::Redirect => BlockingMode::Redirect,
+        }
+    }
+}
+
+/// Scheduling options for the server threads, as `RustdnsThreadOptions`.
+#[derive(uniffi::Record)]
+pub struct PiBlockThreadOptions {
+    /// Nice value (-20..19); 0 keeps the inherited priority.
+    #[uniffi(default = 0)]
+    pub nice: i32,
+    /// CPUs the threads may run on (bit n = CPU n); 0 keeps the inherited affinity.
+    #[uniffi(default = 0)]
+    pub cpu_affinity: u64,
+}
+
+// Ok for RUSTDNS_OK, else the error for `code` with the message the failed call recorded.
+fn check(code: i32) -> Result<(), PiBlockError> {
+    if code == crate::RUSTDNS_OK { return Ok(()) }
+    let message = crate::LAST_ERROR.with(|e| e.borrow().as_ref().map(|m| m.to_string_lossy().into_owned())).unwrap_or_default();
+    Err(match code {
+        crate::RUSTDNS_ERR_ALREADY_RUNNING => PiBlockError::AlreadyRunning { message },
+        crate::RUSTDNS_ERR_INVALID_ADDRESS => PiBlockError::InvalidAddress { message },
+        crate::RUSTDNS_ERR_BIND => PiBlockError::Bind { message },
+        crate::RUSTDNS_ERR_STARTUP => PiBlockError::Startup { message },
+        crate::RUSTDNS_ERR_INVALID_CONFIG => PiBlockError::InvalidConfig { message },
+        crate::RUSTDNS_ERR_LOGGER => PiBlockError::Logger { message },
+        crate::RUSTDNS_ERR_NOT_RUNNING => PiBlockError::NotRunning { message },
+        _ => PiBlockError::InvalidArgument { message },
+    })
+}
+
+// `s` for the C API; strings with a NUL in them are invalid arguments.
+fn c_string(s: String, what: &str) -> Result<CString, PiBlockError> {
+    CString::new(s).map_err(|_| PiBlockError::InvalidArgument { message: format!("{} must not contain NUL", what) })
+}
+
+/// What `start` takes, as rustdns_start_with_config does; unset values keep their RUSTDNS_*
+/// environment value or default.
+#[derive(uniffi::Record)]
+pub struct PiBlockConfig {
+    #[uniffi(default = None)]
+    pub http_addr: Option<String>,
+    #[uniffi(default = None)]
+    pub udp_bind: Option<String>,
+    #[uniffi(default = None)]
+    pub upstream: Option<String>,
+    #[uniffi(default = None)]
+    pub upstream_timeout_ms: Option<u64>,
+    #[uniffi(default = None)]
+    pub blocklist_dir: Option<String>,
+    #[uniffi(default = None)]
+    pub data_dir: Option<String>,
+    #[uniffi(default = None)]
+    pub blocking_mode: Option<PiBlockMode>,
+    #[uniffi(default = None)]
+    pub block_page_ip: Option<String>,
+    #[uniffi(default = None)]
+    pub api_tokens: Option<Vec<String>>,
+    #[uniffi(default = None)]
+    pub log_level: Option<String>,
+    #[uniffi(default = None)]
+    pub thread_options: Option<PiBlockThreadOptions>,
+    /// Any other RUSTDNS_* setting by name.
+    #[uniffi(default = None)]
+    pub settings: Option<HashMap<String, String>>,
+}
+
+/// The server's counters, as `ServerStats`.
+#[derive(uniffi::Record)]
+pub struct PiBlockStats {
+    pub queries: u64,
+    pub blocked: u64,
+    pub audited: u64,
+    pub rate_limited: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_entries: u64,
+    pub uptime_seconds: u64,
+    pub blocking_enabled: bool,
+    /// Seconds until a timed pause ends; None when blocking or paused until resumed.
+    pub seconds_remaining: Option<u64>,
+}
+
+impl From<ServerStats> for PiBlockStats {
+    fn from(s: ServerStats) -> Self {
+        PiBlockStats {
+            queries: s.queries,
+            blocked: s.blocked,
+            audited: s.audited,
+            rate_limited: s.rate_limited,
+            cache_hits: s.cache_hits,
+            cache_misses: s.cache_misses,
+            cache_entries: s.cache_entries as u64,
+            uptime_seconds: s.uptime_seconds,
+            blocking_enabled: s.blocking_enabled,
+            seconds_remaining: s.seconds_remaining,
+        }
+    }
+}
+
+/// A query the server answered, as rustdns_set_query_callback passes it.
+#[derive(uniffi::Record)]
+pub struct QueryEvent {
+    pub id: u64,
+    pub time: u64,
+    pub name: String,
+    pub qtype: String,
+    pub client: String,
+    pub action: String,
+    pub latency_ms: f64,
+}
+
+/// Receives the server's queries; see `set_query_listener`.
+#[uniffi::export(with_foreign)]
+pub trait QueryListener: Send + Sync {
+    fn on_query(&self, event: QueryEvent);
+}
+
+/// Start the server on a background thread and return once it listens.
+#[uniffi::export]
+pub fn start(config: PiBlockConfig) -> Result<(), PiBlockError> {
+    let config = StartConfig {
+        http_addr: config.http_addr,
+        udp_bind: config.udp_bind,
+        upstream: config.upstream,
+        upstream_timeout_ms: config.upstream_timeout_ms,
+        blocklist_dir: config.blocklist_dir,
+        data_dir: config.data_dir,
+        blocking_mode: config.blocking_mode.map(BlockingMode::from),
+        block_page_ip: config.block_page_ip,
+        api_tokens: config.api_tokens,
+        log_level: config.log_level,
+        thread_options: config.thread_options.map(|o| RustdnsThreadOptions { nice: o.nice, cpu_affinity: o.cpu_affinity }),
+        settings: config.settings.unwrap_or_default().into_iter().map(|(k, v)| (k, serde_json::Value::String(v))).collect(),
+    };
+    check(crate::start_with_config(config))
+}
+
+/// Stop the server and return once it has.
+#[uniffi::export]
+pub fn stop() -> Result<(), PiBlockError> {
+    check(crate::rustdns_stop())
+}
+
+#[uniffi::export]
+pub fn is_running() -> bool {
+    crate::rustdns_is_running() == 1
+}
+
+/// Whether blocking is on; None when not running.
+#[uniffi::export]
+pub fn blocking_enabled() -> Option<bool> {
+    crate::current_state().map(|st| st.blocking.is_enabled())
+}
+
+/// The counters; None when not running.
+#[uniffi::export]
+pub fn stats() -> Option<PiBlockStats> {
+    crate::current_state().map(|st| ServerStats::of(&st).into())
+}
+
+/// Pause blocking for `seconds`, or until resumed when None.
+#[uniffi::export]
+pub fn pause(seconds: Option<u32>) -> Result<(), PiBlockError> {
+    check(crate::rustdns_pause(seconds.unwrap_or(0)))
+}
+
+#[uniffi::export]
+pub fn resume() -> Result<(), PiBlockError> {
+    check(crate::rustdns_resume())
+}
+
+/// Block `pattern` for `group`, or the `custom` group when None. `pattern` is an exact name
+/// (`ads.example.com`, not its subdomains), `*.example.com` for the subdomains, `ads.*` for names
+/// starting with `ads.`, or a glob with `*` elsewhere (`ads.*.example.com`). Regular expressions
+/// aren't supported; other patterns fail with `InvalidArgument`.
+#[uniffi::export]
+pub fn add_pattern(pattern: String, group: Option<String>) -> Result<(), PiBlockError> {
+    let pattern = c_string(pattern, "pattern")?;
+    let group = group.map(|g| c_string(g, "group")).transpose()?;
+    // SAFETY: both are NUL-terminated strings that outlive the call
+    check(unsafe { crate::rustdns_add_pattern(pattern.as_ptr(), group.as_ref().map_or(std::ptr::null(), |g| g.as_ptr())) })
+}
+
+#[uniffi::export]
+pub fn remove_pattern(pattern: String) -> Result<(), PiBlockError> {
+    let pattern = c_string(pattern, "pattern")?;
+    // SAFETY: a NUL-terminated string that outlives the call
+    check(unsafe { crate::rustdns_remove_pattern(pattern.as_ptr()) })
+}
+
+#[uniffi::export]
+pub fn reload_lists() -> Result<(), PiBlockError> {
+    check(crate::rustdns_reload_lists())
+}
+
+/// Set how blocked names are answered; `block_ip` is the address redirect answers with.
+#[uniffi::export]
+pub fn set_mode(mode: PiBlockMode, block_ip: Option<String>) -> Result<(), PiBlockError> {
+    let mode = c_string(BlockingMode::from(mode).as_str().to_string(), "mode")?;
+    let block_ip = block_ip.map(|ip| c_string(ip, "block_ip")).transpose()?;
+    // SAFETY: both are NUL-terminated strings that outlive the call
+    check(unsafe { crate::rustdns_set_mode(mode.as_ptr(), block_ip.as_ref().map_or(std::ptr::null(), |ip| ip.as_ptr())) })
+}
+
+/// Log to stderr (logcat on Android) at `max_level`: 1 = error .. 5 = trace, 0 for
+/// RUSTDNS_LOG_LEVEL. Nothing is logged otherwise.
+#[uniffi::export]
+pub fn init_logging(max_level: u8) -> Result<(), PiBlockError> {
+    check(crate::rustdns_init_logging(i32::from(max_level)))
+}
+
+// The listener registered with the C API, kept until it is replaced.
+static LISTENER: Mutex<Option<Box<Arc<dyn QueryListener>>>> = Mutex::new(None);
+
+extern "C" fn deliver(event: *const crate::RustdnsQueryEvent, user_data: *mut c_void) {
+    // SAFETY: user_data is the listener in LISTENER, which outlives its registration, and the
+    // event and its strings are valid during the call
+    let (listener, event) = unsafe { (&*(user_data as *const Arc<dyn QueryListener>), &*event) };
+    let text = |s: *const std::os::raw::c_char| unsafe { std::ffi::CStr::from_ptr(s) }.to_string_lossy().into_owned();
+    listener.on_query(QueryEvent {
+        id: event.id,
+        time: event.time,
+        name: text(event.name),
+        qtype: text(event.qtype),
+        client: text(event.client),
+        action: text(event.action),
+        latency_ms: event.latency_ms,
+    });
+}
+
+/// Have `listener` called with each query the server answers, or stop the calls with None; as
+/// rustdns_set_query_callback.
+#[uniffi::export]
+pub fn set_query_listener(listener: Option<Arc<dyn QueryListener>>) {
+    let mut registered = LISTENER.lock().unwrap_or_else(|e| e.into_inner());
+    let listener = listener.map(Box::new);
+    let user_data = listener.as_ref().map_or(std::ptr::null_mut(), |l| &**l as *const Arc<dyn QueryListener> as *mut c_void);
+    // SAFETY: deliver is callable from any thread, and user_data stays valid until replaced
+    unsafe { crate::rustdns_set_query_callback(listener.as_ref().map(|_| deliver as crate::RustdnsQueryCallback), user_data) };
+    // the previous listener isn't called once that returns, unless this runs within it
+    let previous = std::mem::replace(&mut *registered, listener);
+    if std::thread::current().name() == Some(crate::DISPATCH_THREAD) { std::mem::forget(previous) }
+}
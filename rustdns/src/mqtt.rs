@@ -0,0 +1,580 @@
+use crate::state::{Event, ServerState, MAX_PAUSE};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch};
+
+// Seconds between keepalive pings; the broker is given up on after twice this without a packet.
+const KEEPALIVE: u16 = 60;
+
+// Largest packet read from the broker. Commands are a few bytes; anything bigger is a broken peer.
+const MAX_PACKET: usize = 64 * 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+// Packet types, as the high nibble of the first byte.
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const PINGREQ: u8 = 12;
+const DISCONNECT: u8 = 14;
+
+// Publishing to an MQTT broker (3.1.1, plain TCP), read from RUSTDNS_MQTT_BROKER (`host` or
+// `host:port`, default port 1883; unset leaves MQTT off), RUSTDNS_MQTT_USERNAME and
+// RUSTDNS_MQTT_PASSWORD, RUSTDNS_MQTT_CLIENT_ID (default `piblock`), RUSTDNS_MQTT_TOPIC (topic
+// prefix, default `piblock`), RUSTDNS_MQTT_STATS_SECS (default 30), RUSTDNS_MQTT_BLOCKED (publish
+// every blocked query, default on), RUSTDNS_MQTT_COMMANDS (accept pause/resume commands, default
+// on), RUSTDNS_MQTT_DISCOVERY (Home Assistant discovery prefix, default `homeassistant`; empty
+// turns discovery off) and RUSTDNS_MQTT_PAUSE_SECS (how long the discovered pause button pauses,
+// default 300).
+struct MqttConfig {
+    broker: String,
+    username: Option<String>,
+    password: Option<String>,
+    client_id: String,
+    topic: String,
+    stats_every: Duration,
+    blocked: bool,
+    commands: bool,
+    discovery: Option<String>,
+    pause_secs: u64,
+}
+
+impl MqttConfig {
+    fn from_env() -> Option<Self> {
+        let var = |key: &str| crate::envvars::var(key).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let flag = |key: &str| var(key).is_none_or(|v| !matches!(v.to_lowercase().as_str(), "0" | "false" | "off"));
+        let broker = var("RUSTDNS_MQTT_BROKER")?;
+        let broker = broker.strip_prefix("mqtt://").unwrap_or(&broker).trim_end_matches('/').to_string();
+        let broker = if broker.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) { broker } else { format!("{}:1883", broker) };
+        Some(MqttConfig {
+            broker,
+            username: var("RUSTDNS_MQTT_USERNAME"),
+            password: var("RUSTDNS_MQTT_PASSWORD"),
+            client_id: var("RUSTDNS_MQTT_CLIENT_ID").unwrap_or_else(|| "piblock".to_string()),
+            topic: var("RUSTDNS_MQTT_TOPIC").unwrap_or_else(|| "piblock".to_string()).trim_end_matches('/').to_string(),
+            stats_every: Duration::from_secs(var("RUSTDNS_MQTT_STATS_SECS").and_then(|v| v.parse().ok()).unwrap_or(30).max(1)),
+            blocked: flag("RUSTDNS_MQTT_BLOCKED"),
+            commands: flag("RUSTDNS_MQTT_COMMANDS"),
+            discovery: match crate::envvars::var("RUSTDNS_MQTT_DISCOVERY") {
+                Ok(v) => Some(v.trim().trim_end_matches('/').to_string()).filter(|v| !v.is_empty()),
+                Err(_) => Some("homeassistant".to_string()),
+            },
+            pause_secs: var("RUSTDNS_MQTT_PAUSE_SECS").and_then(|v| v.parse().ok()).unwrap_or(300),
+        })
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}/{}", self.topic, suffix)
+    }
+}
+
+// Start publishing when RUSTDNS_MQTT_BROKER is set. Under the topic prefix:
+//   status          `online`, or `offline` (retained; the broker publishes it if we vanish)
+//   stats           JSON counters every RUSTDNS_MQTT_STATS_SECS (retained)
+//   blocking        `ON` or `OFF` (retained), on every change
+//   events          the /events stream, one JSON event per message
+//   blocked         each blocked query, as /queries entries
+//   blocking/set    command: `ON` resumes blocking, `OFF` pauses it until resumed
+//   pause/set       command: pause for that many seconds (empty or 0: until resumed)
+// Commands are taken from anyone the broker lets publish there, so restrict them with broker
+// ACLs or turn them off. The connection is retried with backoff while the server runs, and
+// closed with `offline` on shutdown.
+pub fn spawn_mqtt(state: Arc<ServerState>, shutdown: watch::Receiver<bool>) -> Option<tokio::task::JoinHandle<()>> {
+    let config = MqttConfig::from_env()?;
+    if config.password.is_some() {
+        tracing::info!("MQTT credentials cross the network in cleartext; keep the broker on a trusted network");
+    }
+    Some(tokio::spawn(async move {
+        let mut shutdown = shutdown;
+        let mut retry = Duration::from_secs(1);
+        loop {
+            let started = Instant::now();
+            match session(&config, &state, &mut shutdown).await {
+                Ok(()) => return,
+                Err(e) => tracing::warn!("MQTT connection to {} lost: {}; retrying in {}s", config.broker, e, retry.as_secs()),
+            }
+            // a session that lasted a while starts the backoff over
+            if started.elapsed() > RETRY_MAX { retry = Duration::from_secs(1); }
+            tokio::select! {
+                _ = tokio::time::sleep(retry) => {}
+                _ = shutdown.changed() => return,
+            }
+            retry = (retry * 2).min(RETRY_MAX);
+        }
+    }))
+}
+
+// One connection to the broker, until shutdown (Ok) or the connection fails (Err).
+async fn session(config: &MqttConfig, state: &Arc<ServerState>, shutdown: &mut watch::Receiver<bool>) -> io::Result<()> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&config.broker)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+    stream.set_nodelay(true)?;
+    let (mut reader, mut writer) = stream.into_split();
+    writer.write_all(&connect_packet(config)).await?;
+    let (kind, body) = tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut reader)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no CONNACK"))??;
+    if kind >> 4 != CONNACK || body.len() < 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "expected CONNACK"));
+    }
+    if body[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, connack_error(body[1])));
+    }
+    tracing::info!("MQTT connected to {} as {}", config.broker, config.client_id);
+
+    // subscribed before the current state is published below, so no change in between is missed
+    let mut events = state.events.subscribe();
+    let mut queries = config.blocked.then(|| state.query_log.subscribe());
+
+    let mut out = Publisher { writer: &mut writer, next_id: 0 };
+    if config.commands {
+        out.subscribe(&[config.topic("blocking/set"), config.topic("pause/set")]).await?;
+    }
+    if let Some(prefix) = &config.discovery {
+        for (topic, payload) in discovery(config, prefix) {
+            // an empty retained message deletes what was announced there before
+            let payload = payload.map(|p| p.to_string()).unwrap_or_default();
+            out.publish(&topic, payload.as_bytes(), true).await?;
+        }
+    }
+    out.publish(&config.topic("status"), b"online", true).await?;
+    out.publish(&config.topic("blocking"), on_off(state.blocking.is_enabled()), true).await?;
+
+    // packets are read in their own task, since a read can't be abandoned halfway through a packet
+    let (packet_tx, mut packets) = mpsc::channel::<io::Result<(u8, Vec<u8>)>>(16);
+    let read_task = tokio::spawn(async move {
+        loop {
+            let packet = read_packet(&mut reader).await;
+            let failed = packet.is_err();
+            if packet_tx.send(packet).await.is_err() || failed { return }
+        }
+    });
+    let _abort = AbortOnDrop(read_task);
+
+    let mut stats_tick = tokio::time::interval(config.stats_every);
+    let mut ping_tick = tokio::time::interval(Duration::from_secs(u64::from(KEEPALIVE) / 2));
+    let mut heard = Instant::now();
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                out.publish(&config.topic("status"), b"offline", true).await?;
+                out.writer.write_all(&[DISCONNECT << 4, 0]).await?;
+                let _ = out.writer.shutdown().await;
+                tracing::info!("MQTT disconnected from {}", config.broker);
+                return Ok(());
+            }
+            packet = packets.recv() => {
+                let (kind, body) = packet.unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))?;
+                heard = Instant::now();
+                if kind >> 4 == PUBLISH {
+                    let (topic, payload, id) = parse_publish(kind, &body)?;
+                    // subscriptions are QoS 0, but a broker may still send QoS 1
+                    if let Some(id) = id { out.writer.write_all(&[PUBACK << 4, 2, (id >> 8) as u8, id as u8]).await?; }
+                    if config.commands { command(config, state, &topic, &payload); }
+                }
+            }
+            _ = stats_tick.tick() => {
+                out.publish(&config.topic("stats"), stats(state).to_string().as_bytes(), true).await?;
+            }
+            _ = ping_tick.tick() => {
+                if heard.elapsed() > Duration::from_secs(2 * u64::from(KEEPALIVE)) {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "broker stopped answering"));
+                }
+                out.writer.write_all(&[PINGREQ << 4, 0]).await?;
+            }
+            ev = events.recv() => match ev {
+                Ok(ev) => {
+                    if let Event::BlockingChanged { blocking_enabled, .. } = &ev {
+                        out.publish(&config.topic("blocking"), on_off(*blocking_enabled), true).await?;
+                    }
+                    if let Ok(json) = serde_json::to_vec(&ev) { out.publish(&config.topic("events"), &json, false).await?; }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => tracing::debug!("MQTT skipped {} events", n),
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            entry = async { queries.as_mut().unwrap().recv().await }, if queries.is_some() => match entry {
+                Ok(e) if e.action == "blocked" => {
+                    if let Ok(json) = serde_json::to_vec(&e) { out.publish(&config.topic("blocked"), &json, false).await?; }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => tracing::debug!("MQTT skipped {} queries", n),
+                Err(broadcast::error::RecvError::Closed) => queries = None,
+            },
+        }
+    }
+}
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn on_off(enabled: bool) -> &'static [u8] {
+    if enabled { b"ON" } else { b"OFF" }
+}
+
+fn connack_error(code: u8) -> &'static str {
+    match code {
+        1 => "broker refused the protocol version",
+        2 => "broker rejected the client id",
+        3 => "broker unavailable",
+        4 => "bad username or password",
+        5 => "not authorized",
+        _ => "broker refused the connection",
+    }
+}
+
+// What the `stats` topic carries.
+fn stats(state: &ServerState) -> serde_json::Value {
+    let queries = state.queries.load(Ordering::Relaxed);
+    let blocked = state.blocked.load(Ordering::Relaxed);
+    let percent = if queries > 0 { (blocked as f64 * 1000.0 / queries as f64).round() / 10.0 } else { 0.0 };
+    serde_json::json!({
+        "queries": queries,
+        "blocked": blocked,
+        "percent_blocked": percent,
+        "audited": state.audited.load(Ordering::Relaxed),
+        "rate_limited": state.rate_limited.load(Ordering::Relaxed),
+        "blocking_enabled": state.blocking.is_enabled(),
+        "seconds_remaining": state.blocking.seconds_remaining(),
+        "uptime_seconds": state.totals.uptime_secs(),
+        "cache": state.cache.stats(),
+    })
+}
+
+// Act on a message published to one of the command topics.
+fn command(config: &MqttConfig, state: &ServerState, topic: &str, payload: &[u8]) {
+    let payload = String::from_utf8_lossy(payload).trim().to_uppercase();
+    if topic == config.topic("blocking/set") {
+        match payload.as_str() {
+            "ON" => {
+                state.resume_blocking();
+                tracing::info!("blocking resumed over MQTT");
+            }
+            "OFF" => {
+                state.pause_blocking(None);
+                tracing::info!("blocking paused over MQTT until resumed");
+            }
+            other => tracing::warn!("MQTT {}: expected ON or OFF, got {:?}", topic, other),
+        }
+    } else if topic == config.topic("pause/set") {
+        let secs = if payload.is_empty() { Some(0) } else { payload.parse::<u64>().ok() };
+        match secs {
+            Some(0) => {
+                state.pause_blocking(None);
+                tracing::info!("blocking paused over MQTT until resumed");
+            }
+            Some(s) if s > MAX_PAUSE.as_secs() => {
+                tracing::warn!("MQTT {}: a pause lasts at most {} seconds, got {}", topic, MAX_PAUSE.as_secs(), s);
+            }
+            Some(s) => {
+                state.pause_blocking(Some(Duration::from_secs(s)));
+                tracing::info!("blocking paused over MQTT for {}s", s);
+            }
+            None => tracing::warn!("MQTT {}: expected a number of seconds, got {:?}", topic, payload),
+        }
+    }
+}
+
+// Home Assistant discovery messages: sensors for the counters, a switch for blocking and a
+// button that pauses it for RUSTDNS_MQTT_PAUSE_SECS.
+fn discovery(config: &MqttConfig, prefix: &str) -> Vec<(String, Option<serde_json::Value>)> {
+    let node: String = config.client_id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect();
+    let device = serde_json::json!({ "identifiers": [node], "name": "PiBlock", "sw_version": env!("CARGO_PKG_VERSION") });
+    let common = |name: &str, object: &str| serde_json::json!({
+        "name": name,
+        "unique_id": format!("{}_{}", node, object),
+        "availability_topic": config.topic("status"),
+        "device": device,
+    });
+    let sensor = |name: &str, object: &str, field: &str, unit: &str| {
+        let mut c = common(name, object);
+        c["state_topic"] = config.topic("stats").into();
+        c["value_template"] = format!("{{{{ value_json.{} }}}}", field).into();
+        c["unit_of_measurement"] = unit.into();
+        c["state_class"] = if unit == "%" { "measurement" } else { "total_increasing" }.into();
+        (format!("{}/sensor/{}/{}/config", prefix, node, object), Some(c))
+    };
+    let mut switch = common("Blocking", "blocking");
+    switch["state_topic"] = config.topic("blocking").into();
+    switch["command_topic"] = config.topic("blocking/set").into();
+    let mut button = common(&format!("Pause blocking {} min", config.pause_secs.div_ceil(60)), "pause");
+    button["command_topic"] = config.topic("pause/set").into();
+    button["payload_press"] = config.pause_secs.to_string().into();
+    let mut out = vec![
+        sensor("Queries", "queries", "queries", "queries"),
+        sensor("Blocked queries", "blocked", "blocked", "queries"),
+        sensor("Blocked share", "percent_blocked", "percent_blocked", "%"),
+    ];
+    // without commands the switch and button would do nothing; remove any announced earlier
+    out.push((format!("{}/switch/{}/blocking/config", prefix, node), config.commands.then_some(switch)));
+    out.push((format!("{}/button/{}/pause/config", prefix, node), config.commands.then_some(button)));
+    out
+}
+
+struct Publisher<'a> {
+    writer: &'a mut OwnedWriteHalf,
+    next_id: u16,
+}
+
+impl Publisher<'_> {
+    // QoS 0 publish.
+    async fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+        put_str(&mut body, topic);
+        body.extend_from_slice(payload);
+        self.writer.write_all(&packet(PUBLISH << 4 | u8::from(retain), &body)).await
+    }
+
+    async fn subscribe(&mut self, topics: &[String]) -> io::Result<()> {
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let mut body = self.next_id.to_be_bytes().to_vec();
+        for t in topics {
+            put_str(&mut body, t);
+            body.push(0);
+        }
+        // the SUBACK is read (and ignored) along with everything else the broker sends
+        self.writer.write_all(&packet(SUBSCRIBE << 4 | 0x02, &body)).await
+    }
+}
+
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut flags = 0x02 | 0x04 | 0x20; // clean session, will, will retained
+    if config.username.is_some() { flags |= 0x80; }
+    if config.password.is_some() { flags |= 0x40; }
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&KEEPALIVE.to_be_bytes());
+    put_str(&mut body, &config.client_id);
+    put_str(&mut body, &config.topic("status"));
+    put_str(&mut body, "offline");
+    if let Some(u) = &config.username { put_str(&mut body, u); }
+    if let Some(p) = &config.password { put_str(&mut body, p); }
+    packet(CONNECT << 4, &body)
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    let b = &s.as_bytes()[..s.len().min(u16::MAX as usize)];
+    out.extend_from_slice(&(b.len() as u16).to_be_bytes());
+    out.extend_from_slice(b);
+}
+
+// A packet: the first byte, the remaining length (7 bits per byte, low first) and the body.
+fn packet(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![first];
+    let mut len = body.len();
+    loop {
+        let mut b = (len % 128) as u8;
+        len /= 128;
+        if len > 0 { b |= 0x80; }
+        out.push(b);
+        if len == 0 { break }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+async fn read_packet(r: &mut (impl AsyncRead + Unpin)) -> io::Result<(u8, Vec<u8>)> {
+    let first = r.read_u8().await?;
+    let mut len = 0usize;
+    for shift in 0..4 {
+        let b = r.read_u8().await?;
+        len |= usize::from(b & 0x7f) << (7 * shift);
+        if b & 0x80 == 0 { break }
+        if shift == 3 { return Err(io::Error::new(io::ErrorKind::InvalidData, "bad packet length")) }
+    }
+    if len > MAX_PACKET { return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too large")) }
+    let mut body = vec![0; len];
+    r.read_exact(&mut body).await?;
+    Ok((first, body))
+}
+
+// Topic, payload and (for QoS 1, which needs a PUBACK) packet id of a PUBLISH from the broker.
+fn parse_publish(first: u8, body: &[u8]) -> io::Result<(String, Vec<u8>, Option<u16>)> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "malformed PUBLISH");
+    let len = usize::from(u16::from_be_bytes([*body.first().ok_or_else(bad)?, *body.get(1).ok_or_else(bad)?]));
+    let topic = String::from_utf8_lossy(body.get(2..2 + len).ok_or_else(bad)?).into_owned();
+    let mut rest = &body[2 + len..];
+    let id = if first & 0x06 == 0x02 {
+        let id = u16::from_be_bytes([*rest.first().ok_or_else(bad)?, *rest.get(1).ok_or_else(bad)?]);
+        rest = &rest[2..];
+        Some(id)
+    } else {
+        None
+    };
+    Ok((topic, rest.to_vec(), id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            broker: "localhost:1883".to_string(),
+            username: None,
+            password: None,
+            client_id: "piblock".to_string(),
+            topic: "piblock".to_string(),
+            stats_every: Duration::from_secs(30),
+            blocked: true,
+            commands: true,
+            discovery: None,
+            pause_secs: 300,
+        }
+    }
+
+    fn state() -> Arc<ServerState> {
+        let config = crate::config::Config::with_addrs("127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()).unwrap();
+        crate::runner::build_state(&config)
+    }
+
+    async fn read(bytes: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        read_packet(&mut &bytes[..]).await
+    }
+
+    #[tokio::test]
+    async fn remaining_length_round_trips() {
+        for len in [0, 1, 127, 128, 300, 16_383, 16_384, MAX_PACKET] {
+            let body: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let p = packet(PUBLISH << 4, &body);
+            let length_bytes = match len { 0..=127 => 1, 128..=16_383 => 2, _ => 3 };
+            assert_eq!(p.len(), 1 + length_bytes + len, "{}", len);
+            let (first, read_body) = read(&p).await.unwrap();
+            assert_eq!((first, read_body), (PUBLISH << 4, body));
+        }
+        // the spec's boundary encodings
+        assert_eq!(packet(0, &[0; 127])[1..2], [0x7f]);
+        assert_eq!(packet(0, &[0; 128])[1..3], [0x80, 0x01]);
+        assert_eq!(packet(0, &[0; 16_384])[1..4], [0x80, 0x80, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn remaining_length_is_at_most_four_bytes() {
+        // four length bytes are allowed by the format, a fifth is not
+        let five = [PUBLISH << 4, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(read(&five).await.err().map(|e| e.to_string()).as_deref(), Some("bad packet length"));
+        let four = [PUBLISH << 4, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(read(&four).await.err().map(|e| e.to_string()).as_deref(), Some("packet too large"));
+        let over = packet(PUBLISH << 4, &vec![0; MAX_PACKET + 1]);
+        assert_eq!(read(&over).await.err().map(|e| e.to_string()).as_deref(), Some("packet too large"));
+    }
+
+    #[tokio::test]
+    async fn truncated_packets_are_errors() {
+        let p = packet(PUBLISH << 4, b"0123456789");
+        for cut in [0, 1, p.len() - 1] {
+            assert_eq!(read(&p[..cut]).await.err().map(|e| e.kind()), Some(io::ErrorKind::UnexpectedEof), "{}", cut);
+        }
+        // a length byte promising more, then nothing
+        assert_eq!(read(&[PUBLISH << 4, 0x80]).await.err().map(|e| e.kind()), Some(io::ErrorKind::UnexpectedEof));
+    }
+
+    fn publish_body(topic: &str, id: Option<u16>, payload: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_str(&mut body, topic);
+        if let Some(id) = id { body.extend_from_slice(&id.to_be_bytes()); }
+        body.extend_from_slice(payload);
+        body
+    }
+
+    #[test]
+    fn publish_carries_a_packet_id_only_at_qos_1() {
+        let qos0 = publish_body("piblock/pause/set", None, b"60");
+        assert_eq!(parse_publish(PUBLISH << 4, &qos0).ok(), Some(("piblock/pause/set".to_string(), b"60".to_vec(), None)));
+        // retained and dup flags don't change the layout
+        assert_eq!(parse_publish(PUBLISH << 4 | 0x09, &qos0).ok(), Some(("piblock/pause/set".to_string(), b"60".to_vec(), None)));
+        let qos1 = publish_body("piblock/blocking/set", Some(0x1234), b"OFF");
+        assert_eq!(parse_publish(PUBLISH << 4 | 0x02, &qos1).ok(), Some(("piblock/blocking/set".to_string(), b"OFF".to_vec(), Some(0x1234))));
+        let empty = publish_body("t", Some(7), b"");
+        assert_eq!(parse_publish(PUBLISH << 4 | 0x02, &empty).ok(), Some(("t".to_string(), Vec::new(), Some(7))));
+    }
+
+    #[test]
+    fn truncated_publish_is_malformed() {
+        let malformed = |first: u8, body: &[u8]| parse_publish(first, body).err().map(|e| e.to_string());
+        let bad = Some("malformed PUBLISH".to_string());
+        assert_eq!(malformed(PUBLISH << 4, &[]), bad);
+        assert_eq!(malformed(PUBLISH << 4, &[0]), bad);
+        // topic length past the end of the body
+        assert_eq!(malformed(PUBLISH << 4, &[0, 5, b'a', b'b']), bad);
+        // QoS 1 without (the whole of) its packet id
+        assert_eq!(malformed(PUBLISH << 4 | 0x02, &publish_body("t", None, b"")), bad);
+        assert_eq!(malformed(PUBLISH << 4 | 0x02, &publish_body("t", None, b"x")), bad);
+    }
+
+    #[test]
+    fn put_str_prefixes_the_length() {
+        let mut out = Vec::new();
+        put_str(&mut out, "MQTT");
+        assert_eq!(out, [0, 4, b'M', b'Q', b'T', b'T']);
+        let mut out = Vec::new();
+        put_str(&mut out, &"x".repeat(70_000));
+        assert_eq!(out.len(), 2 + u16::MAX as usize);
+        assert_eq!(out[..2], [0xff, 0xff]);
+    }
+
+    #[test]
+    fn connect_packet_sets_flags_for_credentials() {
+        let plain = connect_packet(&config());
+        assert_eq!(plain[0], CONNECT << 4);
+        // after the fixed header (2 bytes here) and "MQTT": level 4, then the flags
+        assert_eq!(plain[8..10], [4, 0x26]);
+        let mut c = config();
+        c.username = Some("u".to_string());
+        c.password = Some("p".to_string());
+        let with_auth = connect_packet(&c);
+        assert_eq!(with_auth[9], 0x26 | 0x80 | 0x40);
+        assert!(with_auth.ends_with(&[0, 1, b'u', 0, 1, b'p']));
+    }
+
+    #[tokio::test]
+    async fn commands_pause_and_resume_blocking() {
+        let (config, state) = (config(), state());
+        command(&config, &state, "piblock/blocking/set", b" off\n");
+        assert!(!state.blocking.is_enabled());
+        assert_eq!(state.blocking.seconds_remaining(), None);
+        command(&config, &state, "piblock/blocking/set", b"ON");
+        assert!(state.blocking.is_enabled());
+
+        command(&config, &state, "piblock/pause/set", b"120");
+        assert!(!state.blocking.is_enabled());
+        assert!(state.blocking.seconds_remaining().is_some_and(|s| s > 100 && s <= 120));
+        command(&config, &state, "piblock/blocking/set", b"ON");
+        command(&config, &state, "piblock/pause/set", b"");
+        assert!(!state.blocking.is_enabled());
+        assert_eq!(state.blocking.seconds_remaining(), None);
+        command(&config, &state, "piblock/blocking/set", b"ON");
+        command(&config, &state, "piblock/pause/set", b"0");
+        assert_eq!((state.blocking.is_enabled(), state.blocking.seconds_remaining()), (false, None));
+    }
+
+    #[tokio::test]
+    async fn bad_commands_are_ignored() {
+        let (config, state) = (config(), state());
+        for (topic, payload) in [
+            ("piblock/blocking/set", &b"maybe"[..]),
+            ("piblock/pause/set", b"-5"),
+            ("piblock/pause/set", b"ten"),
+            ("piblock/pause/set", b"18446744073709551615"),
+            ("piblock/pause/set", b"99999999999999999999"),
+            ("piblock/other", b"OFF"),
+            ("other/blocking/set", b"OFF"),
+        ] {
+            command(&config, &state, topic, payload);
+            assert!(state.blocking.is_enabled(), "{} {:?}", topic, payload);
+        }
+    }
+}
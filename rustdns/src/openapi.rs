@@ -0,0 +1,227 @@
+use crate::api::{API_PREFIX, API_VERSION};
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde_json::{Map, Value};
+
+// One control API operation, as described in the OpenAPI document. Paths are written as they
+// are routed (`:id` parameters), relative to API_PREFIX unless `root` is set.
+struct Op {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    query: &'static [&'static str],
+    // JSON body properties and their types ("string", "integer", "boolean", "string[]", "object")
+    body: &'static [(&'static str, &'static str)],
+    // response media type
+    produces: &'static str,
+    root: bool,
+}
+
+const JSON: &str = "application/json";
+
+const fn op(method: &'static str, path: &'static str, tag: &'static str, summary: &'static str) -> Op {
+    Op { method, path, tag, summary, query: &[], body: &[], produces: JSON, root: false }
+}
+
+const fn with_query(mut o: Op, query: &'static [&'static str]) -> Op {
+    o.query = query;
+    o
+}
+
+const fn with_body(mut o: Op, body: &'static [(&'static str, &'static str)]) -> Op {
+    o.body = body;
+    o
+}
+
+const fn producing(mut o: Op, produces: &'static str) -> Op {
+    o.produces = produces;
+    o
+}
+
+const fn at_root(mut o: Op) -> Op {
+    o.root = true;
+    o
+}
+
+// Every route of the control API. Keep in step with the router in runner.rs.
+const OPS: &[Op] = &[
+    at_root(op("get", "/api", "meta", "API versions, server version and build features")),
+    at_root(op("get", "/healthz", "meta", "Liveness: the process is up, with the status of the control API and DNS components (503 while one is restarting)")),
+    at_root(op("get", "/readyz", "meta", "Readiness: lists loaded, UDP bound, upstream reachable (503 otherwise)")),
+    op("post", "/reload", "lists", "Reload the list files"),
+    op("post", "/compile", "lists", "Compile the list files into the memory-mapped index and reload"),
+    with_query(op("get", "/lists", "lists", "Loaded rules, a page at a time"), &["q", "offset", "limit", "sort", "format"]),
+    with_body(op("post", "/add", "lists", "Add a pattern or TLD rule"), &[("pattern", "string"), ("tld", "string"), ("group", "string"), ("comment", "string"), ("tags", "string[]"), ("types", "string[]")]),
+    with_body(op("post", "/remove", "lists", "Remove a pattern or TLD rule"), &[("pattern", "string"), ("tld", "string")]),
+    with_body(op("post", "/add-bulk", "lists", "Add many rules at once (JSON or one pattern per line)"), &[("patterns", "string[]"), ("tlds", "string[]"), ("group", "string"), ("comment", "string"), ("tags", "string[]"), ("types", "string[]")]),
+    with_body(op("post", "/remove-bulk", "lists", "Remove many rules at once (JSON or one pattern per line)"), &[("patterns", "string[]"), ("tlds", "string[]")]),
+    producing(with_query(op("get", "/export", "lists", "The effective blocklist as hosts, ABP or plain list"), &["format"]), "text/plain"),
+    with_query(op("get", "/check", "lists", "Whether a name would be blocked right now"), &["domain", "type"]),
+    op("get", "/stats", "stats", "Counters since start and lifetime totals"),
+    with_query(op("get", "/stats/top", "stats", "Most blocked names, most allowed names or busiest clients"), &["kind", "n"]),
+    with_query(op("get", "/stats/history", "stats", "Queries and blocked queries per interval"), &["range"]),
+    with_query(op("get", "/stats/longterm", "stats", "Persistent query totals per day or week"), &["period", "from", "to"]),
+    with_query(op("get", "/graphql", "stats", "Read-only GraphQL query over stats, queries, clients and lists (graphql feature)"), &["query", "variables", "operationName"]),
+    with_body(op("post", "/graphql", "stats", "Read-only GraphQL query over stats, queries, clients and lists (graphql feature)"), &[("query", "string"), ("variables", "object"), ("operationName", "string")]),
+    producing(op("get", "/metrics", "stats", "Prometheus metrics"), "text/plain"),
+    with_query(op("get", "/queries", "stats", "Recent client queries, newest first"), &["client", "domain", "action", "since", "until", "before", "limit"]),
+    producing(with_query(op("get", "/queries/stream", "stats", "Live client queries as server-sent events"), &["client", "domain", "action"]), "text/event-stream"),
+    with_body(op("post", "/mode", "blocking", "Set the blocking mode"), &[("mode", "string"), ("block_ip", "string")]),
+    with_body(op("post", "/blocking", "blocking", "Pause or resume blocking"), &[("enabled", "boolean"), ("seconds", "integer")]),
+    with_query(op("post", "/pause", "blocking", "Pause blocking, for a while or until resumed"), &["seconds", "minutes"]),
+    op("post", "/resume", "blocking", "Resume blocking"),
+    with_query(op("post", "/disable", "blocking", "Pause blocking (Pi-hole style; 0 = until enabled)"), &["duration"]),
+    op("post", "/enable", "blocking", "Resume blocking (Pi-hole style)"),
+    with_body(op("post", "/audit", "blocking", "Turn audit (dry-run) mode on or off"), &[("enabled", "boolean")]),
+    producing(op("get", "/events", "blocking", "State changes as server-sent events"), "text/event-stream"),
+    op("get", "/groups", "groups", "List groups with their counts, modes and schedules"),
+    op("post", "/groups/:name/enable", "groups", "Enable a group"),
+    op("post", "/groups/:name/disable", "groups", "Disable a group"),
+    with_body(op("post", "/groups/:name/mode", "groups", "Set or clear a group's blocking mode"), &[("mode", "string"), ("block_ip", "string")]),
+    with_body(op("post", "/groups/:name/schedule", "groups", "Set or clear a group's schedule"), &[("windows", "string[]"), ("timezone", "string")]),
+    op("get", "/categories", "categories", "List categories"),
+    op("post", "/categories/reload", "categories", "Reload the category files"),
+    op("post", "/categories/:name/block", "categories", "Block a category"),
+    op("post", "/categories/:name/unblock", "categories", "Unblock a category"),
+    op("get", "/clients", "clients", "List known clients"),
+    with_body(op("post", "/clients", "clients", "Register a client"), &[("name", "string"), ("identifiers", "string[]"), ("groups", "string[]")]),
+    op("get", "/clients/:id", "clients", "Get a client and its activity"),
+    with_body(op("put", "/clients/:id", "clients", "Update a client"), &[("name", "string"), ("identifiers", "string[]"), ("groups", "string[]")]),
+    op("delete", "/clients/:id", "clients", "Delete a client"),
+    op("post", "/clients/:id/unban", "clients", "Clear a client's rate-limit state"),
+    with_body(op("post", "/clients/:id/debug", "clients", "Turn query tracing for a client on or off"), &[("enabled", "boolean")]),
+    op("get", "/clients/:id/debug", "clients", "Recent query traces of a client"),
+    with_query(op("get", "/resolve", "tools", "Resolve a name through the pipeline"), &["name", "type", "upstream", "bypass_cache", "debug"]),
+    with_body(op("post", "/debug/resolve", "tools", "Resolve a name with a step-by-step trace of every pipeline decision"), &[("name", "string"), ("type", "string"), ("bypass_cache", "boolean"), ("listener", "string")]),
+    with_query(op("get", "/lookup/rdap", "tools", "Registration data of a domain"), &["domain"]),
+    with_query(op("get", "/alerts", "alerts", "Recent alerts"), &["kind", "since"]),
+    op("get", "/brands", "alerts", "Protected brands"),
+    with_body(op("post", "/brands", "alerts", "Protect a brand"), &[("brand", "string")]),
+    with_body(op("post", "/brands/remove", "alerts", "Stop protecting a brand"), &[("brand", "string")]),
+    op("get", "/canaries", "alerts", "Canary names"),
+    with_body(op("post", "/canaries", "alerts", "Add a canary name"), &[("domain", "string")]),
+    with_body(op("post", "/canaries/remove", "alerts", "Remove a canary name"), &[("domain", "string")]),
+    op("get", "/allowlist", "allowlist", "Allowlisted names"),
+    op("get", "/allowlist/presets", "allowlist", "Allowlist presets"),
+    op("post", "/allowlist/presets/:name/enable", "allowlist", "Enable an allowlist preset"),
+    op("post", "/allowlist/presets/:name/disable", "allowlist", "Disable an allowlist preset"),
+    with_body(op("post", "/allow", "allowlist", "Allow a name, optionally for a while"), &[("domain", "string"), ("seconds", "integer"), ("minutes", "integer")]),
+    with_body(op("post", "/allow/remove", "allowlist", "Remove a name from the allowlist"), &[("domain", "string")]),
+    with_query(op("get", "/review", "review", "Unblock requests"), &["status"]),
+    with_body(op("post", "/review/:id/request", "review", "Ask for a blocked name to be unblocked"), &[("note", "string")]),
+    with_body(op("post", "/review/:id/approve", "review", "Approve an unblock request"), &[("seconds", "integer"), ("minutes", "integer")]),
+    op("post", "/review/:id/confirm", "review", "Confirm a block"),
+    op("get", "/rpz", "lists", "RPZ feeds and pending transfers"),
+    with_body(op("post", "/rpz/:zone/approve", "lists", "Approve a pending RPZ transfer"), &[("sha256", "string")]),
+    producing(op("get", "/backup", "config", "All user state as a gzipped JSON archive (admin token)"), "application/gzip"),
+    op("post", "/restore", "config", "Restore an archive from /backup"),
+    op("get", "/config", "config", "Effective configuration (admin token)"),
+    with_body(op("put", "/config", "config", "Change settings while running"), &[("upstream", "string"), ("upstream_timeout_ms", "integer"), ("blocking", "object")]),
+    op("get", "/tls", "config", "TLS certificate status"),
+    op("get", "/acme", "config", "ACME certificate status"),
+];
+
+fn schema(ty: &str) -> Value {
+    match ty.strip_suffix("[]") {
+        Some(item) => serde_json::json!({ "type": "array", "items": { "type": item } }),
+        None => serde_json::json!({ "type": ty }),
+    }
+}
+
+// Query parameters that take numbers or flags; the rest are strings.
+fn query_type(name: &str) -> &'static str {
+    match name {
+        "n" | "duration" | "limit" | "offset" | "since" | "until" | "before" | "seconds" | "minutes" => "integer",
+        "bypass_cache" | "debug" => "boolean",
+        _ => "string",
+    }
+}
+
+fn operation(o: &Op) -> Value {
+    let mut params: Vec<Value> = Vec::new();
+    let mut path = String::new();
+    for seg in o.path.split('/').skip(1) {
+        path.push('/');
+        match seg.strip_prefix(':') {
+            Some(name) => {
+                path.push_str(&format!("{{{}}}", name));
+                params.push(serde_json::json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }));
+            }
+            None => path.push_str(seg),
+        }
+    }
+    params.extend(o.query.iter().map(|q| serde_json::json!({ "name": q, "in": "query", "required": false, "schema": { "type": query_type(q) } })));
+    let content = if o.produces == JSON { serde_json::json!({ "type": "object" }) } else { serde_json::json!({ "type": "string" }) };
+    let mut op = serde_json::json!({
+        "tags": [o.tag],
+        "summary": o.summary,
+        "operationId": format!("{}{}", o.method, path.replace(['/', '{', '}', '-', '.'], "_")),
+        "parameters": params,
+        "responses": { "200": { "description": "OK", "content": { o.produces: { "schema": content } } } },
+    });
+    if !o.body.is_empty() {
+        let props: Map<String, Value> = o.body.iter().map(|(k, t)| (k.to_string(), schema(t))).collect();
+        op["requestBody"] = serde_json::json!({ "content": { JSON: { "schema": { "type": "object", "properties": props } } } });
+    }
+    if o.root && matches!(o.path, "/healthz" | "/readyz") {
+        op["security"] = serde_json::json!([]);
+    }
+    op
+}
+
+// The OpenAPI 3.0 document describing OPS.
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for o in OPS {
+        let path: String = o.path.split('/').map(|s| match s.strip_prefix(':') { Some(n) => format!("{{{}}}", n), None => s.to_string() }).collect::<Vec<_>>().join("/");
+        let key = if o.root { path } else { format!("{}{}", API_PREFIX, path) };
+        let item = paths.entry(key).or_insert_with(|| Value::Object(Map::new()));
+        item[o.method] = operation(o);
+    }
+    serde_json::json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "PiBlock rustdns control API",
+            "version": API_VERSION.to_string(),
+            "description": "Read tokens may call GET routes except /config; everything else needs an admin token. Without configured tokens the API is open.",
+        },
+        "components": {
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } },
+        },
+        "security": [{ "bearer": [] }],
+        "paths": paths,
+    })
+}
+
+// GET /api/openapi.json
+pub async fn http_openapi() -> Json<Value> {
+    Json(document())
+}
+
+// GET /api/docs — Swagger UI for the document, when RUSTDNS_API_DOCS=1. The UI itself is loaded
+// from a CDN by the browser.
+pub async fn http_api_docs() -> Response {
+    if !crate::envvars::var("RUSTDNS_API_DOCS").map(|v| v == "1" || v == "true").unwrap_or(false) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Html(SWAGGER_PAGE).into_response()
+}
+
+const SWAGGER_PAGE: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>PiBlock API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#ui" });</script>
+</body>
+</html>
+"##;
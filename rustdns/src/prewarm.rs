@@ -0,0 +1,99 @@
+use crate::metrics::{CappedCounter, OTHER_LABEL};
+use crate::listeners::ListenerPolicy;
+use crate::server::{query_message, resolve_query};
+use crate::state::ServerState;
+use crate::store::{data_dir, read_json, write_json};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use trust_dns_proto::rr::{Name, RecordType};
+
+// Pause between pre-warm lookups, so a restart doesn't fire hundreds of queries upstream at once.
+const PREWARM_SPACING: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize)]
+struct HistoryEntry {
+    name: String,
+    qtype: String,
+    count: u64,
+}
+
+// Query history used to pre-warm the response cache after a restart. With RUSTDNS_PREWARM=N the
+// N most queried name/type pairs are tracked and saved to `query_history.json` in the data
+// directory; at startup the saved ones are resolved in the background. Counts carried over from
+// the previous run are halved, so the list follows recent habits.
+pub struct Prewarm {
+    pub top: usize,
+    path: PathBuf,
+    history: CappedCounter,
+    saved: Vec<(String, RecordType)>,
+}
+
+impl Prewarm {
+    pub fn from_env() -> Self {
+        let top = crate::envvars::var("RUSTDNS_PREWARM").ok().and_then(|v| v.parse().ok()).unwrap_or(0);
+        let path = data_dir().join("query_history.json");
+        let history = CappedCounter::new(top);
+        let mut saved = Vec::new();
+        if top > 0 {
+            match read_json::<Vec<HistoryEntry>>(&path) {
+                Ok(entries) => {
+                    for e in entries.unwrap_or_default() {
+                        let Ok(qtype) = RecordType::from_str(&e.qtype) else { continue };
+                        history.add_n(&label(&e.name, qtype), e.count / 2);
+                        saved.push((e.name, qtype));
+                    }
+                    saved.truncate(top);
+                }
+                Err(e) => tracing::warn!("ignoring unreadable query history {}: {:?}", path.display(), e),
+            }
+        }
+        Prewarm { top, path, history, saved }
+    }
+
+    pub fn record(&self, name: &str, qtype: RecordType) {
+        if self.top == 0 { return }
+        self.history.add(&label(&name.trim_end_matches('.').to_lowercase(), qtype));
+    }
+
+    pub fn save(&self) {
+        if self.top == 0 { return }
+        let entries: Vec<HistoryEntry> = self.history.snapshot().into_iter()
+            .filter(|l| l.label != OTHER_LABEL)
+            .filter_map(|l| {
+                let (name, qtype) = l.label.rsplit_once(' ')?;
+                Some(HistoryEntry { name: name.to_string(), qtype: qtype.to_string(), count: l.count })
+            })
+            .collect();
+        if let Err(e) = write_json(&self.path, &entries) {
+            tracing::warn!("saving query history to {} failed: {:?}", self.path.display(), e);
+        }
+    }
+}
+
+fn label(name: &str, qtype: RecordType) -> String {
+    format!("{} {}", name, qtype)
+}
+
+// Resolve the names saved by the previous run through the normal pipeline, which leaves the
+// answers in the cache (blocked names are answered locally and never reach upstream).
+pub fn spawn_prewarm(tasks: &mut JoinSet<()>, state: Arc<ServerState>) {
+    if state.prewarm.saved.is_empty() || !state.cache.enabled() { return }
+    tasks.spawn(async move {
+        let started = Instant::now();
+        let mut warmed = 0;
+        for (i, (name, qtype)) in state.prewarm.saved.iter().enumerate() {
+            let Ok(qname) = Name::from_str(name) else { continue };
+            let msg = query_message(i as u16, qname, *qtype);
+            let Ok(packet) = msg.to_vec() else { continue };
+            if resolve_query(&state, &msg, &packet, &state.settings.upstream(), false, &ListenerPolicy::All, None).await.is_ok() {
+                warmed += 1;
+            }
+            tokio::time::sleep(PREWARM_SPACING).await;
+        }
+        tracing::info!("pre-warmed cache with {} of {} names in {:.1}s", warmed, state.prewarm.saved.len(), started.elapsed().as_secs_f64());
+    });
+}
@@ -0,0 +1,180 @@
+use crate::blocklist::BlockMatch;
+use crate::querystore::{QueryStore, StoreConfig};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use trust_dns_proto::op::ResponseCode;
+
+// What became of a query: blocked, audited (would have been blocked, forwarded anyway), answered
+// from the cache, forwarded upstream, answered from a local record, refused by a rate limit, a
+// canary hit, SERVFAIL while lists reload, or an upstream error.
+pub const ACTIONS: [&str; 9] = ["blocked", "audited", "cached", "forwarded", "local", "refused", "canary", "servfail", "error"];
+
+// Entries GET /queries returns by default and at most.
+pub const QUERIES_PAGE: usize = 100;
+pub const QUERIES_MAX_PAGE: usize = 1000;
+
+// Entries a /queries/stream subscriber may fall behind by before it skips ahead.
+const STREAM_BUFFER: usize = 1024;
+
+// One client query and what became of it.
+#[derive(Clone, Serialize)]
+pub struct QueryEntry {
+    pub id: u64,
+    pub time: u64,
+    // address the query came from, and the client key policies apply to
+    pub client: String,
+    pub client_key: String,
+    // the client's name in the registry, if it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    pub name: String,
+    pub qtype: String,
+    // one of ACTIONS
+    pub action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lists: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rcode: Option<String>,
+    pub elapsed_ms: f64,
+}
+
+impl QueryEntry {
+    pub fn new(client: String, client_key: String, name: &str, qtype: String, action: &'static str, elapsed_ms: f64) -> Self {
+        QueryEntry {
+            id: 0,
+            time: 0,
+            client,
+            client_key,
+            client_name: None,
+            name: name.trim_end_matches('.').to_lowercase(),
+            qtype,
+            action,
+            rule: None,
+            lists: Vec::new(),
+            upstream: None,
+            rcode: None,
+            elapsed_ms,
+        }
+    }
+
+    pub fn with_match(mut self, m: &BlockMatch) -> Self {
+        self.rule = Some(m.rule.clone());
+        self.lists = m.origins.iter().map(|o| o.source.clone()).collect();
+        self
+    }
+
+    pub fn with_rcode(mut self, rcode: ResponseCode) -> Self {
+        self.rcode = Some(rcode.to_string());
+        self
+    }
+
+    // Take the response code from the header of the raw response.
+    pub fn with_response(self, response: &[u8]) -> Self {
+        match response.get(3) {
+            Some(b) => self.with_rcode(ResponseCode::from(0, b & 0x0f)),
+            None => self,
+        }
+    }
+}
+
+// Which entries GET /queries returns.
+#[derive(Default)]
+pub struct QueryFilter {
+    // client address, client key or client name
+    pub client: Option<String>,
+    // substring of the name
+    pub domain: Option<String>,
+    pub action: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    // only entries older than this id, for paging back
+    pub before: Option<u64>,
+}
+
+impl QueryFilter {
+    pub fn matches(&self, e: &QueryEntry) -> bool {
+        self.client.as_ref().is_none_or(|c| e.client == *c || e.client_key == *c || e.client_name.as_ref() == Some(c))
+            && self.domain.as_ref().is_none_or(|d| e.name.contains(d.as_str()))
+            && self.action.as_ref().is_none_or(|a| e.action == a)
+            && self.since.is_none_or(|s| e.time >= s)
+            && self.until.is_none_or(|u| e.time <= u)
+            && self.before.is_none_or(|b| e.id < b)
+    }
+}
+
+// Recent client queries, for GET /queries. RUSTDNS_QUERY_LOG_SIZE bounds it (default 10000, 1000
+// in the minimal build; 0 disables it); the oldest entries make room for new ones. New entries
+// are also published to /queries/stream subscribers, whether or not the log keeps them. With
+// RUSTDNS_QUERY_LOG_PERSIST=1 every entry is written to disk as well (see QueryStore), ids carry
+// on from the previous run, and GET /queries pages on into the stored history.
+pub struct QueryLog {
+    pub capacity: usize,
+    inner: Mutex<QueryLogInner>,
+    live: broadcast::Sender<QueryEntry>,
+    pub store: Option<QueryStore>,
+}
+
+#[derive(Default)]
+struct QueryLogInner {
+    next_id: u64,
+    buf: VecDeque<QueryEntry>,
+}
+
+impl QueryLog {
+    pub fn from_env() -> Self {
+        let default = if cfg!(feature = "minimal") { 1000 } else { 10_000 };
+        let capacity = crate::envvars::var("RUSTDNS_QUERY_LOG_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        let store = StoreConfig::from_env().map(QueryStore::open);
+        let inner = QueryLogInner { next_id: store.as_ref().map_or(0, |s| s.last_id), buf: VecDeque::new() };
+        QueryLog { capacity, inner: Mutex::new(inner), live: broadcast::channel(STREAM_BUFFER).0, store }
+    }
+
+    // Whether entries are wanted at all: kept in the log or on disk, or streamed to someone.
+    pub fn active(&self) -> bool {
+        self.capacity > 0 || self.store.is_some() || self.live.receiver_count() > 0
+    }
+
+    // Store `entry`, giving it its id and time, and publish it to stream subscribers.
+    pub fn record(&self, mut entry: QueryEntry) {
+        entry.time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        entry.id = inner.next_id;
+        // sent under the lock so subscribers see entries in id order
+        if self.live.receiver_count() > 0 { let _ = self.live.send(entry.clone()); }
+        if let Some(store) = &self.store { store.push(entry.clone()); }
+        if self.capacity == 0 { return }
+        if inner.buf.len() >= self.capacity { inner.buf.pop_front(); }
+        inner.buf.push_back(entry);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<QueryEntry> {
+        self.live.subscribe()
+    }
+
+    // Up to `limit` entries matching `filter`, newest first, and how many match in all.
+    pub fn query(&self, filter: &QueryFilter, limit: usize) -> (Vec<QueryEntry>, usize) {
+        let inner = self.inner.lock().unwrap();
+        let mut total = 0;
+        let mut page = Vec::new();
+        for e in inner.buf.iter().rev().filter(|e| filter.matches(e)) {
+            total += 1;
+            if page.len() < limit { page.push(e.clone()); }
+        }
+        (page, total)
+    }
+
+    // Id of the oldest entry still in memory (the next id when there are none); older entries
+    // are only on disk.
+    pub fn oldest_id(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.buf.front().map_or(inner.next_id + 1, |e| e.id)
+    }
+}
@@ -0,0 +1,254 @@
+use crate::querylog::{QueryEntry, QueryFilter, ACTIONS};
+use crate::store::data_dir;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Entries waiting for the writer before new ones are dropped.
+const WRITE_QUEUE: usize = 4096;
+
+// How long written entries may sit in the write buffer, and how often retention is applied.
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+// Settings of the on-disk query log: RUSTDNS_QUERY_LOG_PERSIST=1 turns it on, RUSTDNS_QUERY_LOG_DIR
+// (default `querylog` in the data directory), RUSTDNS_QUERY_LOG_RETENTION_DAYS (default 7; 0
+// keeps files until the size cap), RUSTDNS_QUERY_LOG_MAX_BYTES (all files, default 256 MiB,
+// 32 MiB in the minimal build) and RUSTDNS_QUERY_LOG_FILE_BYTES (size a file is rotated at,
+// default 16 MiB, 4 MiB in the minimal build).
+#[derive(Clone, Debug, Serialize)]
+pub struct StoreConfig {
+    pub dir: PathBuf,
+    pub retention_days: u64,
+    pub max_bytes: u64,
+    pub file_bytes: u64,
+}
+
+impl StoreConfig {
+    // None unless persistence is turned on.
+    pub fn from_env() -> Option<Self> {
+        let on = crate::envvars::var("RUSTDNS_QUERY_LOG_PERSIST").is_ok_and(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "on"));
+        if !on { return None }
+        let num = |key: &str, default: u64| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        let minimal = cfg!(feature = "minimal");
+        Some(StoreConfig {
+            dir: crate::envvars::var("RUSTDNS_QUERY_LOG_DIR").map(PathBuf::from).unwrap_or_else(|_| data_dir().join("querylog")),
+            retention_days: num("RUSTDNS_QUERY_LOG_RETENTION_DAYS", 7),
+            max_bytes: num("RUSTDNS_QUERY_LOG_MAX_BYTES", if minimal { 32 << 20 } else { 256 << 20 }),
+            file_bytes: num("RUSTDNS_QUERY_LOG_FILE_BYTES", if minimal { 4 << 20 } else { 16 << 20 }).max(4096),
+        })
+    }
+}
+
+// A log file: `queries-<unix time of its first entry>-<first id>.ndjson`, one JSON entry per line.
+struct LogFile {
+    path: PathBuf,
+    start: u64,
+    first_id: u64,
+    size: u64,
+}
+
+// Log files in `dir`, oldest first.
+fn log_files(dir: &Path) -> Vec<LogFile> {
+    let Ok(entries) = std::fs::read_dir(dir) else { return Vec::new() };
+    let mut files: Vec<LogFile> = entries.flatten().filter_map(|e| {
+        let name = e.file_name().to_string_lossy().into_owned();
+        let (start, first_id) = name.strip_prefix("queries-")?.strip_suffix(".ndjson")?.split_once('-')?;
+        Some(LogFile { path: e.path(), start: start.parse().ok()?, first_id: first_id.parse().ok()?, size: e.metadata().ok()?.len() })
+    }).collect();
+    files.sort_by_key(|f| (f.first_id, f.start));
+    files
+}
+
+// A QueryEntry as read back from a file.
+#[derive(Deserialize)]
+struct StoredEntry {
+    id: u64,
+    time: u64,
+    client: String,
+    client_key: String,
+    client_name: Option<String>,
+    name: String,
+    qtype: String,
+    action: String,
+    rule: Option<String>,
+    #[serde(default)]
+    lists: Vec<String>,
+    upstream: Option<String>,
+    rcode: Option<String>,
+    elapsed_ms: f64,
+}
+
+// The entry on `line`, unless it's damaged (such as the last line after a power cut).
+fn parse_entry(line: &str) -> Option<QueryEntry> {
+    let e: StoredEntry = serde_json::from_str(line).ok()?;
+    let action = ACTIONS.iter().copied().find(|a| *a == e.action)?;
+    Some(QueryEntry {
+        id: e.id,
+        time: e.time,
+        client: e.client,
+        client_key: e.client_key,
+        client_name: e.client_name,
+        name: e.name,
+        qtype: e.qtype,
+        action,
+        rule: e.rule,
+        lists: e.lists,
+        upstream: e.upstream,
+        rcode: e.rcode,
+        elapsed_ms: e.elapsed_ms,
+    })
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// The query log on disk, so entries outlive the in-memory buffer and restarts. Entries are handed
+// to a writer thread through a bounded queue and never wait for the disk; when the queue is full
+// they are dropped from the file (and counted) but still kept in memory. Files are rotated by
+// size, and the oldest are deleted once past the retention window or the size cap.
+pub struct QueryStore {
+    pub config: StoreConfig,
+    tx: Mutex<SyncSender<QueryEntry>>,
+    dropped: AtomicU64,
+    // the id the last run ended at, so ids keep increasing across restarts
+    pub last_id: u64,
+}
+
+impl QueryStore {
+    pub fn open(config: StoreConfig) -> Self {
+        if let Err(e) = std::fs::create_dir_all(&config.dir) {
+            tracing::warn!("creating the query log directory {} failed: {}", config.dir.display(), e);
+        }
+        let last_id = log_files(&config.dir).last().and_then(|f| last_entry_id(&f.path)).unwrap_or(0);
+        let (tx, rx) = sync_channel::<QueryEntry>(WRITE_QUEUE);
+        let writer_config = config.clone();
+        // the thread ends, flushing what it has, once the store and with it the sender are dropped
+        std::thread::Builder::new().name("querylog-writer".to_string()).spawn(move || {
+            let mut writer = Writer { config: writer_config, file: None };
+            let mut last_prune = std::time::Instant::now();
+            writer.prune();
+            loop {
+                match rx.recv_timeout(FLUSH_EVERY) {
+                    Ok(entry) => writer.write(&entry),
+                    Err(RecvTimeoutError::Timeout) => writer.flush(),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if last_prune.elapsed() >= PRUNE_EVERY {
+                    writer.flush();
+                    writer.prune();
+                    last_prune = std::time::Instant::now();
+                }
+            }
+            writer.flush();
+        }).expect("starting the query log writer");
+        QueryStore { config, tx: Mutex::new(tx), dropped: AtomicU64::new(0), last_id }
+    }
+
+    pub fn push(&self, entry: QueryEntry) {
+        if let Err(TrySendError::Full(_)) = self.tx.lock().unwrap().try_send(entry) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Up to `limit` stored entries matching `filter` with ids below `below`, newest first, and
+    // whether there are more. Files outside the filter's time range are skipped unread. Entries
+    // still in the write buffer (the last second or so) aren't found here, but are in memory.
+    pub fn search(&self, filter: &QueryFilter, below: u64, limit: usize) -> (Vec<QueryEntry>, bool) {
+        let files = log_files(&self.config.dir);
+        let mut found = Vec::new();
+        for (i, f) in files.iter().enumerate().rev() {
+            if f.first_id >= below || filter.until.is_some_and(|u| f.start > u) { continue }
+            // entries of this file are older than the next file's first entry
+            if let (Some(next), Some(since)) = (files.get(i + 1), filter.since) {
+                if next.start < since { break }
+            }
+            let Ok(file) = File::open(&f.path) else { continue };
+            let entries: Vec<QueryEntry> = BufReader::new(file).lines().map_while(Result::ok)
+                .filter_map(|line| parse_entry(&line))
+                .collect();
+            for e in entries.into_iter().rev().filter(|e| e.id < below && filter.matches(e)) {
+                if found.len() == limit { return (found, true) }
+                found.push(e);
+            }
+        }
+        (found, false)
+    }
+}
+
+// Id of the last complete entry in `path`.
+fn last_entry_id(path: &Path) -> Option<u64> {
+    let file = File::open(path).ok()?;
+    BufReader::new(file).lines().map_while(Result::ok)
+        .filter_map(|line| parse_entry(&line))
+        .map(|e| e.id)
+        .last()
+}
+
+struct Writer {
+    config: StoreConfig,
+    file: Option<(BufWriter<File>, u64)>,
+}
+
+impl Writer {
+    fn write(&mut self, entry: &QueryEntry) {
+        let Ok(mut line) = serde_json::to_vec(entry) else { return };
+        line.push(b'\n');
+        if self.file.as_ref().is_some_and(|(_, size)| size + line.len() as u64 > self.config.file_bytes) {
+            self.flush();
+            self.file = None;
+        }
+        if self.file.is_none() {
+            let path = self.config.dir.join(format!("queries-{}-{}.ndjson", entry.time, entry.id));
+            match File::create(&path) {
+                Ok(f) => self.file = Some((BufWriter::new(f), 0)),
+                Err(e) => {
+                    tracing::warn!("creating query log file {} failed: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        let (w, size) = self.file.as_mut().unwrap();
+        match w.write_all(&line) {
+            Ok(()) => *size += line.len() as u64,
+            Err(e) => {
+                tracing::warn!("writing the query log failed: {}; starting a new file", e);
+                self.file = None;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some((w, _)) = self.file.as_mut() {
+            if let Err(e) = w.flush() { tracing::warn!("flushing the query log failed: {}", e); }
+        }
+    }
+
+    // Delete the oldest files past the retention window or over the size cap; the newest file,
+    // which may be the one being written, always stays.
+    fn prune(&mut self) {
+        let files = log_files(&self.config.dir);
+        let Some((_, older)) = files.split_last() else { return };
+        let cutoff = (self.config.retention_days > 0).then(|| now_secs().saturating_sub(self.config.retention_days * 86_400));
+        let mut total: u64 = files.iter().map(|f| f.size).sum();
+        for (i, f) in older.iter().enumerate() {
+            // the next file started after this one's last entry
+            let expired = cutoff.is_some_and(|c| files[i + 1].start < c);
+            if !expired && total <= self.config.max_bytes { break }
+            match std::fs::remove_file(&f.path) {
+                Ok(()) => total -= f.size,
+                Err(e) => tracing::warn!("removing old query log file {} failed: {}", f.path.display(), e),
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use crate::clients::ClientKey;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Clients tracked before idle ones are forgotten. Queries from further clients while every tracked
+// one is active are answered without limiting, so spoofed source addresses can't grow the map.
+const MAX_TRACKED: usize = if cfg!(feature = "minimal") { 1024 } else { 8192 };
+
+// Per-client query limiter. A client that exceeds `max_per_window` queries inside one window
+// is banned for `ban_for`; a limit of 0 disables limiting entirely.
+pub struct ClientLimiter {
+    pub max_per_window: u64,
+    pub window: Duration,
+    pub ban_for: Duration,
+    clients: Mutex<HashMap<ClientKey, ClientWindow>>,
+}
+
+struct ClientWindow {
+    started: Instant,
+    count: u64,
+    banned_until: Option<Instant>,
+}
+
+impl ClientLimiter {
+    pub fn new(max_per_window: u64, window: Duration, ban_for: Duration) -> Self {
+        ClientLimiter { max_per_window, window, ban_for, clients: Mutex::new(HashMap::new()) }
+    }
+
+    // Reads RUSTDNS_CLIENT_LIMIT (queries per window), RUSTDNS_CLIENT_WINDOW_SECS and RUSTDNS_CLIENT_BAN_SECS.
+    pub fn from_env() -> Self {
+        let num = |key: &str, default: u64| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        ClientLimiter::new(
+            num("RUSTDNS_CLIENT_LIMIT", 0),
+            Duration::from_secs(num("RUSTDNS_CLIENT_WINDOW_SECS", 1)),
+            Duration::from_secs(num("RUSTDNS_CLIENT_BAN_SECS", 60)),
+        )
+    }
+
+    // Record a query from `client` and return whether it may be answered.
+    pub fn check(&self, client: &ClientKey) -> bool {
+        if self.max_per_window == 0 { return true }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_TRACKED && !clients.contains_key(client) {
+            // a client idle past its window and any ban is treated as new anyway
+            let idle = self.window + self.ban_for;
+            clients.retain(|_, w| w.banned_until.is_some_and(|u| now < u) || now.duration_since(w.started) < idle);
+            if clients.len() >= MAX_TRACKED { return true }
+        }
+        let w = clients.entry(client.clone()).or_insert(ClientWindow { started: now, count: 0, banned_until: None });
+        if let Some(until) = w.banned_until {
+            if now < until { return false }
+            w.banned_until = None;
+            w.started = now;
+            w.count = 0;
+        }
+        if now.duration_since(w.started) >= self.window {
+            w.started = now;
+            w.count = 0;
+        }
+        w.count += 1;
+        if w.count > self.max_per_window {
+            w.banned_until = Some(now + self.ban_for);
+            return false;
+        }
+        true
+    }
+
+    // Forget all limiter state for `client`. Returns true if the client was currently banned.
+    pub fn unban(&self, client: &ClientKey) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        match clients.remove(client) {
+            Some(w) => w.banned_until.map(|u| Instant::now() < u).unwrap_or(false),
+            None => false,
+        }
+    }
+}
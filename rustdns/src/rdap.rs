@@ -0,0 +1,195 @@
+use anyhow::Result;
+use serde::Serialize;
+#[cfg(feature = "rdap")]
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+// Registration details pulled out of an RDAP domain response.
+#[derive(Clone, Serialize)]
+pub struct RdapInfo {
+    pub domain: String,
+    pub registrar: Option<String>,
+    pub registered: Option<String>,
+    pub expires: Option<String>,
+    pub last_changed: Option<String>,
+    pub status: Vec<String>,
+}
+
+// Most names the cache holds; past it, expired entries and then the oldest are dropped.
+const CACHE_MAX: usize = 1000;
+
+// Caches RDAP answers per queried name; registries rate-limit aggressively and the data
+// rarely changes, so entries live for `ttl`.
+pub struct RdapClient {
+    pub base: String,
+    pub ttl: Duration,
+    #[cfg(feature = "rdap")]
+    http: reqwest::Client,
+    cache: RwLock<HashMap<String, (Instant, RdapInfo)>>,
+}
+
+impl RdapClient {
+    // RUSTDNS_RDAP_BASE overrides the bootstrap service (default https://rdap.org).
+    pub fn from_env() -> Self {
+        let base = crate::envvars::var("RUSTDNS_RDAP_BASE").unwrap_or_else(|_| "https://rdap.org".to_string());
+        RdapClient {
+            base: base.trim_end_matches('/').to_string(),
+            ttl: Duration::from_secs(24 * 3600),
+            #[cfg(feature = "rdap")]
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Look up `domain`, walking up to its parent names when the registry doesn't know a
+    // subdomain. Returns the cached answer and whether it came from the cache.
+    pub async fn lookup(&self, domain: &str) -> Result<(RdapInfo, bool)> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        // the name becomes part of the request path, so nothing but a host name may get through
+        if !ldh_name(&domain) { return Err(anyhow::anyhow!("{:?} is not a domain name", domain)) }
+        if let Some((at, info)) = self.cache.read().await.get(&domain) {
+            if at.elapsed() < self.ttl { return Ok((info.clone(), true)) }
+        }
+        let info = self.fetch(&domain).await?;
+        cache_insert(&mut *self.cache.write().await, self.ttl, domain, info.clone());
+        Ok((info, false))
+    }
+
+    #[cfg(feature = "rdap")]
+    async fn fetch(&self, domain: &str) -> Result<RdapInfo> {
+        let mut name = domain;
+        loop {
+            let resp = self.http.get(format!("{}/domain/{}", self.base, name)).send().await?;
+            if resp.status().is_success() {
+                let body: Value = resp.json().await?;
+                return Ok(parse_rdap(name, &body));
+            }
+            match name.split_once('.') {
+                Some((_, parent)) if parent.contains('.') && resp.status() == reqwest::StatusCode::NOT_FOUND => name = parent,
+                _ => return Err(anyhow::anyhow!("rdap lookup for {} failed: {}", name, resp.status())),
+            }
+        }
+    }
+
+    #[cfg(not(feature = "rdap"))]
+    async fn fetch(&self, domain: &str) -> Result<RdapInfo> {
+        Err(anyhow::anyhow!("rdap lookup for {} via {} unavailable: built without the rdap feature", domain, self.base))
+    }
+}
+
+// Whether `name` is a DNS name of letter-digit-hyphen labels (`xn--` for internationalized ones).
+fn ldh_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 253
+        && name.split('.').all(|l| {
+            !l.is_empty() && l.len() <= 63 && !l.starts_with('-') && !l.ends_with('-')
+                && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+// Cache `info` for `domain`, first making room when the cache is full.
+fn cache_insert(cache: &mut HashMap<String, (Instant, RdapInfo)>, ttl: Duration, domain: String, info: RdapInfo) {
+    if cache.len() >= CACHE_MAX && !cache.contains_key(&domain) {
+        cache.retain(|_, (at, _)| at.elapsed() < ttl);
+        if cache.len() >= CACHE_MAX {
+            if let Some(oldest) = cache.iter().min_by_key(|(_, (at, _))| *at).map(|(d, _)| d.clone()) {
+                cache.remove(&oldest);
+            }
+        }
+    }
+    cache.insert(domain, (Instant::now(), info));
+}
+
+#[cfg(feature = "rdap")]
+fn parse_rdap(domain: &str, body: &Value) -> RdapInfo {
+    let event = |action: &str| {
+        body["events"].as_array().and_then(|evs| {
+            evs.iter()
+                .find(|e| e["eventAction"].as_str() == Some(action))
+                .and_then(|e| e["eventDate"].as_str().map(str::to_string))
+        })
+    };
+    // registrar name lives in the `fn` property of the registrar entity's jCard
+    let registrar = body["entities"].as_array().and_then(|ents| {
+        ents.iter()
+            .find(|e| e["roles"].as_array().map(|r| r.iter().any(|x| x == "registrar")).unwrap_or(false))
+            .and_then(|e| e["vcardArray"][1].as_array())
+            .and_then(|props| props.iter().find(|p| p[0] == "fn"))
+            .and_then(|p| p[3].as_str().map(str::to_string))
+    });
+    let status = body["status"].as_array()
+        .map(|s| s.iter().filter_map(|v| v.as_str().map(str::to_string)).collect())
+        .unwrap_or_default();
+    RdapInfo {
+        domain: body["ldhName"].as_str().map(|s| s.to_lowercase()).unwrap_or_else(|| domain.to_string()),
+        registrar,
+        registered: event("registration"),
+        expires: event("expiration"),
+        last_changed: event("last changed"),
+        status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(domain: &str) -> RdapInfo {
+        RdapInfo { domain: domain.to_string(), registrar: None, registered: None, expires: None, last_changed: None, status: Vec::new() }
+    }
+
+    #[test]
+    fn only_host_names_are_looked_up() {
+        for ok in ["example.com", "a.b-c.example", "xn--bcher-kva.example", "123.example", "localhost"] {
+            assert!(ldh_name(ok), "{}", ok);
+        }
+        let long_label = format!("{}.com", "a".repeat(64));
+        let long_name = format!("{}com", "a.".repeat(127));
+        for bad in ["", "example..com", ".example.com", "-a.com", "a-.com", "a_b.com", "a/b.com", "../help", "a.com?x=1",
+            "a.com#frag", "a.com/../../entity/x", "a%2fb.com", "a b.com", long_label.as_str(), long_name.as_str()] {
+            assert!(!ldh_name(bad), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_names_fail_before_any_request() {
+        let client = RdapClient::from_env();
+        let err = client.lookup("../../entity/x").await.err().map(|e| e.to_string());
+        assert_eq!(err.as_deref(), Some("\"../../entity/x\" is not a domain name"));
+    }
+
+    #[test]
+    fn the_cache_stays_within_its_cap() {
+        let ttl = Duration::from_secs(60);
+        let mut cache = HashMap::new();
+        for i in 0..CACHE_MAX + 10 {
+            cache_insert(&mut cache, ttl, format!("d{}.example", i), info("x"));
+        }
+        assert_eq!(cache.len(), CACHE_MAX);
+        assert!(cache.contains_key(&format!("d{}.example", CACHE_MAX + 9)));
+        // refreshing a cached name doesn't evict another
+        cache_insert(&mut cache, ttl, format!("d{}.example", CACHE_MAX + 9), info("x"));
+        assert_eq!(cache.len(), CACHE_MAX);
+    }
+
+    #[test]
+    fn expired_entries_go_first() {
+        let ttl = Duration::from_secs(60);
+        let mut cache = HashMap::new();
+        for i in 0..CACHE_MAX {
+            cache_insert(&mut cache, ttl, format!("d{}.example", i), info("x"));
+        }
+        let stale = Instant::now() - Duration::from_secs(120);
+        for i in 0..10 {
+            cache.get_mut(&format!("d{}.example", 500 + i)).unwrap().0 = stale;
+        }
+        cache_insert(&mut cache, ttl, "new.example".to_string(), info("new"));
+        assert_eq!(cache.len(), CACHE_MAX - 9);
+        assert!((0..10).all(|i| !cache.contains_key(&format!("d{}.example", 500 + i))));
+        assert!(cache.contains_key("d0.example"));
+    }
+}
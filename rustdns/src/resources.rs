@@ -0,0 +1,121 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Caps on concurrently held resources; small boards run out of memory or file descriptors long
+// before the runtime notices. Read from RUSTDNS_MAX_TASKS and RUSTDNS_MAX_UPSTREAM_SOCKETS.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ResourceLimits {
+    pub max_tasks: usize,
+    pub max_upstream_sockets: usize,
+}
+
+impl ResourceLimits {
+    pub fn from_env() -> Self {
+        let num = |key: &str, default: usize| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        ResourceLimits {
+            max_tasks: num("RUSTDNS_MAX_TASKS", if cfg!(feature = "minimal") { 256 } else { 1024 }),
+            max_upstream_sockets: num("RUSTDNS_MAX_UPSTREAM_SOCKETS", if cfg!(feature = "minimal") { 64 } else { 256 }),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ResourceKind {
+    QueryTask,
+    UpstreamSocket,
+}
+
+// Live resource counters. Slots are taken with `try_acquire` and given back when the returned
+// guard is dropped.
+pub struct Resources {
+    pub limits: ResourceLimits,
+    active_tasks: AtomicUsize,
+    upstream_sockets: AtomicUsize,
+    rejected_tasks: AtomicU64,
+    rejected_upstream: AtomicU64,
+}
+
+// Snapshot reported under `resources` in /stats.
+#[derive(Serialize)]
+pub struct ResourceUsage {
+    pub active_tasks: usize,
+    pub upstream_sockets: usize,
+    pub rejected_tasks: u64,
+    pub rejected_upstream: u64,
+    // resident set size of the process, where the platform reports it
+    pub resident_bytes: Option<u64>,
+    pub limits: ResourceLimits,
+}
+
+pub struct ResourceGuard {
+    resources: Arc<Resources>,
+    kind: ResourceKind,
+}
+
+impl Drop for ResourceGuard {
+    fn drop(&mut self) {
+        self.resources.counter(self.kind).fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Resources {
+    pub fn new(limits: ResourceLimits) -> Self {
+        Resources {
+            limits,
+            active_tasks: AtomicUsize::new(0),
+            upstream_sockets: AtomicUsize::new(0),
+            rejected_tasks: AtomicU64::new(0),
+            rejected_upstream: AtomicU64::new(0),
+        }
+    }
+
+    fn counter(&self, kind: ResourceKind) -> &AtomicUsize {
+        match kind {
+            ResourceKind::QueryTask => &self.active_tasks,
+            ResourceKind::UpstreamSocket => &self.upstream_sockets,
+        }
+    }
+
+    // Take a slot of `kind`, or None (counting the rejection) when the cap is reached.
+    pub fn try_acquire(self: &Arc<Self>, kind: ResourceKind) -> Option<ResourceGuard> {
+        let (cap, rejected) = match kind {
+            ResourceKind::QueryTask => (self.limits.max_tasks, &self.rejected_tasks),
+            ResourceKind::UpstreamSocket => (self.limits.max_upstream_sockets, &self.rejected_upstream),
+        };
+        let counter = self.counter(kind);
+        let taken = counter.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < cap).then_some(n + 1));
+        match taken {
+            Ok(_) => Some(ResourceGuard { resources: self.clone(), kind }),
+            Err(_) => {
+                rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn usage(&self) -> ResourceUsage {
+        ResourceUsage {
+            active_tasks: self.active_tasks.load(Ordering::Relaxed),
+            upstream_sockets: self.upstream_sockets.load(Ordering::Relaxed),
+            rejected_tasks: self.rejected_tasks.load(Ordering::Relaxed),
+            rejected_upstream: self.rejected_upstream.load(Ordering::Relaxed),
+            resident_bytes: resident_bytes(),
+            limits: self.limits,
+        }
+    }
+}
+
+// Resident memory of this process, from /proc/self/statm (pages) on Linux.
+#[cfg(target_os = "linux")]
+pub fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (page_size > 0).then(|| pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident_bytes() -> Option<u64> {
+    None
+}
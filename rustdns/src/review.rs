@@ -0,0 +1,138 @@
+use crate::blocklist::BlockMatch;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewStatus {
+    // blocked, nobody has asked about it
+    Pending,
+    // someone asked for it to be unblocked
+    Requested,
+    // unblocked: the domain was added to the allowlist
+    Approved,
+    // the block was confirmed and stays
+    Confirmed,
+}
+
+impl ReviewStatus {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "pending" => Some(ReviewStatus::Pending),
+            "requested" => Some(ReviewStatus::Requested),
+            "approved" => Some(ReviewStatus::Approved),
+            "confirmed" => Some(ReviewStatus::Confirmed),
+            _ => None,
+        }
+    }
+
+    fn is_open(self) -> bool {
+        matches!(self, ReviewStatus::Pending | ReviewStatus::Requested)
+    }
+}
+
+// A blocked domain as seen by one client. Repeats while the item is open only bump `count`.
+#[derive(Clone, Serialize)]
+pub struct ReviewItem {
+    pub id: u64,
+    pub domain: String,
+    pub client: String,
+    pub rule: String,
+    pub lists: Vec<String>,
+    pub count: u64,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub status: ReviewStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+// Recently blocked (domain, client) pairs, so someone who hit a block can ask for it to be lifted
+// and the admin can approve (allowlist the domain) or confirm the block from /review.
+// RUSTDNS_REVIEW_SIZE bounds it (default 200, 50 in the minimal build; 0 disables). When full,
+// the oldest item nobody has asked about is dropped first, so open requests survive busy periods.
+pub struct ReviewQueue {
+    pub capacity: usize,
+    inner: Mutex<ReviewInner>,
+}
+
+#[derive(Default)]
+struct ReviewInner {
+    next_id: u64,
+    buf: VecDeque<ReviewItem>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl ReviewQueue {
+    pub fn from_env() -> Self {
+        let default = if cfg!(feature = "minimal") { 50 } else { 200 };
+        let capacity = crate::envvars::var("RUSTDNS_REVIEW_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        ReviewQueue { capacity, inner: Mutex::new(ReviewInner::default()) }
+    }
+
+    // Note a block of `domain` for `client`.
+    pub fn record(&self, domain: &str, client: &str, m: &BlockMatch) {
+        if self.capacity == 0 { return }
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        let now = now_secs();
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(item) = inner.buf.iter_mut().find(|i| i.status.is_open() && i.domain == domain && i.client == client) {
+            item.count += 1;
+            item.last_seen = now;
+            return;
+        }
+        if inner.buf.len() >= self.capacity {
+            let drop_at = inner.buf.iter().position(|i| i.status != ReviewStatus::Requested).unwrap_or(0);
+            inner.buf.remove(drop_at);
+        }
+        inner.next_id += 1;
+        let item = ReviewItem {
+            id: inner.next_id,
+            domain,
+            client: client.to_string(),
+            rule: m.rule.clone(),
+            lists: m.origins.iter().map(|o| o.source.clone()).collect(),
+            count: 1,
+            first_seen: now,
+            last_seen: now,
+            status: ReviewStatus::Pending,
+            note: None,
+        };
+        inner.buf.push_back(item);
+    }
+
+    // Items with `status` (all when None), oldest first.
+    pub fn list(&self, status: Option<ReviewStatus>) -> Vec<ReviewItem> {
+        let inner = self.inner.lock().unwrap();
+        inner.buf.iter().filter(|i| status.map(|s| i.status == s).unwrap_or(true)).cloned().collect()
+    }
+
+    // Ask for item `id` to be unblocked. Only open items can be requested.
+    pub fn request(&self, id: u64, note: Option<&str>) -> Option<ReviewItem> {
+        let mut inner = self.inner.lock().unwrap();
+        let item = inner.buf.iter_mut().find(|i| i.id == id && i.status.is_open())?;
+        item.status = ReviewStatus::Requested;
+        if let Some(n) = note.map(str::trim).filter(|n| !n.is_empty()) {
+            item.note = Some(n.to_string());
+        }
+        Some(item.clone())
+    }
+
+    // Close item `id` with `status`, along with every other open item for the same domain, since
+    // the decision is about the domain. Returns the item, or None if it is unknown or closed.
+    pub fn resolve(&self, id: u64, status: ReviewStatus) -> Option<ReviewItem> {
+        let mut inner = self.inner.lock().unwrap();
+        let domain = inner.buf.iter().find(|i| i.id == id && i.status.is_open())?.domain.clone();
+        let mut resolved = None;
+        for item in inner.buf.iter_mut().filter(|i| i.status.is_open() && i.domain == domain) {
+            item.status = status;
+            if item.id == id { resolved = Some(item.clone()); }
+        }
+        resolved
+    }
+}
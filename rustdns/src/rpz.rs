@@ -0,0 +1,579 @@
+use crate::state::ServerState;
+use crate::store::data_dir;
+use crate::watcher::watch_lists_enabled;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+// Time allowed for one zone transfer, including connecting.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A QNAME policy rule from a Response Policy Zone.
+pub enum RpzRule {
+    // `CNAME .` (NXDOMAIN) and `CNAME rpz-drop.`: blocked like any list entry, with the current
+    // blocking mode
+    Block(String),
+    // `CNAME *.`: an empty NOERROR answer
+    NoData(String),
+    // `CNAME garden.example.`: answered with a CNAME to the walled-garden name
+    Redirect(String, String),
+    // `CNAME rpz-passthru.`: never blocked, even when another rule matches
+    Passthru(String),
+    // A / AAAA local data
+    Local(String, IpAddr),
+}
+
+// Parse an RPZ zone in master file format. Trigger names are the owner names with the zone name
+// (from the SOA record or $ORIGIN) stripped. Only QNAME triggers are supported; rpz-ip,
+// rpz-nsdname, rpz-nsip and rpz-client-ip triggers, rpz-tcp-only and record types other than
+// CNAME / A / AAAA are skipped.
+pub fn parse_rpz(text: &str) -> Vec<RpzRule> {
+    let mut origin = String::new();
+    let mut zone: Option<String> = None;
+    let mut owner = String::new();
+    let mut rules = Vec::new();
+    for (continued, tokens) in records(text) {
+        let Some(first) = tokens.first() else { continue };
+        if first.eq_ignore_ascii_case("$ORIGIN") {
+            if let Some(o) = tokens.get(1) { origin = absolute(o, &origin); }
+            continue;
+        }
+        if first.starts_with('$') { continue }
+        let mut rest = tokens.as_slice();
+        if !continued {
+            owner = absolute(first, &origin);
+            rest = &rest[1..];
+        }
+        // optional TTL and class, in either order
+        while let Some(t) = rest.first() {
+            let is_class = ["in", "ch", "hs", "cs"].iter().any(|c| t.eq_ignore_ascii_case(c));
+            if !is_class && !t.starts_with(|c: char| c.is_ascii_digit()) { break }
+            rest = &rest[1..];
+        }
+        let Some((rtype, rdata)) = rest.split_first() else { continue };
+        let rtype = rtype.to_uppercase();
+        if rtype == "SOA" {
+            if zone.is_none() && !owner.is_empty() { zone = Some(owner.clone()); }
+            continue;
+        }
+        let Some(trigger) = trigger_name(&owner, zone.as_deref().unwrap_or(&origin)) else { continue };
+        if trigger.split('.').any(|l| l.starts_with("rpz-")) { continue }
+        match (rtype.as_str(), rdata.first()) {
+            ("CNAME", Some(target)) => {
+                let target = target.to_lowercase();
+                let rule = match target.as_str() {
+                    "." | "rpz-drop." => RpzRule::Block(trigger),
+                    "*." => RpzRule::NoData(trigger),
+                    "rpz-passthru." => RpzRule::Passthru(trigger),
+                    t if t.starts_with("*.") || t.starts_with("rpz-") => continue,
+                    t => RpzRule::Redirect(trigger, absolute(t, &origin)),
+                };
+                rules.push(rule);
+            }
+            ("A" | "AAAA", Some(ip)) if !trigger.starts_with("*.") => {
+                if let Ok(ip) = ip.parse() { rules.push(RpzRule::Local(trigger, ip)); }
+            }
+            _ => {}
+        }
+    }
+    rules
+}
+
+// Records of a zone file as tokens, with comments removed and parenthesised continuations
+// joined. The flag is set when the record has no owner name (the line starts with a blank), so
+// it reuses the previous one.
+fn records(text: &str) -> Vec<(bool, Vec<String>)> {
+    let mut out = Vec::new();
+    let mut pending: Option<(bool, Vec<String>)> = None;
+    let mut depth = 0i32;
+    for line in text.lines() {
+        let content = line.split(';').next().unwrap_or("");
+        depth += content.matches('(').count() as i32 - content.matches(')').count() as i32;
+        let tokens = content.replace(['(', ')'], " ");
+        let tokens = tokens.split_whitespace().map(str::to_string);
+        match pending.as_mut() {
+            Some((_, acc)) => acc.extend(tokens),
+            None => {
+                let continued = line.starts_with([' ', '\t']);
+                pending = Some((continued, tokens.collect()));
+            }
+        }
+        if depth <= 0 {
+            depth = 0;
+            if let Some(rec) = pending.take() {
+                if !rec.1.is_empty() { out.push(rec); }
+            }
+        }
+    }
+    out.extend(pending.filter(|rec| !rec.1.is_empty()));
+    out
+}
+
+// `name` made absolute against `origin`, lowercase and without the trailing dot.
+fn absolute(name: &str, origin: &str) -> String {
+    let name = name.to_lowercase();
+    if name == "@" { return origin.to_string() }
+    if let Some(abs) = name.strip_suffix('.') { return abs.to_string() }
+    if origin.is_empty() { name } else { format!("{}.{}", name, origin) }
+}
+
+// The trigger an owner name stands for: the part before the zone name. None for the zone apex
+// and names outside the zone.
+fn trigger_name(owner: &str, zone: &str) -> Option<String> {
+    if zone.is_empty() { return Some(owner.to_string()).filter(|o| !o.is_empty()) }
+    owner.strip_suffix(zone)?.strip_suffix('.').filter(|t| !t.is_empty()).map(str::to_string)
+}
+
+// What a sync round does with a feed whose transfer fails.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    // keep serving the last good copy
+    Keep,
+    // drop the feed's rules until a transfer succeeds again
+    Skip,
+    // raise an alert and apply none of the round's updates, so the feeds stay consistent
+    Hold,
+}
+
+impl FailurePolicy {
+    fn name(self) -> &'static str {
+        match self {
+            FailurePolicy::Keep => "keep",
+            FailurePolicy::Skip => "skip",
+            FailurePolicy::Hold => "hold",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "keep" => Some(FailurePolicy::Keep),
+            "skip" => Some(FailurePolicy::Skip),
+            "hold" => Some(FailurePolicy::Hold),
+            _ => None,
+        }
+    }
+}
+
+// Content pin of a feed treated as security-critical configuration.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pin {
+    // the zone must hash to this SHA-256 (hex); other content counts as a failed transfer
+    Sha256(String),
+    // changed content is staged until approved through POST /rpz/{zone}/approve
+    Approve,
+}
+
+// A policy zone transferred from a provider.
+#[derive(Clone, Debug)]
+pub struct RpzFeed {
+    pub zone: String,
+    pub server: SocketAddr,
+    pub on_failure: FailurePolicy,
+    pub pin: Option<Pin>,
+}
+
+impl RpzFeed {
+    // Where the transferred zone is written: the `rpz` group of the blocklist directory.
+    fn path(&self, dir: &str) -> PathBuf {
+        Path::new(dir).join("rpz").join(format!("{}.rpz", self.zone))
+    }
+
+    // Where a transfer waiting for approval is kept, outside the list directory.
+    fn pending_path(&self) -> PathBuf {
+        data_dir().join("rpz-pending").join(format!("{}.rpz", self.zone))
+    }
+}
+
+// RUSTDNS_RPZ_PIN=zone=<sha256>|approve[,...]. The hash is the `sha256` GET /rpz reports for a
+// transfer; it covers the zone's records but not its SOA, so serial bumps alone don't change it.
+fn pins_from_env() -> Vec<(String, Pin)> {
+    let Ok(raw) = crate::envvars::var("RUSTDNS_RPZ_PIN") else { return Vec::new() };
+    raw.split(',').map(str::trim).filter(|s| !s.is_empty()).filter_map(|spec| {
+        let parsed = spec.split_once('=').and_then(|(zone, pin)| {
+            let pin = pin.trim().to_lowercase();
+            let pin = match pin.as_str() {
+                "approve" => Pin::Approve,
+                h if h.len() == 64 && h.bytes().all(|b| b.is_ascii_hexdigit()) => Pin::Sha256(pin),
+                _ => return None,
+            };
+            Some((zone.trim().trim_end_matches('.').to_lowercase(), pin))
+        });
+        if parsed.is_none() { tracing::warn!("ignoring RPZ pin {:?}: expected zone=<sha256> or zone=approve", spec); }
+        parsed
+    }).collect()
+}
+
+// RUSTDNS_RPZ_ZONES=zone@server[/policy][,...], where server is an address with an optional
+// port (default 53) and policy is keep, skip or hold (default RUSTDNS_RPZ_ON_FAILURE, else keep),
+// e.g. `rpz.example.net@192.0.2.53/hold`. Malformed entries are logged and skipped.
+fn feeds_from_env() -> Vec<RpzFeed> {
+    let Ok(raw) = crate::envvars::var("RUSTDNS_RPZ_ZONES") else { return Vec::new() };
+    let pins = pins_from_env();
+    let default_policy = crate::envvars::var("RUSTDNS_RPZ_ON_FAILURE").ok().and_then(|p| FailurePolicy::parse(&p)).unwrap_or(FailurePolicy::Keep);
+    raw.split(',').map(str::trim).filter(|s| !s.is_empty()).filter_map(|spec| {
+        let parsed = spec.split_once('@').and_then(|(zone, rest)| {
+            let (server, on_failure) = match rest.split_once('/') {
+                Some((server, policy)) => (server, FailurePolicy::parse(policy)?),
+                None => (rest, default_policy),
+            };
+            let server = server.parse::<SocketAddr>().ok()
+                .or_else(|| server.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 53)))?;
+            let zone = zone.trim_end_matches('.').to_lowercase();
+            let pin = pins.iter().find(|(z, _)| *z == zone).map(|(_, p)| p.clone());
+            (!zone.is_empty()).then_some(RpzFeed { zone, server, on_failure, pin })
+        });
+        if parsed.is_none() { tracing::warn!("ignoring RPZ feed {:?}: expected zone@server[/keep|skip|hold]", spec); }
+        parsed
+    }).collect()
+}
+
+// Seconds between zone transfers, at least a minute.
+fn refresh_from_env() -> Duration {
+    let secs = crate::envvars::var("RUSTDNS_RPZ_REFRESH").ok().and_then(|v| v.parse().ok()).unwrap_or(3600u64);
+    Duration::from_secs(secs.max(60))
+}
+
+// Sync state of one feed, as reported by GET /rpz.
+#[derive(Clone, Serialize)]
+pub struct FeedStatus {
+    pub zone: String,
+    pub server: String,
+    pub on_failure: FailurePolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pin: Option<Pin>,
+    pub serial: Option<u32>,
+    // hash of the zone in use
+    pub sha256: Option<String>,
+    // a transfer waiting for approval
+    pub pending: Option<PendingZone>,
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    // rules dropped after a failure under the skip policy
+    pub skipped: bool,
+}
+
+#[derive(Clone, Serialize)]
+pub struct PendingZone {
+    pub serial: u32,
+    pub sha256: String,
+    pub since: u64,
+}
+
+// The configured feeds and how their last transfers went.
+pub struct RpzFeeds {
+    pub feeds: Vec<RpzFeed>,
+    pub refresh: Duration,
+    status: Mutex<Vec<FeedStatus>>,
+}
+
+impl RpzFeeds {
+    pub fn from_env() -> Self {
+        let feeds = feeds_from_env();
+        let status = feeds.iter().map(|f| FeedStatus {
+            zone: f.zone.clone(),
+            server: f.server.to_string(),
+            on_failure: f.on_failure,
+            pin: f.pin.clone(),
+            serial: None,
+            sha256: None,
+            pending: None,
+            last_attempt: None,
+            last_success: None,
+            consecutive_failures: 0,
+            last_error: None,
+            skipped: false,
+        }).collect();
+        RpzFeeds { feeds, refresh: refresh_from_env(), status: Mutex::new(status) }
+    }
+
+    pub fn status(&self) -> Vec<FeedStatus> {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Send `query` over TCP to `server` and read responses until `done` says the exchange is over.
+async fn exchange(server: SocketAddr, query: &Message, mut done: impl FnMut(&Message) -> bool) -> Result<()> {
+    let mut stream = TcpStream::connect(server).await?;
+    let raw = query.to_vec()?;
+    stream.write_all(&(raw.len() as u16).to_be_bytes()).await?;
+    stream.write_all(&raw).await?;
+    loop {
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0; usize::from(len)];
+        stream.read_exact(&mut buf).await?;
+        let resp = Message::from_vec(&buf)?;
+        if resp.response_code() != ResponseCode::NoError {
+            return Err(anyhow!("{} answered {}", server, resp.response_code()));
+        }
+        if done(&resp) { return Ok(()) }
+    }
+}
+
+fn soa_serial(rec: &Record) -> Option<u32> {
+    match rec.data() {
+        Some(RData::SOA(soa)) => Some(soa.serial()),
+        _ => None,
+    }
+}
+
+// Current SOA serial of the feed's zone.
+async fn fetch_serial(feed: &RpzFeed) -> Result<u32> {
+    let name = Name::from_str(&format!("{}.", feed.zone))?;
+    let query = crate::server::query_message(rand_id(), name, RecordType::SOA);
+    let mut serial = None;
+    exchange(feed.server, &query, |resp| {
+        serial = resp.answers().iter().find_map(soa_serial);
+        true
+    }).await?;
+    serial.ok_or_else(|| anyhow!("no SOA for {} at {}", feed.zone, feed.server))
+}
+
+// Full zone transfer (AXFR): every record, ending with the closing SOA.
+async fn transfer(feed: &RpzFeed) -> Result<Vec<Record>> {
+    let name = Name::from_str(&format!("{}.", feed.zone))?;
+    let mut query = crate::server::query_message(rand_id(), name, RecordType::AXFR);
+    query.set_recursion_desired(false);
+    let mut records: Vec<Record> = Vec::new();
+    exchange(feed.server, &query, |resp| {
+        records.extend(resp.answers().iter().cloned());
+        let soas = records.iter().filter(|r| r.record_type() == RecordType::SOA).count();
+        soas >= 2 || resp.answers().is_empty()
+    }).await?;
+    if records.iter().filter(|r| r.record_type() == RecordType::SOA).count() < 2 {
+        return Err(anyhow!("transfer of {} from {} ended early", feed.zone, feed.server));
+    }
+    records.pop();
+    Ok(records)
+}
+
+fn rand_id() -> u16 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.subsec_nanos() as u16).unwrap_or(0)
+}
+
+// Serial and hash recorded in the header of a previously written zone file.
+fn saved_header(path: &Path) -> (Option<u32>, Option<String>) {
+    let Ok(text) = std::fs::read_to_string(path) else { return (None, None) };
+    let header = text.lines().next().unwrap_or("");
+    let field = |key: &str| header.split(", ").find_map(|f| f.strip_prefix(key)).map(|v| v.trim().to_string());
+    (field("serial ").and_then(|v| v.parse().ok()), field("sha256 "))
+}
+
+// SHA-256 (hex) over the zone's records in a fixed order, leaving out the SOA.
+fn zone_hash(records: &[Record]) -> String {
+    let mut lines: Vec<String> = records.iter().filter(|r| r.record_type() != RecordType::SOA).map(|r| r.to_string()).collect();
+    lines.sort();
+    let digest = ring::digest::digest(&ring::digest::SHA256, lines.join("\n").as_bytes());
+    digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A transferred zone, ready to be written.
+struct ZoneUpdate {
+    serial: u32,
+    sha256: String,
+    text: String,
+}
+
+enum Fetched {
+    // serial unchanged since the last transfer (or the one waiting for approval), or a new serial
+    // with the same content, which is left alone so the lists aren't reloaded for nothing
+    Unchanged(u32),
+    Update(ZoneUpdate),
+    // changed content of an approve-pinned feed
+    NeedsApproval(ZoneUpdate),
+}
+
+// Transfer the zone when its serial changed since the last transfer (`seen_serial`, else the one
+// in the zone file) or since the transfer waiting for approval, and check the result against the
+// feed's pin.
+async fn fetch_feed(feed: &RpzFeed, dir: &str, seen_serial: Option<u32>, pending_serial: Option<u32>) -> Result<Fetched> {
+    let serial = fetch_serial(feed).await?;
+    let (saved_serial, saved_hash) = saved_header(&feed.path(dir));
+    let pin_holds = match &feed.pin {
+        Some(Pin::Sha256(h)) => saved_hash.as_ref() == Some(h),
+        _ => true,
+    };
+    let seen = seen_serial.or(saved_serial) == Some(serial);
+    if (seen && pin_holds) || pending_serial == Some(serial) { return Ok(Fetched::Unchanged(serial)) }
+    let records = transfer(feed).await?;
+    let sha256 = zone_hash(&records);
+    let mut text = format!("; {} transferred from {}, serial {}, sha256 {}\n$ORIGIN {}.\n", feed.zone, feed.server, serial, sha256, feed.zone);
+    for rec in &records {
+        text.push_str(&rec.to_string());
+        text.push('\n');
+    }
+    let update = ZoneUpdate { serial, sha256, text };
+    match &feed.pin {
+        Some(Pin::Sha256(h)) if *h != update.sha256 => {
+            Err(anyhow!("content sha256 {} of serial {} does not match the pinned {}", update.sha256, serial, h))
+        }
+        _ if saved_hash.as_ref() == Some(&update.sha256) => {
+            tracing::debug!("RPZ {} serial {} has the same content as the zone in use", feed.zone, serial);
+            Ok(Fetched::Unchanged(serial))
+        }
+        Some(Pin::Approve) => Ok(Fetched::NeedsApproval(update)),
+        _ => Ok(Fetched::Update(update)),
+    }
+}
+
+async fn write_zone(path: &Path, text: &str) -> Result<()> {
+    if let Some(parent) = path.parent() { tokio::fs::create_dir_all(parent).await?; }
+    let tmp = path.with_extension("rpz.tmp");
+    tokio::fs::write(&tmp, text).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+// One sync round: transfer every feed, then apply the results according to the failed feeds'
+// policies. Returns whether any zone file changed.
+async fn sync_round(state: &ServerState, dir: &str) -> bool {
+    let rpz = &state.rpz;
+    let mut results = Vec::with_capacity(rpz.feeds.len());
+    for feed in &rpz.feeds {
+        let (seen_serial, pending_serial) = rpz.status.lock().unwrap().iter()
+            .find(|s| s.zone == feed.zone)
+            .map(|s| (s.serial.filter(|_| !s.skipped), s.pending.as_ref().map(|p| p.serial)))
+            .unwrap_or_default();
+        let res = match tokio::time::timeout(TRANSFER_TIMEOUT, fetch_feed(feed, dir, seen_serial, pending_serial)).await {
+            Ok(r) => r,
+            Err(_) => Err(anyhow!("timed out")),
+        };
+        if let Err(e) = &res {
+            tracing::warn!("RPZ transfer of {} from {} failed ({} policy): {:#}", feed.zone, feed.server, feed.on_failure.name(), e);
+        }
+        results.push(res);
+    }
+    let holding: Vec<&RpzFeed> = rpz.feeds.iter().zip(&results)
+        .filter(|(f, r)| f.on_failure == FailurePolicy::Hold && r.is_err())
+        .map(|(f, _)| f)
+        .collect();
+    for feed in &holding {
+        let detail = serde_json::json!({ "server": feed.server.to_string(), "held_feeds": rpz.feeds.len() });
+        state.raise_alert("rpz_hold", "", &feed.zone, detail);
+    }
+    let now = now_secs();
+    let mut changed = false;
+    for (i, (feed, res)) in rpz.feeds.iter().zip(results).enumerate() {
+        let path = feed.path(dir);
+        // None: transferred fine, but not applied this round
+        let applied = match res {
+            Ok(Fetched::NeedsApproval(update)) => {
+                match write_zone(&feed.pending_path(), &update.text).await {
+                    Ok(()) => {
+                        tracing::warn!("RPZ {} serial {} changed content (sha256 {}); waiting for approval", feed.zone, update.serial, update.sha256);
+                        let detail = serde_json::json!({ "serial": update.serial, "sha256": update.sha256 });
+                        state.raise_alert("rpz_approval", "", &feed.zone, detail);
+                        let mut status = rpz.status.lock().unwrap();
+                        let st = &mut status[i];
+                        st.pending = Some(PendingZone { serial: update.serial, sha256: update.sha256, since: now });
+                        st.last_success = Some(now);
+                        st.consecutive_failures = 0;
+                        st.last_error = None;
+                        None
+                    }
+                    Err(e) => Some(Err(e)),
+                }
+            }
+            Ok(_) if !holding.is_empty() => None,
+            Ok(Fetched::Update(update)) => Some(write_zone(&path, &update.text).await.map(|_| {
+                tracing::info!("RPZ {} updated to serial {} from {}", feed.zone, update.serial, feed.server);
+                changed = true;
+                (update.serial, Some(update.sha256))
+            })),
+            Ok(Fetched::Unchanged(serial)) => Some(Ok((serial, None))),
+            Err(e) => Some(Err(e)),
+        };
+        let mut status = rpz.status.lock().unwrap();
+        let st = &mut status[i];
+        st.last_attempt = Some(now);
+        match applied {
+            None => {}
+            Some(Ok((serial, sha256))) => {
+                if st.pending.as_ref().map(|p| p.serial) != Some(serial) {
+                    st.serial = Some(serial);
+                    st.sha256 = sha256.or_else(|| saved_header(&path).1);
+                }
+                st.last_success = Some(now);
+                st.consecutive_failures = 0;
+                st.last_error = None;
+                st.skipped = false;
+            }
+            Some(Err(e)) => {
+                st.consecutive_failures += 1;
+                st.last_error = Some(format!("{:#}", e));
+                if feed.on_failure == FailurePolicy::Skip && !st.skipped && holding.is_empty() {
+                    st.skipped = true;
+                    drop(status);
+                    if std::fs::remove_file(&path).is_ok() {
+                        tracing::warn!("dropped RPZ {} until a transfer succeeds", feed.zone);
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+    if !holding.is_empty() {
+        let zones: Vec<&str> = holding.iter().map(|f| f.zone.as_str()).collect();
+        tracing::warn!("holding the RPZ update: {} failed", zones.join(", "));
+    }
+    changed
+}
+
+// Apply the transfer of `zone` waiting for approval, if its hash is `sha256` (so what was reviewed
+// is what gets applied). Returns the serial now in use.
+pub async fn approve_pending(rpz: &RpzFeeds, zone: &str, sha256: &str, dir: &str) -> Result<u32> {
+    let zone = zone.trim_end_matches('.').to_lowercase();
+    let i = rpz.feeds.iter().position(|f| f.zone == zone).ok_or_else(|| anyhow!("unknown RPZ feed {}", zone))?;
+    let feed = &rpz.feeds[i];
+    let pending = rpz.status.lock().unwrap()[i].pending.clone().ok_or_else(|| anyhow!("nothing is waiting for approval for {}", zone))?;
+    if !pending.sha256.eq_ignore_ascii_case(sha256.trim()) {
+        return Err(anyhow!("sha256 does not match the pending transfer ({})", pending.sha256));
+    }
+    let text = tokio::fs::read_to_string(feed.pending_path()).await?;
+    write_zone(&feed.path(dir), &text).await?;
+    tokio::fs::remove_file(feed.pending_path()).await.ok();
+    let mut status = rpz.status.lock().unwrap();
+    let st = &mut status[i];
+    st.serial = Some(pending.serial);
+    st.sha256 = Some(pending.sha256);
+    st.pending = None;
+    st.skipped = false;
+    tracing::info!("RPZ {} serial {} approved", zone, pending.serial);
+    Ok(pending.serial)
+}
+
+// Keep the zones of RUSTDNS_RPZ_ZONES transferred into `dir/rpz/`, checking their serials every
+// RUSTDNS_RPZ_REFRESH seconds (default 3600). Changed zones are picked up by the list watcher,
+// or reloaded here when it is off. Failed transfers are retried next time; see `FailurePolicy`.
+pub fn spawn_rpz_sync(tasks: &mut JoinSet<()>, state: Arc<ServerState>, dir: &str) {
+    if state.rpz.feeds.is_empty() { return }
+    let dir = dir.to_string();
+    tasks.spawn(async move {
+        let mut tick = tokio::time::interval(state.rpz.refresh);
+        loop {
+            tick.tick().await;
+            if sync_round(&state, &dir).await && !watch_lists_enabled() {
+                match state.reload_lists(&dir).await {
+                    Ok(report) => tracing::info!("reloaded {} domains after RPZ update", report.loaded),
+                    Err(e) => tracing::warn!("reload after RPZ update failed: {:?}", e),
+                }
+            }
+        }
+    });
+}
@@ -1,64 +1,610 @@
-use crate::state::ServerState;
-use crate::blocklist::load_blocklists_into;
-use crate::control::{http_reload, http_stats, http_lists, http_add, http_remove, http_mode};
-use crate::server::run_udp_server;
-use axum::{routing::get, routing::post, Router};
-use std::collections::HashSet;
+use crate::state::{BlockingSwitch, ReloadPolicy, ServerState};
+use crate::blocklist::{Blocklist, GroupModes, LoadOptions};
+use crate::control::{http_reload, http_compile, http_stats, http_stats_top, http_stats_history, http_stats_longterm, http_metrics, http_lists, http_add, http_add_bulk, http_remove, http_remove_bulk, http_mode, http_client_unban, http_groups, http_group_toggle, http_group_mode, http_group_schedule, http_blocking, http_audit, http_events, http_check, http_rdap, http_resolve, http_debug_resolve, http_client_debug, http_client_traces, http_alerts, http_queries, http_queries_stream, http_brands, http_brand_add, http_brand_remove, http_pause, http_resume, http_canaries, http_canary_add, http_canary_remove, http_allowlist, http_allow_presets, http_allow_preset_toggle, http_allow, http_allow_remove, http_acme, http_acme_challenge, http_export, http_tls, http_clients, http_client_create, http_client_get, http_client_update, http_client_delete, http_config, http_config_update, http_backup, http_restore, http_healthz, http_readyz, http_review, http_review_request, http_review_approve, http_review_confirm, http_rpz, http_rpz_approve, http_categories, http_category_toggle, http_categories_reload};
+use crate::listeners::{Listener, ListenerPolicy};
+use crate::systemd::Sockets;
+use crate::supervisor::supervise;
+use crate::ratelimit::ClientLimiter;
+use crate::rdap::RdapClient;
+use crate::trace::DebugClients;
+use crate::resources::{resident_bytes, ResourceLimits, Resources};
+use crate::alerts::Alerts;
+use crate::brands::BrandGuard;
+use crate::canary::CanarySet;
+use crate::allowlist::Allowlist;
+use crate::acme::{spawn_renewal, Acme};
+use crate::tls::CertStore;
+use crate::dga::DgaDetector;
+use crate::clients::{ClientIdentity, ClientRegistry};
+use crate::tunnel::TunnelDetector;
+use crate::metrics::{QueryCounters, QueryLabels};
+use crate::cache::ResponseCache;
+use crate::prewarm::{spawn_prewarm, Prewarm};
+use crate::totals::LifetimeTotals;
+use crate::rpz::{spawn_rpz_sync, RpzFeeds};
+use crate::config::{build_features, effective_config, Config};
+use crate::review::ReviewQueue;
+use crate::categories::Categories;
+use crate::lifecycle::LifecycleHooks;
+use crate::api::{deprecated_alias, http_api_versions, version_header, API_PREFIX};
+use crate::openapi::{http_api_docs, http_openapi};
+use crate::dashboard::{http_dashboard_asset, http_dashboard_index, http_dashboard_redirect};
+use crate::auth::{require_token, ApiAuth, Role};
+use crate::health::Health;
+use crate::cors::{cors, CorsPolicy};
+use crate::apilimits::{rate_limit, ApiLimiter, ApiLimits, LimitedIncoming, HEADER_TIMEOUT};
+use crate::hits::RuleHits;
+use crate::history::StatsHistory;
+use crate::longterm::LongTermStats;
+use crate::querylog::QueryLog;
+use crate::https::{server_config, HttpTls, TlsIncoming};
+use crate::savedstate::SavedState;
+use crate::schedule::{spawn_group_scheduler, GroupSchedules};
+use crate::settings::RuntimeSettings;
+use crate::top::TopStats;
+use crate::selfnames::{SelfNameMode, SelfNames};
+use crate::watcher::{spawn_cert_watcher, spawn_list_watcher};
+use crate::server::{run_udp_server, wait_for_shutdown, PacketErrors};
+use anyhow::{anyhow, Result};
+use axum::{extract::{DefaultBodyLimit, Path}, middleware, routing::get, routing::post, Router};
+use hyper::server::conn::AddrIncoming;
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use std::sync::atomic::AtomicU64;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::JoinSet;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use tracing::info;
 
-pub async fn run_server(http_addr: String, udp_bind: String, shutdown_rx: tokio::sync::watch::Receiver<bool>) {
-    tracing_subscriber::fmt::init();
-
-    let lists = Arc::new(RwLock::new(HashSet::new()));
-    let state = Arc::new(ServerState {
-        lists: lists.clone(),
+// Build the shared server state with `config` and the other settings taken from the environment.
+pub fn build_state(config: &Config) -> Arc<ServerState> {
+    Arc::new(ServerState {
+        lists: Arc::new(RwLock::new(Arc::new(Blocklist::default()))),
+        reloading: Arc::new(AtomicBool::new(false)),
+        reload_policy: ReloadPolicy::from_env(),
+        load_options: LoadOptions::from_env(),
         queries: Arc::new(AtomicU64::new(0)),
         blocked: Arc::new(AtomicU64::new(0)),
-        upstream: "1.1.1.1:53".to_string(),
-        mode: Arc::new(RwLock::new("nx".to_string())),
-        block_page_ip: Arc::new(RwLock::new(None)),
+        totals: Arc::new(LifetimeTotals::from_env()),
+        history: Arc::new(StatsHistory::from_env()),
+        longterm: Arc::new(LongTermStats::from_env()),
+        settings: Arc::new(RuntimeSettings::from_env()),
+        lists_dir: crate::envvars::var("RUSTDNS_BLOCKLIST_DIR").unwrap_or_else(|_| "./blocklist".to_string()),
+        listeners: std::iter::once(Listener { addr: config.udp_bind, policy: ListenerPolicy::All }).chain(config.listeners.iter().cloned()).collect(),
+        mode: Arc::new(RwLock::new(config.blocking_mode)),
+        block_page_ip: Arc::new(RwLock::new(config.block_page_ip)),
+        limiter: Arc::new(ClientLimiter::from_env()),
+        rate_limited: Arc::new(AtomicU64::new(0)),
+        udp_errors: Arc::new(PacketErrors::default()),
+        blocking: Arc::new(BlockingSwitch::default()),
+        events: broadcast::channel(256).0,
+        rdap: Arc::new(RdapClient::from_env()),
+        debug_clients: Arc::new(DebugClients::default()),
+        resources: Arc::new(Resources::new(ResourceLimits::from_env())),
+        alerts: Arc::new(Alerts::default()),
+        brands: Arc::new(BrandGuard::from_env()),
+        canaries: Arc::new(CanarySet::from_env()),
+        allowlist: Arc::new(Allowlist::from_env()),
+        audit: Arc::new(AtomicBool::new(crate::envvars::var("RUSTDNS_AUDIT").map(|v| v == "1" || v == "true").unwrap_or(false))),
+        audited: Arc::new(AtomicU64::new(0)),
+        response_padding: response_padding_from_env(),
+        acme: Arc::new(Acme::from_env()),
+        certs: Arc::new(CertStore::from_env()),
+        dga: Arc::new(DgaDetector::from_env()),
+        identity: Arc::new(ClientIdentity::from_env()),
+        tunnel: Arc::new(TunnelDetector::from_env()),
+        clients: Arc::new(ClientRegistry::from_env()),
+        labels: Arc::new(QueryLabels::from_env()),
+        counters: Arc::new(QueryCounters::default()),
+        top: Arc::new(TopStats::from_env()),
+        cache: Arc::new(ResponseCache::from_env()),
+        prewarm: Arc::new(Prewarm::from_env()),
+        self_names: Arc::new(SelfNames::from_env()),
+        review: Arc::new(ReviewQueue::from_env()),
+        rpz: Arc::new(RpzFeeds::from_env()),
+        categories: Arc::new(Categories::from_env()),
+        lifecycle: Arc::new(LifecycleHooks::from_env()),
+        group_modes: Arc::new(GroupModes::from_env()),
+        hits: Arc::new(RuleHits::new()),
+        schedules: Arc::new(GroupSchedules::from_env()),
+        auth: Arc::new(ApiAuth::from_env()),
+        http_tls: HttpTls::from_env(),
+        query_log: Arc::new(QueryLog::from_env()),
+        last_load: Arc::new(Mutex::new(None)),
+        health: Arc::new(Health::default()),
+        cors: Arc::new(CorsPolicy::from_env()),
+        api_limits: Arc::new(ApiLimiter::new(ApiLimits::from_env())),
+        saved_state: Arc::new(SavedState::from_env()),
+    })
+}
+
+// Resident memory the minimal build aims to stay under once lists are loaded; see "Minimal build"
+// in the README.
+const MINIMAL_MEMORY_TARGET: u64 = 64 << 20;
+
+// Log resident memory after the initial list load, so small boards can see their headroom.
+async fn report_memory(state: &ServerState) {
+    let Some(rss) = resident_bytes() else { return };
+    let bl = state.lists.read().await;
+    let compiled = bl.compiled.as_ref().map(|c| c.len()).unwrap_or(0);
+    info!("resident memory after load: {:.1} MiB ({} names compiled)", rss as f64 / (1 << 20) as f64, compiled);
+    if cfg!(feature = "minimal") && rss > MINIMAL_MEMORY_TARGET {
+        tracing::warn!("resident memory is above the {} MiB target of the minimal build; compile the lists with POST /compile to keep them out of the heap", MINIMAL_MEMORY_TARGET >> 20);
+    }
+}
+
+// RUSTDNS_RESPONSE_PADDING=<block size in bytes>, or "true" for the RFC 8467 recommendation of 468.
+fn response_padding_from_env() -> usize {
+    match crate::envvars::var("RUSTDNS_RESPONSE_PADDING").as_deref() {
+        Ok("true") | Ok("on") => 468,
+        Ok(v) => v.parse().unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+// RUSTDNS_LOG_LEVEL: the most detailed log level written (`error`, `warn`, `info`, `debug` or
+// `trace`; default `info`).
+pub fn log_level_from_env() -> tracing::Level {
+    crate::envvars::var("RUSTDNS_LOG_LEVEL").ok().and_then(|l| l.trim().parse().ok()).unwrap_or(tracing::Level::INFO)
+}
+
+type ControlServer = Pin<Box<dyn Future<Output = hyper::Result<()>> + Send>>;
+
+// The control API serving `app` on `http_addr`, or on `passed` (a socket systemd bound for it),
+// until shutdown.
+async fn bind_control_api(state: &Arc<ServerState>, app: Router, http_addr: SocketAddr, passed: Option<std::net::TcpListener>, mut shutdown_rx: tokio::sync::watch::Receiver<bool>) -> std::io::Result<ControlServer> {
+    let listener = match passed {
+        Some(listener) => tokio::net::TcpListener::from_std(listener)?,
+        None => tokio::net::TcpListener::bind(http_addr).await?,
+    };
+    // HTTP graceful shutdown
+    let http_shutdown = async move {
+        wait_for_shutdown(&mut shutdown_rx).await;
+    };
+    if state.http_tls == HttpTls::Off {
+        let incoming = AddrIncoming::from_listener(listener).map_err(std::io::Error::other)?;
+        info!("control API listening on http://{}", http_addr);
+        Ok(Box::pin(axum::Server::builder(LimitedIncoming::new(incoming, state.api_limits.clone()))
+            .http1_header_read_timeout(HEADER_TIMEOUT)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(http_shutdown)))
+    } else {
+        if state.certs.status().loaded {
+            info!("control API listening on https://{}", http_addr);
+        } else {
+            tracing::warn!("control API on https://{} has no certificate yet ({}); handshakes fail until one is loaded", http_addr, state.certs.cert_path.display());
+        }
+        let incoming = TlsIncoming::new(listener, server_config(state.certs.clone()));
+        Ok(Box::pin(axum::Server::builder(LimitedIncoming::new(incoming, state.api_limits.clone()))
+            .http1_header_read_timeout(HEADER_TIMEOUT)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(http_shutdown)))
+    }
+}
+
+// The control API couldn't be bound to `addr`, e.g. because it is in use. run_server fails with
+// this, so the embedding APIs can tell it from other startup failures by its type.
+#[derive(Debug)]
+pub struct BindError {
+    pub addr: SocketAddr,
+    pub source: std::io::Error,
+}
+
+impl std::fmt::Display for BindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "binding the control API to {}: {}", self.addr, self.source)
+    }
+}
+
+impl std::error::Error for BindError {}
+
+pub async fn run_server(state: Arc<ServerState>, config: Config, shutdown_rx: tokio::sync::watch::Receiver<bool>) -> Result<()> {
+    let http_addr = config.http_addr;
+    let udp_bind = config.udp_bind.to_string();
+    info!("rustdns {} starting (features: {})", env!("CARGO_PKG_VERSION"), build_features().join(", "));
+    info!("effective configuration: {}", effective_config(&state, &http_addr.to_string(), &udp_bind).await);
+
+    // periodic jobs and watchers; they hold the state, so they are stopped before this returns
+    // and none outlive the server into the next one started in the same process
+    let mut tasks = JoinSet::new();
+
+    // resume blocking once a timed pause runs out, so subscribers see the change, and drop
+    // expired temporary allow entries
+    let st_pause = state.clone();
+    tasks.spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            if st_pause.blocking.expire_if_due() {
+                info!("blocking pause expired; blocking re-enabled");
+                st_pause.emit_blocking_changed();
+            }
+            for d in st_pause.allowlist.purge_expired() {
+                info!("temporary allow for {} expired", d);
+            }
+        }
+    });
+
+    // write last-seen times of the client registry back periodically, forgetting idle clients
+    // that were registered automatically
+    let st_clients = state.clients.clone();
+    tasks.spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            tick.tick().await;
+            st_clients.expire();
+            st_clients.flush_if_dirty();
+        }
+    });
+
+    // save lifetime totals every minute
+    let st_totals = state.clone();
+    tasks.spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            tick.tick().await;
+            st_totals.save_totals();
+        }
+    });
+
+    // sample the counters into the stats history and long-term rollups every minute, and save
+    // them every 10 and 5 minutes
+    let st_stats_history = state.clone();
+    tasks.spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(60));
+        for n in 1u64.. {
+            tick.tick().await;
+            st_stats_history.sample_history();
+            if n % 10 == 0 { st_stats_history.history.save(); }
+            if n % 5 == 0 { st_stats_history.longterm.save(); }
+        }
+    });
+
+    // save the query history used for pre-warming every 10 minutes
+    let st_history = state.prewarm.clone();
+    tasks.spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(600));
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            st_history.save();
+        }
     });
 
     // initial load
-    if let Ok(n) = load_blocklists_into("./blocklist", &lists).await {
-        info!("initially loaded {} domains", n);
+    if let Ok(report) = state.reload_lists(&state.lists_dir).await {
+        info!("initially loaded {} domains from {} files", report.loaded, report.files.len());
+    }
+    match state.categories.reload().await {
+        Ok((0, _)) => {}
+        Ok((categories, domains)) => info!("loaded {} categories with {} domains from {}", categories, domains, state.categories.dir.display()),
+        Err(e) => tracing::warn!("loading categories from {} failed: {:?}", state.categories.dir.display(), e),
+    }
+    // put back what users changed before the last shutdown, and from then on save changes within
+    // a few seconds; not before, or a slow list load would overwrite the file with defaults
+    state.saved_state.restore(&state).await;
+    let st_saved = state.clone();
+    tasks.spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            tick.tick().await;
+            st_saved.saved_state.save(&st_saved).await;
+        }
+    });
+    report_memory(&state).await;
+    let own = &state.self_names;
+    match own.mode {
+        _ if own.names.is_empty() => {}
+        SelfNameMode::Exempt => info!("own hostnames {} are never blocked", own.names.join(", ")),
+        SelfNameMode::Local if own.addrs.is_empty() => info!("own hostnames {} resolve to the block page address", own.names.join(", ")),
+        SelfNameMode::Local => {
+            let addrs: Vec<String> = own.addrs.iter().map(|a| a.to_string()).collect();
+            info!("own hostnames {} resolve to {}", own.names.join(", "), addrs.join(", "));
+        }
     }
+    spawn_prewarm(&mut tasks, state.clone());
+    spawn_list_watcher(&mut tasks, state.clone(), &state.lists_dir);
+    spawn_rpz_sync(&mut tasks, state.clone(), &state.lists_dir);
+    spawn_group_scheduler(&mut tasks, state.clone());
+    if state.http_tls == HttpTls::SelfSigned {
+        #[cfg(feature = "self-signed")]
+        match state.certs.ensure_self_signed(crate::https::self_signed_names(&http_addr, &state.self_names.names, &state.self_names.addrs)) {
+            Ok(Some(fingerprint)) => info!("wrote a self-signed certificate to {} (SHA-256 {})", state.certs.cert_path.display(), fingerprint),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("writing a self-signed certificate failed: {:#}", e),
+        }
+        #[cfg(not(feature = "self-signed"))]
+        tracing::warn!("RUSTDNS_HTTP_TLS=self-signed needs a build with the self-signed feature; provide {} instead", state.certs.cert_path.display());
+    }
+    if state.certs.configured() {
+        match state.certs.reload() {
+            Ok(()) => info!("loaded TLS certificate {}", state.certs.cert_path.display()),
+            Err(e) => tracing::warn!("TLS certificate not loaded: {:#}", e),
+        }
+    }
+    spawn_cert_watcher(&mut tasks, state.certs.clone());
+    spawn_renewal(&mut tasks, state.acme.clone(), state.certs.clone());
 
     // HTTP control plane
     let st_http = state.clone();
+    let st_compile = state.clone();
     let st_stats = state.clone();
     let st_lists = state.clone();
     let st_add = state.clone();
     let st_remove = state.clone();
+    let st_add_bulk = state.clone();
+    let st_remove_bulk = state.clone();
     let st_mode = state.clone();
-    let app = Router::new()
+    let st_unban = state.clone();
+    let st_groups = state.clone();
+    let st_enable = state.clone();
+    let st_disable = state.clone();
+    let st_group_mode = state.clone();
+    let st_group_schedule = state.clone();
+    let st_blocking = state.clone();
+    let st_audit = state.clone();
+    let st_events = state.clone();
+    let st_check = state.clone();
+    let st_rdap = state.clone();
+    let st_resolve = state.clone();
+    let st_debug = state.clone();
+    let st_traces = state.clone();
+    let st_alerts = state.clone();
+    let st_queries = state.clone();
+    let st_queries_stream = state.clone();
+    let st_metrics = state.clone();
+    let st_top = state.clone();
+    let st_stats_longterm = state.clone();
+    let st_stats_history = state.clone();
+    let st_brands = state.clone();
+    let st_brand_add = state.clone();
+    let st_brand_remove = state.clone();
+    let st_pause_http = state.clone();
+    let st_resume = state.clone();
+    let st_debug_resolve = state.clone();
+    let st_backup = state.clone();
+    let st_restore = state.clone();
+    let st_blocking_off = state.clone();
+    let st_blocking_on = state.clone();
+    let st_canaries = state.clone();
+    let st_canary_add = state.clone();
+    let st_canary_remove = state.clone();
+    let st_allowlist = state.clone();
+    let st_allow_presets = state.clone();
+    let st_allow_preset_enable = state.clone();
+    let st_allow_preset_disable = state.clone();
+    let st_allow = state.clone();
+    let st_allow_remove = state.clone();
+    let st_acme = state.clone();
+    let st_tls = state.clone();
+    let st_export = state.clone();
+    let st_acme_challenge = state.clone();
+    let st_clients = state.clone();
+    let st_client_create = state.clone();
+    let st_client_get = state.clone();
+    let st_client_update = state.clone();
+    let st_client_delete = state.clone();
+    let st_config = state.clone();
+    let st_healthz = state.clone();
+    let st_readyz = state.clone();
+    let st_config_update = state.clone();
+    let st_review = state.clone();
+    let st_rpz = state.clone();
+    let st_categories = state.clone();
+    let st_category_block = state.clone();
+    let st_category_unblock = state.clone();
+    let st_categories_reload = state.clone();
+    let st_rpz_approve = state.clone();
+    let st_review_request = state.clone();
+    let st_review_approve = state.clone();
+    let st_review_confirm = state.clone();
+    let listen = (http_addr.to_string(), udp_bind.clone());
+    let listen_update = listen.clone();
+    let api = Router::new()
         .route("/reload", post(move || http_reload(st_http.clone())))
+        .route("/compile", post(move || http_compile(st_compile.clone())))
         .route("/stats", get(move || http_stats(st_stats.clone())))
-        .route("/lists", get(move || http_lists(st_lists.clone())))
+        .route("/stats/top", get(move |q| http_stats_top(st_top.clone(), q)))
+        .route("/stats/history", get(move |q| http_stats_history(st_stats_history.clone(), q)))
+        .route("/stats/longterm", get(move |q| http_stats_longterm(st_stats_longterm.clone(), q)))
+        .route("/lists", get(move |q| http_lists(st_lists.clone(), q)))
         .route("/add", post(move |b| http_add(st_add.clone(), b)))
         .route("/remove", post(move |b| http_remove(st_remove.clone(), b)))
-        .route("/mode", post(move |b| http_mode(st_mode.clone(), b)));
+        .route("/add-bulk", post(move |h, b| http_add_bulk(st_add_bulk.clone(), h, b)).layer(DefaultBodyLimit::max(state.api_limits.limits.bulk_max_bytes)))
+        .route("/remove-bulk", post(move |h, b| http_remove_bulk(st_remove_bulk.clone(), h, b)).layer(DefaultBodyLimit::max(state.api_limits.limits.bulk_max_bytes)))
+        .route("/mode", post(move |b| http_mode(st_mode.clone(), b)))
+        .route("/clients", get(move || http_clients(st_clients.clone()))
+            .post(move |b| http_client_create(st_client_create.clone(), b)))
+        .route("/clients/:id", get(move |Path(id): Path<String>| http_client_get(st_client_get.clone(), id))
+            .put(move |Path(id): Path<String>, b| http_client_update(st_client_update.clone(), id, b))
+            .delete(move |Path(id): Path<String>| http_client_delete(st_client_delete.clone(), id)))
+        .route("/clients/:id/unban", post(move |p| http_client_unban(st_unban.clone(), p)))
+        .route("/groups", get(move || http_groups(st_groups.clone())))
+        .route("/groups/:name/enable", post(move |Path(n): Path<String>| http_group_toggle(st_enable.clone(), n, true)))
+        .route("/groups/:name/disable", post(move |Path(n): Path<String>| http_group_toggle(st_disable.clone(), n, false)))
+        .route("/groups/:name/mode", post(move |Path(n): Path<String>, b| http_group_mode(st_group_mode.clone(), n, b)))
+        .route("/groups/:name/schedule", post(move |Path(n): Path<String>, b| http_group_schedule(st_group_schedule.clone(), n, b)))
+        .route("/categories", get(move || http_categories(st_categories.clone())))
+        .route("/categories/reload", post(move || http_categories_reload(st_categories_reload.clone())))
+        .route("/categories/:name/block", post(move |Path(n): Path<String>| http_category_toggle(st_category_block.clone(), n, true)))
+        .route("/categories/:name/unblock", post(move |Path(n): Path<String>| http_category_toggle(st_category_unblock.clone(), n, false)))
+        .route("/blocking", post(move |b| http_blocking(st_blocking.clone(), b)))
+        .route("/audit", post(move |b| http_audit(st_audit.clone(), b)))
+        .route("/events", get(move || http_events(st_events.clone())))
+        .route("/check", get(move |q| http_check(st_check.clone(), q)))
+        .route("/lookup/rdap", get(move |q| http_rdap(st_rdap.clone(), q)))
+        .route("/resolve", get(move |q| http_resolve(st_resolve.clone(), q)))
+        .route("/clients/:id/debug", post(move |p, b| http_client_debug(st_debug.clone(), p, b))
+            .get(move |p| http_client_traces(st_traces.clone(), p)))
+        .route("/alerts", get(move |q| http_alerts(st_alerts.clone(), q)))
+        .route("/queries", get(move |q| http_queries(st_queries.clone(), q)))
+        .route("/queries/stream", get(move |q| http_queries_stream(st_queries_stream.clone(), q)))
+        .route("/metrics", get(move || http_metrics(st_metrics.clone())))
+        .route("/review", get(move |q| http_review(st_review.clone(), q)))
+        .route("/review/:id/request", post(move |Path(id): Path<String>, b| http_review_request(st_review_request.clone(), id, b)))
+        .route("/review/:id/approve", post(move |Path(id): Path<String>, b| http_review_approve(st_review_approve.clone(), id, b)))
+        .route("/review/:id/confirm", post(move |Path(id): Path<String>| http_review_confirm(st_review_confirm.clone(), id)))
+        .route("/brands", get(move || http_brands(st_brands.clone()))
+            .post(move |b| http_brand_add(st_brand_add.clone(), b)))
+        .route("/brands/remove", post(move |b| http_brand_remove(st_brand_remove.clone(), b)))
+        .route("/pause", post(move |q| http_pause(st_pause_http.clone(), q)))
+        .route("/resume", post(move || http_resume(st_resume.clone())))
+        .route("/debug/resolve", post(move |body| http_debug_resolve(st_debug_resolve.clone(), body)))
+        .route("/backup", get(move || http_backup(st_backup.clone())))
+        .route("/restore", post(move |body| http_restore(st_restore.clone(), body)).layer(DefaultBodyLimit::max(state.api_limits.limits.restore_max_bytes)))
+        .route("/disable", post(move |q| http_pause(st_blocking_off.clone(), q)))
+        .route("/enable", post(move || http_resume(st_blocking_on.clone())))
+        .route("/canaries", get(move || http_canaries(st_canaries.clone()))
+            .post(move |b| http_canary_add(st_canary_add.clone(), b)))
+        .route("/canaries/remove", post(move |b| http_canary_remove(st_canary_remove.clone(), b)))
+        .route("/allowlist", get(move || http_allowlist(st_allowlist.clone())))
+        .route("/allowlist/presets", get(move || http_allow_presets(st_allow_presets.clone())))
+        .route("/allowlist/presets/:name/enable", post(move |Path(n): Path<String>| http_allow_preset_toggle(st_allow_preset_enable.clone(), n, true)))
+        .route("/allowlist/presets/:name/disable", post(move |Path(n): Path<String>| http_allow_preset_toggle(st_allow_preset_disable.clone(), n, false)))
+        .route("/allow", post(move |b| http_allow(st_allow.clone(), b)))
+        .route("/allow/remove", post(move |b| http_allow_remove(st_allow_remove.clone(), b)))
+        .route("/rpz", get(move || http_rpz(st_rpz.clone())))
+        .route("/rpz/:zone/approve", post(move |Path(zone): Path<String>, b| http_rpz_approve(st_rpz_approve.clone(), zone, b)))
+        .route("/export", get(move |q| http_export(st_export.clone(), q)))
+        .route("/config", get(move || http_config(st_config.clone(), listen.clone()))
+            .put(move |b| http_config_update(st_config_update.clone(), listen_update.clone(), b)))
+        .route("/tls", get(move || http_tls(st_tls.clone())))
+        .route("/acme", get(move || http_acme(st_acme.clone())));
+    #[cfg(feature = "graphql")]
+    let api = {
+        let st_graphql = state.clone();
+        let st_graphql_post = state.clone();
+        api.route("/graphql", get(move |m, q| crate::graphql::http_graphql(st_graphql.clone(), m, q, None))
+            .post(move |m, q, b| crate::graphql::http_graphql(st_graphql_post.clone(), m, q, b)))
+    };
+    // every route under /api/v1, and at its old unversioned path as a deprecated alias; health
+    // checks and ACME challenges keep fixed paths outside the API
+    let app = Router::new()
+        .nest(API_PREFIX, api.clone())
+        .merge(api.route_layer(middleware::from_fn(deprecated_alias)))
+        .route("/api", get(http_api_versions))
+        .route(API_PREFIX, get(http_api_versions))
+        .route("/api/openapi.json", get(http_openapi))
+        .route("/api/docs", get(http_api_docs))
+        .route("/", get(http_dashboard_redirect))
+        .route("/ui", get(http_dashboard_redirect))
+        .route("/ui/", get(http_dashboard_index))
+        .route("/ui/:file", get(http_dashboard_asset))
+        .route("/healthz", get(move || http_healthz(st_healthz.clone())))
+        .route("/readyz", get(move || http_readyz(st_readyz.clone())))
+        .route("/.well-known/acme-challenge/:token", get(move |Path(token): Path<String>| http_acme_challenge(st_acme_challenge.clone(), token)))
+        .layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .layer(middleware::from_fn(version_header))
+        .layer(middleware::from_fn_with_state(state.clone(), cors))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit));
 
-    let http_addr: SocketAddr = http_addr.parse().unwrap_or_else(|_| "127.0.0.1:9080".parse().unwrap());
-    let server = axum::Server::bind(&http_addr).serve(app.into_make_service());
-    info!("control API listening on http://{}", http_addr);
+    // sockets systemd bound for us take the place of binding the matching addresses
+    let mut sockets = if config.systemd { Sockets::from_env() } else { Sockets::default() };
+    let passed_http = sockets.take_tcp(http_addr).transpose()
+        .map_err(|e| anyhow!("socket systemd passed for the control API on {}: {}", http_addr, e))?;
+    // the control API binds before anything else starts, and failing to is a startup error; the
+    // supervisor retries binding it again after a later failure
+    let first_passed = passed_http.as_ref().map(|l| l.try_clone()).transpose()
+        .map_err(|e| anyhow!("socket systemd passed for the control API on {}: {}", http_addr, e))?;
+    let control = bind_control_api(&state, app.clone(), http_addr, first_passed, shutdown_rx.clone()).await
+        .map_err(|source| BindError { addr: http_addr, source })?;
 
-    let udp_upstream = state.upstream.clone();
+    // the gRPC control plane runs its calls through the same router
+    #[cfg(feature = "grpc")]
+    let grpc_task = crate::grpc::spawn_grpc(app.clone(), state.clone(), shutdown_rx.clone());
+    #[cfg(not(feature = "grpc"))]
+    let grpc_task: Option<tokio::task::JoinHandle<()>> = {
+        if crate::envvars::var("RUSTDNS_GRPC_ADDR").is_ok() { tracing::warn!("RUSTDNS_GRPC_ADDR is set but this build has no gRPC (feature \"grpc\")"); }
+        None
+    };
 
-    // HTTP graceful shutdown
-    let mut http_shutdown_rx = shutdown_rx.clone();
-    let http_future = server.with_graceful_shutdown(async move {
-        let _ = http_shutdown_rx.changed().await;
-    });
+    #[cfg(feature = "mqtt")]
+    let mqtt_task = crate::mqtt::spawn_mqtt(state.clone(), shutdown_rx.clone());
+    #[cfg(not(feature = "mqtt"))]
+    let mqtt_task: Option<tokio::task::JoinHandle<()>> = {
+        if crate::envvars::var("RUSTDNS_MQTT_BROKER").is_ok() { tracing::warn!("RUSTDNS_MQTT_BROKER is set but this build has no MQTT (feature \"mqtt\")"); }
+        None
+    };
+
+    if state.auth.enabled() {
+        info!("control API requires a bearer token ({} admin, {} read-only)", state.auth.token_count(Role::Admin), state.auth.token_count(Role::Read));
+        if state.http_tls == HttpTls::Off && !http_addr.ip().is_loopback() {
+            tracing::warn!("control API tokens cross the network in cleartext; set RUSTDNS_HTTP_TLS=on or self-signed");
+        }
+    } else if !http_addr.ip().is_loopback() {
+        tracing::warn!("control API on {} accepts requests without authentication; set RUSTDNS_API_TOKENS", http_addr);
+    }
+
+    // the control API and the DNS listeners run as separate components: one failing leaves the
+    // other serving while it is restarted
+    let mut first_control = Some(control);
+    let st_control = state.clone();
+    let control_rx = shutdown_rx.clone();
+    let control_task = tokio::spawn(supervise(state.clone(), "control", shutdown_rx.clone(), move || {
+        let bound = first_control.take();
+        let (st, app, rx) = (st_control.clone(), app.clone(), control_rx.clone());
+        let passed = passed_http.as_ref().map(|l| l.try_clone()).transpose();
+        async move {
+            let server = match bound {
+                Some(server) => server,
+                None => {
+                    let passed = passed.map_err(|e| format!("duplicating the socket systemd passed for {}: {}", http_addr, e))?;
+                    bind_control_api(&st, app, http_addr, passed, rx).await
+                        .map_err(|e| format!("binding the control API to {}: {}", http_addr, e))?
+                }
+            };
+            server.await.map_err(|e| format!("control API: {}", e))
+        }
+    }));
 
-    // UDP server runs in a task
-    let udp_bind_owned = udp_bind.clone();
     let st_udp = state.clone();
-    let udp_task = tokio::spawn(async move { run_udp_server(st_udp, udp_bind_owned, udp_upstream).await });
+    let udp_rx = shutdown_rx.clone();
+    let dns_task = tokio::spawn(supervise(state.clone(), "dns", shutdown_rx.clone(), move || {
+        let (st, rx) = (st_udp.clone(), udp_rx.clone());
+        let passed = sockets.try_clone();
+        async move {
+            let passed = passed.map_err(|e| format!("duplicating the sockets systemd passed: {}", e))?;
+            let res = run_udp_server(st.clone(), st.listeners.clone(), passed, rx).await;
+            st.health.udp_bound.store(false, std::sync::atomic::Ordering::Relaxed);
+            res.map_err(|e| {
+                let e = format!("DNS UDP server: {:#}", e);
+                *st.health.udp_error.lock().unwrap() = Some(e.clone());
+                e
+            })
+        }
+    }));
+
+    // readiness and watchdog pings for systemd (Type=notify, WatchdogSec=)
+    let notify_task = config.systemd.then(|| tokio::spawn(crate::systemd::run_notify(state.clone(), shutdown_rx.clone())));
+
+    // tell automation the resolver is up, and that it is going away as soon as shutdown starts so
+    // clients can be moved before the listeners close
+    let st_start = state.clone();
+    let dns_start = udp_bind.clone();
+    tasks.spawn(async move { st_start.lifecycle.announce("start", &dns_start).await });
+    let mut stop_rx = shutdown_rx.clone();
+    let st_stop = state.clone();
+    let dns_stop = udp_bind.clone();
+    let stop_task = tokio::spawn(async move {
+        if stop_rx.changed().await.is_ok() { st_stop.lifecycle.announce("stop", &dns_stop).await; }
+    });
 
-    let _ = tokio::join!(http_future, udp_task);
+    // both end once shutdown starts: the control API after open requests, the DNS server after
+    // the queries in flight
+    let _ = tokio::join!(control_task, dns_task);
+    if let Some(grpc_task) = grpc_task { let _ = grpc_task.await; }
+    if let Some(mqtt_task) = mqtt_task { let _ = mqtt_task.await; }
+    let _ = stop_task.await;
+    if let Some(notify_task) = notify_task { let _ = notify_task.await; }
+    tasks.shutdown().await;
+    state.saved_state.save(&state).await;
+    state.clients.flush_if_dirty();
+    state.prewarm.save();
+    state.save_totals();
+    state.sample_history();
+    state.history.save();
+    state.longterm.save();
+    Ok(())
 }
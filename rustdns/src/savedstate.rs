@@ -0,0 +1,270 @@
+use crate::backup::{decode_backup, restore_backup, runtime_backup, Backup, BlockingBackup, GroupBackup};
+use crate::state::ServerState;
+use crate::store::{data_dir, write_json_synced};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// What users change while the server runs — rules added through the API, the blocking mode,
+// block page address and audit switch, allowlist entries and presets, group settings, blocked
+// categories, brands and canaries — kept in `state.json` in the data directory and put back at
+// startup, so a restart doesn't undo them. The file is a /backup archive, uncompressed and without
+// the client table and list files, which live in files of their own. RUSTDNS_SAVE_STATE=0 turns
+// it off.
+//
+// Settings the environment or configuration file also sets are only put back where they were
+// changed while the server ran: the file records the startup settings it was saved against, so
+// one left as it started follows RUSTDNS_BLOCKING_MODE and the like when they change later.
+pub struct SavedState {
+    pub path: Option<PathBuf>,
+    // the last state written, without its time and the allow entries' countdowns, and the
+    // allowlist's add count then, so unchanged state isn't rewritten
+    saved: Mutex<Option<(Vec<u8>, u64)>>,
+    // the settings this run started with, before the saved state was put back
+    startup: Mutex<Option<Startup>>,
+}
+
+// The settings of a saved state that also come from the environment or configuration file, as
+// they were at startup.
+#[derive(Clone, Serialize, Deserialize)]
+struct Startup {
+    blocking: BlockingBackup,
+    presets: Vec<String>,
+    groups: BTreeMap<String, GroupBackup>,
+    categories: Vec<String>,
+    brands: Vec<String>,
+    canaries: Vec<String>,
+}
+
+impl Startup {
+    fn of(b: &Backup) -> Option<Self> {
+        Some(Startup {
+            blocking: b.blocking.clone()?,
+            presets: b.allowlist.as_ref()?.presets.clone(),
+            groups: b.groups.clone()?,
+            categories: b.categories.clone()?,
+            brands: b.brands.clone()?,
+            canaries: b.canaries.clone()?,
+        })
+    }
+}
+
+// state.json: the saved state and the startup settings it was saved against. Files written before
+// the latter was recorded are put back whole.
+#[derive(Serialize)]
+struct SavedFile<'a> {
+    #[serde(flatten)]
+    state: &'a Backup,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    startup: Option<&'a Startup>,
+}
+
+#[derive(Deserialize)]
+struct SavedStartup {
+    #[serde(default)]
+    startup: Option<Startup>,
+}
+
+// `saved` if it was changed from `then`, the startup value it was saved against; else `now`, the
+// startup value of this run.
+fn pick<T: PartialEq>(saved: T, then: T, now: T) -> T {
+    if saved == then { now } else { saved }
+}
+
+// `now` with the names added to and removed from `then` to get `saved`.
+fn merge_names(saved: &[String], then: &[String], now: &[String]) -> Vec<String> {
+    let mut names: BTreeSet<&String> = now.iter().filter(|n| saved.contains(n) || !then.contains(n)).collect();
+    names.extend(saved.iter().filter(|n| !then.contains(n)));
+    names.into_iter().cloned().collect()
+}
+
+// Group states, per group and setting as `pick` does; groups not listed are enabled with the
+// global mode and no schedule.
+fn merge_groups(saved: &BTreeMap<String, GroupBackup>, then: &BTreeMap<String, GroupBackup>, now: &BTreeMap<String, GroupBackup>) -> BTreeMap<String, GroupBackup> {
+    let plain = GroupBackup { enabled: true, ..Default::default() };
+    let get = |m: &BTreeMap<String, GroupBackup>, g: &String| m.get(g).cloned().unwrap_or_else(|| plain.clone());
+    saved.keys().chain(then.keys()).chain(now.keys())
+        .map(|g| {
+            let (s, t, n) = (get(saved, g), get(then, g), get(now, g));
+            (g.clone(), GroupBackup { enabled: pick(s.enabled, t.enabled, n.enabled), mode: pick(s.mode, t.mode, n.mode), schedule: pick(s.schedule, t.schedule, n.schedule) })
+        })
+        .filter(|(_, g)| *g != plain)
+        .collect()
+}
+
+// Replace the settings of `backup` that weren't changed from `then` with those of `now`.
+fn keep_startup_settings(backup: &mut Backup, then: &Startup, now: &Startup) {
+    if let Some(b) = backup.blocking.as_mut() {
+        b.mode = pick(b.mode, then.blocking.mode, now.blocking.mode);
+        b.block_page_ip = pick(b.block_page_ip, then.blocking.block_page_ip, now.blocking.block_page_ip);
+        b.audit = pick(b.audit, then.blocking.audit, now.blocking.audit);
+    }
+    if let Some(a) = backup.allowlist.as_mut() {
+        a.presets = merge_names(&a.presets, &then.presets, &now.presets);
+    }
+    if let Some(g) = backup.groups.as_mut() {
+        *g = merge_groups(g, &then.groups, &now.groups);
+    }
+    for (section, then, now) in [(&mut backup.categories, &then.categories, &now.categories), (&mut backup.brands, &then.brands, &now.brands), (&mut backup.canaries, &then.canaries, &now.canaries)] {
+        if let Some(names) = section.as_mut() {
+            *names = merge_names(names, then, now);
+        }
+    }
+}
+
+impl SavedState {
+    pub fn from_env() -> Self {
+        let off = crate::envvars::var("RUSTDNS_SAVE_STATE").is_ok_and(|v| matches!(v.trim().to_lowercase().as_str(), "0" | "false" | "off"));
+        SavedState { path: (!off).then(|| data_dir().join("state.json")), saved: Mutex::new(None), startup: Mutex::new(None) }
+    }
+
+    // Put the saved state back. Temporary allow entries keep the time they had left when it was
+    // saved, less the time since, and settings left as they started take this run's startup
+    // value. An unreadable file is moved aside rather than overwritten.
+    pub async fn restore(&self, state: &Arc<ServerState>) {
+        let Some(path) = &self.path else { return };
+        let now = Startup::of(&runtime_backup(state).await);
+        *self.startup.lock().unwrap() = now.clone();
+        let raw = match std::fs::read(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                tracing::warn!("reading saved state {} failed: {}", path.display(), e);
+                return;
+            }
+        };
+        let mut backup = match decode_backup(&raw) {
+            Ok(b) => b,
+            Err(e) => {
+                let aside = path.with_extension(format!("json.damaged-{}", now_secs()));
+                tracing::warn!("unreadable saved state {} ({:#}); moved to {} and starting without it", path.display(), e, aside.display());
+                let _ = std::fs::rename(path, &aside);
+                return;
+            }
+        };
+        let then = serde_json::from_slice::<SavedStartup>(&raw).ok().and_then(|s| s.startup);
+        if let (Some(then), Some(now)) = (&then, &now) {
+            keep_startup_settings(&mut backup, then, now);
+        }
+        let elapsed = now_secs().saturating_sub(backup.created);
+        if let Some(allow) = backup.allowlist.as_mut() {
+            allow.entries.retain_mut(|e| match e.seconds_remaining {
+                Some(s) if s <= elapsed => false,
+                Some(s) => { e.seconds_remaining = Some(s - elapsed); true }
+                None => true,
+            });
+        }
+        let report = restore_backup(state, backup).await;
+        let restored: Vec<String> = report.restored.iter().map(|(section, n)| format!("{} {}", n, section)).collect();
+        tracing::info!("restored saved state from {}: {}", path.display(), restored.join(", "));
+        for e in report.errors {
+            tracing::warn!("restoring saved state: {}", e);
+        }
+    }
+
+    // Write the current state if it changed since the last write.
+    pub async fn save(&self, state: &ServerState) {
+        let Some(path) = &self.path else { return };
+        let mut backup = runtime_backup(state).await;
+        let adds = state.allowlist.adds();
+        let countdowns: Vec<Option<u64>> = backup.allowlist.iter_mut().flat_map(|a| a.entries.iter_mut()).map(|e| e.seconds_remaining.take()).collect();
+        backup.created = 0;
+        let Ok(key) = serde_json::to_vec(&backup) else { return };
+        let mut saved = self.saved.lock().unwrap();
+        if saved.as_ref().is_some_and(|(k, a)| *k == key && *a == adds) { return }
+        for (e, s) in backup.allowlist.iter_mut().flat_map(|a| a.entries.iter_mut()).zip(countdowns) {
+            e.seconds_remaining = s;
+        }
+        backup.created = now_secs();
+        let startup = self.startup.lock().unwrap().clone();
+        match write_json_synced(path, &SavedFile { state: &backup, startup: startup.as_ref() }) {
+            Ok(()) => *saved = Some((key, adds)),
+            Err(e) => tracing::warn!("saving state to {} failed: {:?}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{AllowlistBackup, BACKUP_FORMAT, BACKUP_VERSION};
+    use crate::blocklist::{BlockingMode, GroupMode};
+
+    fn names(n: &[&str]) -> Vec<String> {
+        n.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn startup(mode: BlockingMode, audit: bool, presets: &[&str], groups: &[(&str, GroupBackup)]) -> Startup {
+        Startup {
+            blocking: BlockingBackup { mode, block_page_ip: None, audit },
+            presets: names(presets),
+            groups: groups.iter().map(|(g, b)| (g.to_string(), b.clone())).collect(),
+            categories: Vec::new(),
+            brands: Vec::new(),
+            canaries: Vec::new(),
+        }
+    }
+
+    fn saved(s: &Startup) -> Backup {
+        Backup {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            created: 0,
+            server_version: String::new(),
+            rules: Some(Vec::new()),
+            allowlist: Some(AllowlistBackup { entries: Vec::new(), presets: s.presets.clone() }),
+            blocking: Some(s.blocking.clone()),
+            groups: Some(s.groups.clone()),
+            categories: Some(s.categories.clone()),
+            brands: Some(s.brands.clone()),
+            canaries: Some(s.canaries.clone()),
+            clients: None,
+            list_files: None,
+        }
+    }
+
+    // Settings nobody changed follow the environment after the first save; ones changed through
+    // the API keep their saved value.
+    #[test]
+    fn only_changed_settings_override_the_environment() {
+        let disabled = GroupBackup { enabled: false, ..Default::default() };
+        let null = GroupBackup { enabled: true, mode: GroupMode::new(BlockingMode::Null, None), schedule: None };
+        let then = startup(BlockingMode::Nx, false, &["apple"], &[("ads", disabled.clone())]);
+
+        // saved without changes, then RUSTDNS_BLOCKING_MODE, RUSTDNS_AUDIT, RUSTDNS_ALLOW_PRESETS
+        // and RUSTDNS_GROUP_MODES changed
+        let now = startup(BlockingMode::Null, true, &["microsoft"], &[("kids", null.clone())]);
+        let mut backup = saved(&then);
+        keep_startup_settings(&mut backup, &then, &now);
+        let b = backup.blocking.as_ref().unwrap();
+        assert_eq!((b.mode, b.audit), (BlockingMode::Null, true));
+        assert_eq!(backup.allowlist.as_ref().unwrap().presets, names(&["microsoft"]));
+        assert_eq!(backup.groups.as_ref().unwrap().keys().collect::<Vec<_>>(), ["kids"]);
+
+        // the mode and a preset changed through the API, and the disabled group enabled
+        let mut changed = saved(&then);
+        changed.blocking.as_mut().unwrap().mode = BlockingMode::NoData;
+        changed.allowlist.as_mut().unwrap().presets = names(&["apple", "google"]);
+        changed.groups.as_mut().unwrap().clear();
+        keep_startup_settings(&mut changed, &then, &now);
+        let b = changed.blocking.as_ref().unwrap();
+        assert_eq!((b.mode, b.audit), (BlockingMode::NoData, true));
+        assert_eq!(changed.allowlist.as_ref().unwrap().presets, names(&["google", "microsoft"]));
+        assert!(changed.groups.as_ref().unwrap().get("kids") == Some(&null));
+        assert!(!changed.groups.as_ref().unwrap().contains_key("ads"));
+    }
+
+    #[test]
+    fn name_sets_keep_additions_and_removals() {
+        let merged = merge_names(&names(&["a", "c"]), &names(&["a", "b"]), &names(&["b", "d"]));
+        // b removed and c added through the API; d is new at startup
+        assert_eq!(merged, names(&["c", "d"]));
+        assert_eq!(merge_names(&[], &[], &names(&["x"])), names(&["x"]));
+    }
+}
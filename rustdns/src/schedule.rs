@@ -0,0 +1,215 @@
+use crate::state::{Event, ServerState};
+use crate::tz::{weekday, Zone, DAY};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+
+const DAY_NAMES: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+
+// How often schedules are evaluated.
+const TICK: Duration = Duration::from_secs(20);
+
+// A weekly window such as `mon-fri 08:00-17:00`. A window ending before it starts runs past
+// midnight (`daily 20:00-07:00`), and then its days name the evening it starts on.
+#[derive(Clone)]
+struct Window {
+    spec: String,
+    days: [bool; 7],
+    // minutes after midnight
+    start: u32,
+    end: u32,
+}
+
+// `mon`, `tues`, `wednesday`: any prefix of a day name of at least three letters. Monday = 0.
+fn parse_day(s: &str) -> Option<usize> {
+    if s.len() < 3 { return None }
+    DAY_NAMES.iter().position(|d| d.starts_with(s))
+}
+
+fn parse_minutes(s: &str) -> Option<u32> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    (h < 24 && m < 60 || h == 24 && m == 0).then_some(h * 60 + m)
+}
+
+impl Window {
+    fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim().to_lowercase();
+        let mut parts = spec.split_whitespace();
+        let (days_part, times) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(times), None, None) => ("daily", times),
+            (Some(days), Some(times), None) => (days, times),
+            _ => return None,
+        };
+        let mut days = [false; 7];
+        match days_part {
+            "daily" => days = [true; 7],
+            "weekdays" => days[..5].fill(true),
+            "weekends" => days[5..].fill(true),
+            list => for item in list.split(',') {
+                match item.split_once('-') {
+                    Some((from, to)) => {
+                        let (from, to) = (parse_day(from)?, parse_day(to)?);
+                        // ranges may wrap around the week (`fri-mon`)
+                        let mut d = from;
+                        loop {
+                            days[d] = true;
+                            if d == to { break }
+                            d = (d + 1) % 7;
+                        }
+                    }
+                    None => days[parse_day(item)?] = true,
+                }
+            },
+        }
+        let (start, end) = times.split_once('-')?;
+        let (start, end) = (parse_minutes(start)?, parse_minutes(end)?);
+        if start == end || start == 24 * 60 { return None }
+        Some(Window { spec, days, start, end })
+    }
+
+    fn contains(&self, day: usize, minute: u32) -> bool {
+        if self.start < self.end {
+            self.days[day] && minute >= self.start && minute < self.end
+        } else {
+            (self.days[day] && minute >= self.start) || (self.days[(day + 6) % 7] && minute < self.end)
+        }
+    }
+}
+
+// When a group is active: during any of its windows, in its time zone.
+struct GroupSchedule {
+    windows: Vec<Window>,
+    zone: Arc<Zone>,
+}
+
+impl GroupSchedule {
+    fn active_at(&self, t: i64) -> bool {
+        let local = t + self.zone.offset_at(t);
+        let minute = (local.rem_euclid(DAY) / 60) as u32;
+        let day = weekday(local.div_euclid(DAY));
+        self.windows.iter().any(|w| w.contains(day, minute))
+    }
+}
+
+// A schedule as reported under `schedule` by GET /groups.
+#[derive(Serialize)]
+pub struct ScheduleView {
+    pub windows: Vec<String>,
+    pub timezone: String,
+    // whether the group is inside one of its windows right now
+    pub active: bool,
+}
+
+// Time-of-day activation of list groups, e.g. a `social` group that only blocks during work
+// hours. RUSTDNS_GROUP_SCHEDULES=social=mon-fri 08:00-17:00;games=sat,sun 10:00-20:00+mon-fri 16:00-18:00
+// seeds them (groups separated by `;`, windows by `+`), POST /groups/{name}/schedule changes them.
+// Times are in RUSTDNS_SCHEDULE_TZ (an IANA zone, `+02:00` or `UTC`; default the system zone)
+// unless a schedule names its own. The scheduler enables a group when a window opens and disables
+// it when the window closes; a manual toggle in between holds until the next of those changes.
+pub struct GroupSchedules {
+    pub zone: Arc<Zone>,
+    schedules: Mutex<HashMap<String, GroupSchedule>>,
+    // the state the scheduler last switched each group to
+    applied: Mutex<HashMap<String, bool>>,
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+impl GroupSchedules {
+    pub fn from_env() -> Self {
+        let zone = match crate::envvars::var("RUSTDNS_SCHEDULE_TZ") {
+            Ok(name) => Zone::parse(&name).unwrap_or_else(|e| {
+                tracing::warn!("RUSTDNS_SCHEDULE_TZ: {}; using the system zone", e);
+                Zone::local()
+            }),
+            Err(_) => Zone::local(),
+        };
+        let schedules = GroupSchedules { zone: Arc::new(zone), schedules: Mutex::new(HashMap::new()), applied: Mutex::new(HashMap::new()) };
+        for item in crate::envvars::var("RUSTDNS_GROUP_SCHEDULES").unwrap_or_default().split(';').filter(|i| !i.trim().is_empty()) {
+            let result = match item.split_once('=') {
+                Some((group, windows)) => schedules.set(group.trim(), Some(&windows.split('+').map(str::to_string).collect::<Vec<_>>()), None),
+                None => Err("expected group=windows".to_string()),
+            };
+            if let Err(e) = result {
+                tracing::warn!("ignoring RUSTDNS_GROUP_SCHEDULES entry {:?}: {}", item, e);
+            }
+        }
+        schedules
+    }
+
+    // Give `group` these windows, in `timezone` or the default zone, or drop its schedule with
+    // None. The next evaluation applies it.
+    pub fn set(&self, group: &str, windows: Option<&[String]>, timezone: Option<&str>) -> Result<(), String> {
+        let group = group.to_lowercase();
+        let Some(windows) = windows else {
+            self.schedules.lock().unwrap().remove(&group);
+            self.applied.lock().unwrap().remove(&group);
+            return Ok(());
+        };
+        let windows = windows.iter()
+            .map(|w| Window::parse(w).ok_or_else(|| format!("invalid window {:?}; expected e.g. \"mon-fri 08:00-17:00\"", w)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if windows.is_empty() { return Err("a schedule needs at least one window".to_string()) }
+        let zone = match timezone {
+            Some(name) => Arc::new(Zone::parse(name)?),
+            None => self.zone.clone(),
+        };
+        self.schedules.lock().unwrap().insert(group.clone(), GroupSchedule { windows, zone });
+        self.applied.lock().unwrap().remove(&group);
+        Ok(())
+    }
+
+    fn view(s: &GroupSchedule, now: i64) -> ScheduleView {
+        ScheduleView { windows: s.windows.iter().map(|w| w.spec.clone()).collect(), timezone: s.zone.name.clone(), active: s.active_at(now) }
+    }
+
+    pub fn get(&self, group: &str) -> Option<ScheduleView> {
+        self.schedules.lock().unwrap().get(group).map(|s| Self::view(s, now_secs()))
+    }
+
+    pub fn all(&self) -> HashMap<String, ScheduleView> {
+        let now = now_secs();
+        self.schedules.lock().unwrap().iter().map(|(g, s)| (g.clone(), Self::view(s, now))).collect()
+    }
+
+    // Groups whose scheduled state changed since it was last applied, with the state to apply.
+    fn due(&self, now: i64) -> Vec<(String, bool)> {
+        let schedules = self.schedules.lock().unwrap();
+        let mut applied = self.applied.lock().unwrap();
+        let mut due = Vec::new();
+        for (group, s) in schedules.iter() {
+            let active = s.active_at(now);
+            if applied.insert(group.clone(), active) != Some(active) { due.push((group.clone(), active)); }
+        }
+        due
+    }
+}
+
+// Switch groups whose windows opened or closed since the last call.
+pub async fn apply_schedules(state: &ServerState) {
+    for (group, enabled) in state.schedules.due(now_secs()) {
+        let mut lists = state.lists.write().await;
+        if lists.disabled_groups.contains(&group) == enabled {
+            let lists = Arc::make_mut(&mut lists);
+            if enabled { lists.disabled_groups.remove(&group); } else { lists.disabled_groups.insert(group.clone()); }
+        }
+        drop(lists);
+        tracing::info!("group {} {} by schedule", group, if enabled { "enabled" } else { "disabled" });
+        let _ = state.events.send(Event::GroupScheduled { group, enabled });
+    }
+}
+
+pub fn spawn_group_scheduler(tasks: &mut JoinSet<()>, state: Arc<ServerState>) {
+    tasks.spawn(async move {
+        let mut tick = tokio::time::interval(TICK);
+        loop {
+            tick.tick().await;
+            apply_schedules(&state).await;
+        }
+    });
+}
@@ -0,0 +1,82 @@
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+// What happens to queries for the server's own hostnames.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfNameMode {
+    // answer with this server's addresses
+    Local,
+    // never block, but resolve as usual
+    Exempt,
+}
+
+// Hostnames of the dashboard / control API and the block page, which must keep resolving to this
+// server even if a list update starts blocking them, or the admin UI becomes unreachable.
+// RUSTDNS_SELF_HOSTNAMES (comma separated, default the machine's hostname) and
+// RUSTDNS_BLOCK_PAGE_HOST name them; RUSTDNS_SELF_NAMES=local|exempt picks the behaviour
+// (default local). Local answers use RUSTDNS_SELF_ADDRESS (comma separated), else the block page
+// address set through /mode, else the address of the interface holding the default route.
+pub struct SelfNames {
+    pub names: Vec<String>,
+    pub mode: SelfNameMode,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl SelfNames {
+    pub fn from_env() -> Self {
+        let list = |key: &str| -> Vec<String> {
+            crate::envvars::var(key).unwrap_or_default()
+                .split(',')
+                .map(|n| n.trim().trim_end_matches('.').to_lowercase())
+                .filter(|n| !n.is_empty())
+                .collect()
+        };
+        let mut names = match crate::envvars::var("RUSTDNS_SELF_HOSTNAMES") {
+            Ok(_) => list("RUSTDNS_SELF_HOSTNAMES"),
+            Err(_) => machine_hostname().into_iter().collect(),
+        };
+        for n in list("RUSTDNS_BLOCK_PAGE_HOST") {
+            if !names.contains(&n) { names.push(n); }
+        }
+        let mode = match crate::envvars::var("RUSTDNS_SELF_NAMES").map(|v| v.to_lowercase()).as_deref() {
+            Ok("exempt") => SelfNameMode::Exempt,
+            _ => SelfNameMode::Local,
+        };
+        let mut addrs: Vec<IpAddr> = list("RUSTDNS_SELF_ADDRESS").iter().filter_map(|a| a.parse().ok()).collect();
+        if addrs.is_empty() && mode == SelfNameMode::Local {
+            addrs.extend(default_route_address());
+        }
+        SelfNames { names, mode, addrs }
+    }
+
+    // Whether `name` is one of the server's own hostnames.
+    pub fn contains(&self, name: &str) -> bool {
+        if self.names.is_empty() { return false }
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.names.contains(&name)
+    }
+}
+
+#[cfg(unix)]
+fn machine_hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 { return None }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]).trim().to_lowercase();
+    (!name.is_empty() && name != "localhost").then_some(name)
+}
+
+#[cfg(not(unix))]
+fn machine_hostname() -> Option<String> {
+    crate::envvars::var("COMPUTERNAME").ok().map(|n| n.to_lowercase())
+}
+
+// Source address the kernel picks for outbound traffic. Connecting a UDP socket sends nothing.
+fn default_route_address() -> Option<IpAddr> {
+    let sock = UdpSocket::bind("0.0.0.0:0").ok()?;
+    sock.connect(SocketAddr::from(([192, 0, 2, 1], 53))).ok()?;
+    let ip = sock.local_addr().ok()?.ip();
+    (!ip.is_unspecified()).then_some(ip)
+}
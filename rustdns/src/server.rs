@@ -1,108 +1,681 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
-use trust_dns_proto::op::{Message, ResponseCode};
-use trust_dns_proto::rr::{RData, Record, RecordType, DNSClass};
-use trust_dns_proto::rr::rdata::A as ARecord;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use trust_dns_proto::op::{Edns, Message, ResponseCode};
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use trust_dns_proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use trust_dns_proto::rr::rdata::{A as ARecord, AAAA as AAAARecord, CNAME, TXT};
 use std::time::Duration;
-use crate::state::ServerState;
-use crate::blocklist::is_blocked_domain;
-use std::net::Ipv4Addr;
-use std::sync::atomic::Ordering;
-
-pub async fn run_udp_server(state: Arc<ServerState>, bind_addr: String, udp_upstream: String) -> Result<()> {
-    let sock = UdpSocket::bind(bind_addr.as_str()).await?;
-    let sock = Arc::new(sock);
-    tracing::info!("DNS UDP listening on {}", bind_addr);
+use crate::state::{ReloadPolicy, ServerState};
+use crate::blocklist::{find_match_in, snapshot, BlockAction, BlockMatch, BlockingMode};
+use crate::trace::Trace;
+use crate::dga::DgaMode;
+use crate::resources::{ResourceKind, Resources};
+use crate::selfnames::SelfNameMode;
+use crate::clients::ClientKey;
+use crate::querylog::QueryEntry;
+use crate::listeners::{Listener, ListenerPolicy};
+use crate::systemd::Sockets;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// Outcome of running one query through the pipeline.
+pub struct Resolution {
+    pub response: Vec<u8>,
+    pub blocked: Option<BlockMatch>,
+    // the rule that would have blocked the query had audit mode been off
+    pub audited: Option<BlockMatch>,
+    pub upstream: Option<String>,
+    pub local: bool,
+    // answered from the response cache
+    pub cached: bool,
+}
+
+// Answer DNS queries on every one of `listeners` until `shutdown` flips. All are bound before any
+// is served, so the UDP listener counts as bound once each is. A listener whose socket fails stops
+// on its own; the first such error is returned once all have stopped. A listener whose address
+// systemd passed a socket for serves that socket instead of binding.
+pub async fn run_udp_server(state: Arc<ServerState>, listeners: Vec<Listener>, mut sockets: Sockets, shutdown: watch::Receiver<bool>) -> Result<()> {
+    let mut socks = Vec::new();
+    for listener in listeners {
+        let sock = match sockets.take_udp(listener.addr) {
+            Some(passed) => {
+                let sock = passed.and_then(UdpSocket::from_std).map_err(|e| anyhow!("socket systemd passed for {}: {}", listener.addr, e))?;
+                tracing::info!("DNS UDP listening on {} (policy {}, socket from systemd)", listener.addr, listener.policy);
+                sock
+            }
+            None => {
+                let sock = UdpSocket::bind(listener.addr).await.map_err(|e| anyhow!("binding {}: {}", listener.addr, e))?;
+                tracing::info!("DNS UDP listening on {} (policy {})", listener.addr, listener.policy);
+                sock
+            }
+        };
+        socks.push((Arc::new(sock), Arc::new(listener)));
+    }
+    if !sockets.is_empty() {
+        tracing::warn!("systemd passed sockets no listener is configured for: {}", sockets.unused().join(", "));
+    }
+    state.health.udp_bound.store(true, Ordering::Relaxed);
+    let mut servers = JoinSet::new();
+    for (sock, listener) in socks {
+        servers.spawn(serve_udp(state.clone(), sock, listener, shutdown.clone()));
+    }
+    let mut result = Ok(());
+    while let Some(res) = servers.join_next().await {
+        if let Ok(Err(e)) = res {
+            if result.is_ok() { result = Err(e) } else { tracing::error!("{:#}", e) }
+        }
+    }
+    state.health.udp_bound.store(false, Ordering::Relaxed);
+    result
+}
+
+// Packets the DNS listeners lost: receives that failed, answers that could not be sent, and
+// packets that were not DNS messages.
+#[derive(Default)]
+pub struct PacketErrors {
+    receive: AtomicU64,
+    send: AtomicU64,
+    unparsable: AtomicU64,
+}
+
+// Snapshot reported under `udp_errors` in /stats.
+#[derive(Serialize)]
+pub struct PacketErrorStats {
+    pub receive: u64,
+    pub send: u64,
+    pub unparsable: u64,
+}
+
+impl PacketErrors {
+    pub fn stats(&self) -> PacketErrorStats {
+        PacketErrorStats {
+            receive: self.receive.load(Ordering::Relaxed),
+            send: self.send.load(Ordering::Relaxed),
+            unparsable: self.unparsable.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// How long a listener pauses after a receive failed for want of memory, buffers or descriptors,
+// instead of spinning on the error.
+const RECV_EXHAUSTED_PAUSE: Duration = Duration::from_millis(50);
+
+// Logged at most this often while receives keep failing.
+const RECV_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// Whether a failed receive means the socket itself is unusable, rather than one packet being lost
+// (an ICMP error left by an earlier answer, an interrupt, running out of buffers).
+#[cfg(unix)]
+fn socket_broken(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EBADF | libc::ENOTSOCK | libc::EINVAL | libc::EFAULT))
+}
+
+#[cfg(not(unix))]
+fn socket_broken(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::InvalidInput
+}
+
+// Whether a failed receive came from the process or system running out of something.
+#[cfg(unix)]
+fn resources_exhausted(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENOMEM | libc::ENOBUFS | libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+fn resources_exhausted(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::OutOfMemory
+}
+
+// Send an answer to `dst`, counting it when it cannot be sent.
+async fn send_answer(state: &ServerState, sock: &UdpSocket, out: &[u8], dst: SocketAddr) {
+    if let Err(e) = sock.send_to(out, dst).await {
+        state.udp_errors.send.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!("sending the answer to {}: {}", dst, e);
+    }
+}
+
+// Serve one listener. Each query runs in its own task; on shutdown the socket stops being read and
+// queries in flight get until the upstream timeout (plus a second) to be answered before they are
+// dropped. A failed receive loses at most that packet and is counted; only one that leaves the
+// socket unusable ends the listener.
+async fn serve_udp(state: Arc<ServerState>, sock: Arc<UdpSocket>, listener: Arc<Listener>, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let bind_addr = listener.addr;
+    let mut tasks = JoinSet::new();
+    // when a receive error was last logged, and how many were not logged since
+    let mut error_logged: Option<Instant> = None;
+    let mut errors_unlogged = 0u64;
     loop {
+        while tasks.try_join_next().is_some() {}
         let mut buf = vec![0u8; 4096];
-        let (len, src) = sock.recv_from(&mut buf).await?;
+        let received = tokio::select! {
+            r = sock.recv_from(&mut buf) => r,
+            _ = wait_for_shutdown(&mut shutdown) => break,
+        };
+        let (len, src) = match received {
+            Ok(received) => received,
+            Err(e) if socket_broken(&e) => return Err(anyhow!("DNS UDP listener on {}: {}", bind_addr, e)),
+            Err(e) => {
+                state.udp_errors.receive.fetch_add(1, Ordering::Relaxed);
+                if error_logged.is_none_or(|at| at.elapsed() >= RECV_ERROR_LOG_INTERVAL) {
+                    tracing::warn!("DNS UDP listener on {}: receiving failed: {} ({} more since the last report)", bind_addr, e, errors_unlogged);
+                    error_logged = Some(Instant::now());
+                    errors_unlogged = 0;
+                } else {
+                    errors_unlogged += 1;
+                }
+                if resources_exhausted(&e) { tokio::time::sleep(RECV_EXHAUSTED_PAUSE).await; }
+                continue;
+            }
+        };
         let packet = buf[..len].to_vec();
-    let state_cl = state.clone();
-    let upstream = udp_upstream.clone();
+        // shed load instead of spawning without bound when the task cap is reached
+        let Some(task_slot) = state.resources.try_acquire(ResourceKind::QueryTask) else {
+            state.queries.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        let state_cl = state.clone();
         let sock_cl = sock.clone();
-        tokio::spawn(async move {
+        let listener = listener.clone();
+        tasks.spawn(async move {
+            let _task_slot = task_slot;
+            let started = Instant::now();
             state_cl.queries.fetch_add(1, Ordering::Relaxed);
             match Message::from_vec(&packet) {
                 Ok(msg) => {
+                    let client = state_cl.identity.key(src.ip());
+                    let mac = match src.ip() { IpAddr::V4(v4) => state_cl.identity.mac(&v4), IpAddr::V6(_) => None };
+                    if !state_cl.limiter.check(&client) {
+                        state_cl.rate_limited.fetch_add(1, Ordering::Relaxed);
+                        let resp = Message::error_msg(msg.id(), msg.op_code(), ResponseCode::Refused);
+                        if let Ok(out) = resp.to_vec() { send_answer(&state_cl, &sock_cl, &pad_response(&msg, out, state_cl.response_padding), src).await; }
+                        // refused queries count for a known client but don't register a new one
+                        let client_id = state_cl.clients.lookup_source(src.ip(), &client, mac.as_deref());
+                        log_query(&state_cl, &msg, src.ip(), (&client, client_id), started, "refused", |e| e.with_rcode(ResponseCode::Refused));
+                        return;
+                    }
+                    let client_id = state_cl.clients.touch(src.ip(), &client, mac.as_deref());
+                    if !state_cl.tunnel.allow(&client) {
+                        state_cl.rate_limited.fetch_add(1, Ordering::Relaxed);
+                        let resp = Message::error_msg(msg.id(), msg.op_code(), ResponseCode::Refused);
+                        if let Ok(out) = resp.to_vec() { send_answer(&state_cl, &sock_cl, &pad_response(&msg, out, state_cl.response_padding), src).await; }
+                        log_query(&state_cl, &msg, src.ip(), (&client, client_id), started, "refused", |e| e.with_rcode(ResponseCode::Refused));
+                        return;
+                    }
                     if let Some(q) = msg.queries().first() {
                         let qname = q.name().to_string();
-                        let lists = state_cl.lists.read().await.clone();
-                        if is_blocked_domain(&qname, &lists) {
-                            state_cl.blocked.fetch_add(1, Ordering::Relaxed);
-                            let mode = state_cl.mode.read().await.clone();
-                            let block_ip_opt = state_cl.block_page_ip.read().await.clone();
-                            match mode.as_str() {
-                                "redirect" => {
-                                    if let Some(ipstr) = block_ip_opt {
-                                        if let Ok(ipv4) = ipstr.parse::<Ipv4Addr>() {
-                                            let mut resp = Message::new();
-                                            resp.set_id(msg.id());
-                                            resp.set_message_type(trust_dns_proto::op::MessageType::Response);
-                                            resp.set_op_code(msg.op_code());
-                                            resp.set_response_code(ResponseCode::NoError);
-                                            if let Some(q) = msg.queries().first() {
-                                                let mut rec = Record::new();
-                                                rec.set_name(q.name().clone());
-                                                rec.set_rr_type(RecordType::A);
-                                                rec.set_dns_class(DNSClass::IN);
-                                                rec.set_ttl(60);
-                                                rec.set_data(Some(RData::A(ARecord(ipv4))));
-                                                resp.add_answer(rec);
-                                            }
-                                            if let Ok(out) = resp.to_vec() {
-                                                let _ = sock_cl.send_to(&out, &src).await;
-                                            }
-                                            return;
-                                        }
-                                    }
-                                    let resp = Message::error_msg(msg.id(), msg.op_code(), ResponseCode::NXDomain);
-                                    if let Ok(out) = resp.to_vec() { let _ = sock_cl.send_to(&out, &src).await; }
-                                    return;
-                                }
-                                "null" => {
-                                    let mut resp = Message::new();
-                                    resp.set_id(msg.id());
-                                    resp.set_message_type(trust_dns_proto::op::MessageType::Response);
-                                    resp.set_op_code(msg.op_code());
-                                    resp.set_response_code(ResponseCode::NoError);
-                                    if let Some(q) = msg.queries().first() {
-                                        let mut rec = Record::new();
-                                        rec.set_name(q.name().clone());
-                                        rec.set_rr_type(RecordType::A);
-                                        rec.set_dns_class(DNSClass::IN);
-                                        rec.set_ttl(60);
-                                        rec.set_data(Some(RData::A(ARecord(Ipv4Addr::new(0,0,0,0)))));
-                                        resp.add_answer(rec);
-                                    }
-                                    if let Ok(out) = resp.to_vec() { let _ = sock_cl.send_to(&out, &src).await; }
-                                    return;
-                                }
-                                _ => {
-                                    let resp = Message::error_msg(msg.id(), msg.op_code(), ResponseCode::NXDomain);
-                                    if let Ok(out) = resp.to_vec() { let _ = sock_cl.send_to(&out, &src).await; }
-                                    return;
-                                }
+                        state_cl.labels.record(&client.0, &qname);
+                        state_cl.prewarm.record(&qname, q.query_type());
+                        for f in state_cl.tunnel.observe(&client, &qname, q.query_type()) {
+                            let mut detail = serde_json::json!({ "reason": f.reason, "client_key": client, "qtype": q.query_type().to_string() });
+                            if let (Some(d), Some(extra)) = (detail.as_object_mut(), f.detail.as_object()) {
+                                d.extend(extra.clone());
+                            }
+                            state_cl.raise_alert("tunnel", &src.ip().to_string(), &f.domain, detail);
+                        }
+                        // canary names are never forwarded: answer NXDOMAIN and alert
+                        if let Some(canary) = state_cl.canaries.check(&qname) {
+                            let detail = serde_json::json!({ "canary": canary, "client_addr": src.to_string(), "qtype": q.query_type().to_string() });
+                            state_cl.raise_alert("canary", &src.ip().to_string(), qname.trim_end_matches('.'), detail);
+                            let resp = Message::error_msg(msg.id(), msg.op_code(), ResponseCode::NXDomain);
+                            if let Ok(out) = resp.to_vec() { send_answer(&state_cl, &sock_cl, &pad_response(&msg, out, state_cl.response_padding), src).await; }
+                            log_query(&state_cl, &msg, src.ip(), (&client, client_id), started, "canary", |e| e.with_rcode(ResponseCode::NXDomain));
+                            return;
+                        }
+                        if let Some((brand, reason)) = state_cl.brands.check(&qname) {
+                            let detail = serde_json::json!({ "brand": brand, "reason": reason });
+                            state_cl.raise_alert("lookalike", &src.ip().to_string(), qname.trim_end_matches('.'), detail);
+                        }
+                        if state_cl.allowlist.check(&qname).is_none() {
+                            if let Some(score) = state_cl.dga.check(&qname, DgaMode::Flag) {
+                                let detail = serde_json::json!({ "score": score, "threshold": state_cl.dga.threshold });
+                                state_cl.raise_alert("dga", &src.ip().to_string(), qname.trim_end_matches('.'), detail);
                             }
                         }
                     }
-                    if let Ok(up_resp) = forward_udp_to_upstream(&packet, &upstream).await {
-                        let _ = sock_cl.send_to(&up_resp, &src).await;
+                    let mut trace = if state_cl.debug_clients.is_enabled(&client) {
+                        msg.queries().first().map(|q| {
+                            let mut t = Trace::new(&q.name().to_string(), &q.query_type().to_string());
+                            t.step("client", serde_json::json!({ "addr": src.to_string(), "client": client, "rate_limit": "passed", "listener": *listener }));
+                            t
+                        })
+                    } else { None };
+                    let result = resolve_query(&state_cl, &msg, &packet, &state_cl.settings.upstream(), false, &listener.policy, trace.as_mut()).await;
+                    if let Some(t) = trace {
+                        tracing::info!("trace for {}: {}", client, serde_json::to_string(&t).unwrap_or_default());
+                        state_cl.debug_clients.record(&client, t);
+                    }
+                    match &result {
+                        Ok(res) => {
+                            let action = match (&res.blocked, &res.audited) {
+                                (Some(_), _) => "blocked",
+                                (_, Some(_)) => "audited",
+                                _ if res.cached => "cached",
+                                _ if res.local => "local",
+                                _ if res.upstream.is_some() => "forwarded",
+                                // SERVFAIL while lists reload (RUSTDNS_RELOAD_POLICY=servfail)
+                                _ => "servfail",
+                            };
+                            log_query(&state_cl, &msg, src.ip(), (&client, client_id), started, action, |mut e| {
+                                if let Some(m) = res.blocked.as_ref().or(res.audited.as_ref()) { e = e.with_match(m); }
+                                e.upstream = res.upstream.clone();
+                                e.with_response(&res.response)
+                            });
+                        }
+                        Err(_) => log_query(&state_cl, &msg, src.ip(), (&client, client_id), started, "error", |e| e),
+                    }
+                    if let Ok(res) = result {
+                        if let Some(m) = &res.blocked {
+                            state_cl.blocked.fetch_add(1, Ordering::Relaxed);
+                            state_cl.hits.record(m);
+                            state_cl.counters.blocked_with(&response_mode(&state_cl, m).await);
+                            let qname = msg.queries().first().map(|q| q.name().to_string()).unwrap_or_default();
+                            tracing::debug!("blocked {} from {} (rule {} in {})", qname, src.ip(), m.rule, m.sources());
+                            state_cl.review.record(&qname, &src.ip().to_string(), m);
+                        }
+                        if let Some(m) = &res.audited {
+                            state_cl.audited.fetch_add(1, Ordering::Relaxed);
+                            state_cl.hits.record(m);
+                            let qname = msg.queries().first().map(|q| q.name().to_string()).unwrap_or_default();
+                            tracing::info!("audit: would block {} from {} (rule {} in {})", qname, src.ip(), m.rule, m.sources());
+                        }
+                        let out = pad_response(&msg, res.response, state_cl.response_padding);
+                        send_answer(&state_cl, &sock_cl, &out, src).await;
                     }
                 }
                 Err(_) => {
-                    // ignore unparsable packets
+                    // not a DNS message: dropped unanswered
+                    state_cl.udp_errors.unparsable.fetch_add(1, Ordering::Relaxed);
                 }
             }
         });
     }
+    drop(sock);
+    let in_flight = tasks.len();
+    if in_flight > 0 {
+        tracing::info!("DNS UDP listener closed; waiting for {} queries in flight", in_flight);
+        let grace = state.settings.upstream_timeout() + Duration::from_secs(1);
+        if tokio::time::timeout(grace, async { while tasks.join_next().await.is_some() {} }).await.is_err() {
+            tracing::warn!("dropping {} queries still unanswered after {}s", tasks.len(), grace.as_secs());
+        }
+    }
+    tracing::info!("DNS UDP server on {} stopped", bind_addr);
+    Ok(())
+}
+
+// Resolves once shutdown is requested; never if the sender is gone without requesting it.
+pub async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() { std::future::pending::<()>().await }
+    }
+}
+
+// Add a client query to the query log; `fill` completes the entry with what became of it.
+// The action is also counted for /metrics, /stats/top and the client's activity, whether or not
+// the log is on. `client` is the client key and registry id of the source, if registered.
+fn log_query(state: &ServerState, msg: &Message, src: IpAddr, client: (&ClientKey, Option<u64>), started: Instant, action: &'static str, fill: impl FnOnce(QueryEntry) -> QueryEntry) {
+    let (client, client_id) = client;
+    state.counters.action(action);
+    let Some(q) = msg.queries().first() else { return };
+    let name = q.name().to_string().trim_end_matches('.').to_lowercase();
+    state.top.record(&client.0, &name, action);
+    let client_name = client_id.and_then(|id| state.clients.record_query(id, &name, action));
+    if !state.query_log.active() { return }
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let mut entry = QueryEntry::new(src.to_string(), client.0.clone(), &q.name().to_string(), q.query_type().to_string(), action, elapsed_ms);
+    entry.client_name = client_name;
+    state.query_log.record(fill(entry));
+}
+
+// Decide how to answer `msg` (raw bytes in `packet`): a synthesized block response when the
+// name matches the blocklist, otherwise the answer from `upstream` or the name's forward zone.
+// `policy` is that of the listener the query came in on. When `trace` is given, each stage appends
+// what it looked at and decided.
+pub async fn resolve_query(state: &ServerState, msg: &Message, packet: &[u8], upstream: &str, bypass_cache: bool, policy: &ListenerPolicy, mut trace: Option<&mut Trace>) -> Result<Resolution> {
+    let mut upstream = upstream.to_string();
+    if let Some(q) = msg.queries().first() {
+        let qname = q.name().to_string();
+        if q.query_type() == RecordType::TXT {
+            if let Some(values) = state.acme.txt_records(&qname) {
+                if let Some(t) = trace.as_deref_mut() {
+                    t.step("acme_challenge", serde_json::json!({ "name": qname }));
+                }
+                return Ok(Resolution { response: txt_response(msg, &values).to_vec()?, blocked: None, audited: None, upstream: None, local: true, cached: false });
+            }
+        }
+        let own_name = state.self_names.contains(&qname);
+        if own_name && state.self_names.mode == SelfNameMode::Local {
+            let mut addrs = state.self_names.addrs.clone();
+            if addrs.is_empty() {
+                addrs.extend(state.block_page_ip.read().await.map(IpAddr::V4));
+            }
+            if !addrs.is_empty() {
+                let resp = local_response(msg, &addrs);
+                if let Some(t) = trace.as_deref_mut() {
+                    t.step("self_name", serde_json::json!({ "name": qname, "ips": addrs, "answers": resp.answers().len() }));
+                }
+                return Ok(Resolution { response: resp.to_vec()?, blocked: None, audited: None, upstream: None, local: true, cached: false });
+            }
+        }
+        let lists = snapshot(&state.lists).await;
+        if let Some(ips) = lists.find_local(&qname) {
+            let resp = local_response(msg, ips);
+            if let Some(t) = trace.as_deref_mut() {
+                t.step("local_record", serde_json::json!({ "name": qname, "ips": ips, "answers": resp.answers().len() }));
+            }
+            return Ok(Resolution { response: resp.to_vec()?, blocked: None, audited: None, upstream: None, local: true, cached: false });
+        }
+        if let Some((zone, target)) = lists.find_forward(&qname) {
+            if let Some(t) = trace.as_deref_mut() {
+                t.step("forward_zone", serde_json::json!({ "zone": zone, "upstream": target }));
+            }
+            upstream = target.to_string();
+        }
+        let enabled = state.blocking.is_enabled() && policy.blocks();
+        if let Some(t) = trace.as_deref_mut() {
+            t.step("blocking_switch", serde_json::json!({
+                "blocking_enabled": state.blocking.is_enabled(),
+                "seconds_remaining": state.blocking.seconds_remaining(),
+                "listener_policy": policy,
+            }));
+        }
+        if enabled && state.reload_policy == ReloadPolicy::ServFail && state.reloading.load(Ordering::SeqCst) {
+            if let Some(t) = trace.as_deref_mut() {
+                t.step("reload_policy", serde_json::json!({ "reloading": true, "policy": "servfail" }));
+            }
+            let resp = Message::error_msg(msg.id(), msg.op_code(), ResponseCode::ServFail);
+            return Ok(Resolution { response: resp.to_vec()?, blocked: None, audited: None, upstream: None, local: false, cached: false });
+        }
+        let allowed = if enabled { state.allowlist.check(&qname) } else { None };
+        if let Some(rule) = &allowed {
+            if let Some(t) = trace.as_deref_mut() {
+                t.step("allowlist", serde_json::json!({ "allowed_by": rule }));
+            }
+        }
+        if own_name {
+            if let Some(t) = trace.as_deref_mut() {
+                t.step("self_name", serde_json::json!({ "name": qname, "exempt": true }));
+            }
+        }
+        let matched = if enabled && allowed.is_none() && !own_name {
+            let m = find_match_in(&qname, Some(q.query_type()), &lists, policy.groups());
+            if let Some(t) = trace.as_deref_mut() {
+                let name = qname.trim_end_matches('.').to_lowercase();
+                let wildcards = lists.patterns.keys().filter(|p| p.starts_with("*.") || p.ends_with(".*")).count();
+                t.step("blocklist", serde_json::json!({
+                    "evaluated": {
+                        "tld": name.rsplit('.').next(),
+                        "exact": name,
+                        "qtype": q.query_type().to_string(),
+                        "wildcard_patterns": wildcards,
+                        "disabled_groups": lists.disabled_groups,
+                    },
+                    "matched": m,
+                }));
+            }
+            let m = m.or_else(|| {
+                let c = policy.narrow(state.categories.check(&qname)?)?;
+                if let Some(t) = trace.as_deref_mut() {
+                    t.step("category", serde_json::json!({ "domain": c.rule, "categories": c.origins.iter().map(|o| &o.group).collect::<Vec<_>>() }));
+                }
+                Some(c)
+            });
+            m.or_else(|| {
+                let score = state.dga.check(&qname, DgaMode::Block)?;
+                if let Some(t) = trace.as_deref_mut() {
+                    t.step("dga", serde_json::json!({ "score": score, "threshold": state.dga.threshold }));
+                }
+                Some(BlockMatch { rule: format!("dga score {:.2}", score), kind: "dga", origins: Vec::new(), action: None, types: Vec::new() })
+            })
+        } else { None };
+        if let Some(m) = matched {
+            if state.audit.load(Ordering::Relaxed) {
+                if let Some(t) = trace.as_deref_mut() {
+                    t.step("audit", serde_json::json!({ "would_block": true, "action": "forwarded" }));
+                }
+                let mut res = forward(state, msg, packet, &upstream, bypass_cache, trace).await?;
+                res.audited = Some(m);
+                return Ok(res);
+            }
+            let resp = block_response(state, msg, &m).await;
+            if let Some(t) = trace.as_deref_mut() {
+                let mode = block_mode(state, &m).await;
+                t.step("block_response", serde_json::json!({
+                    "mode": mode.mode,
+                    "mode_group": mode.group,
+                    "action": m.action,
+                    "rcode": resp.response_code().to_string(),
+                    "answers": resp.answers().len(),
+                }));
+            }
+            return Ok(Resolution { response: resp.to_vec()?, blocked: Some(m), audited: None, upstream: None, local: false, cached: false });
+        }
+    }
+    forward(state, msg, packet, &upstream, bypass_cache, trace).await
+}
+
+// Last pipeline stages: answer from the cache, else from upstream (caching the answer).
+async fn forward(state: &ServerState, msg: &Message, packet: &[u8], upstream: &str, bypass_cache: bool, mut trace: Option<&mut Trace>) -> Result<Resolution> {
+    let hit = if bypass_cache { None } else { state.cache.get(msg) };
+    if let Some(t) = trace.as_deref_mut() {
+        let step = match (&hit, state.cache.enabled()) {
+            (_, false) => serde_json::json!({ "consulted": false, "reason": "response cache disabled" }),
+            _ if bypass_cache => serde_json::json!({ "consulted": false, "reason": "bypassed" }),
+            (Some((_, ttl)), _) => serde_json::json!({ "consulted": true, "hit": true, "ttl_remaining": ttl }),
+            (None, _) => serde_json::json!({ "consulted": true, "hit": false }),
+        };
+        t.step("cache", step);
+    }
+    if let Some((response, _)) = hit {
+        return Ok(Resolution { response, blocked: None, audited: None, upstream: None, local: false, cached: true });
+    }
+    let started = std::time::Instant::now();
+    let forwarded = forward_udp_to_upstream(&state.resources, packet, upstream, state.settings.upstream_timeout()).await;
+    match &forwarded {
+        Ok(_) => state.counters.upstream(upstream, started.elapsed()),
+        Err(_) => state.counters.upstream_error(upstream),
+    }
+    if let Some(t) = trace {
+        let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+        match &forwarded {
+            Ok(raw) => {
+                let parsed = Message::from_vec(raw);
+                let rcode = parsed.as_ref().map(|m| m.response_code().to_string()).unwrap_or_else(|_| "unparsable".to_string());
+                let answers: Vec<String> = parsed.iter().flat_map(|m| m.answers().iter().map(|r| r.to_string())).collect();
+                t.step("upstream", serde_json::json!({ "upstream": upstream, "elapsed_ms": elapsed_ms, "rcode": rcode, "bytes": raw.len(), "answers": answers }));
+            }
+            Err(e) => t.step("upstream", serde_json::json!({ "upstream": upstream, "elapsed_ms": elapsed_ms, "error": e.to_string() })),
+        }
+    }
+    let response = forwarded?;
+    state.cache.insert(msg, &response);
+    Ok(Resolution { response, blocked: None, audited: None, upstream: Some(upstream.to_string()), local: false, cached: false })
+}
+
+// Encode stage: pad `raw` (the reply to `query`) with an EDNS padding option (RFC 7830) to a
+// multiple of `block` bytes, so an observer of the client link can't tell names apart by packet
+// size. Only queries that carry EDNS are padded, and never past the client's advertised payload
+// size; anything that can't be padded is returned unchanged.
+pub fn pad_response(query: &Message, raw: Vec<u8>, block: usize) -> Vec<u8> {
+    if block == 0 || query.extensions().is_none() { return raw }
+    let Ok(mut resp) = Message::from_vec(&raw) else { return raw };
+    if resp.extensions().is_none() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(query.max_payload());
+        resp.set_edns(edns);
+    }
+    let set_padding = |resp: &mut Message, len: usize| {
+        if let Some(edns) = resp.extensions_mut() {
+            edns.options_mut().insert(EdnsOption::Unknown(u16::from(EdnsCode::Padding), vec![0; len]));
+        }
+    };
+    set_padding(&mut resp, 0);
+    let Ok(unpadded) = resp.to_vec() else { return raw };
+    let padded_len = unpadded.len().div_ceil(block) * block;
+    if padded_len > usize::from(query.max_payload().max(512)) { return raw }
+    set_padding(&mut resp, padded_len - unpadded.len());
+    resp.to_vec().unwrap_or(raw)
+}
+
+// The blocking mode a match is answered with when its rule has no action of its own: the mode of
+// the group that produced it, else the global one.
+#[derive(Serialize)]
+pub struct AppliedMode {
+    pub mode: BlockingMode,
+    // group whose own mode applies; None for the global mode
+    pub group: Option<String>,
+    pub block_ip: Option<Ipv4Addr>,
+}
+
+pub async fn block_mode(state: &ServerState, m: &BlockMatch) -> AppliedMode {
+    let global_ip = *state.block_page_ip.read().await;
+    match state.group_modes.for_match(m) {
+        Some((group, g)) => AppliedMode { mode: g.mode, group: Some(group), block_ip: g.block_ip.or(global_ip) },
+        None => AppliedMode { mode: *state.mode.read().await, group: None, block_ip: global_ip },
+    }
+}
+
+// Name of the way a blocked query is answered, for the per-mode counters: the rule's own action
+// or the applied blocking mode.
+pub async fn response_mode(state: &ServerState, m: &BlockMatch) -> String {
+    match &m.action {
+        Some(BlockAction::NoData) => "nodata".to_string(),
+        Some(BlockAction::Redirect(_)) => "cname".to_string(),
+        None => block_mode(state, m).await.mode.as_str().to_string(),
+    }
+}
+
+// Build the reply for a blocked query according to the rule's own action, else the mode of the
+// group that produced the match, else the current blocking mode.
+async fn block_response(state: &ServerState, msg: &Message, m: &BlockMatch) -> Message {
+    match &m.action {
+        Some(BlockAction::NoData) => return local_response(msg, &[]),
+        Some(BlockAction::Redirect(target)) => return redirect_response(state, msg, target).await,
+        None => {}
+    }
+    let applied = block_mode(state, m).await;
+    match (applied.mode, applied.block_ip) {
+        (BlockingMode::Redirect, Some(ipv4)) => a_response(msg, ipv4),
+        (BlockingMode::Null, _) => a_response(msg, Ipv4Addr::new(0, 0, 0, 0)),
+        (BlockingMode::NoData, _) => local_response(msg, &[]),
+        // redirect without a block page address answers like nx
+        (BlockingMode::Nx | BlockingMode::Redirect, _) => Message::error_msg(msg.id(), msg.op_code(), ResponseCode::NXDomain),
+    }
+}
+
+// Walled-garden answer: a CNAME from the query name to `target`, followed by `target`'s own
+// records from the cache or upstream. Just the CNAME when those can't be fetched.
+async fn redirect_response(state: &ServerState, msg: &Message, target: &str) -> Message {
+    let mut resp = local_response(msg, &[]);
+    let (Some(q), Ok(name)) = (msg.queries().first(), Name::from_ascii(format!("{}.", target))) else { return resp };
+    let mut rec = Record::new();
+    rec.set_name(q.name().clone());
+    rec.set_rr_type(RecordType::CNAME);
+    rec.set_dns_class(DNSClass::IN);
+    rec.set_ttl(60);
+    rec.set_data(Some(RData::CNAME(CNAME(name.clone()))));
+    resp.add_answer(rec);
+    if q.query_type() == RecordType::CNAME { return resp }
+    let query = query_message(msg.id(), name, q.query_type());
+    let Ok(packet) = query.to_vec() else { return resp };
+    let upstream = snapshot(&state.lists).await.find_forward(target).map(|(_, up)| up.to_string());
+    match forward(state, &query, &packet, &upstream.unwrap_or_else(|| state.settings.upstream()), false, None).await.map(|r| Message::from_vec(&r.response)) {
+        Ok(Ok(answer)) => { resp.add_answers(answer.answers().iter().cloned()); }
+        Ok(Err(e)) => tracing::debug!("unparsable walled-garden answer for {}: {:?}", target, e),
+        Err(e) => tracing::debug!("resolving walled-garden target {} failed: {:?}", target, e),
+    }
+    resp
+}
+
+// Answer for a local override record: the addresses matching the question type (A or AAAA),
+// or an empty NOERROR answer when the name has none of that family.
+fn local_response(msg: &Message, ips: &[IpAddr]) -> Message {
+    let mut resp = Message::new();
+    resp.set_id(msg.id());
+    resp.set_message_type(trust_dns_proto::op::MessageType::Response);
+    resp.set_op_code(msg.op_code());
+    resp.set_recursion_desired(msg.recursion_desired());
+    resp.set_recursion_available(true);
+    resp.set_response_code(ResponseCode::NoError);
+    if let Some(q) = msg.queries().first() {
+        resp.add_query(q.clone());
+        for ip in ips {
+            let data = match (ip, q.query_type()) {
+                (IpAddr::V4(v4), RecordType::A | RecordType::ANY) => RData::A(ARecord(*v4)),
+                (IpAddr::V6(v6), RecordType::AAAA | RecordType::ANY) => RData::AAAA(AAAARecord(*v6)),
+                _ => continue,
+            };
+            let mut rec = Record::new();
+            rec.set_name(q.name().clone());
+            rec.set_rr_type(data.record_type());
+            rec.set_dns_class(DNSClass::IN);
+            rec.set_ttl(60);
+            rec.set_data(Some(data));
+            resp.add_answer(rec);
+        }
+    }
+    resp
+}
+
+// A recursive query for `name`/`rtype`, as a client would send it.
+pub fn query_message(id: u16, name: Name, rtype: RecordType) -> Message {
+    let mut msg = Message::new();
+    msg.set_id(id);
+    msg.set_message_type(trust_dns_proto::op::MessageType::Query);
+    msg.set_op_code(trust_dns_proto::op::OpCode::Query);
+    msg.set_recursion_desired(true);
+    msg.add_query(trust_dns_proto::op::Query::query(name, rtype));
+    msg
+}
+
+// NOERROR response answering the first question with a single A record.
+fn a_response(msg: &Message, ipv4: Ipv4Addr) -> Message {
+    let mut resp = Message::new();
+    resp.set_id(msg.id());
+    resp.set_message_type(trust_dns_proto::op::MessageType::Response);
+    resp.set_op_code(msg.op_code());
+    resp.set_response_code(ResponseCode::NoError);
+    if let Some(q) = msg.queries().first() {
+        let mut rec = Record::new();
+        rec.set_name(q.name().clone());
+        rec.set_rr_type(RecordType::A);
+        rec.set_dns_class(DNSClass::IN);
+        rec.set_ttl(60);
+        rec.set_data(Some(RData::A(ARecord(ipv4))));
+        resp.add_answer(rec);
+    }
+    resp
+}
+
+// Answer a TXT query with a record per value; used for ACME dns-01 challenges.
+fn txt_response(msg: &Message, values: &[String]) -> Message {
+    let mut resp = local_response(msg, &[]);
+    resp.set_authoritative(true);
+    if let Some(q) = msg.queries().first() {
+        for value in values {
+            let mut rec = Record::new();
+            rec.set_name(q.name().clone());
+            rec.set_rr_type(RecordType::TXT);
+            rec.set_dns_class(DNSClass::IN);
+            rec.set_ttl(0);
+            rec.set_data(Some(RData::TXT(TXT::new(vec![value.clone()]))));
+            resp.add_answer(rec);
+        }
+    }
+    resp
 }
 
-pub async fn forward_udp_to_upstream(pkt: &[u8], upstream: &str) -> Result<Vec<u8>> {
+pub async fn forward_udp_to_upstream(resources: &Arc<Resources>, pkt: &[u8], upstream: &str, timeout: Duration) -> Result<Vec<u8>> {
+    let _slot = resources.try_acquire(ResourceKind::UpstreamSocket)
+        .ok_or_else(|| anyhow::anyhow!("upstream socket limit reached"))?;
     let up = UdpSocket::bind(("0.0.0.0", 0)).await?;
     up.send_to(pkt, upstream).await?;
     let mut buf = vec![0u8; 4096];
-    let res = tokio::time::timeout(Duration::from_secs(3), up.recv_from(&mut buf)).await;
+    let res = tokio::time::timeout(timeout, up.recv_from(&mut buf)).await;
     match res {
         Ok(Ok((n, _))) => Ok(buf[..n].to_vec()),
         _ => Err(anyhow::anyhow!("upstream timeout")),
@@ -0,0 +1,57 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+// Bounds of the upstream timeout, in milliseconds.
+pub const UPSTREAM_TIMEOUT_MS: (u64, u64) = (100, 30_000);
+
+// Forwarding settings that PUT /config can change while the server runs, seeded from
+// RUSTDNS_UPSTREAM (default 1.1.1.1:53) and RUSTDNS_UPSTREAM_TIMEOUT_MS (default 3000). Queries
+// read them as they are forwarded, so a change applies to the next query.
+pub struct RuntimeSettings {
+    upstream: RwLock<String>,
+    upstream_timeout_ms: AtomicU64,
+}
+
+// `addr` as an upstream address: an IP address with an optional port, 53 by default.
+pub fn parse_upstream(addr: &str) -> Option<String> {
+    let addr = addr.trim();
+    if let Ok(sa) = addr.parse::<SocketAddr>() { return Some(sa.to_string()) }
+    let ip = addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok()?;
+    Some(SocketAddr::new(ip, 53).to_string())
+}
+
+pub fn valid_upstream_timeout(ms: u64) -> bool {
+    (UPSTREAM_TIMEOUT_MS.0..=UPSTREAM_TIMEOUT_MS.1).contains(&ms)
+}
+
+impl RuntimeSettings {
+    pub fn from_env() -> Self {
+        let upstream = match crate::envvars::var("RUSTDNS_UPSTREAM") {
+            Ok(v) => parse_upstream(&v).unwrap_or_else(|| {
+                tracing::warn!("ignoring invalid RUSTDNS_UPSTREAM {:?}", v);
+                "1.1.1.1:53".to_string()
+            }),
+            Err(_) => "1.1.1.1:53".to_string(),
+        };
+        let timeout = crate::envvars::var("RUSTDNS_UPSTREAM_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).filter(|ms| valid_upstream_timeout(*ms)).unwrap_or(3000);
+        RuntimeSettings { upstream: RwLock::new(upstream), upstream_timeout_ms: AtomicU64::new(timeout) }
+    }
+
+    pub fn upstream(&self) -> String {
+        self.upstream.read().unwrap().clone()
+    }
+
+    pub fn set_upstream(&self, upstream: String) {
+        *self.upstream.write().unwrap() = upstream;
+    }
+
+    pub fn upstream_timeout(&self) -> Duration {
+        Duration::from_millis(self.upstream_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn set_upstream_timeout(&self, ms: u64) {
+        self.upstream_timeout_ms.store(ms, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::configfile::ConfigFile;
+use crate::state::ServerState;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// Signals the standalone binary acts on: SIGTERM and SIGINT (Ctrl-C) start the graceful shutdown
+// rustdns_stop starts for FFI hosts, and a second one exits at once. SIGHUP re-reads the config
+// file and reloads the lists, for `systemctl reload`. Windows has Ctrl-C only.
+pub async fn handle_signals(state: Arc<ServerState>, mut config: Option<ConfigFile>, listen: (String, String), shutdown: watch::Sender<bool>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (Ok(mut term), Ok(mut int), Ok(mut hup)) = (signal(SignalKind::terminate()), signal(SignalKind::interrupt()), signal(SignalKind::hangup())) else {
+            tracing::warn!("installing signal handlers failed; SIGTERM and SIGHUP won't be handled");
+            // dropping `shutdown` would look like a shutdown request to some listeners
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::select! {
+                _ = term.recv() => { tracing::info!("SIGTERM received; shutting down"); break }
+                _ = int.recv() => { tracing::info!("SIGINT received; shutting down"); break }
+                _ = hup.recv() => {
+                    tracing::info!("SIGHUP received; reloading the configuration and lists");
+                    reload(&state, config.as_mut(), &listen).await;
+                }
+            }
+        }
+        let _ = shutdown.send(true);
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = int.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (&state, &mut config, &listen);
+        if tokio::signal::ctrl_c().await.is_err() { return std::future::pending().await }
+        tracing::info!("Ctrl-C received; shutting down");
+        let _ = shutdown.send(true);
+        if tokio::signal::ctrl_c().await.is_err() { return }
+    }
+    tracing::warn!("second shutdown signal received; exiting without finishing shutdown");
+    std::process::exit(1);
+}
+
+// Apply what changed in the config file as PUT /config would, and reload the lists. Changes that
+// need a restart are named in the log and otherwise left alone.
+#[cfg(unix)]
+async fn reload(state: &Arc<ServerState>, config: Option<&mut ConfigFile>, listen: &(String, String)) {
+    use crate::config::{apply_config, effective_config};
+    if let Some(config) = config {
+        match config.reread() {
+            Ok(changes) => {
+                if !changes.need_restart.is_empty() {
+                    tracing::warn!("{} changed in {}; restart to apply", changes.need_restart.join(", "), config.path.display());
+                }
+                if !changes.update.is_empty() {
+                    let current = effective_config(state, &listen.0, &listen.1).await;
+                    // only what was applied counts as applied, so a rejected change is tried again
+                    // on the next SIGHUP instead of being taken for the current setting
+                    match apply_config(state, &current, &serde_json::Value::Object(changes.update.clone())).await {
+                        Ok(changed) => {
+                            if !changed.is_empty() { tracing::info!("applied {} from {}", changed.join(", "), config.path.display()); }
+                            config.commit(changes);
+                        }
+                        Err(errors) => {
+                            for (setting, error) in errors {
+                                tracing::warn!("{} in {}: {}; no setting was changed", setting, config.path.display(), error.as_str().unwrap_or_default());
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => tracing::warn!("re-reading the config file failed: {}; keeping the current settings", e),
+        }
+    }
+    match state.reload_lists(&state.lists_dir).await {
+        Ok(report) => tracing::info!("reloaded {} domains (+{} / -{}) from {} files", report.loaded, report.added, report.removed, report.files.len()),
+        Err(e) => tracing::warn!("reloading the lists failed: {:?}", e),
+    }
+}
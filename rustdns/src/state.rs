@@ -1,21 +1,267 @@
 use serde::Serialize;
-use std::collections::HashSet;
-use std::sync::Arc;
-use std::sync::atomic::AtomicU64;
-use tokio::sync::RwLock;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+use crate::listeners::Listener;
+use crate::ratelimit::ClientLimiter;
+use crate::blocklist::{load_blocklists_into, snapshot, BlockingMode, GroupModes, LoadOptions, LoadReport, SharedBlocklist};
+use crate::rdap::RdapClient;
+use crate::trace::DebugClients;
+use crate::resources::{ResourceUsage, Resources};
+use crate::alerts::{Alert, Alerts};
+use crate::brands::BrandGuard;
+use crate::canary::CanarySet;
+use crate::allowlist::Allowlist;
+use crate::acme::Acme;
+use crate::tls::CertStore;
+use crate::dga::{DgaDetector, DgaStats};
+use crate::clients::{ClientIdentity, ClientRegistry};
+use crate::tunnel::{TunnelDetector, TunnelStats};
+use crate::metrics::{LabelStats, QueryCounters, QueryLabels};
+use crate::cache::{CacheStats, ResponseCache};
+use crate::prewarm::Prewarm;
+use crate::totals::{LifetimeTotals, Totals};
+use crate::selfnames::SelfNames;
+use crate::review::{ReviewItem, ReviewQueue};
+use crate::rpz::RpzFeeds;
+use crate::categories::Categories;
+use crate::lifecycle::LifecycleHooks;
+use crate::auth::ApiAuth;
+use crate::health::Health;
+use crate::server::{PacketErrorStats, PacketErrors};
+use crate::cors::CorsPolicy;
+use crate::apilimits::{ApiLimitUsage, ApiLimiter};
+use crate::hits::RuleHits;
+use crate::history::StatsHistory;
+use crate::longterm::{Counts, LongTermStats};
+use crate::querylog::QueryLog;
+use crate::https::HttpTls;
+use crate::savedstate::SavedState;
+use crate::schedule::GroupSchedules;
+use crate::settings::RuntimeSettings;
+use crate::top::TopStats;
 
 #[derive(Clone)]
 pub struct ServerState {
-    pub lists: Arc<RwLock<HashSet<String>>>,
+    pub lists: SharedBlocklist,
+    pub reloading: Arc<AtomicBool>,
+    pub reload_policy: ReloadPolicy,
+    pub load_options: LoadOptions,
+    // since this start; `totals` adds them to the counts of earlier runs
     pub queries: Arc<AtomicU64>,
     pub blocked: Arc<AtomicU64>,
-    pub upstream: String,
-    pub mode: Arc<RwLock<String>>,
-    pub block_page_ip: Arc<RwLock<Option<String>>>,
+    pub totals: Arc<LifetimeTotals>,
+    pub history: Arc<StatsHistory>,
+    pub longterm: Arc<LongTermStats>,
+    pub settings: Arc<RuntimeSettings>,
+    // list files are read from here (RUSTDNS_BLOCKLIST_DIR, default ./blocklist)
+    pub lists_dir: String,
+    // DNS listeners: RUSTDNS_UDP_BIND, then RUSTDNS_UDP_LISTENERS
+    pub listeners: Vec<Listener>,
+    pub mode: Arc<RwLock<BlockingMode>>,
+    pub block_page_ip: Arc<RwLock<Option<Ipv4Addr>>>,
+    pub limiter: Arc<ClientLimiter>,
+    pub rate_limited: Arc<AtomicU64>,
+    pub udp_errors: Arc<PacketErrors>,
+    pub blocking: Arc<BlockingSwitch>,
+    pub events: broadcast::Sender<Event>,
+    pub rdap: Arc<RdapClient>,
+    pub debug_clients: Arc<DebugClients>,
+    pub resources: Arc<Resources>,
+    pub alerts: Arc<Alerts>,
+    pub brands: Arc<BrandGuard>,
+    pub canaries: Arc<CanarySet>,
+    pub allowlist: Arc<Allowlist>,
+    // audit (dry-run) mode: matches are counted and logged but the query is still forwarded
+    pub audit: Arc<AtomicBool>,
+    pub audited: Arc<AtomicU64>,
+    // pad responses to a multiple of this many bytes; 0 disables padding
+    pub response_padding: usize,
+    pub acme: Arc<Acme>,
+    pub certs: Arc<CertStore>,
+    pub dga: Arc<DgaDetector>,
+    pub identity: Arc<ClientIdentity>,
+    pub tunnel: Arc<TunnelDetector>,
+    pub clients: Arc<ClientRegistry>,
+    pub labels: Arc<QueryLabels>,
+    pub counters: Arc<QueryCounters>,
+    pub top: Arc<TopStats>,
+    pub cache: Arc<ResponseCache>,
+    pub prewarm: Arc<Prewarm>,
+    pub self_names: Arc<SelfNames>,
+    pub review: Arc<ReviewQueue>,
+    pub rpz: Arc<RpzFeeds>,
+    pub categories: Arc<Categories>,
+    pub lifecycle: Arc<LifecycleHooks>,
+    pub group_modes: Arc<GroupModes>,
+    pub hits: Arc<RuleHits>,
+    pub schedules: Arc<GroupSchedules>,
+    pub auth: Arc<ApiAuth>,
+    pub http_tls: HttpTls,
+    pub query_log: Arc<QueryLog>,
+    // report of the last successful list load
+    pub last_load: Arc<Mutex<Option<LoadReport>>>,
+    pub health: Arc<Health>,
+    pub cors: Arc<CorsPolicy>,
+    pub api_limits: Arc<ApiLimiter>,
+    pub saved_state: Arc<SavedState>,
+}
+
+// How queries are answered while a blocklist reload is in progress.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReloadPolicy {
+    // Keep matching against the snapshot that was active before the reload started (default).
+    ServePrevious,
+    // Answer SERVFAIL until the new lists are in place, for setups that prefer failing closed.
+    ServFail,
+}
+
+impl ReloadPolicy {
+    // RUSTDNS_RELOAD_POLICY=previous|servfail
+    pub fn from_env() -> Self {
+        match crate::envvars::var("RUSTDNS_RELOAD_POLICY").map(|v| v.to_lowercase()).as_deref() {
+            Ok("servfail") => ReloadPolicy::ServFail,
+            _ => ReloadPolicy::ServePrevious,
+        }
+    }
+}
+
+impl ServerState {
+    // Reload lists from `dir`, flagging the reload window for `reload_policy`.
+    pub async fn reload_lists(&self, dir: &str) -> anyhow::Result<LoadReport> {
+        self.reloading.store(true, Ordering::SeqCst);
+        let res = load_blocklists_into(dir, &self.lists, &self.load_options).await;
+        self.reloading.store(false, Ordering::SeqCst);
+        if let Ok(report) = &res {
+            self.hits.retain_loaded(&*snapshot(&self.lists).await);
+            *self.last_load.lock().unwrap() = Some(report.clone());
+        }
+        res
+    }
+
+    // Write the lifetime totals, including this run's counters.
+    pub fn save_totals(&self) {
+        self.totals.save(self.queries.load(Ordering::Relaxed), self.blocked.load(Ordering::Relaxed));
+    }
+
+    // Add the counters' increase since the last sample to the stats history and the long-term rollups.
+    pub fn sample_history(&self) {
+        let (queries, blocked) = (self.queries.load(Ordering::Relaxed), self.blocked.load(Ordering::Relaxed));
+        self.history.sample(queries, blocked);
+        self.longterm.sample(Counts {
+            queries,
+            blocked,
+            audited: self.audited.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        });
+    }
+
+    // Pause blocking for `duration`, or until resumed when None, and notify event subscribers.
+    pub fn pause_blocking(&self, duration: Option<Duration>) {
+        self.blocking.pause(duration);
+        self.emit_blocking_changed();
+    }
+
+    pub fn resume_blocking(&self) {
+        self.blocking.resume();
+        self.emit_blocking_changed();
+    }
+
+    // Record an alert (deduplicated), log it and push it to event subscribers.
+    pub fn raise_alert(&self, kind: &'static str, client: &str, domain: &str, detail: serde_json::Value) {
+        if let Some(alert) = self.alerts.push(kind, client, domain, detail) {
+            tracing::warn!("alert [{}] {} queried {}: {}", alert.kind, alert.client, alert.domain, alert.detail);
+            let _ = self.events.send(Event::Alert(alert));
+        }
+    }
+
+    pub fn emit_blocking_changed(&self) {
+        let _ = self.events.send(Event::BlockingChanged {
+            blocking_enabled: self.blocking.is_enabled(),
+            seconds_remaining: self.blocking.seconds_remaining(),
+        });
+    }
+}
+
+// Longest timed pause. The API and MQTT reject longer ones; `pause` clamps to it, so no caller
+// can push the resume time past what an `Instant` can hold.
+pub const MAX_PAUSE: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+// Global blocking on/off switch. `None` means blocking is active; `Some(None)` means paused until
+// explicitly resumed; `Some(Some(t))` means paused until `t`.
+#[derive(Default)]
+pub struct BlockingSwitch {
+    paused: Mutex<Option<Option<Instant>>>,
+}
+
+impl BlockingSwitch {
+    pub fn pause(&self, duration: Option<Duration>) {
+        *self.paused.lock().unwrap() = Some(duration.map(|d| Instant::now() + d.min(MAX_PAUSE)));
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = None;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        match *self.paused.lock().unwrap() {
+            None => true,
+            Some(None) => false,
+            Some(Some(until)) => Instant::now() >= until,
+        }
+    }
+
+    // Seconds until a timed pause ends; None when blocking is active or paused indefinitely.
+    pub fn seconds_remaining(&self) -> Option<u64> {
+        match *self.paused.lock().unwrap() {
+            Some(Some(until)) => until.checked_duration_since(Instant::now()).map(|d| d.as_secs() + u64::from(d.subsec_nanos() > 0)),
+            _ => None,
+        }
+    }
+
+    // Clear a timed pause whose deadline has passed. Returns true if blocking was re-enabled.
+    pub fn expire_if_due(&self) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        if let Some(Some(until)) = *paused {
+            if Instant::now() >= until {
+                *paused = None;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// Change notifications pushed to `/events` subscribers.
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    BlockingChanged { blocking_enabled: bool, seconds_remaining: Option<u64> },
+    Alert(Alert),
+    // someone asked for a blocked domain to be unblocked (see /review)
+    UnblockRequested(ReviewItem),
+    // a group schedule's window opened or closed
+    GroupScheduled { group: String, enabled: bool },
 }
 
 #[derive(Serialize)]
 pub struct Stats {
     pub queries: u64,
     pub blocked: u64,
+    pub uptime_seconds: u64,
+    pub lifetime: Totals,
+    pub rate_limited: u64,
+    pub blocking_enabled: bool,
+    pub seconds_remaining: Option<u64>,
+    pub audit: bool,
+    pub audited: u64,
+    pub dga: DgaStats,
+    pub tunnel: TunnelStats,
+    pub labels: LabelStats,
+    pub cache: CacheStats,
+    pub resources: ResourceUsage,
+    pub api: ApiLimitUsage,
+    pub udp_errors: PacketErrorStats,
 }
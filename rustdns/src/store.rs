@@ -0,0 +1,43 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Directory for state that has to survive restarts (RUSTDNS_DATA_DIR, default ./data).
+pub fn data_dir() -> PathBuf {
+    PathBuf::from(crate::envvars::var("RUSTDNS_DATA_DIR").unwrap_or_else(|_| "./data".to_string()))
+}
+
+// Read `path` as JSON; None when the file doesn't exist yet.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match std::fs::read(path) {
+        Ok(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Write `value` to `path` via a temporary file and rename, so a crash never leaves a torn file.
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent() { std::fs::create_dir_all(dir)?; }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// `write_json`, with the data flushed to disk before the rename and the rename flushed after it,
+// for state that has to survive a power cut.
+pub fn write_json_synced<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let tmp = path.with_extension("json.tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(value)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    // directories can't be opened for syncing on every platform
+    if let Ok(d) = std::fs::File::open(dir) { let _ = d.sync_all(); }
+    Ok(())
+}
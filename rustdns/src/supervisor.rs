@@ -0,0 +1,48 @@
+use crate::server::wait_for_shutdown;
+use crate::state::ServerState;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// Wait before the first restart of a failed component; doubled after each failure that follows
+// soon after the last one, up to MAX_BACKOFF.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// A component that ran this long before failing is restarted after MIN_BACKOFF again.
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+// Run the component `name` until shutdown, starting it again each time it fails: returns an error,
+// panics, or ends before shutdown. Its status in GET /healthz follows along.
+pub async fn supervise<F, Fut>(state: Arc<ServerState>, name: &'static str, mut shutdown: watch::Receiver<bool>, mut start: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        state.health.set_component(name, "running", None);
+        let started = Instant::now();
+        let error = match tokio::spawn(start()).await {
+            Ok(Ok(())) if *shutdown.borrow() => break,
+            Ok(Ok(())) => "stopped unexpectedly".to_string(),
+            Ok(Err(e)) => e,
+            Err(e) if e.is_panic() => {
+                let panic = e.into_panic();
+                let message = panic.downcast_ref::<String>().cloned().or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()));
+                format!("panicked: {}", message.unwrap_or_default())
+            }
+            Err(e) => e.to_string(),
+        };
+        if started.elapsed() >= HEALTHY_RUN { backoff = MIN_BACKOFF; }
+        tracing::error!("component {} failed: {}; restarting in {:?}", name, error, backoff);
+        state.health.set_component(name, "restarting", Some(error));
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = wait_for_shutdown(&mut shutdown) => break,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    state.health.set_component(name, "stopped", None);
+}
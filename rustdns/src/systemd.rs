@@ -0,0 +1,151 @@
+use crate::state::ServerState;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// Sockets systemd bound for the service (socket activation: LISTEN_PID / LISTEN_FDS), so port 53
+// can be served without root or CAP_NET_BIND_SERVICE. Listeners whose address matches one take
+// it instead of binding.
+#[derive(Default)]
+pub struct Sockets {
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+}
+
+impl Sockets {
+    // The sockets passed to this process; none unless LISTEN_PID names it. Descriptors that are
+    // not UDP or TCP sockets are left alone.
+    #[cfg(unix)]
+    pub fn from_env() -> Self {
+        use std::os::fd::FromRawFd;
+        // passed descriptors start right after stdin, stdout and stderr
+        const FIRST_FD: i32 = 3;
+        let mut sockets = Sockets::default();
+        let pid = std::env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+        if pid != Some(std::process::id()) { return sockets }
+        let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok()).unwrap_or(0);
+        for fd in FIRST_FD..FIRST_FD + count {
+            let mut kind: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let rc = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut kind as *mut _ as *mut libc::c_void, &mut len) };
+            if rc != 0 {
+                tracing::warn!("systemd passed descriptor {} that is not a socket; ignoring it", fd);
+                continue;
+            }
+            // systemd hands the descriptors over without close-on-exec
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            match kind {
+                libc::SOCK_DGRAM => sockets.udp.push(unsafe { UdpSocket::from_raw_fd(fd) }),
+                libc::SOCK_STREAM => sockets.tcp.push(unsafe { TcpListener::from_raw_fd(fd) }),
+                _ => tracing::warn!("systemd passed socket {} that is neither UDP nor TCP; ignoring it", fd),
+            }
+        }
+        sockets
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_env() -> Self {
+        Sockets::default()
+    }
+
+    // The passed UDP socket bound to `addr`, ready for tokio.
+    pub fn take_udp(&mut self, addr: SocketAddr) -> Option<std::io::Result<UdpSocket>> {
+        let i = self.udp.iter().position(|s| s.local_addr().ok() == Some(addr))?;
+        let sock = self.udp.swap_remove(i);
+        Some(sock.set_nonblocking(true).map(|_| sock))
+    }
+
+    // The passed TCP listener bound to `addr`, ready for tokio.
+    pub fn take_tcp(&mut self, addr: SocketAddr) -> Option<std::io::Result<TcpListener>> {
+        let i = self.tcp.iter().position(|s| s.local_addr().ok() == Some(addr))?;
+        let listener = self.tcp.swap_remove(i);
+        Some(listener.set_nonblocking(true).map(|_| listener))
+    }
+
+    // Duplicates of the sockets, for serving them again after the listeners using them stopped.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Sockets {
+            udp: self.udp.iter().map(UdpSocket::try_clone).collect::<std::io::Result<_>>()?,
+            tcp: self.tcp.iter().map(TcpListener::try_clone).collect::<std::io::Result<_>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.udp.is_empty() && self.tcp.is_empty()
+    }
+
+    // Passed sockets no listener took, as `udp 0.0.0.0:53`.
+    pub fn unused(&self) -> Vec<String> {
+        let udp = self.udp.iter().filter_map(|s| s.local_addr().ok()).map(|a| format!("udp {}", a));
+        let tcp = self.tcp.iter().filter_map(|s| s.local_addr().ok()).map(|a| format!("tcp {}", a));
+        udp.chain(tcp).collect()
+    }
+}
+
+// Send `message` to the service manager (sd_notify). False when not run by systemd with
+// Type=notify or the message could not be sent.
+#[cfg(unix)]
+pub fn notify(message: &str) -> bool {
+    use std::os::unix::net::UnixDatagram;
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else { return false };
+    let Ok(sock) = UnixDatagram::unbound() else { return false };
+    let sent = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name).and_then(|addr| sock.send_to_addr(message.as_bytes(), &addr))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return false,
+        None => sock.send_to(message.as_bytes(), &path),
+    };
+    if let Err(e) = &sent { tracing::warn!("notifying systemd at {}: {}", path, e); }
+    sent.is_ok()
+}
+
+#[cfg(not(unix))]
+pub fn notify(_message: &str) -> bool {
+    false
+}
+
+// How often systemd expects a watchdog ping: WATCHDOG_USEC, when WATCHDOG_PID is unset or names
+// this process.
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) { return None }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+// Report READY=1 once every DNS listener is bound, then ping the watchdog at half its interval for
+// as long as the DNS server is up, so systemd restarts a resolver whose listeners died or whose
+// runtime hangs. STOPPING=1 once shutdown starts.
+pub async fn run_notify(state: Arc<ServerState>, mut shutdown: watch::Receiver<bool>) {
+    if std::env::var("NOTIFY_SOCKET").is_err() { return }
+    let mut tick = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        if state.health.udp_bound.load(Ordering::Relaxed) { break }
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = crate::server::wait_for_shutdown(&mut shutdown) => { notify("STOPPING=1"); return }
+        }
+    }
+    notify(&format!("READY=1\nSTATUS=answering DNS on {} listener(s)", state.listeners.len()));
+    let watchdog = watchdog_interval();
+    if let Some(interval) = watchdog {
+        tracing::info!("pinging the systemd watchdog every {:?}", interval / 2);
+    }
+    let mut ping = tokio::time::interval(watchdog.map(|i| i / 2).unwrap_or(Duration::from_secs(3600)));
+    loop {
+        tokio::select! {
+            _ = ping.tick() => {
+                if watchdog.is_some() && state.health.udp_bound.load(Ordering::Relaxed) { notify("WATCHDOG=1"); }
+            }
+            _ = crate::server::wait_for_shutdown(&mut shutdown) => break,
+        }
+    }
+    notify("STOPPING=1");
+}
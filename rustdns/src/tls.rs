@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Context, Result};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls_pemfile::Item;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Certificate and key for the encrypted listeners (HTTPS, DoH, DoT). Listeners resolve the
+// certificate through this store on every handshake, so `reload` takes effect for new
+// connections without a restart. Paths come from RUSTDNS_TLS_CERT / RUSTDNS_TLS_KEY and default to
+// cert.pem / key.pem in RUSTDNS_TLS_DIR, where the ACME client writes them.
+pub struct CertStore {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    status: Mutex<CertStatus>,
+}
+
+// Reported by GET /tls.
+#[derive(Clone, Default, Serialize)]
+pub struct CertStatus {
+    pub loaded: bool,
+    pub cert_path: String,
+    pub key_path: String,
+    pub loaded_at: Option<u64>,
+    pub reloads: u64,
+    pub last_error: Option<String>,
+}
+
+impl CertStore {
+    pub fn from_env() -> Self {
+        let dir = PathBuf::from(crate::envvars::var("RUSTDNS_TLS_DIR").unwrap_or_else(|_| "./tls".to_string()));
+        let path = |key: &str, file: &str| crate::envvars::var(key).map(PathBuf::from).unwrap_or_else(|_| dir.join(file));
+        let cert_path = path("RUSTDNS_TLS_CERT", "cert.pem");
+        let key_path = path("RUSTDNS_TLS_KEY", "key.pem");
+        let status = CertStatus {
+            cert_path: cert_path.display().to_string(),
+            key_path: key_path.display().to_string(),
+            ..Default::default()
+        };
+        CertStore { cert_path, key_path, current: RwLock::new(None), status: Mutex::new(status) }
+    }
+
+    // Re-read the certificate and key. On failure the previously loaded pair stays in use.
+    pub fn reload(&self) -> Result<()> {
+        let res = load_certified_key(&self.cert_path, &self.key_path);
+        let mut status = self.status.lock().unwrap();
+        match res {
+            Ok(key) => {
+                *self.current.write().unwrap() = Some(Arc::new(key));
+                status.loaded = true;
+                status.loaded_at = Some(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+                status.reloads += 1;
+                status.last_error = None;
+                Ok(())
+            }
+            Err(e) => {
+                status.last_error = Some(format!("{:#}", e));
+                Err(e)
+            }
+        }
+    }
+
+    // Both files exist; without them there is nothing to load yet.
+    pub fn configured(&self) -> bool {
+        self.cert_path.exists() && self.key_path.exists()
+    }
+
+    pub fn status(&self) -> CertStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Write a self-signed certificate for `names` to the store's paths unless a certificate is
+    // already there. Returns the SHA-256 fingerprint of the new certificate, for clients to pin.
+    #[cfg(feature = "self-signed")]
+    pub fn ensure_self_signed(&self, names: Vec<String>) -> Result<Option<String>> {
+        if self.configured() { return Ok(None) }
+        let cert = rcgen::generate_simple_self_signed(names)?;
+        for dir in [&self.cert_path, &self.key_path].iter().filter_map(|p| p.parent()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        std::fs::write(&self.key_path, cert.serialize_private_key_pem())
+            .with_context(|| format!("writing {}", self.key_path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&self.key_path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        // every serialization signs anew, so the fingerprint is taken from the PEM written
+        let pem = cert.serialize_pem()?;
+        std::fs::write(&self.cert_path, &pem).with_context(|| format!("writing {}", self.cert_path.display()))?;
+        let der = rustls_pemfile::certs(&mut pem.as_bytes())?.into_iter().next().ok_or_else(|| anyhow!("no certificate generated"))?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, &der);
+        Ok(Some(digest.as_ref().iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")))
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(
+        std::fs::File::open(cert_path).with_context(|| format!("opening {}", cert_path.display()))?,
+    ))?;
+    if certs.is_empty() { return Err(anyhow!("no certificates in {}", cert_path.display())) }
+    let mut reader = std::io::BufReader::new(
+        std::fs::File::open(key_path).with_context(|| format!("opening {}", key_path.display()))?,
+    );
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::PKCS8Key(k)) | Some(Item::RSAKey(k)) | Some(Item::ECKey(k)) => break k,
+            Some(_) => continue,
+            None => return Err(anyhow!("no private key in {}", key_path.display())),
+        }
+    };
+    let signing_key = rustls::sign::any_supported_type(&rustls::PrivateKey(key))
+        .map_err(|e| anyhow!("unsupported private key in {}: {}", key_path.display(), e))?;
+    Ok(CertifiedKey::new(certs.into_iter().map(rustls::Certificate).collect(), signing_key))
+}
@@ -0,0 +1,108 @@
+use crate::metrics::{CappedCounter, LabelCount};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Rankings GET /stats/top serves.
+pub const TOP_KINDS: [&str; 3] = ["blocked", "allowed", "clients"];
+
+// Entries GET /stats/top returns by default and at most.
+pub const TOP_DEFAULT: usize = 10;
+pub const TOP_MAX: usize = 100;
+
+const SLOT_SECS: u64 = 3600;
+
+// One hour of counts.
+struct Slot {
+    hour: u64,
+    counters: [CappedCounter; TOP_KINDS.len()],
+    totals: [u64; TOP_KINDS.len()],
+}
+
+// Rolling per-domain and per-client counts behind GET /stats/top: the most blocked names, the
+// most queried names that weren't blocked, and the busiest clients over the last
+// RUSTDNS_TOP_WINDOW_HOURS hours (default 24; 0 turns it off). Counts are kept per hour and the
+// oldest hour is dropped as a new one starts. Each hour tracks at most RUSTDNS_TOP_TRACK values
+// per ranking (default 1000, 200 in the minimal build) the way the /stats labels do, so a flood
+// of random names can't grow memory and the heavy hitters are still counted.
+pub struct TopStats {
+    pub window_hours: u64,
+    track: usize,
+    slots: Mutex<VecDeque<Slot>>,
+}
+
+// A ranking over the window, and how many queries it was drawn from.
+pub struct Ranking {
+    pub top: Vec<LabelCount>,
+    pub total: u64,
+    // unix time of the start of the oldest hour counted
+    pub since: Option<u64>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl TopStats {
+    pub fn from_env() -> Self {
+        let num = |key: &str, default: usize| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        TopStats {
+            window_hours: num("RUSTDNS_TOP_WINDOW_HOURS", 24) as u64,
+            // CappedCounter tracks four times its `top`
+            track: num("RUSTDNS_TOP_TRACK", if cfg!(feature = "minimal") { 200 } else { 1000 }).max(TOP_MAX) / 4,
+            slots: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.window_hours > 0
+    }
+
+    // Count a query by `client` for `name`: under `blocked` or `allowed`, and under `clients`.
+    // Queries that were refused or failed are counted for the client only.
+    pub fn record(&self, client: &str, name: &str, action: &str) {
+        if !self.enabled() { return }
+        let hour = now_secs() / SLOT_SECS;
+        let mut slots = self.slots.lock().unwrap();
+        if slots.back().is_none_or(|s| s.hour != hour) {
+            slots.push_back(Slot { hour, counters: TOP_KINDS.map(|_| CappedCounter::new(self.track)), totals: [0; TOP_KINDS.len()] });
+        }
+        while slots.front().is_some_and(|s| s.hour + self.window_hours <= hour) {
+            slots.pop_front();
+        }
+        let slot = slots.back_mut().unwrap();
+        let domain = match action {
+            "blocked" => Some(0),
+            "audited" | "cached" | "forwarded" | "local" => Some(1),
+            _ => None,
+        };
+        if let Some(i) = domain {
+            slot.counters[i].add(name);
+            slot.totals[i] += 1;
+        }
+        slot.counters[2].add(client);
+        slot.totals[2] += 1;
+    }
+
+    // The `n` highest counts of `kind` (one of TOP_KINDS) over the window.
+    pub fn ranking(&self, kind: &str, n: usize) -> Option<Ranking> {
+        let i = TOP_KINDS.iter().position(|k| *k == kind)?;
+        let hour = now_secs() / SLOT_SECS;
+        let slots = self.slots.lock().unwrap();
+        let live: Vec<&Slot> = slots.iter().filter(|s| s.hour + self.window_hours > hour).collect();
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for slot in &live {
+            for c in slot.counters[i].counts() {
+                *counts.entry(c.label).or_default() += c.count;
+            }
+        }
+        let mut top: Vec<LabelCount> = counts.into_iter().map(|(label, count)| LabelCount { label, count }).collect();
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.label.cmp(&b.label)));
+        top.truncate(n);
+        Some(Ranking {
+            top,
+            total: live.iter().map(|s| s.totals[i]).sum(),
+            since: live.first().map(|s| s.hour * SLOT_SECS),
+        })
+    }
+}
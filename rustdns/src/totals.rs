@@ -0,0 +1,63 @@
+use crate::store::{data_dir, read_json, write_json};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Counts since the first start, as saved in `totals.json`.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Totals {
+    pub queries: u64,
+    pub blocked: u64,
+    // unix time the counting began
+    pub since: u64,
+}
+
+// Lifetime query and blocked totals that survive restarts, kept apart from the since-start
+// counters in `ServerState`. What earlier runs counted is loaded from `totals.json` in the data
+// directory; the current run's counters are added on top whenever the totals are read or saved.
+pub struct LifetimeTotals {
+    path: PathBuf,
+    previous: Totals,
+    started: Instant,
+    // last value written, so an unchanged total isn't rewritten
+    saved: Mutex<Option<(u64, u64)>>,
+}
+
+impl LifetimeTotals {
+    pub fn from_env() -> Self {
+        let path = data_dir().join("totals.json");
+        let previous = match read_json::<Totals>(&path) {
+            Ok(Some(t)) => t,
+            Ok(None) => Totals::default(),
+            Err(e) => {
+                tracing::warn!("ignoring unreadable lifetime totals {}: {:?}", path.display(), e);
+                Totals::default()
+            }
+        };
+        let previous = if previous.since == 0 {
+            Totals { since: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0), ..previous }
+        } else {
+            previous
+        };
+        LifetimeTotals { path, previous, started: Instant::now(), saved: Mutex::new(None) }
+    }
+
+    // Totals including this run's `queries` and `blocked`.
+    pub fn current(&self, queries: u64, blocked: u64) -> Totals {
+        Totals { queries: self.previous.queries + queries, blocked: self.previous.blocked + blocked, since: self.previous.since }
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn save(&self, queries: u64, blocked: u64) {
+        let mut saved = self.saved.lock().unwrap();
+        if *saved == Some((queries, blocked)) { return }
+        match write_json(&self.path, &self.current(queries, blocked)) {
+            Ok(()) => *saved = Some((queries, blocked)),
+            Err(e) => tracing::warn!("saving lifetime totals to {} failed: {:?}", self.path.display(), e),
+        }
+    }
+}
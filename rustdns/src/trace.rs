@@ -0,0 +1,69 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use crate::clients::ClientKey;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Traces kept per debug-enabled client.
+const TRACES_PER_CLIENT: usize = 50;
+
+// Step-by-step record of how the pipeline handled one query.
+#[derive(Clone, Serialize)]
+pub struct Trace {
+    pub name: String,
+    pub qtype: String,
+    pub steps: Vec<TraceStep>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+#[derive(Clone, Serialize)]
+pub struct TraceStep {
+    pub stage: &'static str,
+    pub at_us: u128,
+    pub detail: Value,
+}
+
+impl Trace {
+    pub fn new(name: &str, qtype: &str) -> Self {
+        Trace { name: name.to_string(), qtype: qtype.to_string(), steps: Vec::new(), started: Instant::now() }
+    }
+
+    pub fn step(&mut self, stage: &'static str, detail: Value) {
+        self.steps.push(TraceStep { stage, at_us: self.started.elapsed().as_micros(), detail });
+    }
+}
+
+// Clients whose queries are traced, with their most recent traces.
+#[derive(Default)]
+pub struct DebugClients {
+    clients: Mutex<HashMap<ClientKey, VecDeque<Trace>>>,
+}
+
+impl DebugClients {
+    pub fn set(&self, client: &ClientKey, enabled: bool) {
+        let mut clients = self.clients.lock().unwrap();
+        if enabled {
+            clients.entry(client.clone()).or_default();
+        } else {
+            clients.remove(client);
+        }
+    }
+
+    pub fn is_enabled(&self, client: &ClientKey) -> bool {
+        self.clients.lock().unwrap().contains_key(client)
+    }
+
+    pub fn record(&self, client: &ClientKey, trace: Trace) {
+        if let Some(traces) = self.clients.lock().unwrap().get_mut(client) {
+            if traces.len() >= TRACES_PER_CLIENT { traces.pop_front(); }
+            traces.push_back(trace);
+        }
+    }
+
+    // Recent traces for `client`, oldest first; None if debugging is off for that client.
+    pub fn traces(&self, client: &ClientKey) -> Option<Vec<Trace>> {
+        self.clients.lock().unwrap().get(client).map(|t| t.iter().cloned().collect())
+    }
+}
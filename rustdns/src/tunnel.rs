@@ -0,0 +1,153 @@
+use crate::clients::ClientKey;
+use crate::dga::registrable_domain;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use trust_dns_proto::rr::RecordType;
+
+// Counting window for the volume heuristics.
+const WINDOW: Duration = Duration::from_secs(60);
+
+// Heuristics for DNS tunnelling / exfiltration, per client: unusually long labels, a high volume
+// of TXT/NULL queries, and many distinct subdomains of one domain within a minute. Enabled with
+// RUSTDNS_TUNNEL_DETECT=1; thresholds are RUSTDNS_TUNNEL_LABEL_LEN (default 50),
+// RUSTDNS_TUNNEL_TXT_PER_MIN (60) and RUSTDNS_TUNNEL_SUBDOMAINS_PER_MIN (100). With
+// RUSTDNS_TUNNEL_CLAMP=N a flagged client is held to N queries per second (extra queries are
+// refused) for RUSTDNS_TUNNEL_CLAMP_SECS (default 300).
+pub struct TunnelDetector {
+    pub enabled: bool,
+    pub max_label_len: usize,
+    pub max_txt_per_min: usize,
+    pub max_subdomains_per_min: usize,
+    pub clamp_qps: u64,
+    pub clamp_for: Duration,
+    inner: Mutex<TunnelWindow>,
+    clamped: Mutex<HashMap<ClientKey, Clamp>>,
+    detections: AtomicU64,
+    clamped_queries: AtomicU64,
+}
+
+#[derive(Default)]
+struct TunnelWindow {
+    started: Option<Instant>,
+    txt: HashMap<ClientKey, usize>,
+    subdomains: HashMap<(ClientKey, String), HashSet<String>>,
+}
+
+struct Clamp {
+    until: Instant,
+    second: Instant,
+    count: u64,
+}
+
+// One heuristic that fired for a query.
+pub struct Finding {
+    pub reason: &'static str,
+    pub domain: String,
+    pub detail: Value,
+}
+
+// Reported under `tunnel` in /stats.
+#[derive(Serialize)]
+pub struct TunnelStats {
+    pub enabled: bool,
+    pub detections: u64,
+    pub clamped_clients: usize,
+    pub clamped_queries: u64,
+}
+
+impl TunnelDetector {
+    pub fn from_env() -> Self {
+        let num = |key: &str, default: u64| crate::envvars::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        TunnelDetector {
+            enabled: crate::envvars::var("RUSTDNS_TUNNEL_DETECT").map(|v| v == "1" || v == "true").unwrap_or(false),
+            max_label_len: num("RUSTDNS_TUNNEL_LABEL_LEN", 50) as usize,
+            max_txt_per_min: num("RUSTDNS_TUNNEL_TXT_PER_MIN", 60) as usize,
+            max_subdomains_per_min: num("RUSTDNS_TUNNEL_SUBDOMAINS_PER_MIN", 100) as usize,
+            clamp_qps: num("RUSTDNS_TUNNEL_CLAMP", 0),
+            clamp_for: Duration::from_secs(num("RUSTDNS_TUNNEL_CLAMP_SECS", 300)),
+            inner: Mutex::new(TunnelWindow::default()),
+            clamped: Mutex::new(HashMap::new()),
+            detections: AtomicU64::new(0),
+            clamped_queries: AtomicU64::new(0),
+        }
+    }
+
+    // Feed one query through the heuristics. Each threshold fires once per client, domain and
+    // window; a firing clamps the client when clamping is configured.
+    pub fn observe(&self, client: &ClientKey, name: &str, qtype: RecordType) -> Vec<Finding> {
+        if !self.enabled { return Vec::new() }
+        let name = name.trim_end_matches('.').to_lowercase();
+        let domain = registrable_domain(&name).unwrap_or(&name).to_string();
+        let mut findings = Vec::new();
+
+        if let Some(longest) = name.split('.').map(|l| l.len()).max().filter(|l| *l >= self.max_label_len) {
+            findings.push(Finding { reason: "long_label", domain: domain.clone(), detail: serde_json::json!({ "label_len": longest, "name": name }) });
+        }
+
+        let now = Instant::now();
+        let mut w = self.inner.lock().unwrap();
+        if w.started.map(|s| now.duration_since(s) >= WINDOW).unwrap_or(true) {
+            *w = TunnelWindow { started: Some(now), ..Default::default() };
+        }
+        if matches!(qtype, RecordType::TXT | RecordType::NULL) {
+            let n = w.txt.entry(client.clone()).or_default();
+            *n += 1;
+            if *n == self.max_txt_per_min {
+                findings.push(Finding { reason: "txt_volume", domain: domain.clone(), detail: serde_json::json!({ "txt_null_queries_per_min": *n }) });
+            }
+        }
+        if name.len() > domain.len() {
+            let sub = name[..name.len() - domain.len() - 1].to_string();
+            let seen = w.subdomains.entry((client.clone(), domain.clone())).or_default();
+            // stop growing the set once the threshold is reached; it has already fired
+            if seen.len() < self.max_subdomains_per_min && seen.insert(sub) && seen.len() == self.max_subdomains_per_min {
+                findings.push(Finding { reason: "subdomain_rate", domain: domain.clone(), detail: serde_json::json!({ "unique_subdomains_per_min": seen.len() }) });
+            }
+        }
+        drop(w);
+
+        if !findings.is_empty() {
+            self.detections.fetch_add(findings.len() as u64, Ordering::Relaxed);
+            if self.clamp_qps > 0 {
+                self.clamped.lock().unwrap().insert(client.clone(), Clamp { until: now + self.clamp_for, second: now, count: 0 });
+            }
+        }
+        findings
+    }
+
+    // Whether a query from `client` may proceed under an active clamp.
+    pub fn allow(&self, client: &ClientKey) -> bool {
+        if self.clamp_qps == 0 { return true }
+        let now = Instant::now();
+        let mut clamped = self.clamped.lock().unwrap();
+        let Some(c) = clamped.get_mut(client) else { return true };
+        if now >= c.until {
+            clamped.remove(client);
+            return true;
+        }
+        if now.duration_since(c.second) >= Duration::from_secs(1) {
+            c.second = now;
+            c.count = 0;
+        }
+        c.count += 1;
+        if c.count > self.clamp_qps {
+            self.clamped_queries.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    pub fn stats(&self) -> TunnelStats {
+        let now = Instant::now();
+        TunnelStats {
+            enabled: self.enabled,
+            detections: self.detections.load(Ordering::Relaxed),
+            clamped_clients: self.clamped.lock().unwrap().values().filter(|c| now < c.until).count(),
+            clamped_queries: self.clamped_queries.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::io::Read;
+use std::path::Path;
+
+pub const DAY: i64 = 86_400;
+
+// Where IANA zones are read from; the tzdata package installs them here on Linux.
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+// Largest zoneinfo file read; real ones are a few KiB.
+const MAX_TZIF_SIZE: u64 = 64 * 1024;
+
+// Days since 1970-01-01 of a proleptic Gregorian date.
+pub fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Proleptic Gregorian (year, month, day) of the date `days` after 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(m <= 2), m, d)
+}
+
+// Day of the week of the date `days` after 1970-01-01, Monday = 0.
+pub fn weekday(days: i64) -> usize {
+    (days + 3).rem_euclid(7) as usize
+}
+
+// `Mm.w.d/time` transition rule of a POSIX TZ string: weekday `d` (0 = Sunday) of week `w`
+// (5 = the last) of month `m`, at `time` seconds of local time.
+#[derive(Clone, Copy)]
+struct Transition {
+    month: i64,
+    week: i64,
+    weekday: i64,
+    time: i64,
+}
+
+impl Transition {
+    // Local wall-clock seconds since the epoch at which the transition happens in `year`.
+    fn local_at(&self, year: i64) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        let first_weekday = (first + 4).rem_euclid(7);
+        let mut day = first + (self.weekday - first_weekday).rem_euclid(7) + (self.week - 1) * 7;
+        let next_month = if self.month == 12 { days_from_civil(year + 1, 1, 1) } else { days_from_civil(year, self.month + 1, 1) };
+        while day >= next_month { day -= 7; }
+        day * DAY + self.time
+    }
+}
+
+// A POSIX TZ rule such as `CET-1CEST,M3.5.0,M10.5.0/3`. Offsets are seconds east of UTC.
+struct PosixRule {
+    std: i64,
+    dst: Option<(i64, Transition, Transition)>,
+}
+
+// Skip a zone abbreviation: letters, or anything in `<...>`.
+fn skip_abbrev(s: &str) -> Option<&str> {
+    if let Some(rest) = s.strip_prefix('<') {
+        return rest.split_once('>').map(|(_, r)| r);
+    }
+    let end = s.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(s.len());
+    (end > 0).then(|| &s[end..])
+}
+
+// `[+-]hh[:mm[:ss]]` as seconds, and the rest of `s`. POSIX allows up to 167 hours.
+fn parse_hms(s: &str) -> Option<(i64, &str)> {
+    let (sign, s) = match s.as_bytes().first() {
+        Some(b'-') => (-1, &s[1..]),
+        Some(b'+') => (1, &s[1..]),
+        _ => (1, s),
+    };
+    let end = s.find(|c: char| !c.is_ascii_digit() && c != ':').unwrap_or(s.len());
+    let mut secs = 0;
+    for (i, part) in s[..end].split(':').enumerate() {
+        if i > 2 || part.is_empty() { return None }
+        secs = part.parse::<i64>().ok()?.checked_mul([3600, 60, 1][i])?.checked_add(secs)?;
+    }
+    (secs < 168 * 3600).then_some((sign * secs, &s[end..]))
+}
+
+fn parse_transition(s: &str) -> Option<Transition> {
+    let (date, time) = match s.split_once('/') {
+        Some((d, t)) => (d, parse_hms(t).filter(|(_, rest)| rest.is_empty())?.0),
+        None => (s, 7200),
+    };
+    let mut parts = date.strip_prefix('M')?.split('.').map(|p| p.parse::<i64>().ok());
+    let (Some(Some(month)), Some(Some(week)), Some(Some(weekday)), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else { return None };
+    ((1..=12).contains(&month) && (1..=5).contains(&week) && (0..=6).contains(&weekday)).then_some(Transition { month, week, weekday, time })
+}
+
+impl PosixRule {
+    // Only `M` transition rules are understood, which is what every current zone uses.
+    fn parse(s: &str) -> Option<Self> {
+        let rest = skip_abbrev(s)?;
+        let (west, rest) = parse_hms(rest)?;
+        let std = -west;
+        if rest.is_empty() { return Some(PosixRule { std, dst: None }) }
+        let rest = skip_abbrev(rest)?;
+        let (dst, rest) = if rest.starts_with(',') { (std + 3600, rest) } else {
+            let (west, rest) = parse_hms(rest)?;
+            (-west, rest)
+        };
+        let (start, end) = rest.strip_prefix(',')?.split_once(',')?;
+        Some(PosixRule { std, dst: Some((dst, parse_transition(start)?, parse_transition(end)?)) })
+    }
+
+    fn offset_at(&self, t: i64) -> i64 {
+        let Some((dst, start, end)) = self.dst else { return self.std };
+        let year = civil_from_days((t + self.std).div_euclid(DAY)).0;
+        let start = start.local_at(year) - self.std;
+        let end = end.local_at(year) - dst;
+        // southern-hemisphere zones start daylight time late in the year and end it early
+        let in_dst = if start < end { t >= start && t < end } else { t >= start || t < end };
+        if in_dst { dst } else { self.std }
+    }
+}
+
+// A compiled zoneinfo (TZif) file: its transitions, and the POSIX rule for times after them.
+struct Tzif {
+    transitions: Vec<i64>,
+    // offset after each transition
+    offsets: Vec<i64>,
+    // offset before the first transition
+    initial: i64,
+    rule: Option<PosixRule>,
+}
+
+fn be_int(b: &[u8]) -> i64 {
+    match b.len() {
+        4 => i64::from(i32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        _ => i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+    }
+}
+
+// Bytes of data after a TZif header with `counts` and `size`-byte times; None when the counts
+// don't fit in memory, which no real file has.
+fn data_len(counts: [usize; 6], size: usize) -> Option<usize> {
+    let [isut, isstd, leap, times, types, chars] = counts;
+    [times.checked_mul(size + 1)?, types.checked_mul(6)?, chars, leap.checked_mul(size + 4)?, isstd, isut]
+        .into_iter()
+        .try_fold(0usize, |sum, n| sum.checked_add(n))
+}
+
+impl Tzif {
+    fn parse(data: &[u8]) -> Option<Self> {
+        // header: magic, version, 15 reserved bytes, then six counts
+        let counts = |d: &[u8]| -> Option<[usize; 6]> {
+            if d.len() < 44 || &d[..4] != b"TZif" { return None }
+            let mut c = [0; 6];
+            for (i, v) in c.iter_mut().enumerate() { *v = usize::try_from(be_int(&d[20 + i * 4..24 + i * 4])).ok()?; }
+            Some(c)
+        };
+        // version 2+ files repeat the data with 64-bit times after the version 1 block
+        let (block, size) = if data.get(4).is_some_and(|v| *v >= b'2') {
+            let v1 = data_len(counts(data)?, 4)?.checked_add(44)?;
+            (data.get(v1..)?, 8)
+        } else {
+            (data, 4)
+        };
+        let c = counts(block)?;
+        let [_, _, _, times, types, _] = c;
+        let body = block.get(44..data_len(c, size)?.checked_add(44)?)?;
+        let transitions: Vec<i64> = body[..times * size].chunks(size).map(be_int).collect();
+        let type_offsets: Vec<i64> = body[times * (size + 1)..times * (size + 1) + types * 6].chunks(6).map(|t| be_int(&t[..4])).collect();
+        let offsets = body[times * size..times * (size + 1)].iter()
+            .map(|i| type_offsets.get(usize::from(*i)).copied())
+            .collect::<Option<Vec<i64>>>()?;
+        let footer = &block[44 + body.len()..];
+        let rule = std::str::from_utf8(footer).ok()
+            .and_then(|f| f.strip_prefix('\n'))
+            .and_then(|f| f.split('\n').next())
+            .filter(|f| !f.is_empty())
+            .and_then(PosixRule::parse);
+        Some(Tzif { transitions, offsets, initial: *type_offsets.first()?, rule })
+    }
+
+    fn offset_at(&self, t: i64) -> i64 {
+        match self.transitions.partition_point(|x| *x <= t) {
+            0 if self.transitions.is_empty() => self.rule.as_ref().map(|r| r.offset_at(t)).unwrap_or(self.initial),
+            0 => self.initial,
+            i if i == self.transitions.len() && self.rule.is_some() => self.rule.as_ref().map(|r| r.offset_at(t)).unwrap_or(0),
+            i => self.offsets[i - 1],
+        }
+    }
+}
+
+// Whether `name` can be an IANA zone name (`America/Argentina/Buenos_Aires`, `Etc/GMT+2`): a
+// relative path of letters, digits and `_+-`, so it can't leave the zoneinfo directory.
+fn zoneinfo_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('/')
+        && !name.contains("//")
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "_+-/".contains(c))
+}
+
+// The zoneinfo file at `path`, unless it isn't a regular file or is larger than any real one.
+fn read_zoneinfo(path: &Path) -> Option<Vec<u8>> {
+    let file = std::fs::File::open(path).ok()?;
+    if !file.metadata().ok()?.is_file() { return None }
+    let mut data = Vec::new();
+    file.take(MAX_TZIF_SIZE + 1).read_to_end(&mut data).ok()?;
+    (data.len() as u64 <= MAX_TZIF_SIZE).then_some(data)
+}
+
+enum ZoneKind {
+    Fixed(i64),
+    Tzif(Tzif),
+    Posix(PosixRule),
+}
+
+// A time zone: an IANA name from the system's zoneinfo database (`Europe/Berlin`), a fixed
+// offset (`+02:00`, `UTC`) or a POSIX TZ rule (`CET-1CEST,M3.5.0,M10.5.0/3`).
+pub struct Zone {
+    pub name: String,
+    kind: ZoneKind,
+}
+
+impl Zone {
+    pub fn utc() -> Self {
+        Zone { name: "UTC".to_string(), kind: ZoneKind::Fixed(0) }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        let name = name.trim().trim_start_matches(':');
+        if name.eq_ignore_ascii_case("utc") || name == "Z" { return Ok(Zone::utc()) }
+        if name.starts_with(['+', '-']) {
+            return match parse_hms(name) {
+                Some((secs, "")) if secs.abs() < DAY => Ok(Zone { name: name.to_string(), kind: ZoneKind::Fixed(secs) }),
+                _ => Err(format!("invalid offset {}", name)),
+            };
+        }
+        if zoneinfo_name(name) {
+            if let Some(tzif) = read_zoneinfo(&Path::new(ZONEINFO_DIR).join(name)).and_then(|d| Tzif::parse(&d)) {
+                return Ok(Zone { name: name.to_string(), kind: ZoneKind::Tzif(tzif) });
+            }
+        }
+        match PosixRule::parse(name) {
+            Some(rule) => Ok(Zone { name: name.to_string(), kind: ZoneKind::Posix(rule) }),
+            None => Err(format!("unknown time zone {}", name)),
+        }
+    }
+
+    // The system zone: TZ, else /etc/localtime, else UTC.
+    pub fn local() -> Self {
+        if let Some(zone) = crate::envvars::var("TZ").ok().filter(|t| !t.is_empty()).and_then(|t| Zone::parse(&t).ok()) {
+            return zone;
+        }
+        let Some(tzif) = read_zoneinfo(Path::new("/etc/localtime")).and_then(|d| Tzif::parse(&d)) else { return Zone::utc() };
+        let name = std::fs::read_link("/etc/localtime").ok()
+            .and_then(|p| p.to_string_lossy().split_once("zoneinfo/").map(|(_, n)| n.to_string()))
+            .unwrap_or_else(|| "localtime".to_string());
+        Zone { name, kind: ZoneKind::Tzif(tzif) }
+    }
+
+    // Offset from UTC in seconds at `t` (seconds since the epoch).
+    pub fn offset_at(&self, t: i64) -> i64 {
+        match &self.kind {
+            ZoneKind::Fixed(o) => *o,
+            ZoneKind::Tzif(z) => z.offset_at(t),
+            ZoneKind::Posix(r) => r.offset_at(t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Seconds since the epoch of a UTC date and time.
+    fn utc(y: i64, m: i64, d: i64, h: i64, min: i64, sec: i64) -> i64 {
+        days_from_civil(y, m, d) * DAY + h * 3600 + min * 60 + sec
+    }
+
+    fn posix(rule: &str) -> PosixRule {
+        PosixRule::parse(rule).unwrap_or_else(|| panic!("{} should parse", rule))
+    }
+
+    #[test]
+    fn civil_dates_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(days_from_civil(2000, 2, 29)), (2000, 2, 29));
+        assert_eq!(days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28), 1);
+        for days in days_from_civil(1899, 12, 1)..days_from_civil(2101, 3, 1) {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+        // 1970-01-01 was a Thursday
+        assert_eq!(weekday(0), 3);
+        assert_eq!(weekday(days_from_civil(2024, 3, 31)), 6);
+    }
+
+    #[test]
+    fn transitions_fall_on_the_right_day() {
+        let at = |rule: &str, year| parse_transition(rule).unwrap().local_at(year);
+        // last Sunday of March, at 02:00 by default
+        assert_eq!(at("M3.5.0", 2024), utc(2024, 3, 31, 2, 0, 0));
+        assert_eq!(at("M3.5.0", 2023), utc(2023, 3, 26, 2, 0, 0));
+        // first Sunday of October and April, at a given time
+        assert_eq!(at("M10.1.0", 2024), utc(2024, 10, 6, 2, 0, 0));
+        assert_eq!(at("M4.1.0/3", 2024), utc(2024, 4, 7, 3, 0, 0));
+        // second Sunday of March when the 1st is a Sunday
+        assert_eq!(at("M3.2.0", 2026), utc(2026, 3, 8, 2, 0, 0));
+        // week 5 of a month with only four of that weekday is the fourth, leap day included
+        assert_eq!(at("M2.5.4", 2024), utc(2024, 2, 29, 2, 0, 0));
+        assert_eq!(at("M2.5.4", 2023), utc(2023, 2, 23, 2, 0, 0));
+        // a last week in December stays in the year
+        assert_eq!(at("M12.5.6/23:30", 2024), utc(2024, 12, 28, 23, 30, 0));
+        assert!(parse_transition("M13.1.0").is_none());
+        assert!(parse_transition("M3.6.0").is_none());
+        assert!(parse_transition("J60").is_none());
+    }
+
+    #[test]
+    fn central_european_time_changes() {
+        let cet = posix("CET-1CEST,M3.5.0,M10.5.0/3");
+        // 2024: summer time from 01:00 UTC on 31 March to 01:00 UTC on 27 October
+        assert_eq!(cet.offset_at(utc(2024, 1, 15, 12, 0, 0)), 3600);
+        assert_eq!(cet.offset_at(utc(2024, 3, 31, 0, 59, 59)), 3600);
+        assert_eq!(cet.offset_at(utc(2024, 3, 31, 1, 0, 0)), 7200);
+        assert_eq!(cet.offset_at(utc(2024, 7, 1, 0, 0, 0)), 7200);
+        assert_eq!(cet.offset_at(utc(2024, 10, 27, 0, 59, 59)), 7200);
+        assert_eq!(cet.offset_at(utc(2024, 10, 27, 1, 0, 0)), 3600);
+        // New Year in Berlin is still the old year in UTC
+        assert_eq!(cet.offset_at(utc(2024, 12, 31, 23, 30, 0)), 3600);
+    }
+
+    #[test]
+    fn australian_eastern_time_changes() {
+        let sydney = posix("AEST-10AEDT,M10.1.0,M4.1.0/3");
+        // daylight time ends at 03:00 AEDT on 7 April 2024 (16:00 UTC the day before) ...
+        assert_eq!(sydney.offset_at(utc(2024, 1, 15, 0, 0, 0)), 39_600);
+        assert_eq!(sydney.offset_at(utc(2024, 4, 6, 15, 59, 59)), 39_600);
+        assert_eq!(sydney.offset_at(utc(2024, 4, 6, 16, 0, 0)), 36_000);
+        assert_eq!(sydney.offset_at(utc(2024, 7, 1, 0, 0, 0)), 36_000);
+        // ... and starts at 02:00 AEST on 6 October 2024 (16:00 UTC the day before)
+        assert_eq!(sydney.offset_at(utc(2024, 10, 5, 15, 59, 59)), 36_000);
+        assert_eq!(sydney.offset_at(utc(2024, 10, 5, 16, 0, 0)), 39_600);
+        // across New Year, which comes first in Sydney
+        assert_eq!(sydney.offset_at(utc(2024, 12, 31, 14, 0, 0)), 39_600);
+        assert_eq!(sydney.offset_at(utc(2025, 1, 1, 0, 0, 0)), 39_600);
+    }
+
+    #[test]
+    fn posix_rules_without_daylight_time_or_with_explicit_offsets() {
+        assert_eq!(posix("JST-9").offset_at(0), 9 * 3600);
+        assert_eq!(posix("<+0330>-3:30").offset_at(0), 12_600);
+        assert_eq!(posix("<-03>3").offset_at(0), -3 * 3600);
+        // daylight time an explicit 30 minutes ahead (Lord Howe Island)
+        let lhi = posix("<+1030>-10:30<+11>-11,M10.1.0,M4.1.0");
+        assert_eq!(lhi.offset_at(utc(2024, 1, 15, 0, 0, 0)), 39_600);
+        assert_eq!(lhi.offset_at(utc(2024, 7, 1, 0, 0, 0)), 37_800);
+        assert!(PosixRule::parse("CET-1CEST,J60,J300").is_none());
+        assert!(PosixRule::parse("-1").is_none());
+    }
+
+    #[test]
+    fn fixed_offsets() {
+        let offset = |name: &str| Zone::parse(name).map(|z| z.offset_at(utc(2024, 7, 1, 0, 0, 0)));
+        assert_eq!(offset("UTC"), Ok(0));
+        assert_eq!(offset("utc"), Ok(0));
+        assert_eq!(offset("Z"), Ok(0));
+        assert_eq!(offset("+02:00"), Ok(7200));
+        assert_eq!(offset("+05:30"), Ok(19_800));
+        assert_eq!(offset("-03"), Ok(-10_800));
+        assert_eq!(offset("-09:30:15"), Ok(-34_215));
+        assert_eq!(offset("+24:00"), Err("invalid offset +24:00".to_string()));
+        assert_eq!(offset("+02:xx"), Err("invalid offset +02:xx".to_string()));
+        assert_eq!(offset("+1:2:3:4"), Err("invalid offset +1:2:3:4".to_string()));
+        assert_eq!(Zone::parse("+05:30").unwrap().name, "+05:30");
+        assert_eq!(offset("+9223372036854775807"), Err("invalid offset +9223372036854775807".to_string()));
+        assert!(PosixRule::parse("X-168").is_none());
+    }
+
+    #[test]
+    fn zone_names_stay_in_the_zoneinfo_directory() {
+        assert!(zoneinfo_name("Europe/Berlin"));
+        assert!(zoneinfo_name("America/Argentina/Buenos_Aires"));
+        assert!(zoneinfo_name("Etc/GMT+2"));
+        assert!(zoneinfo_name("America/Port-au-Prince"));
+        assert!(!zoneinfo_name(""));
+        assert!(!zoneinfo_name("/etc/localtime"));
+        assert!(!zoneinfo_name("../../../etc/localtime"));
+        assert!(!zoneinfo_name("Europe/../../etc/passwd"));
+        assert!(!zoneinfo_name("Europe//Berlin"));
+        assert!(!zoneinfo_name("Europe/Berlin\0"));
+        assert!(!zoneinfo_name("Europe\\Berlin"));
+        assert!(Zone::parse("/etc/localtime").is_err());
+        assert!(Zone::parse("../../../etc/localtime").is_err());
+    }
+
+    // A TZif header with `counts` (isut, isstd, leap, times, types, chars) and `body` after it.
+    fn tzif(version: u8, counts: [u32; 6], body: &[u8]) -> Vec<u8> {
+        let mut d = b"TZif".to_vec();
+        d.push(version);
+        d.extend([0; 15]);
+        for c in counts { d.extend(c.to_be_bytes()); }
+        d.extend(body);
+        d
+    }
+
+    #[test]
+    fn tzif_files() {
+        // one transition at t = 1000 from UTC to +01:00
+        let mut body = 1000i32.to_be_bytes().to_vec();
+        body.push(1);
+        body.extend([0, 0, 0, 0, 0, 0]);
+        body.extend([0, 0, 0x0e, 0x10, 0, 0]);
+        let z = Tzif::parse(&tzif(0, [0, 0, 0, 1, 2, 0], &body)).unwrap();
+        assert_eq!((z.offset_at(999), z.offset_at(1000)), (0, 3600));
+        assert!(Tzif::parse(&tzif(0, [0, 0, 0, 1, 2, 0], &body[..body.len() - 1])).is_none());
+        assert!(Tzif::parse(b"TZif").is_none());
+        assert!(Tzif::parse(b"").is_none());
+        // counts whose sizes overflow, in the version 1 block and the one after it
+        for version in [0, b'2'] {
+            assert!(Tzif::parse(&tzif(version, [u32::MAX; 6], &body)).is_none());
+            assert!(Tzif::parse(&tzif(version, [0, 0, 0, 0x7fff_ffff, 0x7fff_ffff, 0x7fff_ffff], &body)).is_none());
+        }
+        let mut v2 = tzif(b'2', [0; 6], &[]);
+        v2.extend(tzif(b'2', [u32::MAX; 6], &[]));
+        assert!(Tzif::parse(&v2).is_none());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::state::ServerState;
+use crate::tls::CertStore;
+use notify::{RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+// Watch `dir` for list changes and reload once it has been quiet for `debounce`, so tools that
+// sync lists to disk don't also have to call /reload. Disabled with RUSTDNS_WATCH_LISTS=0;
+// RUSTDNS_WATCH_DEBOUNCE_MS tunes the quiet period (default 1000).
+pub fn spawn_list_watcher(tasks: &mut JoinSet<()>, state: Arc<ServerState>, dir: &str) {
+    if !watch_lists_enabled() { return }
+    let debounce = debounce_from_env();
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(ev) = res {
+            if !ev.kind.is_access() { let _ = tx.send(()); }
+        }
+    }) {
+        Ok(w) => w,
+        Err(e) => {
+            tracing::warn!("list watcher unavailable: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(Path::new(dir), RecursiveMode::Recursive) {
+        tracing::warn!("not watching {} for changes: {:?}", dir, e);
+        return;
+    }
+    tracing::info!("watching {} for list changes", dir);
+
+    let dir = dir.to_string();
+    tasks.spawn(async move {
+        // keep the watcher alive for as long as this task runs
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            // wait until no further events arrive for `debounce`
+            while let Ok(Some(())) = tokio::time::timeout(debounce, rx.recv()).await {}
+            match state.reload_lists(&dir).await {
+                Ok(report) => tracing::info!("lists changed on disk; reloaded {} domains (+{} / -{})", report.loaded, report.added, report.removed),
+                Err(e) => tracing::warn!("automatic reload failed: {:?}", e),
+            }
+        }
+    });
+}
+
+pub fn watch_lists_enabled() -> bool {
+    !crate::envvars::var("RUSTDNS_WATCH_LISTS").map(|v| v == "0" || v == "false").unwrap_or(false)
+}
+
+pub fn debounce_from_env() -> Duration {
+    crate::envvars::var("RUSTDNS_WATCH_DEBOUNCE_MS").ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_millis(1000))
+}
+
+// Reload the TLS certificate when its cert or key file changes (certbot renewals, ACME, manual
+// copies). The containing directories are watched rather than the files, since renewals usually
+// replace files or re-point symlinks.
+pub fn spawn_cert_watcher(tasks: &mut JoinSet<()>, certs: Arc<CertStore>) {
+    let names: Vec<_> = [&certs.cert_path, &certs.key_path].iter().filter_map(|p| p.file_name().map(|n| n.to_os_string())).collect();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(ev) = res {
+            let ours = ev.paths.iter().any(|p| p.file_name().map(|n| names.iter().any(|x| x == n)).unwrap_or(false));
+            if ours && !ev.kind.is_access() { let _ = tx.send(()); }
+        }
+    }) {
+        Ok(w) => w,
+        Err(e) => {
+            tracing::warn!("certificate watcher unavailable: {:?}", e);
+            return;
+        }
+    };
+    let mut dirs: Vec<&Path> = [&certs.cert_path, &certs.key_path].iter()
+        .map(|p| p.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new(".")))
+        .collect();
+    dirs.dedup();
+    let watched = dirs.iter().filter(|d| watcher.watch(d, RecursiveMode::NonRecursive).is_ok()).count();
+    if watched == 0 { return }
+
+    let debounce = debounce_from_env();
+    tasks.spawn(async move {
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            while let Ok(Some(())) = tokio::time::timeout(debounce, rx.recv()).await {}
+            match certs.reload() {
+                Ok(()) => tracing::info!("TLS certificate {} reloaded", certs.cert_path.display()),
+                Err(e) => tracing::warn!("TLS certificate reload failed, keeping the previous one: {:#}", e),
+            }
+        }
+    });
+}
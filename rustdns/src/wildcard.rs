@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+// Whether `pattern` needs the glob matcher: it has a `*` somewhere other than a single leading
+// `*.` or trailing `.*`, which `find_match` handles as plain suffix / prefix rules.
+pub fn is_glob(pattern: &str) -> bool {
+    if !pattern.contains('*') { return false }
+    let simple_suffix = pattern.strip_prefix("*.").is_some_and(|s| !s.contains('*'));
+    let simple_prefix = pattern.strip_suffix(".*").is_some_and(|p| !p.contains('*'));
+    !(simple_suffix || simple_prefix)
+}
+
+// A glob split at its `*`s: the name must start with the first part, end with the last and
+// contain the others in order in between. `*` matches any run of characters, dots included.
+#[derive(Clone)]
+struct Glob {
+    pattern: String,
+    parts: Vec<String>,
+}
+
+impl Glob {
+    fn matches(&self, name: &str) -> bool {
+        let (Some(first), Some(last)) = (self.parts.first(), self.parts.last()) else { return false };
+        if name.len() < first.len() + last.len() || !name.starts_with(first.as_str()) || !name.ends_with(last.as_str()) {
+            return false;
+        }
+        let (mut pos, end) = (first.len(), name.len() - last.len());
+        for part in &self.parts[1..self.parts.len() - 1] {
+            match name[pos..end].find(part.as_str()) {
+                Some(i) => pos += i + part.len(),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+// Glob patterns like `ads.*.example.com`, compiled once on insert. They are indexed by the
+// label-aligned tail of their fixed ending (`example.com` for the example), so a lookup only
+// tries the patterns filed under one of the name's own tails instead of every glob.
+#[derive(Clone, Default)]
+pub struct GlobMatcher {
+    by_tail: HashMap<String, Vec<Glob>>,
+    len: usize,
+}
+
+// The longest whole-label tail every name ending in `last` shares.
+fn tail_key(last: &str) -> &str {
+    match last.strip_prefix('.') {
+        Some(rest) => rest,
+        None => last.split_once('.').map(|(_, rest)| rest).unwrap_or(""),
+    }
+}
+
+impl GlobMatcher {
+    pub fn insert(&mut self, pattern: &str) {
+        let parts: Vec<String> = pattern.split('*').map(str::to_string).collect();
+        let key = tail_key(parts.last().map(String::as_str).unwrap_or("")).to_string();
+        self.by_tail.entry(key).or_default().push(Glob { pattern: pattern.to_string(), parts });
+        self.len += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Patterns matching `name` (lowercase, no trailing dot), most specific tail first.
+    pub fn matches<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let mut tails = vec![name];
+        let mut cur = name;
+        while let Some((_, rest)) = cur.split_once('.') {
+            tails.push(rest);
+            cur = rest;
+        }
+        tails.push("");
+        tails.into_iter()
+            .filter_map(|t| self.by_tail.get(t))
+            .flatten()
+            .filter(move |g| g.matches(name))
+            .map(|g| g.pattern.as_str())
+    }
+}
@@ -0,0 +1,4 @@
+// uniffi's binding generator, built with the crate's uniffi version; see bindings/generate.sh.
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
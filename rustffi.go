@@ -35,3 +35,20 @@ func StopRustLinked() error {
     }
     return nil
 }
+
+// PauseRustLinked pauses blocking for `seconds` (0 = until ResumeRustLinked).
+func PauseRustLinked(seconds uint) error {
+    rc := C.rustdns_pause(C.uint(seconds))
+    if rc != 0 {
+        return fmt.Errorf("rustdns_pause returned %d", int(rc))
+    }
+    return nil
+}
+
+func ResumeRustLinked() error {
+    rc := C.rustdns_resume()
+    if rc != 0 {
+        return fmt.Errorf("rustdns_resume returned %d", int(rc))
+    }
+    return nil
+}
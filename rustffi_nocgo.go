@@ -14,3 +14,11 @@ func StartRustLinked(httpAddr, udpBind string) error {
 func StopRustLinked() error {
     return fmt.Errorf("StopRustLinked unavailable: CGO is disabled")
 }
+
+func PauseRustLinked(seconds uint) error {
+    return fmt.Errorf("PauseRustLinked unavailable: CGO is disabled")
+}
+
+func ResumeRustLinked() error {
+    return fmt.Errorf("ResumeRustLinked unavailable: CGO is disabled")
+}
@@ -11,3 +11,11 @@ func StartRustLinked(httpAddr, udpBind string) error {
 func StopRustLinked() error {
     return fmt.Errorf("StopRustLinked not supported on Windows in this build")
 }
+
+func PauseRustLinked(seconds uint) error {
+    return fmt.Errorf("PauseRustLinked not supported on Windows in this build")
+}
+
+func ResumeRustLinked() error {
+    return fmt.Errorf("ResumeRustLinked not supported on Windows in this build")
+}